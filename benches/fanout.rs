@@ -0,0 +1,33 @@
+use bytes::Bytes;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use ws_s::socket_manager::SocketManager;
+
+/// 接続数ごとにブロードキャスト1回分の配信 (キューへの投入と取り出し) を計測する
+fn fanout(c: &mut Criterion) {
+    let payload = Bytes::from(vec![b'x'; 256]);
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+
+    let mut group = c.benchmark_group("broadcast");
+    for connections in [10, 100, 1000] {
+        let manager = SocketManager::default();
+        let queues: Vec<_> = (0..connections).map(|_| manager.add().1).collect();
+
+        group.throughput(Throughput::Elements(connections as u64));
+        group.bench_with_input(
+            BenchmarkId::from_parameter(connections),
+            &connections,
+            |b, _| {
+                b.to_async(&runtime).iter(|| async {
+                    manager.broadcast(payload.clone());
+                    for queue in queues.iter() {
+                        let _ = queue.recv().await;
+                    }
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, fanout);
+criterion_main!(benches);
@@ -1,8 +1,8 @@
+use log::info;
+use serde::{Deserialize, Serialize};
 use std::fmt::Debug;
 use std::io::{Cursor, Read};
-use serde::{Deserialize, Serialize};
 use wasm_bindgen::prelude::wasm_bindgen;
-use log::info;
 
 #[wasm_bindgen]
 #[derive(Eq, Serialize, Deserialize, Clone, Copy)]
@@ -11,6 +11,14 @@ pub enum MessageType {
     Exit,
     FileTransfer,
     List,
+    Error,
+    Join,
+    UploadBegin,
+    UploadChunk,
+    UploadCommit,
+    UploadAck,
+    UploadProgress,
+    Moderation,
     Unknown,
 }
 
@@ -21,6 +29,14 @@ impl MessageType {
             MessageType::Exit => 0x02,
             MessageType::FileTransfer => 0x03,
             MessageType::List => 0x04,
+            MessageType::Error => 0x05,
+            MessageType::Join => 0x06,
+            MessageType::UploadBegin => 0x07,
+            MessageType::UploadChunk => 0x08,
+            MessageType::UploadCommit => 0x09,
+            MessageType::UploadAck => 0x0a,
+            MessageType::UploadProgress => 0x0b,
+            MessageType::Moderation => 0x0c,
             MessageType::Unknown => 0x00,
         }
     }
@@ -31,6 +47,14 @@ impl MessageType {
             0x02 => Ok(MessageType::Exit),
             0x03 => Ok(MessageType::FileTransfer),
             0x04 => Ok(MessageType::List),
+            0x05 => Ok(MessageType::Error),
+            0x06 => Ok(MessageType::Join),
+            0x07 => Ok(MessageType::UploadBegin),
+            0x08 => Ok(MessageType::UploadChunk),
+            0x09 => Ok(MessageType::UploadCommit),
+            0x0a => Ok(MessageType::UploadAck),
+            0x0b => Ok(MessageType::UploadProgress),
+            0x0c => Ok(MessageType::Moderation),
             0x00 => Ok(MessageType::Unknown),
             _ => Err("Invalid message category (1)".to_string()),
         }
@@ -44,6 +68,14 @@ impl Debug for MessageType {
             MessageType::Exit => write!(f, "Exit"),
             MessageType::FileTransfer => write!(f, "FileTransfer"),
             MessageType::List => write!(f, "List"),
+            MessageType::Error => write!(f, "Error"),
+            MessageType::Join => write!(f, "Join"),
+            MessageType::UploadBegin => write!(f, "UploadBegin"),
+            MessageType::UploadChunk => write!(f, "UploadChunk"),
+            MessageType::UploadCommit => write!(f, "UploadCommit"),
+            MessageType::UploadAck => write!(f, "UploadAck"),
+            MessageType::UploadProgress => write!(f, "UploadProgress"),
+            MessageType::Moderation => write!(f, "Moderation"),
             MessageType::Unknown => write!(f, "Unknown"),
         }
     }
@@ -78,6 +110,14 @@ pub enum UnifiedMessage {
     FileTransferMessage(FileTransferMessage),
     ListMessage(ListMessage),
     Exit(ExitMessage),
+    Error(ErrorMessage),
+    Join(JoinMessage),
+    UploadBegin(UploadBeginMessage),
+    UploadChunk(UploadChunkMessage),
+    UploadCommit(UploadCommitMessage),
+    UploadAck(UploadAckMessage),
+    UploadProgress(UploadProgressMessage),
+    Moderation(ModerationMessage),
 }
 
 pub fn get_type(b: &u8) -> MessageType {
@@ -86,6 +126,14 @@ pub fn get_type(b: &u8) -> MessageType {
         0x02 => MessageType::Exit,
         0x03 => MessageType::FileTransfer,
         0x04 => MessageType::List,
+        0x05 => MessageType::Error,
+        0x06 => MessageType::Join,
+        0x07 => MessageType::UploadBegin,
+        0x08 => MessageType::UploadChunk,
+        0x09 => MessageType::UploadCommit,
+        0x0a => MessageType::UploadAck,
+        0x0b => MessageType::UploadProgress,
+        0x0c => MessageType::Moderation,
         _ => MessageType::Unknown,
     }
 }
@@ -98,6 +146,14 @@ impl BinarySerializable for UnifiedMessage {
             UnifiedMessage::FileTransferMessage(msg) => msg.to_bytes(),
             UnifiedMessage::ListMessage(msg) => msg.to_bytes(),
             UnifiedMessage::Exit(msg) => msg.to_bytes(), // TextMessage の to_bytes を呼び出し
+            UnifiedMessage::Error(msg) => msg.to_bytes(),
+            UnifiedMessage::Join(msg) => msg.to_bytes(),
+            UnifiedMessage::UploadBegin(msg) => msg.to_bytes(),
+            UnifiedMessage::UploadChunk(msg) => msg.to_bytes(),
+            UnifiedMessage::UploadCommit(msg) => msg.to_bytes(),
+            UnifiedMessage::UploadAck(msg) => msg.to_bytes(),
+            UnifiedMessage::UploadProgress(msg) => msg.to_bytes(),
+            UnifiedMessage::Moderation(msg) => msg.to_bytes(),
         }
     }
 }
@@ -130,6 +186,38 @@ impl BinaryDeserializable for UnifiedMessage {
                 let message = ListMessage::from_bytes(data)?;
                 Ok(UnifiedMessage::ListMessage(message))
             }
+            MessageType::Error => {
+                let message = ErrorMessage::from_bytes(data)?;
+                Ok(UnifiedMessage::Error(message))
+            }
+            MessageType::Join => {
+                let message = JoinMessage::from_bytes(data)?;
+                Ok(UnifiedMessage::Join(message))
+            }
+            MessageType::UploadBegin => {
+                let message = UploadBeginMessage::from_bytes(data)?;
+                Ok(UnifiedMessage::UploadBegin(message))
+            }
+            MessageType::UploadChunk => {
+                let message = UploadChunkMessage::from_bytes(data)?;
+                Ok(UnifiedMessage::UploadChunk(message))
+            }
+            MessageType::UploadCommit => {
+                let message = UploadCommitMessage::from_bytes(data)?;
+                Ok(UnifiedMessage::UploadCommit(message))
+            }
+            MessageType::UploadAck => {
+                let message = UploadAckMessage::from_bytes(data)?;
+                Ok(UnifiedMessage::UploadAck(message))
+            }
+            MessageType::UploadProgress => {
+                let message = UploadProgressMessage::from_bytes(data)?;
+                Ok(UnifiedMessage::UploadProgress(message))
+            }
+            MessageType::Moderation => {
+                let message = ModerationMessage::from_bytes(data)?;
+                Ok(UnifiedMessage::Moderation(message))
+            }
             _ => Err("Invalid message category (2)".to_string()),
         }
     }
@@ -427,6 +515,11 @@ impl BinaryDeserializable for FileTransferMessage {
             .read_exact(&mut content_len_buf)
             .map_err(|_| "Failed to read content length")?;
         let content_len = u32::from_be_bytes(content_len_buf) as usize;
+        // 宣言された長さを鵜呑みにして巨大なバッファを確保しないよう、残りのバイト数と比較する
+        let remaining = data.len().saturating_sub(cursor.position() as usize);
+        if content_len > remaining {
+            return Err("Content length exceeds frame size".to_string());
+        }
         let mut content_buf = vec![0u8; content_len];
         cursor
             .read_exact(&mut content_buf)
@@ -447,6 +540,76 @@ impl BinaryDeserializable for FileTransferMessage {
     }
 }
 
+/// `FileTransferMessage` の本文より前の部分
+///
+/// 本文をコピーせずに送信者や宣言されたサイズを確認するために使う。
+#[derive(Debug, Eq, PartialEq)]
+pub struct FileTransferHeader {
+    pub sender: String,
+    pub filename: String,
+    pub room: i32,
+    pub content_len: u32,
+}
+
+impl FileTransferHeader {
+    pub fn peek(data: &[u8]) -> Result<Self, String> {
+        let mut cursor = Cursor::new(data);
+
+        let mut category_buf = [0u8; 1];
+        cursor
+            .read_exact(&mut category_buf)
+            .map_err(|_| "Failed to read category")?;
+        if MessageType::from_bytes(&category_buf[0])? != MessageType::FileTransfer {
+            return Err("Not a file transfer message".to_string());
+        }
+
+        let mut room_buf = [0u8; 4];
+        cursor
+            .read_exact(&mut room_buf)
+            .map_err(|_| "Failed to read room")?;
+        let room = i32::from_be_bytes(room_buf);
+
+        let mut sender_len_buf = [0u8; 1];
+        cursor
+            .read_exact(&mut sender_len_buf)
+            .map_err(|_| "Failed to read sender length")?;
+        let mut sender_buf = vec![0u8; sender_len_buf[0] as usize];
+        cursor
+            .read_exact(&mut sender_buf)
+            .map_err(|_| "Failed to read sender")?;
+        let sender = String::from_utf8(sender_buf).map_err(|_| "Invalid UTF-8 in sender")?;
+
+        let mut filename_len_buf = [0u8; 1];
+        cursor
+            .read_exact(&mut filename_len_buf)
+            .map_err(|_| "Failed to read filename length")?;
+        let mut filename_buf = vec![0u8; filename_len_buf[0] as usize];
+        cursor
+            .read_exact(&mut filename_buf)
+            .map_err(|_| "Failed to read filename")?;
+        let filename = String::from_utf8(filename_buf).map_err(|_| "Invalid UTF-8 in filename")?;
+
+        let mut content_len_buf = [0u8; 4];
+        cursor
+            .read_exact(&mut content_len_buf)
+            .map_err(|_| "Failed to read content length")?;
+        let content_len = u32::from_be_bytes(content_len_buf);
+
+        // 宣言されたサイズがフレームに収まっていなければ壊れている
+        let remaining = data.len() as u64 - cursor.position();
+        if u64::from(content_len) > remaining {
+            return Err("Content length exceeds frame size".to_string());
+        }
+
+        Ok(FileTransferHeader {
+            sender,
+            filename,
+            room,
+            content_len,
+        })
+    }
+}
+
 pub struct ExitMessage {}
 impl BinarySerializable for ExitMessage {
     fn to_bytes(&self) -> Vec<u8> {
@@ -557,13 +720,604 @@ impl BinaryDeserializable for ListMessage {
     }
 }
 
+/// サーバーからクライアントへ返すエラーコード
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum ErrorCode {
+    /// 空のフレームを受信した
+    EmptyFrame,
+    /// 不明なカテゴリのフレームを受信した
+    UnknownCategory,
+    /// フレームのデコードに失敗した
+    MalformedFrame,
+    /// 不正なファイル名
+    InvalidFilename,
+    /// 不正なリスト対象
+    InvalidTarget,
+    /// ファイルが大きすぎる
+    FileTooLarge,
+    /// 保存容量の上限を超える
+    QuotaExceeded,
+    /// 分割アップロードの ID が見つからない
+    UnknownUpload,
+    /// チャンクのオフセットがサーバーの受信済みの位置と一致しない
+    OffsetMismatch,
+    /// チャンクまたはファイル全体のチェックサムが一致しない
+    ChecksumMismatch,
+    /// 分割アップロードの内容が開始時の宣言と矛盾する
+    InvalidUpload,
+    /// サーバーの設定で受け付けない種類のファイル
+    FileTypeRejected,
+    /// アップロードされたファイルが検査で断られた
+    UploadRejected,
+    /// 役割が足りず、その操作を許されていない
+    PermissionDenied,
+    /// 送る頻度が高すぎる
+    RateLimited,
+    /// サーバー側の入出力エラー
+    Io,
+    /// 上記以外
+    Other(u16),
+}
+
+impl ErrorCode {
+    pub fn to_u16(&self) -> u16 {
+        match self {
+            ErrorCode::EmptyFrame => 1,
+            ErrorCode::UnknownCategory => 2,
+            ErrorCode::MalformedFrame => 3,
+            ErrorCode::InvalidFilename => 4,
+            ErrorCode::InvalidTarget => 5,
+            ErrorCode::FileTooLarge => 6,
+            ErrorCode::QuotaExceeded => 7,
+            ErrorCode::UnknownUpload => 8,
+            ErrorCode::OffsetMismatch => 9,
+            ErrorCode::ChecksumMismatch => 10,
+            ErrorCode::InvalidUpload => 11,
+            ErrorCode::FileTypeRejected => 12,
+            ErrorCode::UploadRejected => 13,
+            ErrorCode::PermissionDenied => 14,
+            ErrorCode::RateLimited => 15,
+            ErrorCode::Io => 100,
+            ErrorCode::Other(code) => *code,
+        }
+    }
+
+    pub fn from_u16(code: u16) -> Self {
+        match code {
+            1 => ErrorCode::EmptyFrame,
+            2 => ErrorCode::UnknownCategory,
+            3 => ErrorCode::MalformedFrame,
+            4 => ErrorCode::InvalidFilename,
+            5 => ErrorCode::InvalidTarget,
+            6 => ErrorCode::FileTooLarge,
+            7 => ErrorCode::QuotaExceeded,
+            8 => ErrorCode::UnknownUpload,
+            9 => ErrorCode::OffsetMismatch,
+            10 => ErrorCode::ChecksumMismatch,
+            11 => ErrorCode::InvalidUpload,
+            12 => ErrorCode::FileTypeRejected,
+            13 => ErrorCode::UploadRejected,
+            14 => ErrorCode::PermissionDenied,
+            15 => ErrorCode::RateLimited,
+            100 => ErrorCode::Io,
+            code => ErrorCode::Other(code),
+        }
+    }
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub struct ErrorMessage {
+    pub code: ErrorCode,
+    pub reason: String,
+}
+
+impl BinarySerializable for ErrorMessage {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut buffer: Vec<u8> = Vec::new();
+        buffer.push(0x05);
+
+        // エラーコード (u16、ビッグエンディアン形式)
+        buffer.extend(&self.code.to_u16().to_be_bytes());
+
+        // 理由の長さ (u16、ビッグエンディアン形式) と本体
+        let reason_bytes = self.reason.as_bytes();
+        let reason_len = reason_bytes.len().min(u16::MAX as usize);
+        buffer.extend(&(reason_len as u16).to_be_bytes());
+        buffer.extend(&reason_bytes[..reason_len]);
+
+        let checksum: u8 = buffer.iter().fold(0, |acc, &x| acc.wrapping_add(x));
+        buffer.push(checksum);
+
+        buffer
+    }
+}
+
+impl BinaryDeserializable for ErrorMessage {
+    fn from_bytes(data: &[u8]) -> Result<Self, String>
+    where
+        Self: Sized,
+    {
+        let mut cursor = Cursor::new(data);
+
+        let mut category_buf = [0u8; 1];
+        cursor
+            .read_exact(&mut category_buf)
+            .map_err(|_| "Failed to read category")?;
+        if MessageType::from_bytes(&category_buf[0])? != MessageType::Error {
+            return Err("Not an error message".to_string());
+        }
+
+        let mut code_buf = [0u8; 2];
+        cursor
+            .read_exact(&mut code_buf)
+            .map_err(|_| "Failed to read error code")?;
+        let code = ErrorCode::from_u16(u16::from_be_bytes(code_buf));
+
+        let mut reason_len_buf = [0u8; 2];
+        cursor
+            .read_exact(&mut reason_len_buf)
+            .map_err(|_| "Failed to read reason length")?;
+        let reason_len = u16::from_be_bytes(reason_len_buf) as usize;
+        let mut reason_buf = vec![0u8; reason_len];
+        cursor
+            .read_exact(&mut reason_buf)
+            .map_err(|_| "Failed to read reason")?;
+        let reason = String::from_utf8(reason_buf).map_err(|_| "Invalid UTF-8 in reason")?;
+
+        let mut checksum_buf = [0u8; 1];
+        cursor
+            .read_exact(&mut checksum_buf)
+            .map_err(|_| "Failed to read checksum")?;
+
+        Ok(ErrorMessage { code, reason })
+    }
+}
+
+/// ルームへの参加。サーバーはそのルームの直近の履歴を返す
+#[derive(Debug, Eq, PartialEq)]
+pub struct JoinMessage {
+    pub sender: String,
+    pub room: i32,
+}
+
+impl BinarySerializable for JoinMessage {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut buffer: Vec<u8> = Vec::new();
+        buffer.push(0x06);
+        buffer.extend(&self.room.to_be_bytes());
+        buffer.push(self.sender.len() as u8);
+        buffer.extend(self.sender.as_bytes());
+
+        let checksum: u8 = buffer.iter().fold(0, |acc, &x| acc.wrapping_add(x));
+        buffer.push(checksum);
+
+        buffer
+    }
+}
+
+impl BinaryDeserializable for JoinMessage {
+    fn from_bytes(data: &[u8]) -> Result<Self, String>
+    where
+        Self: Sized,
+    {
+        let mut cursor = Cursor::new(data);
+
+        let mut category_buf = [0u8; 1];
+        cursor
+            .read_exact(&mut category_buf)
+            .map_err(|_| "Failed to read category")?;
+        if MessageType::from_bytes(&category_buf[0])? != MessageType::Join {
+            return Err("Not a join message".to_string());
+        }
+
+        let mut room_buf = [0u8; 4];
+        cursor
+            .read_exact(&mut room_buf)
+            .map_err(|_| "Failed to read room")?;
+        let room = i32::from_be_bytes(room_buf);
+
+        let mut sender_len_buf = [0u8; 1];
+        cursor
+            .read_exact(&mut sender_len_buf)
+            .map_err(|_| "Failed to read sender length")?;
+        let sender_len = sender_len_buf[0] as usize;
+        let mut sender_buf = vec![0u8; sender_len];
+        cursor
+            .read_exact(&mut sender_buf)
+            .map_err(|_| "Failed to read sender")?;
+        let sender = String::from_utf8(sender_buf).map_err(|_| "Invalid UTF-8 in sender")?;
+
+        let mut checksum_buf = [0u8; 1];
+        cursor
+            .read_exact(&mut checksum_buf)
+            .map_err(|_| "Failed to read checksum")?;
+
+        Ok(JoinMessage { sender, room })
+    }
+}
+
+/// 分割アップロードの ID。クライアントが決め、再開する時も同じ値を送る
+pub type UploadId = [u8; 16];
+
+/// 固定長のフィールドを読む
+fn read_array<const N: usize>(cursor: &mut Cursor<&[u8]>, what: &str) -> Result<[u8; N], String> {
+    let mut buf = [0u8; N];
+    cursor
+        .read_exact(&mut buf)
+        .map_err(|_| format!("Failed to read {}", what))?;
+    Ok(buf)
+}
+
+/// 1バイトの長さが前に付いた文字列を読む
+fn read_short_string(cursor: &mut Cursor<&[u8]>, what: &str) -> Result<String, String> {
+    let [len] = read_array::<1>(cursor, &format!("{} length", what))?;
+    let mut buf = vec![0u8; len as usize];
+    cursor
+        .read_exact(&mut buf)
+        .map_err(|_| format!("Failed to read {}", what))?;
+    String::from_utf8(buf).map_err(|_| format!("Invalid UTF-8 in {}", what))
+}
+
+/// 先頭のカテゴリを確認する
+fn expect_category(
+    cursor: &mut Cursor<&[u8]>,
+    expected: MessageType,
+    name: &str,
+) -> Result<(), String> {
+    let [category] = read_array::<1>(cursor, "category")?;
+    if MessageType::from_bytes(&category)? != expected {
+        return Err(format!("Not an {} message", name));
+    }
+    Ok(())
+}
+
+fn push_checksum(buffer: &mut Vec<u8>) {
+    let checksum: u8 = buffer.iter().fold(0, |acc, &x| acc.wrapping_add(x));
+    buffer.push(checksum);
+}
+
+/// 分割アップロードの開始。同じ ID で送り直すと中断したところから再開する
+///
+/// サーバーは受信済みのオフセットを `UploadAckMessage` で返す。
+#[derive(Debug, Eq, PartialEq)]
+pub struct UploadBeginMessage {
+    pub upload_id: UploadId,
+    pub sender: String,
+    pub room: i32,
+    pub filename: String,
+    /// ファイル全体の大きさ
+    pub size: u64,
+    /// ファイル全体の SHA-256
+    pub sha256: [u8; 32],
+}
+
+impl BinarySerializable for UploadBeginMessage {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut buffer: Vec<u8> = Vec::new();
+        buffer.push(0x07);
+        buffer.extend(&self.upload_id);
+        buffer.extend(&self.room.to_be_bytes());
+        buffer.push(self.sender.len() as u8);
+        buffer.extend(self.sender.as_bytes());
+        buffer.push(self.filename.len() as u8);
+        buffer.extend(self.filename.as_bytes());
+        buffer.extend(&self.size.to_be_bytes());
+        buffer.extend(&self.sha256);
+
+        push_checksum(&mut buffer);
+        buffer
+    }
+}
+
+impl BinaryDeserializable for UploadBeginMessage {
+    fn from_bytes(data: &[u8]) -> Result<Self, String>
+    where
+        Self: Sized,
+    {
+        let mut cursor = Cursor::new(data);
+        expect_category(&mut cursor, MessageType::UploadBegin, "upload begin")?;
+
+        let upload_id = read_array::<16>(&mut cursor, "upload id")?;
+        let room = i32::from_be_bytes(read_array::<4>(&mut cursor, "room")?);
+        let sender = read_short_string(&mut cursor, "sender")?;
+        let filename = read_short_string(&mut cursor, "filename")?;
+        let size = u64::from_be_bytes(read_array::<8>(&mut cursor, "size")?);
+        let sha256 = read_array::<32>(&mut cursor, "sha256")?;
+        read_array::<1>(&mut cursor, "checksum")?;
+
+        Ok(UploadBeginMessage {
+            upload_id,
+            sender,
+            room,
+            filename,
+            size,
+            sha256,
+        })
+    }
+}
+
+/// 分割アップロードの1チャンク
+#[derive(Debug, Eq, PartialEq)]
+pub struct UploadChunkMessage {
+    pub upload_id: UploadId,
+    /// ファイル中の位置。サーバーが受信済みの位置と一致しなければならない
+    pub offset: u64,
+    /// `data` の CRC-32
+    pub crc32: u32,
+    pub data: Vec<u8>,
+}
+
+impl UploadChunkMessage {
+    /// チェックサムを計算してチャンクを作る
+    pub fn new(upload_id: UploadId, offset: u64, data: Vec<u8>) -> Self {
+        Self {
+            upload_id,
+            offset,
+            crc32: crc32fast::hash(&data),
+            data,
+        }
+    }
+
+    /// `data` が `crc32` と一致するか
+    pub fn verify(&self) -> bool {
+        crc32fast::hash(&self.data) == self.crc32
+    }
+}
+
+impl BinarySerializable for UploadChunkMessage {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut buffer: Vec<u8> = Vec::with_capacity(self.data.len() + 34);
+        buffer.push(0x08);
+        buffer.extend(&self.upload_id);
+        buffer.extend(&self.offset.to_be_bytes());
+        buffer.extend(&self.crc32.to_be_bytes());
+        buffer.extend(&(self.data.len() as u32).to_be_bytes());
+        buffer.extend(&self.data);
+
+        push_checksum(&mut buffer);
+        buffer
+    }
+}
+
+impl BinaryDeserializable for UploadChunkMessage {
+    fn from_bytes(data: &[u8]) -> Result<Self, String>
+    where
+        Self: Sized,
+    {
+        let mut cursor = Cursor::new(data);
+        expect_category(&mut cursor, MessageType::UploadChunk, "upload chunk")?;
+
+        let upload_id = read_array::<16>(&mut cursor, "upload id")?;
+        let offset = u64::from_be_bytes(read_array::<8>(&mut cursor, "offset")?);
+        let crc32 = u32::from_be_bytes(read_array::<4>(&mut cursor, "crc32")?);
+        let len = u32::from_be_bytes(read_array::<4>(&mut cursor, "data length")?) as u64;
+
+        // 宣言された長さを確保する前にフレームに収まっているか確認する
+        if len > data.len() as u64 - cursor.position() {
+            return Err("Chunk length exceeds frame size".to_string());
+        }
+        let mut chunk = vec![0u8; len as usize];
+        cursor
+            .read_exact(&mut chunk)
+            .map_err(|_| "Failed to read chunk data")?;
+        read_array::<1>(&mut cursor, "checksum")?;
+
+        Ok(UploadChunkMessage {
+            upload_id,
+            offset,
+            crc32,
+            data: chunk,
+        })
+    }
+}
+
+/// 分割アップロードの完了。サーバーは大きさと SHA-256 を確かめてから保存する
+#[derive(Debug, Eq, PartialEq)]
+pub struct UploadCommitMessage {
+    pub upload_id: UploadId,
+}
+
+impl BinarySerializable for UploadCommitMessage {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut buffer: Vec<u8> = Vec::new();
+        buffer.push(0x09);
+        buffer.extend(&self.upload_id);
+
+        push_checksum(&mut buffer);
+        buffer
+    }
+}
+
+impl BinaryDeserializable for UploadCommitMessage {
+    fn from_bytes(data: &[u8]) -> Result<Self, String>
+    where
+        Self: Sized,
+    {
+        let mut cursor = Cursor::new(data);
+        expect_category(&mut cursor, MessageType::UploadCommit, "upload commit")?;
+
+        let upload_id = read_array::<16>(&mut cursor, "upload id")?;
+        read_array::<1>(&mut cursor, "checksum")?;
+
+        Ok(UploadCommitMessage { upload_id })
+    }
+}
+
+/// サーバーが受信済みのオフセット。クライアントはここから続きを送る
+#[derive(Debug, Eq, PartialEq)]
+pub struct UploadAckMessage {
+    pub upload_id: UploadId,
+    pub offset: u64,
+}
+
+impl BinarySerializable for UploadAckMessage {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut buffer: Vec<u8> = Vec::new();
+        buffer.push(0x0a);
+        buffer.extend(&self.upload_id);
+        buffer.extend(&self.offset.to_be_bytes());
+
+        push_checksum(&mut buffer);
+        buffer
+    }
+}
+
+impl BinaryDeserializable for UploadAckMessage {
+    fn from_bytes(data: &[u8]) -> Result<Self, String>
+    where
+        Self: Sized,
+    {
+        let mut cursor = Cursor::new(data);
+        expect_category(&mut cursor, MessageType::UploadAck, "upload ack")?;
+
+        let upload_id = read_array::<16>(&mut cursor, "upload id")?;
+        let offset = u64::from_be_bytes(read_array::<8>(&mut cursor, "offset")?);
+        read_array::<1>(&mut cursor, "checksum")?;
+
+        Ok(UploadAckMessage { upload_id, offset })
+    }
+}
+
+/// 分割アップロードの進み具合。サーバーが一定の間隔で送る
+#[derive(Debug, Eq, PartialEq)]
+pub struct UploadProgressMessage {
+    pub upload_id: UploadId,
+    /// 受信済みの大きさ
+    pub received: u64,
+    /// ファイル全体の大きさ
+    pub total: u64,
+}
+
+impl BinarySerializable for UploadProgressMessage {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut buffer: Vec<u8> = Vec::new();
+        buffer.push(0x0b);
+        buffer.extend(&self.upload_id);
+        buffer.extend(&self.received.to_be_bytes());
+        buffer.extend(&self.total.to_be_bytes());
+
+        push_checksum(&mut buffer);
+        buffer
+    }
+}
+
+impl BinaryDeserializable for UploadProgressMessage {
+    fn from_bytes(data: &[u8]) -> Result<Self, String>
+    where
+        Self: Sized,
+    {
+        let mut cursor = Cursor::new(data);
+        expect_category(&mut cursor, MessageType::UploadProgress, "upload progress")?;
+
+        let upload_id = read_array::<16>(&mut cursor, "upload id")?;
+        let received = u64::from_be_bytes(read_array::<8>(&mut cursor, "received")?);
+        let total = u64::from_be_bytes(read_array::<8>(&mut cursor, "total")?);
+        read_array::<1>(&mut cursor, "checksum")?;
+
+        Ok(UploadProgressMessage {
+            upload_id,
+            received,
+            total,
+        })
+    }
+}
+
+/// モデレーターの操作
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ModerationAction {
+    /// 今の接続を切る
+    Kick,
+    /// 切断し、以降の接続も断る
+    Ban,
+    /// `Ban` を取り消す
+    Unban,
+}
+
+impl ModerationAction {
+    pub fn to_u8(&self) -> u8 {
+        match self {
+            ModerationAction::Kick => 1,
+            ModerationAction::Ban => 2,
+            ModerationAction::Unban => 3,
+        }
+    }
+
+    pub fn from_u8(action: u8) -> Result<Self, String> {
+        match action {
+            1 => Ok(ModerationAction::Kick),
+            2 => Ok(ModerationAction::Ban),
+            3 => Ok(ModerationAction::Unban),
+            _ => Err(format!("Invalid moderation action {}", action)),
+        }
+    }
+}
+
+/// キックや BAN の依頼。`target` は `name:<名前>`、`user:<認証された名前>`、`ip:<アドレス>`
+/// または接続の UUID
+#[derive(Debug, Eq, PartialEq)]
+pub struct ModerationMessage {
+    pub action: ModerationAction,
+    pub sender: String,
+    pub room: i32,
+    pub target: String,
+    /// BAN の期間 (秒)。0 なら無期限
+    pub duration_secs: u64,
+    pub reason: String,
+}
+
+impl BinarySerializable for ModerationMessage {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut buffer: Vec<u8> = Vec::new();
+        buffer.push(0x0c);
+        buffer.push(self.action.to_u8());
+        buffer.extend(&self.room.to_be_bytes());
+        buffer.push(self.sender.len() as u8);
+        buffer.extend(self.sender.as_bytes());
+        buffer.push(self.target.len() as u8);
+        buffer.extend(self.target.as_bytes());
+        buffer.extend(&self.duration_secs.to_be_bytes());
+        buffer.push(self.reason.len() as u8);
+        buffer.extend(self.reason.as_bytes());
+
+        push_checksum(&mut buffer);
+        buffer
+    }
+}
+
+impl BinaryDeserializable for ModerationMessage {
+    fn from_bytes(data: &[u8]) -> Result<Self, String>
+    where
+        Self: Sized,
+    {
+        let mut cursor = Cursor::new(data);
+        expect_category(&mut cursor, MessageType::Moderation, "moderation")?;
+
+        let [action] = read_array::<1>(&mut cursor, "action")?;
+        let action = ModerationAction::from_u8(action)?;
+        let room = i32::from_be_bytes(read_array::<4>(&mut cursor, "room")?);
+        let sender = read_short_string(&mut cursor, "sender")?;
+        let target = read_short_string(&mut cursor, "target")?;
+        let duration_secs = u64::from_be_bytes(read_array::<8>(&mut cursor, "duration")?);
+        let reason = read_short_string(&mut cursor, "reason")?;
+        read_array::<1>(&mut cursor, "checksum")?;
+
+        Ok(ModerationMessage {
+            action,
+            sender,
+            room,
+            target,
+            duration_secs,
+            reason,
+        })
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
     #[test]
     fn test_encode_decode() {
-
         let message = TextMessage {
             sender: "Alice".to_string(),
             room: 42,
@@ -577,4 +1331,154 @@ mod tests {
 
         assert_eq!(message, decoded);
     }
+
+    #[test]
+    fn test_error_message_encode_decode() {
+        let message = ErrorMessage {
+            code: ErrorCode::MalformedFrame,
+            reason: "Failed to read room".to_string(),
+        };
+
+        let bytes = message.to_bytes();
+        let decoded = ErrorMessage::from_bytes(&bytes).unwrap();
+
+        assert_eq!(message, decoded);
+        assert!(matches!(
+            UnifiedMessage::from_bytes(&bytes),
+            Ok(UnifiedMessage::Error(_))
+        ));
+    }
+
+    #[test]
+    fn test_truncated_frames_are_rejected() {
+        let message = TextMessage {
+            sender: "Alice".to_string(),
+            room: 42,
+            category: MessageType::Chat,
+            content: "Hello, world!".to_string(),
+        };
+        let bytes = message.to_bytes();
+
+        for len in 0..bytes.len() {
+            assert!(TextMessage::from_bytes(&bytes[..len]).is_err());
+        }
+    }
+
+    #[test]
+    fn test_join_message_encode_decode() {
+        let message = JoinMessage {
+            sender: "Alice".to_string(),
+            room: -7,
+        };
+
+        let bytes = message.to_bytes();
+
+        assert_eq!(JoinMessage::from_bytes(&bytes).unwrap(), message);
+    }
+
+    #[test]
+    fn test_peek_file_transfer_header() {
+        let message = FileTransferMessage {
+            sender: "Alice".to_string(),
+            filename: "report.txt".to_string(),
+            room: 42,
+            content: vec![0xab; 300],
+            category: MessageType::FileTransfer,
+        };
+
+        let header = FileTransferHeader::peek(&message.to_bytes()).unwrap();
+
+        assert_eq!(
+            header,
+            FileTransferHeader {
+                sender: "Alice".to_string(),
+                filename: "report.txt".to_string(),
+                room: 42,
+                content_len: 300,
+            }
+        );
+
+        let truncated = &message.to_bytes()[..100];
+        assert!(FileTransferHeader::peek(truncated).is_err());
+    }
+
+    #[test]
+    fn test_upload_messages_round_trip() {
+        let upload_id = [7u8; 16];
+
+        let begin = UploadBeginMessage {
+            upload_id,
+            sender: "Alice".to_string(),
+            room: 42,
+            filename: "video.mp4".to_string(),
+            size: 5_000_000_000,
+            sha256: [0xab; 32],
+        };
+        assert_eq!(
+            UploadBeginMessage::from_bytes(&begin.to_bytes()).unwrap(),
+            begin
+        );
+
+        let chunk = UploadChunkMessage::new(upload_id, 1 << 33, vec![1, 2, 3, 4]);
+        let decoded = UploadChunkMessage::from_bytes(&chunk.to_bytes()).unwrap();
+        assert!(decoded.verify());
+        assert_eq!(decoded, chunk);
+
+        let mut corrupted = chunk.to_bytes();
+        corrupted[33] ^= 0xff;
+        assert!(!UploadChunkMessage::from_bytes(&corrupted).unwrap().verify());
+
+        let commit = UploadCommitMessage { upload_id };
+        assert_eq!(
+            UploadCommitMessage::from_bytes(&commit.to_bytes()).unwrap(),
+            commit
+        );
+
+        let ack = UploadAckMessage {
+            upload_id,
+            offset: 1 << 33,
+        };
+        assert!(matches!(
+            UnifiedMessage::from_bytes(&ack.to_bytes()),
+            Ok(UnifiedMessage::UploadAck(decoded)) if decoded == ack
+        ));
+
+        let progress = UploadProgressMessage {
+            upload_id,
+            received: 1 << 32,
+            total: 5_000_000_000,
+        };
+        assert!(matches!(
+            UnifiedMessage::from_bytes(&progress.to_bytes()),
+            Ok(UnifiedMessage::UploadProgress(decoded)) if decoded == progress
+        ));
+    }
+
+    #[test]
+    fn test_moderation_message_round_trip() {
+        let message = ModerationMessage {
+            action: ModerationAction::Ban,
+            sender: "Mia".to_string(),
+            room: 3,
+            target: "name:spammer".to_string(),
+            duration_secs: 3600,
+            reason: "flooding".to_string(),
+        };
+        assert!(matches!(
+            UnifiedMessage::from_bytes(&message.to_bytes()),
+            Ok(UnifiedMessage::Moderation(decoded)) if decoded == message
+        ));
+
+        let mut bytes = message.to_bytes();
+        bytes[1] = 9;
+        assert!(ModerationMessage::from_bytes(&bytes).is_err());
+    }
+
+    #[test]
+    fn test_chunk_length_is_checked_before_allocating() {
+        let mut frame = UploadChunkMessage::new([0; 16], 0, vec![0; 8]).to_bytes();
+        // 長さのフィールドを 4GiB 近くに書き換える
+        frame[29..33].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert!(UploadChunkMessage::from_bytes(&frame).is_err());
+    }
 }
@@ -0,0 +1,169 @@
+use super::ApiError;
+use crate::app::AppState;
+use crate::auth::Principal;
+use crate::moderation::{self, Ban, BanTarget};
+use crate::reload::ReloadReport;
+use crate::upload::{FileRecord, Usage};
+use crate::utils::parse_duration;
+use axum::extract::{Path, State};
+use axum::{Extension, Json};
+use serde::{Deserialize, Serialize};
+use std::io;
+
+/// アップロードの上限。`None` は無制限
+#[derive(Debug, Serialize)]
+pub struct LimitsBody {
+    pub max_file_size: u64,
+    pub user_quota: Option<u64>,
+    pub room_quota: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct UsageBody {
+    pub limits: LimitsBody,
+    pub usage: Usage,
+}
+
+/// `GET /api/admin/usage`
+pub async fn usage(State(state): State<AppState>) -> Json<UsageBody> {
+    let limits = state.quota.limits();
+    Json(UsageBody {
+        limits: LimitsBody {
+            max_file_size: limits.max_file_size,
+            user_quota: limits.user_quota,
+            room_quota: limits.room_quota,
+        },
+        usage: state.quota.usage(),
+    })
+}
+
+/// `DELETE /api/admin/files/:id`
+///
+/// 一覧から外し、送信者とルームの使用量を戻す。同じ中身が他に残っていれば blob は消さない。
+pub async fn delete_file(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<Json<FileRecord>, ApiError> {
+    let record = state
+        .uploads
+        .delete(&id)
+        .await
+        .map_err(ApiError::internal)?
+        .ok_or_else(|| ApiError::not_found(format!("no such file: {}", id)))?;
+    state
+        .quota
+        .credit(&record.sender, record.room, record.size)
+        .await;
+    Ok(Json(record))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct KickRequest {
+    pub target: String,
+    #[serde(default)]
+    pub reason: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BanRequest {
+    pub target: String,
+    #[serde(default)]
+    pub reason: String,
+    /// `30m` や `7d`。省略すると無期限
+    pub duration: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct KickBody {
+    /// 切った接続の数
+    pub kicked: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BanBody {
+    pub ban: Ban,
+    pub kicked: usize,
+}
+
+fn parse_target(target: &str) -> Result<BanTarget, ApiError> {
+    target.parse().map_err(ApiError::bad_request)
+}
+
+/// `POST /api/admin/kick`
+pub async fn kick(
+    State(state): State<AppState>,
+    Json(request): Json<KickRequest>,
+) -> Result<Json<KickBody>, ApiError> {
+    let target = parse_target(&request.target)?;
+    let kicked = moderation::kick(&state, &target, &request.reason);
+    Ok(Json(KickBody { kicked }))
+}
+
+/// `GET /api/admin/bans`
+pub async fn list_bans(State(state): State<AppState>) -> Json<Vec<Ban>> {
+    Json(state.bans.list())
+}
+
+/// `POST /api/admin/bans`
+///
+/// 接続の UUID を指定した場合は、その接続の認証された名前か接続元のアドレスを BAN する。
+pub async fn ban(
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+    Json(request): Json<BanRequest>,
+) -> Result<Json<BanBody>, ApiError> {
+    let target = parse_target(&request.target)?;
+    let duration = request
+        .duration
+        .as_deref()
+        .map(parse_duration)
+        .transpose()
+        .map_err(ApiError::bad_request)?;
+    let (ban, kicked) = moderation::ban(
+        &state,
+        target,
+        &request.reason,
+        Some(principal.name),
+        duration,
+    )
+    .map_err(|e| match e.kind() {
+        io::ErrorKind::InvalidInput => ApiError::bad_request(e.to_string()),
+        _ => ApiError::internal(e),
+    })?;
+    Ok(Json(BanBody { ban, kicked }))
+}
+
+/// `DELETE /api/admin/bans/:target`
+pub async fn unban(
+    State(state): State<AppState>,
+    Path(target): Path<String>,
+) -> Result<Json<Ban>, ApiError> {
+    let target = parse_target(&target)?;
+    state
+        .bans
+        .unban(&target)
+        .map_err(ApiError::internal)?
+        .map(Json)
+        .ok_or_else(|| ApiError::not_found(format!("{} is not banned", target)))
+}
+
+/// `GET /api/admin/reload`
+pub async fn last_reload(State(state): State<AppState>) -> Result<Json<ReloadReport>, ApiError> {
+    state
+        .reloads
+        .last()
+        .map(Json)
+        .ok_or_else(|| ApiError::not_found("configuration has not been reloaded"))
+}
+
+/// `POST /api/admin/reload`
+///
+/// 読み直しの結果を返す。設定に誤りがあった場合も 200 で、`success` が `false` になる。
+pub async fn reload(State(state): State<AppState>) -> Result<Json<ReloadReport>, ApiError> {
+    state
+        .reloads
+        .reload()
+        .await
+        .map(Json)
+        .ok_or_else(|| ApiError::unavailable("configuration reload did not finish"))
+}
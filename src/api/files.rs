@@ -0,0 +1,246 @@
+use super::{authorize_read, ApiError};
+use crate::app::AppState;
+use crate::auth::{Action, Principal};
+use crate::upload::FileRecord;
+use axum::body::Body;
+use axum::extract::{Path, Query, State};
+use axum::http::header::{
+    ACCEPT_RANGES, CACHE_CONTROL, CONTENT_DISPOSITION, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE,
+    ETAG, IF_NONE_MATCH, RANGE, X_CONTENT_TYPE_OPTIONS,
+};
+use axum::http::{HeaderMap, HeaderValue, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::{Extension, Json};
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use serde::{Deserialize, Serialize};
+use std::ops::Range;
+
+#[derive(Debug, Deserialize)]
+pub struct FilesQuery {
+    /// 指定するとそのルームで共有されたファイルだけ
+    pub room: Option<i32>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct FilesBody {
+    pub files: Vec<FileRecord>,
+}
+
+/// `GET /api/files?room=`
+///
+/// ルームを指定しなければ、読めるルームのファイルだけを返す。
+pub async fn list_files(
+    State(state): State<AppState>,
+    Extension(principal): Extension<Option<Principal>>,
+    Query(query): Query<FilesQuery>,
+) -> Result<Json<FilesBody>, ApiError> {
+    let files = match query.room {
+        Some(room) => {
+            authorize_read(&state, principal.as_ref(), Some(room))?;
+            state.uploads.list_room(room)
+        }
+        None => {
+            let roles = &state.settings.current().roles;
+            state
+                .uploads
+                .list()
+                .into_iter()
+                .filter(|record| roles.allows(principal.as_ref(), record.room, Action::Read))
+                .collect()
+        }
+    };
+    Ok(Json(FilesBody { files }))
+}
+
+/// `Range` ヘッダーの解釈
+#[derive(Debug, Eq, PartialEq)]
+enum RangeRequest {
+    Full,
+    /// 終端を含まない
+    Partial(Range<u64>),
+    Unsatisfiable,
+}
+
+/// `GET /api/files/:id`
+///
+/// 1つだけの `Range` と、中身の SHA-256 を使った `ETag` に対応する。
+pub async fn download(
+    State(state): State<AppState>,
+    Extension(principal): Extension<Option<Principal>>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+) -> Result<Response, ApiError> {
+    let record = state
+        .uploads
+        .get(&id)
+        .ok_or_else(|| ApiError::not_found(format!("no such file: {}", id)))?;
+    authorize_read(&state, principal.as_ref(), record.room)?;
+
+    // 同じ ID の中身は変わらない
+    let etag = format!("\"{}\"", record.sha256);
+    if headers
+        .get(IF_NONE_MATCH)
+        .is_some_and(|value| value.as_bytes() == etag.as_bytes() || value == "*")
+    {
+        return Ok((StatusCode::NOT_MODIFIED, [(ETAG, etag)]).into_response());
+    }
+
+    let range = match headers.get(RANGE).and_then(|value| value.to_str().ok()) {
+        Some(value) => parse_range(value, record.size),
+        None => RangeRequest::Full,
+    };
+    let (status, range) = match range {
+        RangeRequest::Full => (StatusCode::OK, 0..record.size),
+        RangeRequest::Partial(range) => (StatusCode::PARTIAL_CONTENT, range),
+        RangeRequest::Unsatisfiable => {
+            return Ok((
+                StatusCode::RANGE_NOT_SATISFIABLE,
+                [(CONTENT_RANGE, format!("bytes */{}", record.size))],
+            )
+                .into_response());
+        }
+    };
+
+    let requested = (status == StatusCode::PARTIAL_CONTENT).then(|| range.clone());
+    let stream = state
+        .uploads
+        .open_blob(&record, requested)
+        .await
+        .map_err(ApiError::internal)?
+        .ok_or_else(|| ApiError::internal(format!("content of {} is missing", record.id)))?;
+
+    let mut response = Response::new(Body::from_stream(stream));
+    *response.status_mut() = status;
+    let headers = response.headers_mut();
+    if let Ok(value) = HeaderValue::from_str(&record.content_type) {
+        headers.insert(CONTENT_TYPE, value);
+    }
+    headers.insert(CONTENT_LENGTH, HeaderValue::from(range.end - range.start));
+    headers.insert(ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+    if let Ok(value) = HeaderValue::from_str(&etag) {
+        headers.insert(ETAG, value);
+    }
+    if status == StatusCode::PARTIAL_CONTENT {
+        let value = format!("bytes {}-{}/{}", range.start, range.end - 1, record.size);
+        if let Ok(value) = HeaderValue::from_str(&value) {
+            headers.insert(CONTENT_RANGE, value);
+        }
+    }
+    if let Ok(value) = HeaderValue::from_str(&content_disposition(&record.name)) {
+        headers.insert(CONTENT_DISPOSITION, value);
+    }
+    headers.insert(X_CONTENT_TYPE_OPTIONS, HeaderValue::from_static("nosniff"));
+    Ok(response)
+}
+
+/// `GET /api/files/:id/thumbnail`
+///
+/// 画像の縮小版 (PNG)。縮小版の無いファイルは 404
+pub async fn thumbnail(
+    State(state): State<AppState>,
+    Extension(principal): Extension<Option<Principal>>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+) -> Result<Response, ApiError> {
+    let record = state
+        .uploads
+        .get(&id)
+        .ok_or_else(|| ApiError::not_found(format!("no such file: {}", id)))?;
+    authorize_read(&state, principal.as_ref(), record.room)?;
+
+    let etag = format!("\"{}-thumbnail\"", record.sha256);
+    if headers
+        .get(IF_NONE_MATCH)
+        .is_some_and(|value| value.as_bytes() == etag.as_bytes())
+    {
+        return Ok((StatusCode::NOT_MODIFIED, [(ETAG, etag)]).into_response());
+    }
+
+    let stream = state
+        .uploads
+        .open_thumbnail(&record)
+        .await
+        .map_err(ApiError::internal)?
+        .ok_or_else(|| ApiError::not_found(format!("no thumbnail for {}", id)))?;
+    Ok((
+        [
+            (CONTENT_TYPE, "image/png".to_string()),
+            (ETAG, etag),
+            (CACHE_CONTROL, "public, max-age=86400".to_string()),
+        ],
+        Body::from_stream(stream),
+    )
+        .into_response())
+}
+
+/// `bytes=0-99` / `bytes=100-` / `bytes=-100` のいずれか1つ。それ以外は無視して全体を返す
+fn parse_range(value: &str, size: u64) -> RangeRequest {
+    let Some(spec) = value.trim().strip_prefix("bytes=") else {
+        return RangeRequest::Full;
+    };
+    let Some((start, end)) = spec.split_once('-') else {
+        return RangeRequest::Full;
+    };
+    if spec.contains(',') {
+        return RangeRequest::Full;
+    }
+
+    let range = match (start.trim(), end.trim()) {
+        ("", suffix) => match suffix.parse::<u64>() {
+            Ok(0) => return RangeRequest::Unsatisfiable,
+            Ok(n) => size.saturating_sub(n)..size,
+            Err(_) => return RangeRequest::Full,
+        },
+        (start, "") => match start.parse::<u64>() {
+            Ok(start) => start..size,
+            Err(_) => return RangeRequest::Full,
+        },
+        (start, end) => match (start.parse::<u64>(), end.parse::<u64>()) {
+            (Ok(start), Ok(end)) if start <= end => start..end.saturating_add(1).min(size),
+            _ => return RangeRequest::Full,
+        },
+    };
+    if range.start >= size {
+        RangeRequest::Unsatisfiable
+    } else {
+        RangeRequest::Partial(range)
+    }
+}
+
+/// ASCII 以外の名前は RFC 6266 の `filename*` で送る
+pub fn content_disposition(name: &str) -> String {
+    let fallback: String = name
+        .chars()
+        .map(|c| {
+            if (c.is_ascii_graphic() && c != '"' && c != '\\') || c == ' ' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    format!(
+        "attachment; filename=\"{}\"; filename*=UTF-8''{}",
+        fallback,
+        utf8_percent_encode(name, NON_ALPHANUMERIC)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_range() {
+        assert_eq!(parse_range("bytes=7-11", 12), RangeRequest::Partial(7..12));
+        assert_eq!(parse_range("bytes=7-100", 12), RangeRequest::Partial(7..12));
+        assert_eq!(parse_range("bytes=10-", 12), RangeRequest::Partial(10..12));
+        assert_eq!(parse_range("bytes=-5", 12), RangeRequest::Partial(7..12));
+        assert_eq!(parse_range("bytes=-50", 12), RangeRequest::Partial(0..12));
+        assert_eq!(parse_range("bytes=12-", 12), RangeRequest::Unsatisfiable);
+        assert_eq!(parse_range("bytes=-0", 12), RangeRequest::Unsatisfiable);
+        assert_eq!(parse_range("bytes=0-1,4-5", 12), RangeRequest::Full);
+        assert_eq!(parse_range("bytes=5-1", 12), RangeRequest::Full);
+        assert_eq!(parse_range("items=0-1", 12), RangeRequest::Full);
+    }
+}
@@ -0,0 +1,138 @@
+use super::{authorize_read, ApiError};
+use crate::app::AppState;
+use crate::auth::Principal;
+use crate::history::{self, PageQuery, StoredMessage};
+use axum::extract::{Path, Query, State};
+use axum::{Extension, Json};
+use serde::{Deserialize, Serialize};
+
+/// 1ページの既定の件数
+pub const DEFAULT_PAGE_LIMIT: usize = 50;
+
+/// 1ページの最大件数
+pub const MAX_PAGE_LIMIT: usize = 500;
+
+const CURSOR_PREFIX: &str = "c1";
+
+/// 履歴の位置を表すカーソル。クライアントは中身を解釈せずにそのまま送り返す
+pub fn encode_cursor(seq: u64) -> String {
+    format!("{}{:016x}", CURSOR_PREFIX, seq)
+}
+
+pub fn decode_cursor(cursor: &str) -> Option<u64> {
+    let hex = cursor.strip_prefix(CURSOR_PREFIX)?;
+    if hex.len() != 16 {
+        return None;
+    }
+    u64::from_str_radix(hex, 16).ok()
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MessagesQuery {
+    pub before: Option<String>,
+    pub after: Option<String>,
+    pub limit: Option<usize>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ApiMessage {
+    pub cursor: String,
+    pub room: i32,
+    pub sender: String,
+    pub content: String,
+    /// UNIX 時間 (ミリ秒)
+    pub timestamp: u64,
+}
+
+impl From<StoredMessage> for ApiMessage {
+    fn from(message: StoredMessage) -> Self {
+        Self {
+            cursor: encode_cursor(message.seq),
+            room: message.room,
+            sender: message.sender,
+            content: message.content,
+            timestamp: message.timestamp,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct MessagesPage {
+    pub room: i32,
+    /// 古い順
+    pub messages: Vec<ApiMessage>,
+    /// これより古いメッセージを取るには `before` に渡す。無ければ `null`
+    pub prev_cursor: Option<String>,
+    /// これより新しいメッセージを取るには `after` に渡す。新着の確認にも使える
+    pub next_cursor: Option<String>,
+    /// `next_cursor` より新しいメッセージが既にあるか
+    pub has_newer: bool,
+}
+
+fn parse_cursor(name: &str, value: Option<&str>) -> Result<Option<u64>, ApiError> {
+    match value {
+        None | Some("") => Ok(None),
+        Some(cursor) => decode_cursor(cursor)
+            .map(Some)
+            .ok_or_else(|| ApiError::bad_request(format!("invalid `{}` cursor", name))),
+    }
+}
+
+/// `GET /api/rooms/:room/messages?before=&after=&limit=`
+pub async fn list_messages(
+    State(state): State<AppState>,
+    Extension(principal): Extension<Option<Principal>>,
+    Path(room): Path<i32>,
+    Query(query): Query<MessagesQuery>,
+) -> Result<Json<MessagesPage>, ApiError> {
+    authorize_read(&state, principal.as_ref(), Some(room))?;
+    let before = parse_cursor("before", query.before.as_deref())?;
+    let after = parse_cursor("after", query.after.as_deref())?;
+    let limit = match query.limit {
+        None => DEFAULT_PAGE_LIMIT,
+        Some(0) => return Err(ApiError::bad_request("`limit` must be positive")),
+        Some(limit) => limit.min(MAX_PAGE_LIMIT),
+    };
+
+    let query = PageQuery {
+        before,
+        after,
+        limit,
+    };
+    let page = history::blocking(&state.history, move |history| history.page(room, query))
+        .await
+        .map_err(ApiError::internal)?;
+
+    let prev_cursor = match page.messages.first() {
+        Some(first) if page.has_older => Some(encode_cursor(first.seq)),
+        _ => None,
+    };
+    let next_cursor = match page.messages.last() {
+        Some(last) => Some(encode_cursor(last.seq)),
+        // 空の場合は受け取った位置をそのまま返し、続けて新着を確認できるようにする
+        None => after.map(encode_cursor),
+    };
+
+    Ok(Json(MessagesPage {
+        room,
+        messages: page.messages.into_iter().map(ApiMessage::from).collect(),
+        prev_cursor,
+        next_cursor,
+        has_newer: page.has_newer,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cursor_round_trip() {
+        for seq in [0, 1, 42, u64::MAX] {
+            assert_eq!(decode_cursor(&encode_cursor(seq)), Some(seq));
+        }
+        assert_eq!(decode_cursor("42"), None);
+        assert_eq!(decode_cursor("c1zz"), None);
+        assert_eq!(decode_cursor("c1000000000000002a00"), None);
+    }
+}
@@ -0,0 +1,191 @@
+pub mod admin;
+pub mod files;
+pub mod history;
+
+use crate::app::AppState;
+use crate::auth::{self, Action, AuthError, Principal};
+use crate::metrics::Metrics;
+use axum::extract::{Query, Request, State};
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::middleware::{self, Next};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{delete, get, post};
+use axum::{Json, Router};
+use log::warn;
+use serde::Serialize;
+use std::collections::HashMap;
+
+/// `/api` 以下の JSON API
+pub fn routes(state: AppState) -> Router<AppState> {
+    public_routes(state.clone()).merge(admin_routes(state))
+}
+
+/// 管理 API を除いたもの。読めるのは認証した相手がそのルームで読めるものだけ
+pub fn public_routes(state: AppState) -> Router<AppState> {
+    Router::new()
+        .route("/rooms/:room/messages", get(history::list_messages))
+        .route("/files", get(files::list_files))
+        .route("/files/:id", get(files::download))
+        .route("/files/:id/thumbnail", get(files::thumbnail))
+        .route_layer(middleware::from_fn_with_state(state, require_reader))
+}
+
+/// `/api/admin/*`。モデレーター以上に限る
+pub fn admin_routes(state: AppState) -> Router<AppState> {
+    Router::new()
+        .route("/admin/usage", get(admin::usage))
+        .route("/admin/files/:id", delete(admin::delete_file))
+        .route("/admin/kick", post(admin::kick))
+        .route("/admin/bans", get(admin::list_bans).post(admin::ban))
+        .route("/admin/bans/:target", delete(admin::unban))
+        .route("/admin/reload", get(admin::last_reload).post(admin::reload))
+        .route_layer(middleware::from_fn_with_state(state, require_admin))
+}
+
+/// リクエストの資格情報から相手を確かめる。認証しない設定なら `None`
+pub async fn authenticate(
+    state: &AppState,
+    headers: &HeaderMap,
+    query: &HashMap<String, String>,
+) -> Result<Option<Principal>, ApiError> {
+    auth::authenticate(state.auth.as_ref(), headers, query)
+        .await
+        .map_err(|e| {
+            if let AuthError::InvalidCredentials(reason) = &e {
+                warn!("rejected API request: {}", reason);
+            }
+            Metrics::inc(&state.metrics.auth_failures);
+            ApiError::unauthorized(e.to_string())
+        })
+}
+
+/// 認証した相手を `Extension<Option<Principal>>` で取り出せるようにする
+///
+/// 認証しない設定では誰でも `None` で通る。ルームごとの確認は `authorize_read` でする。
+async fn require_reader(
+    State(state): State<AppState>,
+    Query(query): Query<HashMap<String, String>>,
+    headers: HeaderMap,
+    mut request: Request,
+    next: Next,
+) -> Result<Response, ApiError> {
+    let principal = authenticate(&state, &headers, &query).await?;
+    request.extensions_mut().insert(principal);
+    Ok(next.run(request).await)
+}
+
+/// `room` (`None` はルームの外) を読めるか。WebSocket と同じ役割で確かめる
+pub fn authorize_read(
+    state: &AppState,
+    principal: Option<&Principal>,
+    room: Option<i32>,
+) -> Result<(), ApiError> {
+    let roles = &state.settings.current().roles;
+    if roles.allows(principal, room, Action::Read) {
+        return Ok(());
+    }
+    Err(ApiError::forbidden(format!(
+        "{} is not allowed to {}",
+        roles.role(principal, room),
+        Action::Read
+    )))
+}
+
+/// 認証した上で、全ルームでモデレーター以上の役割を持つ相手だけを通す
+///
+/// 認証しない設定では誰も通さない。確かめた相手は `Extension<Principal>` で取り出せる。
+async fn require_admin(
+    State(state): State<AppState>,
+    Query(query): Query<HashMap<String, String>>,
+    headers: HeaderMap,
+    mut request: Request,
+    next: Next,
+) -> Result<Response, ApiError> {
+    let principal = authenticate(&state, &headers, &query)
+        .await?
+        .ok_or_else(|| ApiError::unauthorized("authentication is required"))?;
+    let roles = &state.settings.current().roles;
+    if !roles.allows(Some(&principal), None, Action::Administer) {
+        warn!("{} is not allowed to use the admin API", principal.name);
+        return Err(ApiError::forbidden(format!(
+            "{} is not allowed to {}",
+            principal.name,
+            Action::Administer
+        )));
+    }
+    request.extensions_mut().insert(principal);
+    Ok(next.run(request).await)
+}
+
+/// JSON API のエラー応答
+#[derive(Debug)]
+pub struct ApiError {
+    pub status: StatusCode,
+    pub message: String,
+}
+
+#[derive(Serialize)]
+struct ErrorBody<'a> {
+    error: &'a str,
+}
+
+impl ApiError {
+    pub fn bad_request(message: impl Into<String>) -> Self {
+        Self {
+            status: StatusCode::BAD_REQUEST,
+            message: message.into(),
+        }
+    }
+
+    pub fn unauthorized(message: impl Into<String>) -> Self {
+        Self {
+            status: StatusCode::UNAUTHORIZED,
+            message: message.into(),
+        }
+    }
+
+    pub fn forbidden(message: impl Into<String>) -> Self {
+        Self {
+            status: StatusCode::FORBIDDEN,
+            message: message.into(),
+        }
+    }
+
+    pub fn not_found(message: impl Into<String>) -> Self {
+        Self {
+            status: StatusCode::NOT_FOUND,
+            message: message.into(),
+        }
+    }
+
+    pub fn unavailable(message: impl Into<String>) -> Self {
+        Self {
+            status: StatusCode::SERVICE_UNAVAILABLE,
+            message: message.into(),
+        }
+    }
+
+    pub fn internal(err: impl std::fmt::Display) -> Self {
+        warn!("internal error in API: {}", err);
+        Self {
+            status: StatusCode::INTERNAL_SERVER_ERROR,
+            message: "internal server error".to_string(),
+        }
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let body = ErrorBody {
+            error: &self.message,
+        };
+        let mut response = (self.status, Json(body)).into_response();
+        if self.status == StatusCode::UNAUTHORIZED {
+            response.headers_mut().insert(
+                header::WWW_AUTHENTICATE,
+                header::HeaderValue::from_static("Bearer"),
+            );
+        }
+        response
+    }
+}
@@ -0,0 +1,372 @@
+use crate::api;
+use crate::auth::{self, Authenticator, RolePolicy};
+use crate::connection::handle_socket;
+use crate::heartbeat::HeartbeatSettings;
+use crate::history::{HistoryStore, MemoryHistory, DEFAULT_REPLAY_LIMIT};
+use crate::listener::RouteSet;
+use crate::metrics::Metrics;
+use crate::moderation::BanList;
+use crate::origin::OriginPolicy;
+use crate::rate_limit::RateLimitSettings;
+use crate::reload::Reloads;
+use crate::send_queue::SendQueueSettings;
+use crate::shutdown::{Shutdown, SHUTDOWN_MESSAGE};
+use crate::socket_manager::SocketManager;
+use crate::tls::TlsCerts;
+use crate::upload::chunked::DEFAULT_PROGRESS_INTERVAL;
+use crate::upload::{
+    BlobStore, ChunkedUploads, FsBlobStore, QuotaLedger, ScanPipeline, UploadLimits, UploadStore,
+    BLOBS_DIRNAME,
+};
+use axum::extract::{ConnectInfo, Query, Request, State, WebSocketUpgrade};
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::middleware::{self, Next};
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::{Json, Router};
+use futures_util::stream::{self, Stream};
+use log::{info, warn};
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::fmt::{Display, Formatter};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{watch, Mutex};
+use tokio_stream::StreamExt as _;
+use tower::{Layer, ServiceExt};
+use tower_http::cors::CorsLayer;
+use tower_http::services::ServeDir;
+
+pub const UPLOAD_DIRNAME: &str = "./uploads";
+
+/// 1接続あたりに許容するプロトコルエラーの既定値
+pub const DEFAULT_MAX_PROTOCOL_ERRORS: u32 = 5;
+
+/// ファイル転送フレームのうちファイル本体以外の部分 (送信者名やファイル名) の最大長
+const FRAME_OVERHEAD: u64 = 1024;
+
+/// 動かし方。本番では安全側の既定値を使う
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Mode {
+    #[default]
+    Development,
+    Production,
+}
+
+impl FromStr for Mode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "development" | "dev" => Ok(Mode::Development),
+            "production" | "prod" => Ok(Mode::Production),
+            _ => Err(format!("unknown mode `{}` (development, production)", s)),
+        }
+    }
+}
+
+impl Display for Mode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Mode::Development => write!(f, "development"),
+            Mode::Production => write!(f, "production"),
+        }
+    }
+}
+
+/// 接続の処理に使う設定値
+#[derive(Debug, Clone)]
+pub struct ServerSettings {
+    pub upload_dir: PathBuf,
+    /// この回数だけ不正なフレームを送ってきたクライアントは切断する
+    pub max_protocol_errors: u32,
+    pub send_queue: SendQueueSettings,
+    /// ルームに参加したクライアントへ返す履歴の件数
+    pub history_replay: usize,
+    pub upload_limits: UploadLimits,
+    /// 実行ファイルやスクリプトのアップロードを断る
+    pub refuse_executables: bool,
+    /// アップロードを一覧に載せる前に通す検査
+    pub scanners: ScanPipeline,
+    /// 分割アップロードの進み具合を知らせる間隔
+    pub upload_progress_interval: Duration,
+    /// 接続の役割と、ルームごとの役割
+    pub roles: RolePolicy,
+    /// 接続ごと、フレームの種類ごとの頻度の制限
+    pub rate_limits: RateLimitSettings,
+    /// 他のオリジンからの API の呼び出しと `/ws` への接続を許す範囲
+    pub origins: OriginPolicy,
+    /// Ping の間隔と、応答の無い接続を切るまでの時間
+    pub heartbeat: HeartbeatSettings,
+}
+
+impl Default for ServerSettings {
+    fn default() -> Self {
+        Self {
+            upload_dir: PathBuf::from(UPLOAD_DIRNAME),
+            max_protocol_errors: DEFAULT_MAX_PROTOCOL_ERRORS,
+            send_queue: SendQueueSettings::default(),
+            history_replay: DEFAULT_REPLAY_LIMIT,
+            upload_limits: UploadLimits::default(),
+            refuse_executables: false,
+            scanners: ScanPipeline::default(),
+            upload_progress_interval: DEFAULT_PROGRESS_INTERVAL,
+            roles: RolePolicy::default(),
+            rate_limits: RateLimitSettings::default(),
+            origins: OriginPolicy::default(),
+            heartbeat: HeartbeatSettings::default(),
+        }
+    }
+}
+
+/// 設定の読み直しで差し替えられる `ServerSettings`
+///
+/// 読む側はその時点の値を `current` で取る。接続ごとに持つ値は `subscribe` で変更を知る。
+#[derive(Debug, Clone)]
+pub struct SharedSettings(Arc<watch::Sender<Arc<ServerSettings>>>);
+
+impl SharedSettings {
+    pub fn new(settings: ServerSettings) -> Self {
+        Self(Arc::new(watch::Sender::new(Arc::new(settings))))
+    }
+
+    pub fn current(&self) -> Arc<ServerSettings> {
+        self.0.borrow().clone()
+    }
+
+    pub fn subscribe(&self) -> watch::Receiver<Arc<ServerSettings>> {
+        self.0.subscribe()
+    }
+
+    /// 今の値を書き換えて、購読している接続に知らせる
+    pub fn update(&self, f: impl FnOnce(&mut ServerSettings)) {
+        self.0.send_modify(|settings| f(Arc::make_mut(settings)));
+    }
+}
+
+#[derive(Clone)]
+pub struct AppState {
+    pub manager: SocketManager,
+    pub settings: SharedSettings,
+    pub metrics: Arc<Metrics>,
+    pub history: Arc<dyn HistoryStore>,
+    pub uploads: UploadStore,
+    pub quota: Arc<QuotaLedger>,
+    pub chunked_uploads: Arc<ChunkedUploads>,
+    /// `/ws` への接続の認証。`None` なら誰でも接続できる
+    pub auth: Option<Arc<dyn Authenticator>>,
+    pub bans: Arc<BanList>,
+    pub shutdown: Arc<Shutdown>,
+    pub reloads: Arc<Reloads>,
+    /// TLS で待ち受ける場合の証明書。読み直しで差し替える
+    pub tls: Option<Arc<TlsCerts>>,
+}
+
+impl AppState {
+    pub fn new(settings: ServerSettings) -> Self {
+        let metrics = Arc::new(Metrics::default());
+        let blobs = Arc::new(FsBlobStore::new(settings.upload_dir.join(BLOBS_DIRNAME)));
+        let uploads = UploadStore::open(settings.upload_dir.clone(), blobs)
+            .refuse_executables(settings.refuse_executables)
+            .scanners(settings.scanners.clone());
+        let quota = Arc::new(QuotaLedger::open(
+            &settings.upload_dir,
+            settings.upload_limits,
+        ));
+        let chunked_uploads = Arc::new(
+            ChunkedUploads::new(uploads.clone(), quota.clone())
+                .progress_interval(settings.upload_progress_interval),
+        );
+        Self {
+            manager: SocketManager::new(settings.send_queue, metrics.clone()),
+            settings: SharedSettings::new(settings),
+            metrics,
+            history: Arc::new(MemoryHistory::default()),
+            uploads,
+            quota,
+            chunked_uploads,
+            auth: None,
+            bans: Arc::new(BanList::in_memory()),
+            shutdown: Arc::new(Shutdown::default()),
+            reloads: Arc::new(Reloads::default()),
+            tls: None,
+        }
+    }
+
+    /// 履歴の保存先を差し替える。既定はメモリ上
+    pub fn with_history(mut self, history: Arc<dyn HistoryStore>) -> Self {
+        self.history = history;
+        self
+    }
+
+    /// `/ws` への接続に認証を求める
+    pub fn with_auth(mut self, auth: Arc<dyn Authenticator>) -> Self {
+        self.auth = Some(auth);
+        self
+    }
+
+    /// BAN の一覧を差し替える。既定はメモリ上
+    pub fn with_bans(mut self, bans: Arc<BanList>) -> Self {
+        self.bans = bans;
+        self
+    }
+
+    /// 読み直しの対象にする証明書
+    pub fn with_tls(mut self, certs: Arc<TlsCerts>) -> Self {
+        self.tls = Some(certs);
+        self
+    }
+
+    /// アップロードされたファイルの保存先を差し替える。既定はアップロード先のディレクトリ
+    pub fn with_blobs(mut self, blobs: Arc<dyn BlobStore>) -> Self {
+        let settings = self.settings.current();
+        self.uploads = UploadStore::open(settings.upload_dir.clone(), blobs)
+            .refuse_executables(settings.refuse_executables)
+            .scanners(settings.scanners.clone());
+        self.chunked_uploads = Arc::new(
+            ChunkedUploads::new(self.uploads.clone(), self.quota.clone())
+                .progress_interval(settings.upload_progress_interval),
+        );
+        self
+    }
+}
+
+pub fn router(state: AppState) -> Router {
+    router_for(state, RouteSet::All)
+}
+
+/// `routes` に含まれる経路だけを公開する。待ち受けごとに作る
+pub fn router_for(state: AppState, routes: RouteSet) -> Router {
+    let api = match routes {
+        RouteSet::All => api::routes(state.clone()),
+        RouteSet::Public => api::public_routes(state.clone()),
+        RouteSet::Admin => api::admin_routes(state.clone()),
+    };
+    let mut router = axum::Router::new().route(
+        "/api/health.json",
+        axum::routing::get(|| async { Json("{\"success\": \"true\"}") }),
+    );
+    if routes != RouteSet::Admin {
+        let sse_sent = Arc::new(Mutex::new(0));
+        router = router
+            .nest_service("/", ServeDir::new("./front/dist"))
+            .route(
+                "/api/sse",
+                get({
+                    let sse_sent = sse_sent.clone();
+                    move || sse_handler(sse_sent)
+                }),
+            )
+            .route("/ws", axum::routing::get(handle_websocket));
+    }
+    if routes != RouteSet::Public {
+        router = router.route("/api/metrics", get(metrics_handler));
+    }
+
+    router
+        .nest("/api", api)
+        .layer(middleware::from_fn_with_state(state.clone(), cors))
+        .with_state(state)
+}
+
+async fn sse_handler(sent: Arc<Mutex<i32>>) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    let stream = stream::unfold(sent.clone(), |sent_ref| async move {
+        let current_count;
+
+        {
+            let mut count = sent_ref.lock().await; // ロックをスコープ内で限定
+            *count += 1;
+            current_count = *count; // カウントの値をコピー
+        }
+
+        // ロックが解放された後にイベントを生成
+
+        if current_count > 100 {
+            {
+                let mut count = sent_ref.lock().await; // ロックをスコープ内で限定
+                *count = 0;
+                info!("reset sent count");
+            }
+            None
+        } else {
+            let event = Event::default().data(format!("hi! ({})", current_count));
+            Some((Ok(event), sent_ref)) // `sent_ref` を次に渡す（move 必要なし）
+        }
+    })
+    .throttle(Duration::from_secs(1));
+
+    Sse::new(stream).keep_alive(KeepAlive::default())
+}
+
+async fn metrics_handler(State(state): State<AppState>) -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        state.metrics.render(&state.manager),
+    )
+}
+
+async fn handle_websocket(
+    State(state): State<AppState>,
+    Query(query): Query<HashMap<String, String>>,
+    headers: HeaderMap,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    ws: WebSocketUpgrade,
+) -> axum::response::Response {
+    let addr = connect_info.map(|ConnectInfo(addr)| addr.ip());
+    if state.shutdown.is_stopping() {
+        return (StatusCode::SERVICE_UNAVAILABLE, SHUTDOWN_MESSAGE).into_response();
+    }
+    let settings = state.settings.current();
+    if let Err(reason) = settings.origins.check_upgrade(&headers) {
+        warn!("rejected websocket upgrade: {}", reason);
+        Metrics::inc(&state.metrics.origin_rejections);
+        return (StatusCode::FORBIDDEN, reason).into_response();
+    }
+    let principal = match auth::authenticate(state.auth.as_ref(), &headers, &query).await {
+        Ok(principal) => principal,
+        Err(e) => {
+            if let auth::AuthError::InvalidCredentials(reason) = &e {
+                warn!("rejected websocket upgrade: {}", reason);
+            }
+            Metrics::inc(&state.metrics.auth_failures);
+            return (
+                StatusCode::UNAUTHORIZED,
+                [(header::WWW_AUTHENTICATE, "Bearer")],
+                e.to_string(),
+            )
+                .into_response();
+        }
+    };
+
+    if let Some(ban) = state.bans.find(principal.as_ref(), None, addr) {
+        warn!("refused websocket upgrade from banned {}", ban.target);
+        Metrics::inc(&state.metrics.banned_connections);
+        return (StatusCode::FORBIDDEN, "banned").into_response();
+    }
+
+    // 上限を超えるフレームは受信する前に切る。ヘッダーの分だけ余裕を持たせる
+    let max_frame = settings
+        .upload_limits
+        .max_file_size
+        .saturating_add(FRAME_OVERHEAD);
+    ws.max_message_size(usize::try_from(max_frame).unwrap_or(usize::MAX))
+        .max_frame_size(usize::try_from(max_frame).unwrap_or(usize::MAX))
+        .on_upgrade(move |socket| handle_socket(state, socket, principal, addr))
+}
+
+pub fn cors_handler(policy: &OriginPolicy) -> CorsLayer {
+    policy.layer()
+}
+
+/// 許すオリジンは設定の読み直しで変わるので、リクエストごとにその時点の設定で処理する
+async fn cors(State(state): State<AppState>, request: Request, next: Next) -> Response {
+    let cors = cors_handler(&state.settings.current().origins);
+    match cors.layer(next).oneshot(request).await {
+        Ok(response) => response,
+        Err(never) => match never {},
+    }
+}
@@ -0,0 +1,87 @@
+use super::{AuthError, Authenticator, Credentials, Principal};
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use serde::{Deserialize, Serialize};
+
+/// JWT のクレーム。`sub` を名前として使う
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Claims {
+    pub sub: String,
+    /// 有効期限 (UNIX 時間、秒)
+    pub exp: u64,
+}
+
+/// HMAC (HS256 / HS384 / HS512) で署名した JWT で認証する。`exp` は必須
+pub struct JwtAuth {
+    key: DecodingKey,
+    validation: Validation,
+}
+
+impl JwtAuth {
+    pub fn new(secret: &[u8]) -> Self {
+        let mut validation = Validation::new(Algorithm::HS256);
+        validation.algorithms = vec![Algorithm::HS256, Algorithm::HS384, Algorithm::HS512];
+        validation.set_required_spec_claims(&["exp", "sub"]);
+        Self {
+            key: DecodingKey::from_secret(secret),
+            validation,
+        }
+    }
+}
+
+impl Authenticator for JwtAuth {
+    fn authenticate(&self, credentials: &Credentials) -> Result<Principal, AuthError> {
+        let Credentials::Token(token) = credentials else {
+            return Err(AuthError::InvalidCredentials(
+                "a token is required".to_string(),
+            ));
+        };
+        let data = jsonwebtoken::decode::<Claims>(token, &self.key, &self.validation)
+            .map_err(|e| AuthError::InvalidCredentials(format!("invalid jwt: {}", e)))?;
+        Ok(Principal::new(data.claims.sub))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use jsonwebtoken::{EncodingKey, Header};
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    fn token(secret: &[u8], sub: &str, exp: u64) -> Credentials {
+        let claims = Claims {
+            sub: sub.to_string(),
+            exp,
+        };
+        let token = jsonwebtoken::encode(
+            &Header::default(),
+            &claims,
+            &EncodingKey::from_secret(secret),
+        )
+        .unwrap();
+        Credentials::Token(token)
+    }
+
+    #[test]
+    fn test_jwt() {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let auth = JwtAuth::new(b"secret");
+
+        assert_eq!(
+            auth.authenticate(&token(b"secret", "alice", now + 60)),
+            Ok(Principal::new("alice"))
+        );
+        // 期限切れ、別の鍵
+        assert!(auth
+            .authenticate(&token(b"secret", "alice", now - 3600))
+            .is_err());
+        assert!(auth
+            .authenticate(&token(b"other", "alice", now + 60))
+            .is_err());
+        assert!(auth
+            .authenticate(&Credentials::Token("not.a.jwt".to_string()))
+            .is_err());
+    }
+}
@@ -0,0 +1,108 @@
+use super::{AuthError, Authenticator, Credentials, Principal};
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+
+/// ユーザー名とパスワードで認証する
+///
+/// ファイルは1行に `<名前>:<argon2 の PHC 文字列>` (`$argon2id$v=19$...`)。空行と `#` で
+/// 始まる行は読み飛ばす。
+pub struct PasswordFile {
+    users: HashMap<String, String>,
+    /// 知らないユーザーでも同じだけ時間をかけるために使う
+    dummy: String,
+}
+
+impl PasswordFile {
+    pub fn load(path: &Path) -> io::Result<Self> {
+        Self::parse(&std::fs::read_to_string(path)?)
+    }
+
+    pub fn parse(content: &str) -> io::Result<Self> {
+        let invalid = |n: usize, reason: &str| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("line {}: {}", n + 1, reason),
+            )
+        };
+
+        let mut users = HashMap::new();
+        for (n, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (name, hash) = line
+                .split_once(':')
+                .filter(|(name, _)| !name.is_empty())
+                .ok_or_else(|| invalid(n, "expected `<name>:<argon2 hash>`"))?;
+            PasswordHash::new(hash).map_err(|e| invalid(n, &e.to_string()))?;
+            users.insert(name.to_string(), hash.to_string());
+        }
+
+        Ok(Self {
+            users,
+            dummy: hash_password("")?,
+        })
+    }
+}
+
+impl Authenticator for PasswordFile {
+    fn authenticate(&self, credentials: &Credentials) -> Result<Principal, AuthError> {
+        let Credentials::Password { user, password } = credentials else {
+            return Err(AuthError::InvalidCredentials(
+                "a user name and password are required".to_string(),
+            ));
+        };
+        let hash = self.users.get(user).unwrap_or(&self.dummy);
+        let verified = PasswordHash::new(hash).is_ok_and(|hash| {
+            Argon2::default()
+                .verify_password(password.as_bytes(), &hash)
+                .is_ok()
+        });
+
+        if verified && self.users.contains_key(user) {
+            Ok(Principal::new(user))
+        } else {
+            Err(AuthError::InvalidCredentials(format!(
+                "wrong password for {:?}",
+                user
+            )))
+        }
+    }
+}
+
+/// パスワードファイルに書くハッシュを作る
+pub fn hash_password(password: &str) -> io::Result<String> {
+    let salt = SaltString::generate(&mut argon2::password_hash::rand_core::OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|e| io::Error::other(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_password_file() {
+        let content = format!("# users\nalice:{}\n", hash_password("wonderland").unwrap());
+        let users = PasswordFile::parse(&content).unwrap();
+        let login = |user: &str, password: &str| {
+            users.authenticate(&Credentials::Password {
+                user: user.to_string(),
+                password: password.to_string(),
+            })
+        };
+
+        assert_eq!(login("alice", "wonderland"), Ok(Principal::new("alice")));
+        assert!(login("alice", "looking glass").is_err());
+        // ダミーのハッシュは空のパスワードなので、知らないユーザーで試しても通らない
+        assert!(login("mallory", "").is_err());
+
+        assert!(PasswordFile::parse("alice:plaintext").is_err());
+    }
+}
@@ -0,0 +1,240 @@
+use super::Principal;
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+
+/// 接続の役割。後ろほど強い
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Role {
+    /// 読むこともできない。ルームを限られた人にだけ見せる時に使う
+    None,
+    /// 読むだけ
+    Guest,
+    Member,
+    Moderator,
+    Admin,
+}
+
+impl FromStr for Role {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Role::None),
+            "guest" => Ok(Role::Guest),
+            "member" => Ok(Role::Member),
+            "moderator" => Ok(Role::Moderator),
+            "admin" => Ok(Role::Admin),
+            _ => Err(format!(
+                "unknown role `{}` (none, guest, member, moderator, admin)",
+                s
+            )),
+        }
+    }
+}
+
+impl Display for Role {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Role::None => write!(f, "none"),
+            Role::Guest => write!(f, "guest"),
+            Role::Member => write!(f, "member"),
+            Role::Moderator => write!(f, "moderator"),
+            Role::Admin => write!(f, "admin"),
+        }
+    }
+}
+
+/// 権限を確かめる操作
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    /// ルームの履歴や共有されたファイルを読む
+    Read,
+    /// チャットを送る
+    Post,
+    /// ファイルをアップロードする
+    Upload,
+    /// `/list socket` で接続の一覧を見る
+    ListSockets,
+    /// `/list socket` で接続の UUID を見る
+    ViewSocketIds,
+    /// 他の接続を切断させる
+    Kick,
+    /// 切断させ、以降の接続も断る。取り消すのにも使う
+    Ban,
+    /// まだ誰もいないルームに参加する
+    CreateRoom,
+    /// 管理 API (`/api/admin/*`) を使う
+    Administer,
+}
+
+impl Action {
+    /// この操作に必要な最低限の役割
+    pub fn required_role(&self) -> Role {
+        match self {
+            Action::Read | Action::ListSockets => Role::Guest,
+            Action::Post | Action::Upload | Action::CreateRoom => Role::Member,
+            Action::ViewSocketIds | Action::Kick | Action::Ban | Action::Administer => {
+                Role::Moderator
+            }
+        }
+    }
+}
+
+impl Display for Action {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Action::Read => write!(f, "read"),
+            Action::Post => write!(f, "post"),
+            Action::Upload => write!(f, "upload"),
+            Action::ListSockets => write!(f, "list sockets"),
+            Action::ViewSocketIds => write!(f, "view socket ids"),
+            Action::Kick => write!(f, "kick"),
+            Action::Ban => write!(f, "ban"),
+            Action::CreateRoom => write!(f, "create rooms"),
+            Action::Administer => write!(f, "use the admin API"),
+        }
+    }
+}
+
+/// 名前 (`None` なら全員) とルーム (`None` なら全ルーム) ごとの役割
+///
+/// ファイルは1行に `<名前>[@<ルーム>]:<役割>`。名前の `*` は全員を表し、認証していない
+/// 接続にも当てはまる。空行と `#` で始まる行は読み飛ばす。
+///
+/// ```text
+/// alice:admin
+/// *@3:guest
+/// bob@3:moderator
+/// ```
+///
+/// 役割は、ルームでのその人、全ルームでのその人、ルームでの全員、全員の既定の順に探す。
+#[derive(Debug, Clone)]
+pub struct RolePolicy {
+    default: Role,
+    roles: HashMap<(Option<String>, Option<i32>), Role>,
+}
+
+impl Default for RolePolicy {
+    fn default() -> Self {
+        Self::new(Role::Member)
+    }
+}
+
+impl RolePolicy {
+    /// 誰にでも `default` を与える
+    pub fn new(default: Role) -> Self {
+        Self {
+            default,
+            roles: HashMap::new(),
+        }
+    }
+
+    pub fn load(path: &Path, default: Role) -> io::Result<Self> {
+        Self::parse(&std::fs::read_to_string(path)?, default)
+    }
+
+    pub fn parse(content: &str, default: Role) -> io::Result<Self> {
+        let invalid = |n: usize, reason: &str| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("line {}: {}", n + 1, reason),
+            )
+        };
+
+        let mut policy = Self::new(default);
+        for (n, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (subject, role) = line
+                .rsplit_once(':')
+                .ok_or_else(|| invalid(n, "expected `<name>[@<room>]:<role>`"))?;
+            let role = role.trim().parse().map_err(|e: String| invalid(n, &e))?;
+            let subject = subject.trim();
+            let (name, room) = match subject.rsplit_once('@') {
+                Some((name, room)) => {
+                    let room = room
+                        .parse()
+                        .map_err(|_| invalid(n, &format!("invalid room `{}`", room)))?;
+                    (name, Some(room))
+                }
+                None => (subject, None),
+            };
+            if name.is_empty() {
+                return Err(invalid(n, "expected `<name>[@<room>]:<role>`"));
+            }
+            let name = (name != "*").then(|| name.to_string());
+            policy.roles.insert((name, room), role);
+        }
+        Ok(policy)
+    }
+
+    /// `room` での `principal` の役割
+    pub fn role(&self, principal: Option<&Principal>, room: Option<i32>) -> Role {
+        let name = principal.map(|principal| principal.name.clone());
+        let mut keys = Vec::with_capacity(4);
+        if name.is_some() {
+            if room.is_some() {
+                keys.push((name.clone(), room));
+            }
+            keys.push((name, None));
+        }
+        if room.is_some() {
+            keys.push((None, room));
+        }
+        keys.push((None, None));
+
+        keys.iter()
+            .find_map(|key| self.roles.get(key).copied())
+            .unwrap_or(self.default)
+    }
+
+    /// ファイルで役割を決めているルームか。そのようなルームは初めから有るものとして扱う
+    pub fn has_room(&self, room: i32) -> bool {
+        self.roles.keys().any(|(_, r)| *r == Some(room))
+    }
+
+    pub fn allows(&self, principal: Option<&Principal>, room: Option<i32>, action: Action) -> bool {
+        self.role(principal, room) >= action.required_role()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_role_policy() {
+        let content = "# roles\nalice:admin\n*@3:guest\nbob@3:moderator\n*@5:none\nbob@5:guest\n";
+        let policy = RolePolicy::parse(content, Role::Member).unwrap();
+        let alice = Principal::new("alice");
+        let bob = Principal::new("bob");
+        let dave = Principal::new("dave");
+
+        assert_eq!(policy.role(Some(&alice), Some(3)), Role::Admin);
+        assert_eq!(policy.role(Some(&bob), Some(3)), Role::Moderator);
+        assert_eq!(policy.role(Some(&bob), Some(1)), Role::Member);
+        assert_eq!(policy.role(Some(&dave), Some(3)), Role::Guest);
+        assert_eq!(policy.role(None, Some(3)), Role::Guest);
+        assert_eq!(policy.role(None, None), Role::Member);
+
+        assert!(!policy.allows(Some(&dave), Some(3), Action::Upload));
+        assert!(policy.allows(Some(&dave), Some(3), Action::ListSockets));
+        assert!(policy.allows(Some(&bob), Some(3), Action::Kick));
+        assert!(!policy.allows(Some(&bob), Some(1), Action::ViewSocketIds));
+        assert!(!policy.allows(Some(&dave), Some(5), Action::Read));
+        assert!(policy.allows(Some(&bob), Some(5), Action::Read));
+
+        assert!(policy.has_room(3));
+        assert!(!policy.has_room(1));
+        assert!(policy.has_room(5));
+
+        assert!(RolePolicy::parse("alice", Role::Member).is_err());
+        assert!(RolePolicy::parse("alice:root", Role::Member).is_err());
+        assert!(RolePolicy::parse("alice@lobby:guest", Role::Member).is_err());
+    }
+}
@@ -0,0 +1,86 @@
+use super::{AuthError, Authenticator, Credentials, Principal};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+
+/// 固定のトークンで認証する
+///
+/// ファイルは1行に `<名前>:<トークン>`。空行と `#` で始まる行は読み飛ばす。
+/// トークンは SHA-256 にしてから引くので、比べる時間から中身が漏れることはない。
+#[derive(Debug, Default)]
+pub struct TokenFile {
+    tokens: HashMap<[u8; 32], String>,
+}
+
+impl TokenFile {
+    pub fn load(path: &Path) -> io::Result<Self> {
+        Self::parse(&std::fs::read_to_string(path)?)
+    }
+
+    pub fn parse(content: &str) -> io::Result<Self> {
+        let mut tokens = HashMap::new();
+        for (n, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            match line.split_once(':') {
+                Some((name, token)) if !name.is_empty() && !token.is_empty() => {
+                    tokens.insert(digest(token), name.to_string());
+                }
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("line {}: expected `<name>:<token>`", n + 1),
+                    ))
+                }
+            }
+        }
+        Ok(Self { tokens })
+    }
+
+    pub fn len(&self) -> usize {
+        self.tokens.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tokens.is_empty()
+    }
+}
+
+impl Authenticator for TokenFile {
+    fn authenticate(&self, credentials: &Credentials) -> Result<Principal, AuthError> {
+        let Credentials::Token(token) = credentials else {
+            return Err(AuthError::InvalidCredentials(
+                "a token is required".to_string(),
+            ));
+        };
+        self.tokens
+            .get(&digest(token))
+            .map(Principal::new)
+            .ok_or_else(|| AuthError::InvalidCredentials("unknown token".to_string()))
+    }
+}
+
+fn digest(token: &str) -> [u8; 32] {
+    Sha256::digest(token.as_bytes()).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_file() {
+        let tokens = TokenFile::parse("# comment\n\nalice:abc\nbob:a:b\n").unwrap();
+        assert_eq!(tokens.len(), 2);
+        let token = |t: &str| tokens.authenticate(&Credentials::Token(t.to_string()));
+        assert_eq!(token("abc"), Ok(Principal::new("alice")));
+        assert_eq!(token("a:b"), Ok(Principal::new("bob")));
+        assert!(token("alice").is_err());
+
+        assert!(TokenFile::parse("no separator").is_err());
+        assert!(TokenFile::parse("alice:").is_err());
+    }
+}
@@ -1,99 +1,438 @@
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine as _;
 use clap::Parser;
-use futures_util::{future, pin_mut, SinkExt, StreamExt};
+use futures_util::{SinkExt, StreamExt};
 use log::{error, info, warn};
 use message_pack::{
-    BinarySerializable, ExitMessage, FileTransferMessage, ListMessage, MessageType, TextMessage,
-    UnifiedMessage,
+    BinaryDeserializable, BinarySerializable, ErrorMessage, ExitMessage, JoinMessage, ListMessage,
+    MessageType, ModerationAction, ModerationMessage, TextMessage, UnifiedMessage,
+    UploadAckMessage, UploadBeginMessage, UploadChunkMessage, UploadCommitMessage, UploadId,
+    UploadProgressMessage,
 };
+use percent_encoding::{percent_decode_str, utf8_percent_encode, NON_ALPHANUMERIC};
 use rfd::AsyncFileDialog;
 use rnglib::{Language, RNG};
+use sha2::{Digest, Sha256};
 use std::env;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio_tungstenite::{connect_async, tungstenite::protocol::Message};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, Mutex};
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::http::header::{HeaderValue, AUTHORIZATION};
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::{
+    connect_async_tls_with_config, tungstenite, tungstenite::protocol::Message, Connector,
+    MaybeTlsStream, WebSocketStream,
+};
+use ws_s::heartbeat::{
+    self, Beat, Heartbeat, HeartbeatSettings, DEFAULT_PING_INTERVAL, DEFAULT_PONG_TIMEOUT,
+};
+use ws_s::tls;
+use ws_s::upload::filename::numbered;
+use ws_s::upload::sanitize_filename;
 use ws_s::utils::{
-    parse_arguments, replace_full_width_spaces_to_half_width_spaces_if_not_in_quotes,
+    format_bytes, format_progress, parse_arguments, parse_duration,
+    replace_full_width_spaces_to_half_width_spaces_if_not_in_quotes,
 };
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
-    /// ホスト名 (環境変数から取得またはデフォルト値を適用)
-    #[arg(long, default_value_t = String::new())]
+    /// 接続先のホスト名とポート
+    #[arg(long, env = "WS_S_SERVER", default_value = "127.0.0.1:8080")]
     hostname: String,
+
+    /// 表示名。省略すると `--user` の名前か、ランダムに決める。同じ名前で送り直すと中断した
+    /// アップロードを再開できる
+    #[arg(long)]
+    name: Option<String>,
+
+    /// サーバーに渡すトークン (固定のトークンまたは JWT)
+    #[arg(
+        long,
+        env = "WS_S_TOKEN",
+        hide_env_values = true,
+        conflicts_with = "user"
+    )]
+    token: Option<String>,
+
+    /// パスワードで認証するユーザー名。パスワードは環境変数 WS_S_PASSWORD か標準入力から読む
+    #[arg(long)]
+    user: Option<String>,
+
+    /// 何も受け取らないまま、この秒数が過ぎたら Ping を送る (0 なら送らない)
+    #[arg(long, default_value_t = DEFAULT_PING_INTERVAL.as_secs())]
+    ping_interval_secs: u64,
+
+    /// Ping を送ってからこの秒数内に応答が無ければ繋ぎ直す
+    #[arg(long, default_value_t = DEFAULT_PONG_TIMEOUT.as_secs())]
+    pong_timeout_secs: u64,
+
+    /// wss:// と https:// で接続する
+    #[arg(long)]
+    tls: bool,
+
+    /// サーバーの証明書を確かめる CA 証明書 (PEM)。指定すると --tls も付けたことになる
+    #[arg(long, value_name = "PEM")]
+    ca_file: Option<PathBuf>,
+
+    /// サーバーの証明書を確かめない (自己署名の証明書を試す場合のみ)。--tls も付けたことになる
+    #[arg(long, conflicts_with = "ca_file")]
+    insecure: bool,
+}
+
+impl Args {
+    /// 接続要求に付ける `Authorization` ヘッダー
+    fn authorization(&self) -> std::io::Result<Option<String>> {
+        if let Some(token) = &self.token {
+            return Ok(Some(format!("Bearer {}", token)));
+        }
+        let Some(user) = &self.user else {
+            return Ok(None);
+        };
+        let password = match env::var("WS_S_PASSWORD") {
+            Ok(password) => password,
+            Err(_) => {
+                eprint!("password for {}: ", user);
+                let mut password = String::new();
+                std::io::stdin().read_line(&mut password)?;
+                password.trim_end_matches(['\r', '\n']).to_string()
+            }
+        };
+        let credentials = STANDARD.encode(format!("{}:{}", user, password));
+        Ok(Some(format!("Basic {}", credentials)))
+    }
+
+    /// TLS で接続する場合の設定
+    fn tls_config(&self) -> std::io::Result<Option<Arc<rustls::ClientConfig>>> {
+        if !(self.tls || self.ca_file.is_some() || self.insecure) {
+            return Ok(None);
+        }
+        let config = tls::client_config(self.ca_file.as_deref(), self.insecure)?;
+        Ok(Some(Arc::new(config)))
+    }
 }
 
 const NEWLINE_PROMPT: &[u8; 3] = b"\n> ";
 
+/// 接続直後に参加するルーム
+const DEFAULT_ROOM: i32 = 42;
+
+/// 分割アップロードの1チャンクの大きさ
+const UPLOAD_CHUNK_SIZE: usize = 256 * 1024;
+
+/// 繋ぎ直すまでの最初の待ち時間。失敗するたびに倍にする
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(30);
+
+/// サーバーからの受信確認を待つ時間
+const UPLOAD_ACK_TIMEOUT: Duration = Duration::from_secs(30);
+
+type Client = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// 分割アップロード中にサーバーから届くもの
+enum UploadEvent {
+    Ack(UploadAckMessage),
+    Progress(UploadProgressMessage),
+}
+
+/// サーバーからの受信確認と進み具合。アップロードは一度にひとつずつ行う
+type AckReceiver = Arc<Mutex<mpsc::UnboundedReceiver<UploadEvent>>>;
+
 #[tokio::main]
 async fn main() {
     let args = Args::parse();
-
-    // サーバーと同様
-    let env_hostname = env::var("HOSTNAME").ok();
-    let hostname = if !args.hostname.is_empty() {
-        args.hostname
-    } else if let Some(env) = env_hostname {
-        env
-    } else {
-        String::from("127.0.0.1:8080") // デフォルト値
+    let authorization = args.authorization().expect("Failed to read password");
+    let tls_config = args.tls_config().expect("Failed to read the CA file");
+    if args.insecure {
+        warn!("not verifying the server certificate (--insecure)");
+    }
+    let heartbeat = HeartbeatSettings {
+        interval: Duration::from_secs(args.ping_interval_secs),
+        timeout: Duration::from_secs(args.pong_timeout_secs),
     };
 
+    let hostname = args.hostname;
     info!("hostname: {}", hostname);
 
-    let url = format!("ws://{}/ws", hostname);
+    let (url, http_base) = match &tls_config {
+        Some(_) => (
+            format!("wss://{}/ws", hostname),
+            format!("https://{}", hostname),
+        ),
+        None => (
+            format!("ws://{}/ws", hostname),
+            format!("http://{}", hostname),
+        ),
+    };
+    let http = match &tls_config {
+        Some(config) => reqwest::Client::builder().use_preconfigured_tls((**config).clone()),
+        None => reqwest::Client::builder(),
+    }
+    .build()
+    .expect("Failed to build the HTTP client");
 
-    let name = {
+    let name = args.name.or(args.user).unwrap_or_else(|| {
         let rng = RNG::from(&Language::Fantasy);
 
         let first_name = rng.generate_name();
         let last_name = rng.generate_name();
         format!("{first_name} {last_name}")
-    };
+    });
+
+    let room = Arc::new(AtomicI32::new(DEFAULT_ROOM));
+    let (ack_tx, ack_rx) = mpsc::unbounded_channel();
+    let (stdin_tx, mut stdin_rx) = futures_channel::mpsc::unbounded();
+    tokio::spawn(read_stdin(
+        name.to_string(),
+        http,
+        http_base,
+        room.clone(),
+        stdin_tx,
+        Arc::new(Mutex::new(ack_rx)),
+    ));
+
+    // 切れたら、間隔を広げながら繋ぎ直す。入力は繋がるまでチャネルに溜まる
+    let mut delay = RECONNECT_DELAY;
+    let mut connected_once = false;
+    loop {
+        let ws_stream = match connect(&url, authorization.as_deref(), tls_config.clone()).await {
+            Ok(ws_stream) => ws_stream,
+            Err(e) if !connected_once => panic!("Failed to connect: {}", e),
+            Err(e) => {
+                warn!("reconnect failed: {}; retrying in {:?}", e, delay);
+                tokio::time::sleep(delay).await;
+                delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+                continue;
+            }
+        };
+        info!("WebSocket handshake has been successfully completed");
+        if connected_once {
+            print_line("reconnected").await;
+        }
+        connected_once = true;
+        delay = RECONNECT_DELAY;
+
+        let session = run_session(
+            ws_stream,
+            &name,
+            room.load(Ordering::Relaxed),
+            &mut stdin_rx,
+            &ack_tx,
+            heartbeat,
+        );
+        match session.await {
+            Disconnect::Finished => break,
+            Disconnect::Lost => print_line("connection lost; reconnecting...").await,
+        }
+    }
+}
 
-    let (stdin_tx, stdin_rx) = futures_channel::mpsc::unbounded();
-    tokio::spawn(read_stdin(name.to_string(), stdin_tx));
+/// 接続が終わった理由
+enum Disconnect {
+    /// `/exit` や標準入力の終わり
+    Finished,
+    /// サーバーからの応答が途絶えた。繋ぎ直す
+    Lost,
+}
 
-    let (ws_stream, _) = connect_async(&url).await.expect("Failed to connect");
-    info!("WebSocket handshake has been successfully completed");
+/// 認証に失敗した場合と BAN されている場合は、繋ぎ直しても無駄なので終了する
+async fn connect(
+    url: &str,
+    authorization: Option<&str>,
+    tls_config: Option<Arc<rustls::ClientConfig>>,
+) -> Result<Client, tungstenite::Error> {
+    let mut request = url.into_client_request().expect("Invalid hostname");
+    if let Some(authorization) = authorization {
+        request.headers_mut().insert(
+            AUTHORIZATION,
+            HeaderValue::from_str(authorization).expect("Invalid credentials"),
+        );
+    }
+    let connector = tls_config.map(Connector::Rustls);
+    match connect_async_tls_with_config(request, None, false, connector).await {
+        Ok((ws_stream, _)) => Ok(ws_stream),
+        Err(tungstenite::Error::Http(response)) if response.status() == 401 => {
+            eprintln!("authentication failed; check --token or --user");
+            std::process::exit(1)
+        }
+        Err(tungstenite::Error::Http(response)) if response.status() == 403 => {
+            eprintln!("you are banned from this server");
+            std::process::exit(1)
+        }
+        Err(e) => Err(e),
+    }
+}
 
-    let (mut write, read) = ws_stream.split();
+/// 名乗ってルームに入り、切れるまで入力を送って受信したものを表示する
+async fn run_session(
+    ws_stream: Client,
+    name: &str,
+    room: i32,
+    outgoing: &mut futures_channel::mpsc::UnboundedReceiver<Message>,
+    ack_tx: &mpsc::UnboundedSender<UploadEvent>,
+    heartbeat: HeartbeatSettings,
+) -> Disconnect {
+    let (mut write, mut read) = ws_stream.split();
 
     {
         // `I am {name}` メッセージをWebSocketに送信
         let intro_message = format!("I am {name}");
-        write
-            .send(Message::Text(intro_message))
-            .await
-            .expect("Sending Message failed.");
+        if write.send(Message::Text(intro_message)).await.is_err() {
+            return Disconnect::Lost;
+        }
         info!("Message sent: `I am {}`", name);
+
+        // 今いるルームに参加して履歴を受け取る
+        let join_message = JoinMessage {
+            sender: name.to_string(),
+            room,
+        };
+        if write
+            .send(Message::binary(join_message.to_bytes()))
+            .await
+            .is_err()
+        {
+            return Disconnect::Lost;
+        }
     }
 
-    let stdin_to_ws = stdin_rx.map(Ok).forward(write);
-    let ws_to_stdout = {
-        read.for_each(|message| async {
-            let data = match message {
-                Ok(d) => d,
-                Err(e) => {
-                    error!("{:?}", e);
-                    std::process::exit(1)
-                }
-            };
-
-            let data = data.into_data();
-            // データの出力
-            let mut stdout = tokio::io::stdout(); // mutable な stdout ハンドルの作成
-            stdout.write_all(&data).await.unwrap();
-            let _ = stdout.write(NEWLINE_PROMPT).await.unwrap();
-            stdout.flush().await.unwrap(); // フラッシュを明示的に実行
-        })
+    let mut heartbeat = Heartbeat::new(heartbeat, Instant::now());
+    // `/exit` を送った後に切れるのは正常
+    let mut exiting = false;
+    let lost = |exiting: bool| {
+        if exiting {
+            Disconnect::Finished
+        } else {
+            Disconnect::Lost
+        }
     };
 
-    pin_mut!(stdin_to_ws, ws_to_stdout);
-    future::select(stdin_to_ws, ws_to_stdout).await;
+    loop {
+        tokio::select! {
+            message = outgoing.next() => {
+                let Some(message) = message else {
+                    let _ = write.close().await;
+                    return Disconnect::Finished;
+                };
+                if let Message::Binary(bytes) = &message {
+                    exiting |= bytes.first() == Some(&MessageType::Exit.to_bytes());
+                }
+                if let Err(e) = write.send(message).await {
+                    warn!("failed to send: {}", e);
+                    return lost(exiting);
+                }
+            }
+            message = read.next() => {
+                heartbeat.seen(Instant::now());
+                match message {
+                    Some(Ok(message)) => {
+                        if let Some(disconnect) = handle_incoming(message, ack_tx).await {
+                            return disconnect;
+                        }
+                    }
+                    Some(Err(e)) => {
+                        if !exiting {
+                            error!("{:?}", e);
+                        }
+                        return lost(exiting);
+                    }
+                    None => return lost(exiting),
+                }
+            }
+            _ = heartbeat::sleep_until(heartbeat.deadline()) => {
+                let sent = match heartbeat.tick(Instant::now()) {
+                    Some(Beat::Ping) => write.send(Message::Ping(Vec::new())).await,
+                    Some(Beat::Expired) => {
+                        warn!("no pong from the server");
+                        return lost(exiting);
+                    }
+                    None => Ok(()),
+                };
+                if sent.is_err() {
+                    return lost(exiting);
+                }
+            }
+        }
+    }
 }
 
-async fn read_stdin(name: String, tx: futures_channel::mpsc::UnboundedSender<Message>) {
+/// サーバーから届いたものを表示する。接続を終える場合はその理由を返す
+async fn handle_incoming(
+    message: Message,
+    ack_tx: &mpsc::UnboundedSender<UploadEvent>,
+) -> Option<Disconnect> {
+    let data = match message {
+        // 分割アップロードの受信確認は送信中のタスクに渡す
+        Message::Binary(bytes) if bytes.first() == Some(&MessageType::UploadAck.to_bytes()) => {
+            match UploadAckMessage::from_bytes(&bytes) {
+                Ok(ack) => {
+                    let _ = ack_tx.send(UploadEvent::Ack(ack));
+                    return None;
+                }
+                Err(e) => format!("broken upload ack: {}", e).into_bytes(),
+            }
+        }
+        Message::Binary(bytes)
+            if bytes.first() == Some(&MessageType::UploadProgress.to_bytes()) =>
+        {
+            match UploadProgressMessage::from_bytes(&bytes) {
+                Ok(progress) => {
+                    let _ = ack_tx.send(UploadEvent::Progress(progress));
+                    return None;
+                }
+                Err(e) => format!("broken upload progress: {}", e).into_bytes(),
+            }
+        }
+        // サーバーからのエラー応答はデコードして表示する
+        Message::Binary(bytes) if bytes.first() == Some(&MessageType::Error.to_bytes()) => {
+            match ErrorMessage::from_bytes(&bytes) {
+                Ok(e) => format!("error [{}]: {}", e.code.to_u16(), e.reason).into_bytes(),
+                Err(e) => format!("broken error frame: {}", e).into_bytes(),
+            }
+        }
+        // Pong は tungstenite が返す
+        Message::Ping(_) | Message::Pong(_) => return None,
+        // サーバーの再起動なら繋ぎ直す
+        Message::Close(Some(frame)) if frame.code == CloseCode::Away => {
+            warn!("server is going away: {}", frame.reason);
+            return Some(Disconnect::Lost);
+        }
+        Message::Close(Some(frame)) => {
+            warn!(
+                "closed by server: {} {}",
+                u16::from(frame.code),
+                frame.reason
+            );
+            std::process::exit(1)
+        }
+        Message::Close(None) => return Some(Disconnect::Lost),
+        data => data.into_data(),
+    };
+    // データの出力
+    let mut stdout = tokio::io::stdout(); // mutable な stdout ハンドルの作成
+    stdout.write_all(&data).await.unwrap();
+    let _ = stdout.write(NEWLINE_PROMPT).await.unwrap();
+    stdout.flush().await.unwrap(); // フラッシュを明示的に実行
+    None
+}
+
+async fn read_stdin(
+    name: String,
+    http: reqwest::Client,
+    http_base: String,
+    current_room: Arc<AtomicI32>,
+    tx: futures_channel::mpsc::UnboundedSender<Message>,
+    acks: AckReceiver,
+) {
     let mut stdin = tokio::io::stdin();
+    let mut room = DEFAULT_ROOM;
     loop {
         let mut buf = vec![0; 1024];
         let n = match stdin.read(&mut buf).await {
@@ -123,29 +462,59 @@ async fn read_stdin(name: String, tx: futures_channel::mpsc::UnboundedSender<Mes
 
                     let chat_message: Option<UnifiedMessage> = match command {
                         "/exit" => Some(UnifiedMessage::Exit(ExitMessage {})),
-                        "/file" => {
-                            let file = AsyncFileDialog::new()
-                                .add_filter("text", &["txt", "rs"])
-                                .add_filter("rust", &["rs", "toml"])
-                                .add_filter("any file", &["*"])
-                                .set_directory("/")
-                                .pick_file()
-                                .await;
-
-                            if let Some(file) = file {
-                                let bytes = file.read().await;
-                                info!("filename: {:?}", file.file_name());
-
-                                Some(UnifiedMessage::FileTransferMessage(FileTransferMessage {
-                                    category: MessageType::FileTransfer,
-                                    room: 42,
-                                    filename: file.file_name(),
+                        "/join" => match args.first().map(|arg| arg.parse::<i32>()) {
+                            Some(Ok(new_room)) => {
+                                room = new_room;
+                                current_room.store(room, Ordering::Relaxed);
+                                Some(UnifiedMessage::Join(JoinMessage {
                                     sender: name.clone(),
-                                    content: bytes,
+                                    room,
                                 }))
-                            } else {
+                            }
+                            _ => {
+                                warn!("usage: /join <room>");
                                 None
                             }
+                        },
+                        "/file" => {
+                            // パスを指定しなければダイアログで選ぶ
+                            let path = match args.first() {
+                                Some(path) => Some(PathBuf::from(path)),
+                                None => AsyncFileDialog::new()
+                                    .add_filter("text", &["txt", "rs"])
+                                    .add_filter("rust", &["rs", "toml"])
+                                    .add_filter("any file", &["*"])
+                                    .set_directory("/")
+                                    .pick_file()
+                                    .await
+                                    .map(|file| file.path().to_path_buf()),
+                            };
+
+                            if let Some(path) = path {
+                                info!("filename: {:?}", path);
+                                tokio::spawn(upload_file(
+                                    path,
+                                    name.clone(),
+                                    room,
+                                    tx.clone(),
+                                    acks.clone(),
+                                ));
+                            }
+                            None
+                        }
+                        "/download" => {
+                            match args.first() {
+                                Some(id) => {
+                                    tokio::spawn(download_file(
+                                        http.clone(),
+                                        http_base.clone(),
+                                        id.clone(),
+                                        args.get(1).map(PathBuf::from),
+                                    ));
+                                }
+                                None => warn!("usage: /download <id> [path]"),
+                            }
+                            None
                         }
                         "/list" => {
                             let target = if !args.is_empty() {
@@ -156,14 +525,17 @@ async fn read_stdin(name: String, tx: futures_channel::mpsc::UnboundedSender<Mes
 
                             Some(UnifiedMessage::ListMessage(ListMessage {
                                 category: MessageType::List,
-                                room: 42,
+                                room,
                                 target: target.to_string(),
                                 sender: name.clone(),
                             }))
                         }
+                        "/kick" => moderation(ModerationAction::Kick, args, &name, room),
+                        "/ban" => moderation(ModerationAction::Ban, args, &name, room),
+                        "/unban" => moderation(ModerationAction::Unban, args, &name, room),
                         _ => Some(UnifiedMessage::ChatMessage(TextMessage {
                             sender: name.clone(),
-                            room: 42, // 仮のルーム番号
+                            room,
                             category: MessageType::Chat,
                             content: input.trim().to_string(), // 標準入力からのメッセージ
                         })),
@@ -184,3 +556,269 @@ async fn read_stdin(name: String, tx: futures_channel::mpsc::UnboundedSender<Mes
         }
     }
 }
+
+/// `/kick <対象> [理由]`、`/ban <対象> [期間] [理由]`、`/unban <対象>`
+///
+/// 対象は `name:<名前>`、`user:<名前>`、`ip:<アドレス>` または `/list socket` の UUID。
+fn moderation(
+    action: ModerationAction,
+    args: &[String],
+    name: &str,
+    room: i32,
+) -> Option<UnifiedMessage> {
+    let Some(target) = args.first() else {
+        warn!("usage: /kick <target> [reason], /ban <target> [duration] [reason], /unban <target>");
+        return None;
+    };
+    let mut rest = &args[1..];
+    let mut duration = Duration::ZERO;
+    if action == ModerationAction::Ban {
+        if let Some(parsed) = rest.first().and_then(|arg| parse_duration(arg).ok()) {
+            duration = parsed;
+            rest = &rest[1..];
+        }
+    }
+    // 長さは1バイトで送るので切り詰める
+    let mut reason = rest.join(" ");
+    let mut end = reason.len().min(u8::MAX as usize);
+    while !reason.is_char_boundary(end) {
+        end -= 1;
+    }
+    reason.truncate(end);
+
+    Some(UnifiedMessage::Moderation(ModerationMessage {
+        action,
+        sender: name.to_string(),
+        room,
+        target: target.clone(),
+        duration_secs: duration.as_secs(),
+        reason,
+    }))
+}
+
+/// ファイルを少しずつ読みながら分割アップロードする
+///
+/// アップロード ID は送信者・ファイル名・内容から決まるので、中断した後に
+/// 同じ名前で同じファイルを送り直すとサーバーが受信済みの位置から再開する。
+async fn upload_file(
+    path: PathBuf,
+    sender: String,
+    room: i32,
+    tx: futures_channel::mpsc::UnboundedSender<Message>,
+    acks: AckReceiver,
+) {
+    if let Err(e) = try_upload_file(&path, sender, room, &tx, &acks).await {
+        print_line(&format!("upload of {} failed: {}", path.display(), e)).await;
+    }
+}
+
+async fn try_upload_file(
+    path: &Path,
+    sender: String,
+    room: i32,
+    tx: &futures_channel::mpsc::UnboundedSender<Message>,
+    acks: &AckReceiver,
+) -> anyhow::Result<()> {
+    let filename = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .ok_or_else(|| anyhow::anyhow!("not a file"))?;
+
+    let mut file = File::open(path).await?;
+    let size = file.metadata().await?.len();
+    let sha256 = sha256_file(&mut file).await?;
+    let upload_id = upload_id(&sender, &filename, &sha256);
+
+    // 受信確認の取り違えを防ぐため、アップロードは一度にひとつ
+    let mut acks = acks.lock().await;
+    let send = |message: Vec<u8>| {
+        tx.unbounded_send(Message::binary(message))
+            .map_err(|_| anyhow::anyhow!("connection closed"))
+    };
+
+    send(
+        UploadBeginMessage {
+            upload_id,
+            sender,
+            room,
+            filename: filename.clone(),
+            size,
+            sha256,
+        }
+        .to_bytes(),
+    )?;
+    let mut offset = wait_ack(&mut acks, &upload_id, &filename).await?;
+    if offset > 0 {
+        print_line(&format!("resuming {} from {} bytes", filename, offset)).await;
+    }
+
+    file.seek(std::io::SeekFrom::Start(offset)).await?;
+    let mut buf = vec![0u8; UPLOAD_CHUNK_SIZE];
+    while offset < size {
+        let n = file.read(&mut buf).await?;
+        if n == 0 {
+            anyhow::bail!("file shrank while uploading");
+        }
+        send(UploadChunkMessage::new(upload_id, offset, buf[..n].to_vec()).to_bytes())?;
+        offset = wait_ack(&mut acks, &upload_id, &filename).await?;
+    }
+
+    send(UploadCommitMessage { upload_id }.to_bytes())?;
+    Ok(())
+}
+
+/// 受信確認を待つ。途中で届いた進み具合は表示する
+async fn wait_ack(
+    acks: &mut mpsc::UnboundedReceiver<UploadEvent>,
+    upload_id: &UploadId,
+    filename: &str,
+) -> anyhow::Result<u64> {
+    loop {
+        let event = tokio::time::timeout(UPLOAD_ACK_TIMEOUT, acks.recv())
+            .await
+            .map_err(|_| anyhow::anyhow!("no response from server; run /file again to resume"))?
+            .ok_or_else(|| anyhow::anyhow!("connection closed"))?;
+        // 以前に諦めたアップロードのものは読み捨てる
+        match event {
+            UploadEvent::Ack(ack) if &ack.upload_id == upload_id => return Ok(ack.offset),
+            UploadEvent::Progress(progress) if &progress.upload_id == upload_id => {
+                print_progress(filename, &progress).await;
+            }
+            _ => {}
+        }
+    }
+}
+
+/// 同じ行を書き換えて進み具合を表示する。受信し終えたら改行する
+async fn print_progress(filename: &str, progress: &UploadProgressMessage) {
+    let line = format!(
+        "uploading {}: {}",
+        filename,
+        format_progress(progress.received, progress.total)
+    );
+    if progress.received >= progress.total {
+        print_line(&format!("\r{}\x1b[K", line)).await;
+        return;
+    }
+    let mut stdout = tokio::io::stdout();
+    let _ = stdout
+        .write_all(format!("\r{}\x1b[K", line).as_bytes())
+        .await;
+    let _ = stdout.flush().await;
+}
+
+async fn sha256_file(file: &mut File) -> std::io::Result<[u8; 32]> {
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; UPLOAD_CHUNK_SIZE];
+    loop {
+        let n = file.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    Ok(hasher.finalize().into())
+}
+
+fn upload_id(sender: &str, filename: &str, sha256: &[u8; 32]) -> UploadId {
+    let mut hasher = Sha256::new();
+    hasher.update(sender.as_bytes());
+    hasher.update([0]);
+    hasher.update(filename.as_bytes());
+    hasher.update([0]);
+    hasher.update(sha256);
+
+    let mut id = [0u8; 16];
+    id.copy_from_slice(&hasher.finalize()[..16]);
+    id
+}
+
+/// 共有されたファイルを HTTP で取得する。保存先を省略するとサーバーが付けた名前で保存する
+async fn download_file(
+    http: reqwest::Client,
+    http_base: String,
+    id: String,
+    path: Option<PathBuf>,
+) {
+    if let Err(e) = try_download_file(&http, &http_base, &id, path).await {
+        print_line(&format!("download of {} failed: {}", id, e)).await;
+    }
+}
+
+async fn try_download_file(
+    http: &reqwest::Client,
+    http_base: &str,
+    id: &str,
+    path: Option<PathBuf>,
+) -> anyhow::Result<()> {
+    let url = format!(
+        "{}/api/files/{}",
+        http_base,
+        utf8_percent_encode(id, NON_ALPHANUMERIC)
+    );
+    let response = http.get(url).send().await?.error_for_status()?;
+
+    let path = match path {
+        Some(path) => {
+            if tokio::fs::try_exists(&path).await? {
+                anyhow::bail!("{} already exists", path.display());
+            }
+            path
+        }
+        None => {
+            // 同じ名前のファイルはいくつでも共有できるので、手元で重ならない名前にする
+            let name = filename_from_disposition(response.headers()).unwrap_or(id.to_string());
+            let mut path = PathBuf::from(&name);
+            let mut n = 0;
+            while tokio::fs::try_exists(&path).await? {
+                n += 1;
+                path = PathBuf::from(numbered(&name, n));
+            }
+            path
+        }
+    };
+
+    // 書き終わるまでは別の名前にしておく
+    let mut part_path = path.clone().into_os_string();
+    part_path.push(".part");
+    let mut file = File::create(&part_path).await?;
+    let mut size = 0;
+    let mut body = response.bytes_stream();
+    while let Some(chunk) = body.next().await {
+        let chunk = chunk?;
+        file.write_all(&chunk).await?;
+        size += chunk.len() as u64;
+    }
+    file.flush().await?;
+    tokio::fs::rename(&part_path, &path).await?;
+
+    print_line(&format!(
+        "downloaded {} ({})",
+        path.display(),
+        format_bytes(size)
+    ))
+    .await;
+    Ok(())
+}
+
+/// `Content-Disposition` の `filename*` から保存先の名前を決める
+fn filename_from_disposition(headers: &reqwest::header::HeaderMap) -> Option<String> {
+    let value = headers
+        .get(reqwest::header::CONTENT_DISPOSITION)?
+        .to_str()
+        .ok()?;
+    let encoded = value
+        .split(';')
+        .find_map(|part| part.trim().strip_prefix("filename*=UTF-8''"))?;
+    let name = percent_decode_str(encoded).decode_utf8().ok()?;
+
+    // サーバーが付けた名前でも、そのままパスとしては使わない
+    sanitize_filename(&name).ok()
+}
+
+async fn print_line(line: &str) {
+    let mut stdout = tokio::io::stdout();
+    let _ = stdout.write_all(line.as_bytes()).await;
+    let _ = stdout.write(NEWLINE_PROMPT).await;
+    let _ = stdout.flush().await;
+}
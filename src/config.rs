@@ -0,0 +1,1341 @@
+use crate::app::{Mode, DEFAULT_MAX_PROTOCOL_ERRORS, UPLOAD_DIRNAME};
+use crate::auth::{AuthBackend, Role, RolePolicy};
+use crate::heartbeat::{HeartbeatSettings, DEFAULT_PING_INTERVAL, DEFAULT_PONG_TIMEOUT};
+use crate::history::{
+    HistoryBackend, RetentionPolicy, DEFAULT_HISTORY_FILE, DEFAULT_MAX_MESSAGES_PER_ROOM,
+    DEFAULT_REPLAY_LIMIT,
+};
+use crate::listener::{Listener, RouteSet};
+use crate::moderation::DEFAULT_BAN_FILE;
+use crate::origin::{parse_origin, OriginPolicy, DEFAULT_CORS_METHODS};
+use crate::rate_limit::{RateLimit, RateLimitSettings};
+use crate::send_queue::{
+    SendQueueSettings, SlowConsumerPolicy, DEFAULT_MAX_LAG, DEFAULT_SEND_QUEUE_CAPACITY,
+};
+use crate::shutdown::DEFAULT_SHUTDOWN_TIMEOUT;
+use crate::tls::TlsFiles;
+use crate::upload::blob::S3Config;
+use crate::upload::chunked::DEFAULT_PROGRESS_INTERVAL;
+use crate::upload::scan::DEFAULT_SCAN_TIMEOUT;
+use crate::upload::{
+    BlobBackend, CommandScanner, ScanPipeline, SecretScanner, TypePolicy, UploadLimits,
+};
+use crate::utils::parse_size;
+use axum::http::Method;
+use log::LevelFilter;
+use serde::{Deserialize, Deserializer};
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// 待ち受けるアドレスの既定値
+pub const DEFAULT_BIND: &str = "127.0.0.1:8080";
+
+/// 設定ファイル、環境変数、コマンドラインのどれか1つから読んだ設定
+///
+/// 設定ファイルでは節に分けて書き、コマンドラインと環境変数 (`WS_S_*`) では
+/// 平らに並べる。指定しなかった項目は `None` のままにして、`or` で下の層から補う。
+#[derive(Debug, Clone, Default, Deserialize, clap::Args)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConfigLayer {
+    #[command(flatten)]
+    pub server: ServerConfig,
+    #[command(flatten)]
+    pub log: LogConfig,
+    #[command(flatten)]
+    pub limits: LimitsConfig,
+    #[command(flatten)]
+    pub auth: AuthConfig,
+    #[command(flatten)]
+    pub cors: CorsConfig,
+    #[command(flatten)]
+    pub tls: TlsConfig,
+    #[command(flatten)]
+    pub send_queue: SendQueueConfig,
+    #[command(flatten)]
+    pub heartbeat: HeartbeatConfig,
+    #[command(flatten)]
+    pub history: HistoryConfig,
+    #[command(flatten)]
+    pub moderation: ModerationConfig,
+    #[command(flatten)]
+    pub uploads: UploadsConfig,
+    #[command(flatten)]
+    pub blobs: BlobsConfig,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, clap::Args)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    /// 待ち受けるアドレス (複数指定可、既定は 127.0.0.1:8080)。`admin@127.0.0.1:9090` のように
+    /// 前に付けると、そこで公開する経路を絞る (all, public, admin)
+    #[arg(
+        long,
+        visible_alias = "hostname",
+        value_name = "[ROUTES@]ADDR",
+        env = "WS_S_BIND",
+        value_delimiter = ','
+    )]
+    #[serde(deserialize_with = "one_or_many")]
+    pub bind: Option<Vec<String>>,
+
+    /// 動かし方 (development, production)。production では他のオリジンを既定で断る
+    #[arg(long, env = "WS_S_MODE")]
+    pub mode: Option<String>,
+
+    /// アップロードされたファイルの保存先 (既定は ./uploads)
+    #[arg(long, env = "WS_S_UPLOAD_DIR")]
+    pub upload_dir: Option<PathBuf>,
+
+    /// 停止の合図の後、アップロードや履歴の書き込みが終わるのを待つ秒数 (既定は 10)
+    #[arg(long, env = "WS_S_SHUTDOWN_TIMEOUT_SECS")]
+    pub shutdown_timeout_secs: Option<u64>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, clap::Args)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    /// ログの詳しさ (off, error, warn, info, debug, trace)
+    #[arg(long = "log-level", env = "WS_S_LOG_LEVEL")]
+    pub level: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, clap::Args)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    /// 1ファイルの大きさの上限 (例: 64MiB)
+    #[arg(long, env = "WS_S_MAX_UPLOAD_SIZE")]
+    pub max_upload_size: Option<String>,
+
+    /// 1ユーザーあたりの保存容量の上限 (既定は 1GiB)。0 なら無制限
+    #[arg(long, env = "WS_S_USER_QUOTA")]
+    pub user_quota: Option<String>,
+
+    /// 1ルームあたりの保存容量の上限 (既定は 0)。0 なら無制限
+    #[arg(long, env = "WS_S_ROOM_QUOTA")]
+    pub room_quota: Option<String>,
+
+    /// この回数だけ不正なフレームを送ってきたクライアントを切断する
+    #[arg(long, env = "WS_S_MAX_PROTOCOL_ERRORS")]
+    pub max_protocol_errors: Option<u32>,
+
+    /// 1接続あたりのチャットの頻度 (`<1秒あたりの件数>:<続けて送れる件数>`、0 なら無制限)
+    #[arg(long, env = "WS_S_CHAT_RATE")]
+    pub chat_rate: Option<String>,
+
+    /// 1接続あたりのファイル転送の頻度
+    #[arg(long, env = "WS_S_FILE_RATE")]
+    pub file_rate: Option<String>,
+
+    /// 1接続あたりの `/list` の頻度
+    #[arg(long, env = "WS_S_LIST_RATE")]
+    pub list_rate: Option<String>,
+
+    /// 1接続あたりの分割アップロードのチャンクの頻度
+    #[arg(long, env = "WS_S_CHUNK_RATE")]
+    pub chunk_rate: Option<String>,
+
+    /// 頻度の制限を越えたクライアントに、この回数までは警告だけする (既定は 3)
+    #[arg(long, env = "WS_S_RATE_LIMIT_WARNINGS")]
+    pub rate_limit_warnings: Option<u32>,
+
+    /// 警告の後、この回数までは受信を遅らせ、それを越えたら切断する (既定は 3)
+    #[arg(long, env = "WS_S_RATE_LIMIT_THROTTLES")]
+    pub rate_limit_throttles: Option<u32>,
+
+    /// 受信を遅らせる時間 (ミリ秒、既定は 1000)
+    #[arg(long, env = "WS_S_RATE_LIMIT_THROTTLE_MS")]
+    pub rate_limit_throttle_ms: Option<u64>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, clap::Args)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    /// `/ws` への接続の認証 (none, token, jwt, password)
+    #[arg(long = "auth", env = "WS_S_AUTH")]
+    pub backend: Option<String>,
+
+    /// `<名前>:<トークン>` を並べたファイル (auth が token の場合)
+    #[arg(long = "auth-token-file", env = "WS_S_AUTH_TOKEN_FILE")]
+    pub token_file: Option<PathBuf>,
+
+    /// JWT の署名を確かめる鍵 (auth が jwt の場合)
+    #[arg(long, env = "WS_S_JWT_SECRET", hide_env_values = true)]
+    pub jwt_secret: Option<String>,
+
+    /// `<名前>:<argon2 のハッシュ>` を並べたファイル (auth が password の場合)
+    #[arg(long, env = "WS_S_PASSWORD_FILE")]
+    pub password_file: Option<PathBuf>,
+
+    /// `<名前>[@<ルーム>]:<役割>` を並べたファイル
+    #[arg(long, env = "WS_S_ROLES_FILE")]
+    pub roles_file: Option<PathBuf>,
+
+    /// ファイルに無い接続の役割 (none, guest, member, moderator, admin)
+    #[arg(long, env = "WS_S_DEFAULT_ROLE")]
+    pub default_role: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, clap::Args)]
+#[serde(default, deny_unknown_fields)]
+pub struct CorsConfig {
+    /// API の呼び出しと `/ws` への接続を許すオリジン (複数指定可、`*` なら全て)
+    #[arg(
+        long = "allowed-origin",
+        value_name = "ORIGIN",
+        env = "WS_S_ALLOWED_ORIGINS",
+        value_delimiter = ','
+    )]
+    pub allowed_origins: Option<Vec<String>>,
+
+    /// 他のオリジンに許すメソッド (複数指定可、既定は GET, POST, PUT, DELETE, OPTIONS)
+    #[arg(
+        long = "cors-method",
+        value_name = "METHOD",
+        env = "WS_S_CORS_METHODS",
+        value_delimiter = ','
+    )]
+    pub methods: Option<Vec<String>>,
+
+    /// 他のオリジンからの Cookie や Authorization を付けた呼び出しを許す
+    #[arg(
+        long = "cors-credentials",
+        env = "WS_S_CORS_CREDENTIALS",
+        num_args = 0..=1,
+        default_missing_value = "true"
+    )]
+    pub credentials: Option<bool>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, clap::Args)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsConfig {
+    /// サーバーの証明書 (PEM)。鍵と合わせて指定すると wss:// と https:// で待ち受ける
+    #[arg(long = "tls-cert", env = "WS_S_TLS_CERT")]
+    pub cert_file: Option<PathBuf>,
+
+    /// 証明書の秘密鍵 (PEM)
+    #[arg(long = "tls-key", env = "WS_S_TLS_KEY")]
+    pub key_file: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, clap::Args)]
+#[serde(default, deny_unknown_fields)]
+pub struct SendQueueConfig {
+    /// 1接続あたりの送信キューの長さ (既定は 100)
+    #[arg(long = "send-queue-size", env = "WS_S_SEND_QUEUE_SIZE")]
+    pub size: Option<usize>,
+
+    /// 送信キューが一杯になった時の振る舞い (drop-oldest, drop-newest, disconnect)
+    #[arg(long, env = "WS_S_SLOW_CONSUMER_POLICY")]
+    pub slow_consumer_policy: Option<String>,
+
+    /// disconnect の場合に、追いつかないままこの件数を取りこぼしたクライアントを切断する (既定は 1000)
+    #[arg(long, env = "WS_S_MAX_LAG")]
+    pub max_lag: Option<u64>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, clap::Args)]
+#[serde(default, deny_unknown_fields)]
+pub struct HeartbeatConfig {
+    /// 何も受け取らないまま、この秒数が過ぎたら Ping を送る (既定は 30、0 なら送らない)
+    #[arg(long, env = "WS_S_PING_INTERVAL_SECS")]
+    pub ping_interval_secs: Option<u64>,
+
+    /// Ping を送ってからこの秒数内に応答が無ければ切断する (既定は 10)
+    #[arg(long, env = "WS_S_PONG_TIMEOUT_SECS")]
+    pub pong_timeout_secs: Option<u64>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, clap::Args)]
+#[serde(default, deny_unknown_fields)]
+pub struct HistoryConfig {
+    /// チャット履歴の保存先 (file, memory)
+    #[arg(
+        id = "history_backend",
+        long = "history-backend",
+        value_name = "BACKEND",
+        env = "WS_S_HISTORY_BACKEND"
+    )]
+    pub backend: Option<String>,
+
+    /// チャット履歴のファイル (backend が file の場合、既定は ./history.jsonl)
+    #[arg(long = "history-file", env = "WS_S_HISTORY_FILE")]
+    pub file: Option<PathBuf>,
+
+    /// 1ルームあたりに残す履歴の件数 (既定は 10000、0 なら無制限)
+    #[arg(long = "history-max-messages", env = "WS_S_HISTORY_MAX_MESSAGES")]
+    pub max_messages: Option<usize>,
+
+    /// これより古い履歴を捨てる (秒)
+    #[arg(long = "history-max-age-secs", env = "WS_S_HISTORY_MAX_AGE_SECS")]
+    pub max_age_secs: Option<u64>,
+
+    /// ルームに参加したクライアントへ返す履歴の件数 (既定は 50)
+    #[arg(long = "history-replay", env = "WS_S_HISTORY_REPLAY")]
+    pub replay: Option<usize>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, clap::Args)]
+#[serde(default, deny_unknown_fields)]
+pub struct ModerationConfig {
+    /// BAN の一覧を保存するファイル (既定は ./bans.json)
+    #[arg(long, env = "WS_S_BAN_FILE")]
+    pub ban_file: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, clap::Args)]
+#[serde(default, deny_unknown_fields)]
+pub struct UploadsConfig {
+    /// 分割アップロードの進み具合を知らせる間隔 (ミリ秒、既定は 500)
+    #[arg(
+        long = "upload-progress-interval-ms",
+        env = "WS_S_UPLOAD_PROGRESS_INTERVAL_MS"
+    )]
+    pub progress_interval_ms: Option<u64>,
+
+    /// 実行ファイルやスクリプトのアップロードを断る
+    #[arg(
+        long,
+        env = "WS_S_REFUSE_EXECUTABLES",
+        num_args = 0..=1,
+        default_missing_value = "true"
+    )]
+    pub refuse_executables: Option<bool>,
+
+    /// 受け付けない種類 (複数指定可)。`video/` のように `/` で終われば前方一致
+    #[arg(
+        long = "deny-type",
+        value_name = "MIME",
+        env = "WS_S_DENIED_TYPES",
+        value_delimiter = ','
+    )]
+    pub denied_types: Option<Vec<String>>,
+
+    /// アップロードを公開する前に実行する検査コマンド (複数指定可)。ファイルの場所を最後の引数に
+    /// 付けて実行し、終了コードが 0 なら受け付ける
+    #[arg(
+        long = "scan-command",
+        value_name = "COMMAND",
+        env = "WS_S_SCAN_COMMAND"
+    )]
+    #[serde(deserialize_with = "one_or_many")]
+    pub scan_commands: Option<Vec<String>>,
+
+    /// 検査コマンドを待つ時間 (秒、既定は 60)
+    #[arg(long, env = "WS_S_SCAN_TIMEOUT_SECS")]
+    pub scan_timeout_secs: Option<u64>,
+
+    /// 秘密鍵やアクセスキーを含むファイルを断る
+    #[arg(
+        long,
+        env = "WS_S_SCAN_SECRETS",
+        num_args = 0..=1,
+        default_missing_value = "true"
+    )]
+    pub scan_secrets: Option<bool>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, clap::Args)]
+#[serde(default, deny_unknown_fields)]
+pub struct BlobsConfig {
+    /// アップロードされたファイルの保存先 (fs, memory, s3)
+    #[arg(
+        id = "blob_backend",
+        long = "blob-backend",
+        value_name = "BACKEND",
+        env = "WS_S_BLOB_BACKEND"
+    )]
+    pub backend: Option<String>,
+
+    /// S3 互換ストレージの URL (backend が s3 の場合)
+    #[arg(long, env = "WS_S_S3_ENDPOINT")]
+    pub s3_endpoint: Option<String>,
+
+    /// S3 のバケット
+    #[arg(long, env = "WS_S_S3_BUCKET")]
+    pub s3_bucket: Option<String>,
+
+    /// S3 のリージョン (既定は us-east-1)
+    #[arg(long, env = "WS_S_S3_REGION")]
+    pub s3_region: Option<String>,
+
+    /// S3 のアクセスキー
+    #[arg(long, env = "AWS_ACCESS_KEY_ID", hide_env_values = true)]
+    pub s3_access_key: Option<String>,
+
+    /// S3 のシークレットキー
+    #[arg(long, env = "AWS_SECRET_ACCESS_KEY", hide_env_values = true)]
+    pub s3_secret_key: Option<String>,
+
+    /// S3 のキーの前に付ける文字列 (例: ws_s/)
+    #[arg(long, env = "WS_S_S3_PREFIX")]
+    pub s3_prefix: Option<String>,
+}
+
+impl ConfigLayer {
+    pub fn load(path: &Path) -> io::Result<Self> {
+        Self::parse(&std::fs::read_to_string(path)?)
+    }
+
+    pub fn parse(content: &str) -> io::Result<Self> {
+        toml::from_str(content).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    /// 指定の無い項目を `lower` で補う
+    pub fn or(self, lower: Self) -> Self {
+        Self {
+            server: ServerConfig {
+                bind: self.server.bind.or(lower.server.bind),
+                mode: self.server.mode.or(lower.server.mode),
+                upload_dir: self.server.upload_dir.or(lower.server.upload_dir),
+                shutdown_timeout_secs: self
+                    .server
+                    .shutdown_timeout_secs
+                    .or(lower.server.shutdown_timeout_secs),
+            },
+            log: LogConfig {
+                level: self.log.level.or(lower.log.level),
+            },
+            limits: LimitsConfig {
+                max_upload_size: self.limits.max_upload_size.or(lower.limits.max_upload_size),
+                user_quota: self.limits.user_quota.or(lower.limits.user_quota),
+                room_quota: self.limits.room_quota.or(lower.limits.room_quota),
+                max_protocol_errors: self
+                    .limits
+                    .max_protocol_errors
+                    .or(lower.limits.max_protocol_errors),
+                chat_rate: self.limits.chat_rate.or(lower.limits.chat_rate),
+                file_rate: self.limits.file_rate.or(lower.limits.file_rate),
+                list_rate: self.limits.list_rate.or(lower.limits.list_rate),
+                chunk_rate: self.limits.chunk_rate.or(lower.limits.chunk_rate),
+                rate_limit_warnings: self
+                    .limits
+                    .rate_limit_warnings
+                    .or(lower.limits.rate_limit_warnings),
+                rate_limit_throttles: self
+                    .limits
+                    .rate_limit_throttles
+                    .or(lower.limits.rate_limit_throttles),
+                rate_limit_throttle_ms: self
+                    .limits
+                    .rate_limit_throttle_ms
+                    .or(lower.limits.rate_limit_throttle_ms),
+            },
+            auth: AuthConfig {
+                backend: self.auth.backend.or(lower.auth.backend),
+                token_file: self.auth.token_file.or(lower.auth.token_file),
+                jwt_secret: self.auth.jwt_secret.or(lower.auth.jwt_secret),
+                password_file: self.auth.password_file.or(lower.auth.password_file),
+                roles_file: self.auth.roles_file.or(lower.auth.roles_file),
+                default_role: self.auth.default_role.or(lower.auth.default_role),
+            },
+            cors: CorsConfig {
+                allowed_origins: self.cors.allowed_origins.or(lower.cors.allowed_origins),
+                methods: self.cors.methods.or(lower.cors.methods),
+                credentials: self.cors.credentials.or(lower.cors.credentials),
+            },
+            tls: TlsConfig {
+                cert_file: self.tls.cert_file.or(lower.tls.cert_file),
+                key_file: self.tls.key_file.or(lower.tls.key_file),
+            },
+            send_queue: SendQueueConfig {
+                size: self.send_queue.size.or(lower.send_queue.size),
+                slow_consumer_policy: self
+                    .send_queue
+                    .slow_consumer_policy
+                    .or(lower.send_queue.slow_consumer_policy),
+                max_lag: self.send_queue.max_lag.or(lower.send_queue.max_lag),
+            },
+            heartbeat: HeartbeatConfig {
+                ping_interval_secs: self
+                    .heartbeat
+                    .ping_interval_secs
+                    .or(lower.heartbeat.ping_interval_secs),
+                pong_timeout_secs: self
+                    .heartbeat
+                    .pong_timeout_secs
+                    .or(lower.heartbeat.pong_timeout_secs),
+            },
+            history: HistoryConfig {
+                backend: self.history.backend.or(lower.history.backend),
+                file: self.history.file.or(lower.history.file),
+                max_messages: self.history.max_messages.or(lower.history.max_messages),
+                max_age_secs: self.history.max_age_secs.or(lower.history.max_age_secs),
+                replay: self.history.replay.or(lower.history.replay),
+            },
+            moderation: ModerationConfig {
+                ban_file: self.moderation.ban_file.or(lower.moderation.ban_file),
+            },
+            uploads: UploadsConfig {
+                progress_interval_ms: self
+                    .uploads
+                    .progress_interval_ms
+                    .or(lower.uploads.progress_interval_ms),
+                refuse_executables: self
+                    .uploads
+                    .refuse_executables
+                    .or(lower.uploads.refuse_executables),
+                denied_types: self.uploads.denied_types.or(lower.uploads.denied_types),
+                scan_commands: self.uploads.scan_commands.or(lower.uploads.scan_commands),
+                scan_timeout_secs: self
+                    .uploads
+                    .scan_timeout_secs
+                    .or(lower.uploads.scan_timeout_secs),
+                scan_secrets: self.uploads.scan_secrets.or(lower.uploads.scan_secrets),
+            },
+            blobs: BlobsConfig {
+                backend: self.blobs.backend.or(lower.blobs.backend),
+                s3_endpoint: self.blobs.s3_endpoint.or(lower.blobs.s3_endpoint),
+                s3_bucket: self.blobs.s3_bucket.or(lower.blobs.s3_bucket),
+                s3_region: self.blobs.s3_region.or(lower.blobs.s3_region),
+                s3_access_key: self.blobs.s3_access_key.or(lower.blobs.s3_access_key),
+                s3_secret_key: self.blobs.s3_secret_key.or(lower.blobs.s3_secret_key),
+                s3_prefix: self.blobs.s3_prefix.or(lower.blobs.s3_prefix),
+            },
+        }
+    }
+}
+
+/// 設定ファイルでは1つなら文字列、複数なら配列で書ける
+fn one_or_many<'de, D>(deserializer: D) -> Result<Option<Vec<String>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+
+    Ok(Some(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(value) => vec![value],
+        OneOrMany::Many(values) => values,
+    }))
+}
+
+/// 設定の誤り。`key` は設定ファイルでの名前
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigError {
+    pub key: &'static str,
+    pub message: String,
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.key, self.message)
+    }
+}
+
+/// 起動時に見つかった誤りをまとめて報告する
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidConfig(pub Vec<ConfigError>);
+
+impl Display for InvalidConfig {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "invalid configuration")?;
+        for error in &self.0 {
+            write!(f, "\n  {}", error)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for InvalidConfig {}
+
+/// 認証の方式と、その方式に要るもの
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuthMethod {
+    None,
+    Token(PathBuf),
+    Jwt(String),
+    Password(PathBuf),
+}
+
+impl AuthMethod {
+    pub fn backend(&self) -> AuthBackend {
+        match self {
+            AuthMethod::None => AuthBackend::None,
+            AuthMethod::Token(_) => AuthBackend::Token,
+            AuthMethod::Jwt(_) => AuthBackend::Jwt,
+            AuthMethod::Password(_) => AuthBackend::Password,
+        }
+    }
+}
+
+/// アップロードされたファイルの保存先と、その保存先に要るもの
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BlobMethod {
+    Fs,
+    Memory,
+    S3(S3Config),
+}
+
+impl BlobMethod {
+    pub fn backend(&self) -> BlobBackend {
+        match self {
+            BlobMethod::Fs => BlobBackend::Fs,
+            BlobMethod::Memory => BlobBackend::Memory,
+            BlobMethod::S3(_) => BlobBackend::S3,
+        }
+    }
+}
+
+/// チャット履歴の保存先と残し方
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HistorySettings {
+    pub backend: HistoryBackend,
+    /// `backend` が file の場合だけ使う
+    pub file: PathBuf,
+    pub retention: RetentionPolicy,
+    /// ルームに参加したクライアントへ返す件数
+    pub replay: usize,
+}
+
+/// アップロードを一覧に載せる前の検査と、進み具合の知らせ方
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UploadSettings {
+    pub progress_interval: Duration,
+    pub refuse_executables: bool,
+    pub denied_types: Vec<String>,
+    pub scan_secrets: bool,
+    /// 待つ時間は設定済み
+    pub scan_commands: Vec<CommandScanner>,
+    pub scan_timeout: Duration,
+}
+
+impl UploadSettings {
+    /// 軽い検査から順に並べる
+    pub fn scan_pipeline(&self) -> ScanPipeline {
+        let mut pipeline = ScanPipeline::new();
+        if !self.denied_types.is_empty() {
+            pipeline = pipeline.with(TypePolicy::new(self.denied_types.clone()));
+        }
+        if self.scan_secrets {
+            pipeline = pipeline.with(SecretScanner);
+        }
+        for scanner in &self.scan_commands {
+            pipeline = pipeline.with(scanner.clone());
+        }
+        pipeline
+    }
+}
+
+/// 全ての層を合わせて確かめた設定
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// 1つ以上
+    pub listeners: Vec<Listener>,
+    pub mode: Mode,
+    pub upload_dir: PathBuf,
+    pub log_level: LevelFilter,
+    pub upload_limits: UploadLimits,
+    pub max_protocol_errors: u32,
+    pub rate_limits: RateLimitSettings,
+    pub auth: AuthMethod,
+    pub roles_file: Option<PathBuf>,
+    pub default_role: Role,
+    pub origins: OriginPolicy,
+    /// 指定が無ければ平文で待ち受ける
+    pub tls: Option<TlsFiles>,
+    pub send_queue: SendQueueSettings,
+    pub heartbeat: HeartbeatSettings,
+    /// 停止の合図の後、処理中のものを待つ時間
+    pub shutdown_timeout: Duration,
+    pub history: HistorySettings,
+    pub ban_file: PathBuf,
+    pub uploads: UploadSettings,
+    pub blobs: BlobMethod,
+}
+
+impl Config {
+    /// 値を読み、組み合わせを確かめる。誤りは1つ目で止めずに全て返す
+    pub fn resolve(layer: ConfigLayer) -> Result<Self, InvalidConfig> {
+        let mut errors = Vec::new();
+
+        let listeners = resolve_listeners(&mut errors, layer.server.bind.as_deref());
+        let mode = field(
+            &mut errors,
+            "server.mode",
+            layer.server.mode.as_deref(),
+            "development",
+            str::parse::<Mode>,
+        );
+        let level = field(
+            &mut errors,
+            "log.level",
+            layer.log.level.as_deref(),
+            "info",
+            |s| {
+                s.parse::<LevelFilter>().map_err(|_| {
+                    format!(
+                        "unknown log level `{}` (off, error, warn, info, debug, trace)",
+                        s
+                    )
+                })
+            },
+        );
+
+        let limits = &layer.limits;
+        let max_file_size = field(
+            &mut errors,
+            "limits.max_upload_size",
+            limits.max_upload_size.as_deref(),
+            "64MiB",
+            parse_size,
+        );
+        let user_quota = field(
+            &mut errors,
+            "limits.user_quota",
+            limits.user_quota.as_deref(),
+            "1GiB",
+            parse_size,
+        );
+        let room_quota = field(
+            &mut errors,
+            "limits.room_quota",
+            limits.room_quota.as_deref(),
+            "0",
+            parse_size,
+        );
+        positive(&mut errors, "limits.max_upload_size", max_file_size == 0);
+        let max_protocol_errors = limits
+            .max_protocol_errors
+            .unwrap_or(DEFAULT_MAX_PROTOCOL_ERRORS);
+        positive(
+            &mut errors,
+            "limits.max_protocol_errors",
+            max_protocol_errors == 0,
+        );
+        let defaults = RateLimitSettings::default();
+        let mut rate = |key, value: &Option<String>, default: RateLimit| {
+            field(
+                &mut errors,
+                key,
+                value.as_deref(),
+                &default.to_string(),
+                str::parse::<RateLimit>,
+            )
+        };
+        let rate_limits = RateLimitSettings {
+            chat: rate("limits.chat_rate", &limits.chat_rate, defaults.chat),
+            file: rate("limits.file_rate", &limits.file_rate, defaults.file),
+            list: rate("limits.list_rate", &limits.list_rate, defaults.list),
+            chunk: rate("limits.chunk_rate", &limits.chunk_rate, defaults.chunk),
+            warnings: limits.rate_limit_warnings.unwrap_or(defaults.warnings),
+            throttles: limits.rate_limit_throttles.unwrap_or(defaults.throttles),
+            throttle_delay: limits
+                .rate_limit_throttle_ms
+                .map(Duration::from_millis)
+                .unwrap_or(defaults.throttle_delay),
+            ..defaults
+        };
+
+        let auth = resolve_auth(&mut errors, &layer.auth);
+        // 管理 API は認証した相手にしか使わせないので、認証しないなら管理用の待ち受けは意味が無い
+        if auth == AuthMethod::None
+            && listeners
+                .iter()
+                .any(|listener| listener.routes == RouteSet::Admin)
+        {
+            errors.push(ConfigError {
+                key: "server.bind",
+                message: "admin routes require authentication (auth.backend is none)".to_string(),
+            });
+        }
+        let default_role = field(
+            &mut errors,
+            "auth.default_role",
+            layer.auth.default_role.as_deref(),
+            "member",
+            str::parse::<Role>,
+        );
+
+        let origins = resolve_origins(&mut errors, mode, &layer.cors);
+        let tls = resolve_tls(&mut errors, &layer.tls);
+        let send_queue = resolve_send_queue(&mut errors, &layer.send_queue);
+        let heartbeat = resolve_heartbeat(&mut errors, &layer.heartbeat);
+        let history = resolve_history(&mut errors, &layer.history);
+        let uploads = resolve_uploads(&mut errors, &layer.uploads);
+        let blobs = resolve_blobs(&mut errors, &layer.blobs);
+
+        if !errors.is_empty() {
+            return Err(InvalidConfig(errors));
+        }
+        Ok(Self {
+            listeners,
+            mode,
+            upload_dir: layer
+                .server
+                .upload_dir
+                .unwrap_or_else(|| PathBuf::from(UPLOAD_DIRNAME)),
+            log_level: level,
+            upload_limits: UploadLimits {
+                max_file_size,
+                user_quota: Some(user_quota).filter(|quota| *quota > 0),
+                room_quota: Some(room_quota).filter(|quota| *quota > 0),
+            },
+            max_protocol_errors,
+            rate_limits,
+            auth,
+            roles_file: layer.auth.roles_file,
+            default_role,
+            origins,
+            tls,
+            send_queue,
+            heartbeat,
+            shutdown_timeout: layer
+                .server
+                .shutdown_timeout_secs
+                .map(Duration::from_secs)
+                .unwrap_or(DEFAULT_SHUTDOWN_TIMEOUT),
+            history,
+            ban_file: layer
+                .moderation
+                .ban_file
+                .unwrap_or_else(|| PathBuf::from(DEFAULT_BAN_FILE)),
+            uploads,
+            blobs,
+        })
+    }
+
+    /// 役割の設定ファイルを読む。読み直しのたびに呼ぶ
+    pub fn role_policy(&self) -> io::Result<RolePolicy> {
+        match &self.roles_file {
+            Some(path) => RolePolicy::load(path, self.default_role)
+                .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e))),
+            None => Ok(RolePolicy::new(self.default_role)),
+        }
+    }
+}
+
+/// 指定が無ければ `default` を読む。読めなければ誤りを記録し、既定値で続ける
+fn field<T>(
+    errors: &mut Vec<ConfigError>,
+    key: &'static str,
+    value: Option<&str>,
+    default: &str,
+    parse: impl Fn(&str) -> Result<T, String>,
+) -> T {
+    match parse(value.unwrap_or(default).trim()) {
+        Ok(value) => value,
+        Err(message) => {
+            errors.push(ConfigError { key, message });
+            parse(default).expect("default values are valid")
+        }
+    }
+}
+
+/// `zero` なら「0 より大きく」と記録する
+fn positive(errors: &mut Vec<ConfigError>, key: &'static str, zero: bool) {
+    if zero {
+        errors.push(ConfigError {
+            key,
+            message: "must be greater than 0".to_string(),
+        });
+    }
+}
+
+fn resolve_auth(errors: &mut Vec<ConfigError>, auth: &AuthConfig) -> AuthMethod {
+    let backend = match auth.backend.as_deref().unwrap_or("none").parse() {
+        Ok(backend) => backend,
+        Err(message) => {
+            errors.push(ConfigError {
+                key: "auth.backend",
+                message,
+            });
+            return AuthMethod::None;
+        }
+    };
+    let mut required = |key: &'static str, present: bool| {
+        if !present {
+            errors.push(ConfigError {
+                key,
+                message: format!("required for {} auth", backend),
+            });
+        }
+    };
+    match backend {
+        AuthBackend::None => AuthMethod::None,
+        AuthBackend::Token => {
+            required("auth.token_file", auth.token_file.is_some());
+            AuthMethod::Token(auth.token_file.clone().unwrap_or_default())
+        }
+        AuthBackend::Jwt => {
+            let secret = auth.jwt_secret.clone().unwrap_or_default();
+            required("auth.jwt_secret", !secret.is_empty());
+            AuthMethod::Jwt(secret)
+        }
+        AuthBackend::Password => {
+            required("auth.password_file", auth.password_file.is_some());
+            AuthMethod::Password(auth.password_file.clone().unwrap_or_default())
+        }
+    }
+}
+
+fn resolve_listeners(errors: &mut Vec<ConfigError>, bind: Option<&[String]>) -> Vec<Listener> {
+    let bind = match bind {
+        Some(bind) if !bind.is_empty() => bind,
+        Some(_) => {
+            errors.push(ConfigError {
+                key: "server.bind",
+                message: "at least one address is required".to_string(),
+            });
+            return Vec::new();
+        }
+        None => return vec![DEFAULT_BIND.parse().expect("default values are valid")],
+    };
+    bind.iter()
+        .filter_map(|s| match s.trim().parse() {
+            Ok(listener) => Some(listener),
+            Err(message) => {
+                errors.push(ConfigError {
+                    key: "server.bind",
+                    message,
+                });
+                None
+            }
+        })
+        .collect()
+}
+
+fn resolve_send_queue(errors: &mut Vec<ConfigError>, queue: &SendQueueConfig) -> SendQueueSettings {
+    let capacity = queue.size.unwrap_or(DEFAULT_SEND_QUEUE_CAPACITY);
+    positive(errors, "send_queue.size", capacity == 0);
+    SendQueueSettings {
+        capacity,
+        policy: field(
+            errors,
+            "send_queue.slow_consumer_policy",
+            queue.slow_consumer_policy.as_deref(),
+            "drop-oldest",
+            str::parse::<SlowConsumerPolicy>,
+        ),
+        max_lag: queue.max_lag.unwrap_or(DEFAULT_MAX_LAG),
+    }
+}
+
+/// Ping を送るなら、応答を待つ時間も要る
+fn resolve_heartbeat(
+    errors: &mut Vec<ConfigError>,
+    heartbeat: &HeartbeatConfig,
+) -> HeartbeatSettings {
+    let interval = heartbeat
+        .ping_interval_secs
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_PING_INTERVAL);
+    let timeout = heartbeat
+        .pong_timeout_secs
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_PONG_TIMEOUT);
+    positive(
+        errors,
+        "heartbeat.pong_timeout_secs",
+        !interval.is_zero() && timeout.is_zero(),
+    );
+    HeartbeatSettings { interval, timeout }
+}
+
+fn resolve_history(errors: &mut Vec<ConfigError>, history: &HistoryConfig) -> HistorySettings {
+    HistorySettings {
+        backend: field(
+            errors,
+            "history.backend",
+            history.backend.as_deref(),
+            "file",
+            str::parse::<HistoryBackend>,
+        ),
+        file: history
+            .file
+            .clone()
+            .unwrap_or_else(|| PathBuf::from(DEFAULT_HISTORY_FILE)),
+        retention: RetentionPolicy {
+            max_messages_per_room: Some(
+                history
+                    .max_messages
+                    .unwrap_or(DEFAULT_MAX_MESSAGES_PER_ROOM),
+            )
+            .filter(|max| *max > 0),
+            max_age: history.max_age_secs.map(Duration::from_secs),
+        },
+        replay: history.replay.unwrap_or(DEFAULT_REPLAY_LIMIT),
+    }
+}
+
+fn resolve_uploads(errors: &mut Vec<ConfigError>, uploads: &UploadsConfig) -> UploadSettings {
+    let scan_timeout = uploads
+        .scan_timeout_secs
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_SCAN_TIMEOUT);
+    let scan_commands = uploads
+        .scan_commands
+        .iter()
+        .flatten()
+        .filter_map(|command| match CommandScanner::parse(command) {
+            Ok(scanner) => Some(scanner.timeout(scan_timeout)),
+            Err(message) => {
+                errors.push(ConfigError {
+                    key: "uploads.scan_commands",
+                    message: format!("invalid command {:?}: {}", command, message),
+                });
+                None
+            }
+        })
+        .collect();
+    UploadSettings {
+        progress_interval: uploads
+            .progress_interval_ms
+            .map(Duration::from_millis)
+            .unwrap_or(DEFAULT_PROGRESS_INTERVAL),
+        refuse_executables: uploads.refuse_executables.unwrap_or(false),
+        denied_types: uploads.denied_types.clone().unwrap_or_default(),
+        scan_secrets: uploads.scan_secrets.unwrap_or(false),
+        scan_commands,
+        scan_timeout,
+    }
+}
+
+/// S3 なら接続先と鍵が全て要る
+fn resolve_blobs(errors: &mut Vec<ConfigError>, blobs: &BlobsConfig) -> BlobMethod {
+    let backend = match blobs.backend.as_deref().unwrap_or("fs").trim().parse() {
+        Ok(backend) => backend,
+        Err(message) => {
+            errors.push(ConfigError {
+                key: "blobs.backend",
+                message,
+            });
+            return BlobMethod::Fs;
+        }
+    };
+    let mut required = |key: &'static str, value: &Option<String>| match value {
+        Some(value) if !value.is_empty() => value.clone(),
+        _ => {
+            errors.push(ConfigError {
+                key,
+                message: format!("required for {} blobs", backend),
+            });
+            String::new()
+        }
+    };
+    match backend {
+        BlobBackend::Fs => BlobMethod::Fs,
+        BlobBackend::Memory => BlobMethod::Memory,
+        BlobBackend::S3 => BlobMethod::S3(S3Config {
+            endpoint: required("blobs.s3_endpoint", &blobs.s3_endpoint),
+            bucket: required("blobs.s3_bucket", &blobs.s3_bucket),
+            region: blobs
+                .s3_region
+                .clone()
+                .unwrap_or_else(|| "us-east-1".to_string()),
+            access_key: required("blobs.s3_access_key", &blobs.s3_access_key),
+            secret_key: required("blobs.s3_secret_key", &blobs.s3_secret_key),
+            prefix: blobs.s3_prefix.clone().unwrap_or_default(),
+        }),
+    }
+}
+
+/// 証明書と鍵は両方指定するか、どちらも指定しない
+fn resolve_tls(errors: &mut Vec<ConfigError>, tls: &TlsConfig) -> Option<TlsFiles> {
+    match (&tls.cert_file, &tls.key_file) {
+        (Some(cert), Some(key)) => Some(TlsFiles {
+            cert: cert.clone(),
+            key: key.clone(),
+        }),
+        (None, None) => None,
+        (Some(_), None) => {
+            errors.push(ConfigError {
+                key: "tls.key_file",
+                message: "required when tls.cert_file is set".to_string(),
+            });
+            None
+        }
+        (None, Some(_)) => {
+            errors.push(ConfigError {
+                key: "tls.cert_file",
+                message: "required when tls.key_file is set".to_string(),
+            });
+            None
+        }
+    }
+}
+
+/// オリジンを指定しなければ、開発中はどこからでも許し、本番では全て断る
+fn resolve_origins(errors: &mut Vec<ConfigError>, mode: Mode, cors: &CorsConfig) -> OriginPolicy {
+    let mut policy = match &cors.allowed_origins {
+        Some(origins) if !origins.is_empty() => {
+            let origins = origins
+                .iter()
+                .filter_map(|origin| match parse_origin(origin) {
+                    Ok(origin) => Some(origin),
+                    Err(message) => {
+                        errors.push(ConfigError {
+                            key: "cors.allowed_origins",
+                            message,
+                        });
+                        None
+                    }
+                })
+                .collect();
+            OriginPolicy::allow(origins)
+        }
+        _ if mode == Mode::Production => OriginPolicy::deny_all(),
+        _ => OriginPolicy::default(),
+    };
+    policy.methods = match &cors.methods {
+        Some(methods) if !methods.is_empty() => methods
+            .iter()
+            .filter_map(
+                |method| match method.trim().to_ascii_uppercase().parse::<Method>() {
+                    Ok(method) => Some(method),
+                    Err(_) => {
+                        errors.push(ConfigError {
+                            key: "cors.methods",
+                            message: format!("invalid method `{}`", method),
+                        });
+                        None
+                    }
+                },
+            )
+            .collect(),
+        _ => DEFAULT_CORS_METHODS.to_vec(),
+    };
+    policy.allow_credentials = cors.credentials.unwrap_or(false);
+    if let Err(message) = policy.validate() {
+        errors.push(ConfigError {
+            key: "cors.credentials",
+            message,
+        });
+    }
+    policy
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FILE: &str = r#"
+[server]
+bind = "0.0.0.0:9000"
+mode = "production"
+upload_dir = "/var/lib/ws_s"
+
+[log]
+level = "debug"
+
+[limits]
+max_upload_size = "8MiB"
+room_quota = "1GiB"
+chat_rate = "2:4"
+
+[auth]
+backend = "token"
+token_file = "tokens.txt"
+
+[cors]
+allowed_origins = ["https://chat.example.com"]
+credentials = true
+
+[tls]
+cert_file = "/etc/ws_s/cert.pem"
+key_file = "/etc/ws_s/key.pem"
+
+[send_queue]
+size = 10
+slow_consumer_policy = "disconnect"
+
+[heartbeat]
+ping_interval_secs = 0
+
+[history]
+backend = "memory"
+max_messages = 0
+replay = 5
+
+[moderation]
+ban_file = "/var/lib/ws_s/bans.json"
+
+[uploads]
+scan_commands = "clamdscan --no-summary"
+scan_timeout_secs = 5
+scan_secrets = true
+
+[blobs]
+backend = "s3"
+s3_endpoint = "http://127.0.0.1:9000"
+s3_bucket = "ws-s"
+s3_access_key = "key"
+s3_secret_key = "secret"
+"#;
+
+    #[test]
+    fn test_file_and_overrides() {
+        let file = ConfigLayer::parse(FILE).unwrap();
+        let overrides = ConfigLayer {
+            server: ServerConfig {
+                bind: Some(vec!["127.0.0.1:9001".to_string()]),
+                ..ServerConfig::default()
+            },
+            log: LogConfig {
+                level: Some("warn".to_string()),
+            },
+            history: HistoryConfig {
+                replay: Some(20),
+                ..HistoryConfig::default()
+            },
+            ..ConfigLayer::default()
+        };
+        let config = Config::resolve(overrides.or(file)).unwrap();
+
+        assert_eq!(config.listeners, ["127.0.0.1:9001".parse().unwrap()]);
+        assert_eq!(config.log_level, LevelFilter::Warn);
+        assert_eq!(config.mode, Mode::Production);
+        assert_eq!(config.upload_dir, PathBuf::from("/var/lib/ws_s"));
+        assert_eq!(config.upload_limits.max_file_size, 8 * 1024 * 1024);
+        assert_eq!(config.upload_limits.user_quota, Some(1024 * 1024 * 1024));
+        assert_eq!(config.upload_limits.room_quota, Some(1024 * 1024 * 1024));
+        assert_eq!(config.rate_limits.chat, RateLimit::new(2.0, 4));
+        assert_eq!(config.auth, AuthMethod::Token(PathBuf::from("tokens.txt")));
+        assert!(config.origins.allows("https://chat.example.com"));
+        assert!(config.origins.allow_credentials);
+        assert_eq!(
+            config.tls,
+            Some(TlsFiles {
+                cert: PathBuf::from("/etc/ws_s/cert.pem"),
+                key: PathBuf::from("/etc/ws_s/key.pem"),
+            })
+        );
+        assert_eq!(config.send_queue.capacity, 10);
+        assert_eq!(config.send_queue.policy, SlowConsumerPolicy::Disconnect);
+        assert!(config.heartbeat.interval.is_zero());
+        assert_eq!(config.history.backend, HistoryBackend::Memory);
+        assert_eq!(config.history.retention.max_messages_per_room, None);
+        assert_eq!(config.history.replay, 20);
+        assert_eq!(config.ban_file, PathBuf::from("/var/lib/ws_s/bans.json"));
+        assert_eq!(
+            config.uploads.scan_commands,
+            [
+                CommandScanner::new("clamdscan", vec!["--no-summary".to_string()])
+                    .timeout(Duration::from_secs(5))
+            ]
+        );
+        assert!(config.uploads.scan_secrets && !config.uploads.refuse_executables);
+        let BlobMethod::S3(s3) = &config.blobs else {
+            panic!("expected s3 blobs, got {:?}", config.blobs);
+        };
+        assert_eq!(s3.region, "us-east-1");
+        assert_eq!(s3.secret_key, "secret");
+    }
+
+    #[test]
+    fn test_multiple_listeners() {
+        let layer = ConfigLayer::parse(
+            "[server]\nbind = [\"0.0.0.0:8080\", \"[::]:8080\", \"admin@localhost:9090\"]\n\
+             [auth]\nbackend = \"token\"\ntoken_file = \"tokens.txt\"\n",
+        )
+        .unwrap();
+        let config = Config::resolve(layer).unwrap();
+        let listeners: Vec<String> = config.listeners.iter().map(ToString::to_string).collect();
+        assert_eq!(
+            listeners,
+            ["0.0.0.0:8080", "[::]:8080", "admin@localhost:9090"]
+        );
+
+        let layer = ConfigLayer::parse("[server]\nbind = []\n").unwrap();
+        let InvalidConfig(errors) = Config::resolve(layer).unwrap_err();
+        assert_eq!(
+            errors[0].to_string(),
+            "server.bind: at least one address is required"
+        );
+
+        // 認証しないなら管理用の待ち受けは作れない
+        let layer = ConfigLayer::parse("[server]\nbind = [\"admin@localhost:9090\"]\n").unwrap();
+        let InvalidConfig(errors) = Config::resolve(layer).unwrap_err();
+        assert_eq!(
+            errors[0].to_string(),
+            "server.bind: admin routes require authentication (auth.backend is none)"
+        );
+    }
+
+    #[test]
+    fn test_defaults() {
+        let config = Config::resolve(ConfigLayer::default()).unwrap();
+        assert_eq!(config.listeners, [DEFAULT_BIND.parse().unwrap()]);
+        assert_eq!(config.upload_dir, PathBuf::from(UPLOAD_DIRNAME));
+        assert_eq!(config.log_level, LevelFilter::Info);
+        assert_eq!(config.auth, AuthMethod::None);
+        assert_eq!(config.origins, OriginPolicy::default());
+        assert_eq!(config.tls, None);
+        assert_eq!(config.send_queue, SendQueueSettings::default());
+        assert_eq!(config.heartbeat, HeartbeatSettings::default());
+        assert_eq!(config.rate_limits, RateLimitSettings::default());
+        assert_eq!(config.history.backend, HistoryBackend::File);
+        assert_eq!(config.ban_file, PathBuf::from(DEFAULT_BAN_FILE));
+        assert_eq!(config.blobs, BlobMethod::Fs);
+        assert!(config.uploads.scan_pipeline().is_empty());
+    }
+
+    #[test]
+    fn test_reports_every_error() {
+        assert!(ConfigLayer::parse("[server]\nport = 1\n").is_err());
+
+        let layer = ConfigLayer::parse(
+            r#"
+[server]
+bind = "localhost"
+
+[limits]
+max_upload_size = "lots"
+max_protocol_errors = 0
+
+[auth]
+backend = "jwt"
+
+[cors]
+allowed_origins = ["*"]
+credentials = true
+
+[tls]
+cert_file = "cert.pem"
+
+[send_queue]
+size = 0
+
+[heartbeat]
+pong_timeout_secs = 0
+
+[uploads]
+scan_commands = ["clamdscan", ""]
+
+[blobs]
+backend = "s3"
+s3_bucket = "ws-s"
+"#,
+        )
+        .unwrap();
+        let InvalidConfig(errors) = Config::resolve(layer).unwrap_err();
+        let keys: Vec<&str> = errors.iter().map(|error| error.key).collect();
+        assert_eq!(
+            keys,
+            [
+                "server.bind",
+                "limits.max_upload_size",
+                "limits.max_protocol_errors",
+                "auth.jwt_secret",
+                "cors.credentials",
+                "tls.key_file",
+                "send_queue.size",
+                "heartbeat.pong_timeout_secs",
+                "uploads.scan_commands",
+                "blobs.s3_endpoint",
+                "blobs.s3_access_key",
+                "blobs.s3_secret_key"
+            ]
+        );
+        assert_eq!(
+            errors[3].to_string(),
+            "auth.jwt_secret: required for jwt auth"
+        );
+    }
+}
@@ -0,0 +1,667 @@
+use crate::app::AppState;
+use crate::auth::{Action, Principal};
+use crate::heartbeat::{self, Beat, Heartbeat};
+use crate::history::{self, StoredMessage};
+use crate::metrics::Metrics;
+use crate::moderation::{self, BanTarget};
+use crate::protocol_error::{ErrorBudget, ProtocolError};
+use crate::rate_limit::{RateClass, RateDecision, RateLimiter};
+use crate::shutdown;
+use crate::socket_manager::Outbound;
+use crate::upload::FileRecord;
+use crate::utils::format_bytes;
+use axum::extract::ws::{close_code, CloseFrame, Message, WebSocket};
+use bytes::Bytes;
+use futures_util::{SinkExt, StreamExt};
+use log::{debug, info, warn};
+use message_pack::{
+    get_type, BinaryDeserializable, BinarySerializable, ErrorCode, FileTransferHeader,
+    FileTransferMessage, JoinMessage, ListMessage, MessageType, ModerationAction,
+    ModerationMessage, TextMessage, UploadAckMessage, UploadBeginMessage, UploadChunkMessage,
+    UploadCommitMessage, UploadId,
+};
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+/// バイナリフレームを処理した後、接続を続けるかどうか
+enum Flow {
+    Continue,
+    Exit,
+}
+
+pub async fn handle_socket(
+    state: AppState,
+    mut socket: WebSocket,
+    principal: Option<Principal>,
+    addr: Option<IpAddr>,
+) {
+    if let Err(e) = socket
+        .send(Message::from("connected(server)".to_string()))
+        .await
+    {
+        warn!("Error while sending connected message: {:?}", e);
+        return;
+    }
+
+    let (mut sender, mut receiver) = socket.split();
+
+    // クライアントを管理に追加
+    if let Some(principal) = &principal {
+        info!("authenticated as {}", principal.name);
+    }
+    let (uuid, queue) = state.manager.add_as(principal, addr);
+    // 停止の合図と入れ違いになった接続は、ここで閉じる
+    if state.shutdown.is_stopping() {
+        state.manager.send_to(uuid, shutdown::close_message());
+        state.manager.remove(uuid);
+    }
+
+    // クライアントへの送信タスク。停止する時は Close を送り終えるまで待ってもらう
+    let manager_clone = state.manager.clone();
+    let writer_queue = queue.clone();
+    let in_flight = state.shutdown.track();
+    tokio::spawn(async move {
+        let _in_flight = in_flight;
+        while let Some(message) = writer_queue.recv().await {
+            // 送信キューから捨てられたメッセージがあれば先に知らせる
+            let missed = writer_queue.take_missed();
+            if missed > 0 {
+                let notice = Outbound::text(format!("You missed {} messages.", missed));
+                if sender.send(notice.into_message()).await.is_err() {
+                    warn!("Error sending message to client");
+                    break;
+                }
+            }
+
+            let closing = matches!(message, Outbound::Close(_));
+            if sender.send(message.into_message()).await.is_err() {
+                warn!("Error sending message to client");
+                break;
+            }
+            if closing {
+                break;
+            }
+        }
+
+        // クライアント切断時に管理から削除
+        writer_queue.close();
+        manager_clone.remove(uuid);
+    });
+
+    // クライアントから受信タスク
+    tokio::spawn(async move {
+        let mut settings = state.settings.subscribe();
+        let current = settings.borrow_and_update().clone();
+        let mut budget = ErrorBudget::new(current.max_protocol_errors);
+        let mut limiter = RateLimiter::new(current.rate_limits, Instant::now());
+        let mut heartbeat = Heartbeat::new(current.heartbeat, Instant::now());
+
+        loop {
+            let msg = tokio::select! {
+                msg = receiver.next() => match msg {
+                    Some(Ok(msg)) => msg,
+                    _ => break,
+                },
+                _ = heartbeat::sleep_until(heartbeat.deadline()) => {
+                    match heartbeat.tick(Instant::now()) {
+                        Some(Beat::Ping) => {
+                            state.manager.send_to(uuid, Outbound::Ping(Bytes::new()));
+                        }
+                        Some(Beat::Expired) => {
+                            reap_idle(&state, uuid);
+                            break;
+                        }
+                        None => {}
+                    }
+                    continue;
+                }
+            };
+            heartbeat.seen(Instant::now());
+
+            // キックされた接続からはもう受け取らない
+            if !state.manager.contains(uuid) {
+                break;
+            }
+
+            // 読み直した設定を、この接続にも反映する
+            if settings.has_changed().unwrap_or(false) {
+                let current = settings.borrow_and_update().clone();
+                budget.set_limit(current.max_protocol_errors);
+                limiter.update(current.rate_limits, Instant::now());
+                heartbeat.update(current.heartbeat, Instant::now());
+            }
+
+            if let Some(class) = rate_class(&msg) {
+                let decision = limiter.check(class, Instant::now());
+                if decision != RateDecision::Allow {
+                    warn!(
+                        "{} is sending {} frames too fast: {:?}",
+                        uuid, class, decision
+                    );
+                    Metrics::inc(&state.metrics.rate_limited);
+                }
+                match decision {
+                    RateDecision::Allow => {}
+                    RateDecision::Warn => {
+                        state
+                            .manager
+                            .send_to(uuid, ProtocolError::rate_limited(class).to_message());
+                        continue;
+                    }
+                    RateDecision::Throttle(delay) => {
+                        state
+                            .manager
+                            .send_to(uuid, ProtocolError::rate_limited(class).to_message());
+                        // 読むのを止めて、送り手を待たせる
+                        tokio::time::sleep(delay).await;
+                        continue;
+                    }
+                    RateDecision::Disconnect => {
+                        warn!("closing {}: rate limit exceeded", uuid);
+                        Metrics::inc(&state.metrics.rate_limit_disconnects);
+                        state.manager.send_to(uuid, limiter.close_message());
+                        break;
+                    }
+                }
+            }
+
+            match msg {
+                Message::Text(text) => {
+                    if let Err(err) = authorize(&state, uuid, None, Action::Post) {
+                        warn!("refused text from {}: {}", uuid, err);
+                        state.manager.send_to(uuid, err.to_message());
+                        continue;
+                    }
+
+                    // // メッセージを全クライアントに送信 (ブロードキャスト)
+                    let message_string = text.trim().to_string(); // 安全に加工
+                    info!("received: {}", message_string);
+
+                    // 受け取ったメッセージを全クライアントにブロードキャスト
+                    state.manager.broadcast(message_string);
+                }
+                Message::Binary(m) => match handle_binary(&state, uuid, &m).await {
+                    Ok(Flow::Continue) => {}
+                    Ok(Flow::Exit) => break,
+                    Err(err) => {
+                        warn!("protocol error from {}: {}", uuid, err);
+                        Metrics::inc(&state.metrics.protocol_errors);
+
+                        let exhausted = budget.record(&err);
+                        state.manager.send_to(uuid, err.to_message());
+
+                        if exhausted {
+                            warn!("closing {}: error budget exhausted", uuid);
+                            state.manager.send_to(uuid, budget.close_message());
+                            break;
+                        }
+                    }
+                },
+                Message::Close(_) => break,
+                // Pong は受け取った時点で生きているとわかる。Ping には axum が応答する
+                Message::Ping(_) | Message::Pong(_) => {}
+            }
+        }
+
+        // クライアント切断時に管理から削除。送信タスクは残りを送ってから終わる
+        state.manager.remove(uuid);
+        queue.close();
+    });
+}
+
+/// Ping に応答しなかった接続を切り、他のクライアントに知らせる
+fn reap_idle(state: &AppState, uuid: Uuid) {
+    warn!(
+        "closing {}: no response within {:?} of a ping",
+        uuid,
+        state.settings.current().heartbeat.timeout
+    );
+    Metrics::inc(&state.metrics.idle_disconnects);
+    state.manager.send_to(
+        uuid,
+        Outbound::Close(Some(CloseFrame {
+            code: close_code::AWAY,
+            reason: "ping timeout".into(),
+        })),
+    );
+    state.manager.remove(uuid);
+    state
+        .manager
+        .broadcast(format!("User {} has left the chat.", uuid));
+}
+
+/// 頻度を制限するフレームの種類
+fn rate_class(msg: &Message) -> Option<RateClass> {
+    match msg {
+        Message::Text(_) => Some(RateClass::Chat),
+        Message::Binary(m) => match get_type(m.first()?) {
+            MessageType::Chat => Some(RateClass::Chat),
+            MessageType::FileTransfer | MessageType::UploadBegin => Some(RateClass::File),
+            MessageType::List => Some(RateClass::List),
+            MessageType::UploadChunk => Some(RateClass::Chunk),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+async fn handle_binary(state: &AppState, uuid: Uuid, m: &[u8]) -> Result<Flow, ProtocolError> {
+    // アップロードや履歴の書き込みは、停止の合図があっても最後まで行う
+    let _in_flight = state.shutdown.track();
+
+    let Some(first) = m.first() else {
+        return Err(ProtocolError::new(
+            ErrorCode::EmptyFrame,
+            "empty binary frame",
+        ));
+    };
+
+    match get_type(first) {
+        MessageType::Chat => {
+            // chat
+            let mut chat_message =
+                TextMessage::from_bytes(m).map_err(|e| ProtocolError::malformed("chat", e))?;
+            chat_message.sender = sender_name(state, uuid, chat_message.sender);
+            if !identify(state, uuid, &chat_message.sender) {
+                return Ok(Flow::Exit);
+            }
+            authorize(state, uuid, Some(chat_message.room), Action::Post)?;
+
+            // 保存に失敗しても配信は止めない
+            let (room, sender, content) = (
+                chat_message.room,
+                chat_message.sender.clone(),
+                chat_message.content.clone(),
+            );
+            if let Err(e) = history::blocking(&state.history, move |history| {
+                history.append(room, &sender, &content)
+            })
+            .await
+            {
+                warn!("failed to store chat message: {:?}", e);
+            }
+
+            // チャットメッセージを何らかの形で文字列に変換してルームにブロードキャスト
+            let message_string = format_chat(
+                chat_message.room,
+                &chat_message.sender,
+                &chat_message.content,
+            );
+            state
+                .manager
+                .broadcast_room(chat_message.room, message_string);
+        }
+        MessageType::Join => {
+            let mut join_message =
+                JoinMessage::from_bytes(m).map_err(|e| ProtocolError::malformed("join", e))?;
+            join_message.sender = sender_name(state, uuid, join_message.sender);
+            if !identify(state, uuid, &join_message.sender) {
+                return Ok(Flow::Exit);
+            }
+            // 誰もいないルームに入るのはルームを作るのと同じ
+            let room = join_message.room;
+            authorize(state, uuid, Some(room), Action::Read)?;
+            if !state.manager.is_occupied(room) && !state.settings.current().roles.has_room(room) {
+                authorize(state, uuid, Some(room), Action::CreateRoom)?;
+            }
+            info!("{} joined room {}", uuid, join_message.room);
+            state.manager.join(uuid, join_message.room);
+
+            // 直近の履歴を参加したクライアントにだけ返す
+            let replay = state.settings.current().history_replay;
+            let recent =
+                history::blocking(&state.history, move |history| history.recent(room, replay))
+                    .await
+                    .map_err(|e| ProtocolError::io("failed to read history", e))?;
+            for message in recent {
+                state.manager.direct_message(uuid, format_stored(&message));
+            }
+        }
+        MessageType::Exit => {
+            // exit
+            info!("received exit message");
+
+            // 離脱メッセージをブロードキャスト
+            let leave_message = format!("User {} has left the chat.", uuid);
+            state.manager.broadcast(leave_message);
+
+            // UUIDの削除
+            state.manager.remove(uuid);
+
+            // スレッド終了
+            return Ok(Flow::Exit);
+        }
+        MessageType::FileTransfer => {
+            // 本体をデコードする前にヘッダーだけ読んで、上限を確認する
+            let header = FileTransferHeader::peek(m)
+                .map_err(|e| ProtocolError::malformed("file transfer", e))?;
+            authorize(state, uuid, Some(header.room), Action::Upload)?;
+            let sender = sender_name(state, uuid, header.sender);
+            let reservation =
+                state
+                    .quota
+                    .reserve(&sender, header.room, u64::from(header.content_len))?;
+
+            // file transfer
+            let saved = match FileTransferMessage::from_bytes(m) {
+                Ok(d) => state
+                    .uploads
+                    .save(&d.filename, Bytes::from(d.content), &sender, header.room)
+                    .await
+                    .map_err(Into::into),
+                Err(e) => Err(ProtocolError::malformed("file transfer", e)),
+            };
+            let record = match saved {
+                Ok(record) => {
+                    state.quota.commit(reservation, record.size).await;
+                    record
+                }
+                Err(e) => {
+                    state.quota.release(reservation);
+                    return Err(e);
+                }
+            };
+
+            announce_upload(state, uuid, &record);
+        }
+        MessageType::UploadBegin => {
+            let mut begin = UploadBeginMessage::from_bytes(m)
+                .map_err(|e| ProtocolError::malformed("upload begin", e))?;
+            begin.sender = sender_name(state, uuid, begin.sender);
+            authorize(state, uuid, Some(begin.room), Action::Upload)?;
+            let offset = state.chunked_uploads.begin(uuid, &begin).await?;
+            send_ack(state, uuid, begin.upload_id, offset);
+        }
+        MessageType::UploadChunk => {
+            let chunk = UploadChunkMessage::from_bytes(m)
+                .map_err(|e| ProtocolError::malformed("upload chunk", e))?;
+            let offset = state.chunked_uploads.write_chunk(uuid, &chunk).await?;
+            // 受信確認を待つクライアントが先に表示できるよう、進み具合を先に送る
+            if let Some(progress) = state.chunked_uploads.progress(uuid, &chunk.upload_id).await {
+                state
+                    .manager
+                    .send_to(uuid, Outbound::Binary(Bytes::from(progress.to_bytes())));
+            }
+            send_ack(state, uuid, chunk.upload_id, offset);
+        }
+        MessageType::UploadCommit => {
+            let commit = UploadCommitMessage::from_bytes(m)
+                .map_err(|e| ProtocolError::malformed("upload commit", e))?;
+            let record = state
+                .chunked_uploads
+                .commit(uuid, &commit.upload_id)
+                .await?;
+            announce_upload(state, uuid, &record);
+        }
+        MessageType::List => {
+            let d: ListMessage =
+                ListMessage::from_bytes(m).map_err(|e| ProtocolError::malformed("list", e))?;
+
+            match d.target.as_str() {
+                "socket" => {
+                    authorize(state, uuid, Some(d.room), Action::ListSockets)?;
+                    // UUID はモデレーター以上にだけ見せる
+                    let show_ids =
+                        authorize(state, uuid, Some(d.room), Action::ViewSocketIds).is_ok();
+                    let messages: Vec<String> = state
+                        .manager
+                        .queue_stats()
+                        .iter()
+                        .map(|stat| {
+                            let room = stat
+                                .room
+                                .map(|room| room.to_string())
+                                .unwrap_or_else(|| "-".to_string());
+                            if !show_ids {
+                                let name = stat
+                                    .principal
+                                    .as_ref()
+                                    .map_or("anonymous", |principal| principal.name.as_str());
+                                return format!("{} (room: {})", name, room);
+                            }
+                            let user = stat
+                                .principal
+                                .as_ref()
+                                .map(|principal| format!("user: {}, ", principal.name))
+                                .unwrap_or_default();
+                            let ip = stat
+                                .addr
+                                .map(|addr| format!("ip: {}, ", addr))
+                                .unwrap_or_default();
+                            format!(
+                                "{} ({}{}room: {}, queue: {}/{}, missed: {})",
+                                stat.id,
+                                user,
+                                ip,
+                                room,
+                                stat.depth,
+                                stat.capacity,
+                                stat.missed_total
+                            )
+                        })
+                        .collect();
+                    state.manager.direct_message(uuid, messages.join("\n"));
+                }
+                "files" => {
+                    state.manager.direct_message(
+                        uuid,
+                        format_files(state, &sender_name(state, uuid, d.sender), d.room),
+                    );
+                }
+                target => {
+                    return Err(ProtocolError::new(
+                        ErrorCode::InvalidTarget,
+                        format!("invalid list target: {}", target),
+                    ));
+                }
+            }
+        }
+        MessageType::Moderation => {
+            let d = ModerationMessage::from_bytes(m)
+                .map_err(|e| ProtocolError::malformed("moderation", e))?;
+            moderate(state, uuid, d)?;
+        }
+        category => {
+            return Err(ProtocolError::new(
+                ErrorCode::UnknownCategory,
+                format!("unknown message category: 0x{:02x} ({:?})", first, category),
+            ));
+        }
+    }
+
+    Ok(Flow::Continue)
+}
+
+/// 発言やアップロードの送信者
+///
+/// 認証した接続では、名乗った名前の代わりに認証された名前を使う。名乗った名前のままだと
+/// 他人の割り当てを使ったり、他人になりすましたり、名前を変えて BAN を逃れたりできる。
+fn sender_name(state: &AppState, uuid: Uuid, declared: String) -> String {
+    match state.manager.principal(uuid) {
+        Some(principal) => {
+            if principal.name != declared {
+                debug!(
+                    "{} declared `{}`, using authenticated name `{}`",
+                    uuid, declared, principal.name
+                );
+            }
+            principal.name
+        }
+        None => declared,
+    }
+}
+
+/// 名乗った名前を覚える。その名前が BAN されていれば切断し、`false` を返す
+fn identify(state: &AppState, uuid: Uuid, sender: &str) -> bool {
+    state.manager.set_name(uuid, sender);
+    let Some(ban) = state.bans.find(None, Some(sender), None) else {
+        return true;
+    };
+    warn!("closing {}: {} is banned", uuid, ban.target);
+    let close = moderation::close_message("banned", &ban.reason);
+    Metrics::add(
+        &state.metrics.kicked_connections,
+        state.manager.kick(&BanTarget::Connection(uuid), close) as u64,
+    );
+    false
+}
+
+/// `/kick`、`/ban`、`/unban`。結果は依頼した接続にだけ返す
+fn moderate(state: &AppState, uuid: Uuid, d: ModerationMessage) -> Result<(), ProtocolError> {
+    let action = match d.action {
+        ModerationAction::Kick => Action::Kick,
+        ModerationAction::Ban | ModerationAction::Unban => Action::Ban,
+    };
+    authorize(state, uuid, Some(d.room), action)?;
+    let target: BanTarget = d
+        .target
+        .parse()
+        .map_err(|e: String| ProtocolError::new(ErrorCode::InvalidTarget, e))?;
+    // 名乗った名前より認証された名前を記録する
+    let by = state
+        .manager
+        .principal(uuid)
+        .map_or(d.sender, |principal| principal.name);
+
+    let reply = match d.action {
+        ModerationAction::Kick => {
+            let kicked = moderation::kick(state, &target, &d.reason);
+            format!("kicked {} connection(s) matching {}", kicked, target)
+        }
+        ModerationAction::Ban => {
+            let duration = (d.duration_secs > 0).then(|| Duration::from_secs(d.duration_secs));
+            let (ban, kicked) = moderation::ban(state, target, &d.reason, Some(by), duration)
+                .map_err(|e| match e.kind() {
+                    std::io::ErrorKind::InvalidInput => {
+                        ProtocolError::new(ErrorCode::InvalidTarget, e.to_string())
+                    }
+                    _ => ProtocolError::io("failed to save bans", e),
+                })?;
+            let until = match duration {
+                Some(duration) => format!("for {}s", duration.as_secs()),
+                None => "permanently".to_string(),
+            };
+            format!(
+                "banned {} {}, closed {} connection(s)",
+                ban.target, until, kicked
+            )
+        }
+        ModerationAction::Unban => match state.bans.unban(&target) {
+            Ok(Some(_)) => format!("unbanned {}", target),
+            Ok(None) => format!("{} is not banned", target),
+            Err(e) => return Err(ProtocolError::io("failed to save bans", e)),
+        },
+    };
+    state.manager.direct_message(uuid, reply);
+    Ok(())
+}
+
+/// 接続の役割で `room` での `action` が許されているか確かめる
+fn authorize(
+    state: &AppState,
+    uuid: Uuid,
+    room: Option<i32>,
+    action: Action,
+) -> Result<(), ProtocolError> {
+    let principal = state.manager.principal(uuid);
+    let role = state
+        .settings
+        .current()
+        .roles
+        .role(principal.as_ref(), room);
+    if role >= action.required_role() {
+        Ok(())
+    } else {
+        Err(ProtocolError::denied(action, role))
+    }
+}
+
+/// 送信者に結果を返してルームに知らせる
+fn announce_upload(state: &AppState, uuid: Uuid, record: &FileRecord) {
+    let transferred_bytes = format_bytes(record.size);
+    info!(
+        "uploaded: {} ({}) {} bytes transferred.",
+        record.name, record.sha256, transferred_bytes
+    );
+
+    state.manager.direct_message(
+        uuid,
+        format!(
+            "{} bytes transferred. (saved as {})",
+            transferred_bytes, record.name
+        ),
+    );
+    let Some(room) = record.room else {
+        return;
+    };
+    state.manager.broadcast_room(
+        room,
+        format!(
+            "[Room {} - {}] shared {} ({}). /download {}",
+            room, record.sender, record.name, transferred_bytes, record.id
+        ),
+    );
+}
+
+/// 受信済みのオフセットを返す
+fn send_ack(state: &AppState, uuid: Uuid, upload_id: UploadId, offset: u64) {
+    let ack = UploadAckMessage { upload_id, offset };
+    state
+        .manager
+        .send_to(uuid, Outbound::Binary(Bytes::from(ack.to_bytes())));
+}
+
+fn format_chat(room: i32, sender: &str, content: &str) -> String {
+    format!("[Room {} - {}]: {}", room, sender, content)
+}
+
+fn format_stored(message: &StoredMessage) -> String {
+    format_chat(message.room, &message.sender, &message.content)
+}
+
+/// `/list files` の応答。ルームで共有されたファイルと、自分とルームの使用量と上限
+fn format_files(state: &AppState, sender: &str, room: i32) -> String {
+    let limits = state.quota.limits();
+    let usage = state.quota.usage();
+    let quota = |limit: Option<u64>| {
+        limit
+            .map(format_bytes)
+            .unwrap_or_else(|| "unlimited".to_string())
+    };
+
+    let mut lines: Vec<String> = state
+        .uploads
+        .list_room(room)
+        .iter()
+        .map(|record| {
+            format!(
+                "{} {} ({}, by {})",
+                record.id,
+                record.name,
+                format_bytes(record.size),
+                record.sender
+            )
+        })
+        .collect();
+    if lines.is_empty() {
+        lines.push(format!("no files in room {}", room));
+    }
+
+    lines.extend([
+        format!("max file size: {}", format_bytes(limits.max_file_size)),
+        format!(
+            "user {}: {} / {}",
+            sender,
+            format_bytes(usage.user(sender)),
+            quota(limits.user_quota)
+        ),
+        format!(
+            "room {}: {} / {}",
+            room,
+            format_bytes(usage.room(room)),
+            quota(limits.room_quota)
+        ),
+    ]);
+    lines.join("\n")
+}
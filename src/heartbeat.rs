@@ -0,0 +1,171 @@
+use std::time::{Duration, Instant};
+
+/// 何も受け取らないまま、この間隔が過ぎたら Ping を送る
+pub const DEFAULT_PING_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Ping を送ってからこの時間内に何も届かなければ、相手はもういないとみなす
+pub const DEFAULT_PONG_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeartbeatSettings {
+    /// 0 なら Ping を送らず、切断もしない
+    pub interval: Duration,
+    pub timeout: Duration,
+}
+
+impl Default for HeartbeatSettings {
+    fn default() -> Self {
+        Self {
+            interval: DEFAULT_PING_INTERVAL,
+            timeout: DEFAULT_PONG_TIMEOUT,
+        }
+    }
+}
+
+impl HeartbeatSettings {
+    pub fn disabled() -> Self {
+        Self {
+            interval: Duration::ZERO,
+            ..Self::default()
+        }
+    }
+
+    pub fn is_disabled(&self) -> bool {
+        self.interval.is_zero()
+    }
+}
+
+/// 期限が来た時にすること
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Beat {
+    /// Ping を送る
+    Ping,
+    /// Ping に応答が無かった
+    Expired,
+}
+
+/// 1接続分の死活監視
+///
+/// Pong に限らず、何か受け取れば相手は生きているとみなす。サーバーとクライアントの
+/// 両方で使う。
+#[derive(Debug)]
+pub struct Heartbeat {
+    settings: HeartbeatSettings,
+    deadline: Instant,
+    awaiting_pong: bool,
+}
+
+impl Heartbeat {
+    pub fn new(settings: HeartbeatSettings, now: Instant) -> Self {
+        Self {
+            settings,
+            deadline: now + settings.interval,
+            awaiting_pong: false,
+        }
+    }
+
+    /// 相手から何か受け取った
+    pub fn seen(&mut self, now: Instant) {
+        self.awaiting_pong = false;
+        self.deadline = now + self.settings.interval;
+    }
+
+    /// 読み直した間隔に切り替える。応答を待っている間なら、待つ時間を新しいものにする
+    pub fn update(&mut self, settings: HeartbeatSettings, now: Instant) {
+        if settings == self.settings {
+            return;
+        }
+        self.settings = settings;
+        self.deadline = now
+            + if self.awaiting_pong {
+                settings.timeout
+            } else {
+                settings.interval
+            };
+    }
+
+    /// 次に `tick` を呼ぶ時刻。無効なら `None`
+    pub fn deadline(&self) -> Option<Instant> {
+        (!self.settings.is_disabled()).then_some(self.deadline)
+    }
+
+    pub fn tick(&mut self, now: Instant) -> Option<Beat> {
+        if self.settings.is_disabled() || now < self.deadline {
+            return None;
+        }
+        if self.awaiting_pong {
+            return Some(Beat::Expired);
+        }
+        self.awaiting_pong = true;
+        self.deadline = now + self.settings.timeout;
+        Some(Beat::Ping)
+    }
+}
+
+/// `deadline` まで待つ。`None` ならずっと待つ
+pub async fn sleep_until(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline.into()).await,
+        None => std::future::pending().await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ping_then_expire() {
+        let settings = HeartbeatSettings {
+            interval: Duration::from_secs(30),
+            timeout: Duration::from_secs(10),
+        };
+        let start = Instant::now();
+        let mut heartbeat = Heartbeat::new(settings, start);
+
+        assert_eq!(heartbeat.tick(start), None);
+        let ping_at = start + settings.interval;
+        assert_eq!(heartbeat.deadline(), Some(ping_at));
+        assert_eq!(heartbeat.tick(ping_at), Some(Beat::Ping));
+        assert_eq!(heartbeat.deadline(), Some(ping_at + settings.timeout));
+
+        // 応答があれば次の Ping まで待つ
+        let pong_at = ping_at + Duration::from_secs(1);
+        heartbeat.seen(pong_at);
+        assert_eq!(heartbeat.tick(ping_at + settings.timeout), None);
+        let ping_at = pong_at + settings.interval;
+        assert_eq!(heartbeat.tick(ping_at), Some(Beat::Ping));
+        assert_eq!(
+            heartbeat.tick(ping_at + settings.timeout),
+            Some(Beat::Expired)
+        );
+
+        let mut heartbeat = Heartbeat::new(HeartbeatSettings::disabled(), start);
+        assert_eq!(heartbeat.deadline(), None);
+        assert_eq!(heartbeat.tick(start + Duration::from_secs(3600)), None);
+    }
+
+    #[test]
+    fn test_update() {
+        let settings = HeartbeatSettings {
+            interval: Duration::from_secs(30),
+            timeout: Duration::from_secs(10),
+        };
+        let start = Instant::now();
+        let mut heartbeat = Heartbeat::new(HeartbeatSettings::disabled(), start);
+
+        let now = start + Duration::from_secs(5);
+        heartbeat.update(settings, now);
+        assert_eq!(heartbeat.deadline(), Some(now + settings.interval));
+        assert_eq!(heartbeat.tick(now + settings.interval), Some(Beat::Ping));
+
+        // 応答を待っている間なら、新しい待ち時間で待つ
+        let shorter = HeartbeatSettings {
+            interval: Duration::from_secs(30),
+            timeout: Duration::from_secs(1),
+        };
+        let now = now + settings.interval;
+        heartbeat.update(shorter, now);
+        assert_eq!(heartbeat.tick(now + shorter.timeout), Some(Beat::Expired));
+    }
+}
@@ -0,0 +1,268 @@
+use super::memory::Rooms;
+use super::{now_millis, HistoryPage, HistoryStore, PageQuery, RetentionPolicy, StoredMessage};
+use anyhow::Context;
+use log::{error, info, warn};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// 捨てた件数がこれを超えたらログファイルを書き直す
+const COMPACT_THRESHOLD: usize = 1000;
+
+/// 1行1メッセージの JSON を追記していくファイル
+///
+/// 起動時に全件を読み込み、読み出しはメモリ上のコピーから返す。
+pub struct FileHistory {
+    path: PathBuf,
+    retention: RetentionPolicy,
+    inner: Mutex<Inner>,
+}
+
+struct Inner {
+    rooms: Rooms,
+    file: File,
+    /// 前回書き直してから、ファイルには残っているが保存期間を過ぎた件数
+    stale: usize,
+}
+
+impl FileHistory {
+    pub fn open(path: impl AsRef<Path>, retention: RetentionPolicy) -> anyhow::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let mut rooms = Rooms::default();
+        // 読めなかった行の番号と、空でない最後の行の番号
+        let mut broken_lines = Vec::new();
+        let mut last_line = 0;
+
+        if path.exists() {
+            let reader = BufReader::new(
+                File::open(&path).with_context(|| format!("opening {}", path.display()))?,
+            );
+            for (i, line) in reader.lines().enumerate() {
+                let line = line.with_context(|| format!("reading {}", path.display()))?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+                last_line = i + 1;
+                match serde_json::from_str::<StoredMessage>(&line) {
+                    Ok(message) => rooms.insert(message),
+                    Err(e) => {
+                        warn!("{}:{}: skipping broken entry: {}", path.display(), i + 1, e);
+                        broken_lines.push(i + 1);
+                    }
+                }
+            }
+        }
+
+        // 書き込み途中で落ちた最終行は捨ててよいが、それ以外が読めなければ書き直しで
+        // 消えないように元のファイルを残しておく
+        if broken_lines.iter().any(|&line| line != last_line) {
+            let mut aside = path.clone().into_os_string();
+            aside.push(format!(".broken-{}", now_millis()));
+            let aside = PathBuf::from(aside);
+            fs::rename(&path, &aside)
+                .with_context(|| format!("moving {} to {}", path.display(), aside.display()))?;
+            error!(
+                "{} has {} broken entries, moved it to {}",
+                path.display(),
+                broken_lines.len(),
+                aside.display()
+            );
+        }
+
+        let stale = rooms.prune(&retention, now_millis());
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .with_context(|| format!("opening {}", path.display()))?;
+
+        let history = Self {
+            path,
+            retention,
+            inner: Mutex::new(Inner { rooms, file, stale }),
+        };
+        {
+            let mut inner = history.inner.lock().unwrap();
+            // 壊れた行の後ろに追記しないよう、その場合も書き直す
+            if inner.stale > 0 || !broken_lines.is_empty() {
+                history.compact(&mut inner)?;
+            }
+        }
+
+        Ok(history)
+    }
+
+    /// 保存期間内のメッセージだけで一時ファイルを作り、置き換える
+    fn compact(&self, inner: &mut Inner) -> anyhow::Result<()> {
+        let tmp_path = self.path.with_extension("tmp");
+        {
+            let mut writer = BufWriter::new(File::create(&tmp_path)?);
+            let mut messages: Vec<&StoredMessage> = inner.rooms.rooms.values().flatten().collect();
+            messages.sort_by_key(|message| message.seq);
+            for message in messages {
+                serde_json::to_writer(&mut writer, message)?;
+                writer.write_all(b"\n")?;
+            }
+            writer.into_inner()?.sync_all()?;
+        }
+        fs::rename(&tmp_path, &self.path)?;
+
+        inner.file = OpenOptions::new().append(true).open(&self.path)?;
+        info!(
+            "compacted {} ({} stale entries removed)",
+            self.path.display(),
+            inner.stale
+        );
+        inner.stale = 0;
+
+        Ok(())
+    }
+}
+
+impl HistoryStore for FileHistory {
+    fn append(&self, room: i32, sender: &str, content: &str) -> anyhow::Result<StoredMessage> {
+        let mut inner = self.inner.lock().unwrap();
+        let message = inner.rooms.next(room, sender, content);
+
+        let mut line = serde_json::to_vec(&message)?;
+        line.push(b'\n');
+        inner
+            .file
+            .write_all(&line)
+            .with_context(|| format!("appending to {}", self.path.display()))?;
+
+        inner.rooms.insert(message.clone());
+        inner.stale += inner.rooms.prune(&self.retention, message.timestamp);
+        if inner.stale > COMPACT_THRESHOLD {
+            self.compact(&mut inner)?;
+        }
+
+        Ok(message)
+    }
+
+    fn recent(&self, room: i32, limit: usize) -> anyhow::Result<Vec<StoredMessage>> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.stale += inner.rooms.prune(&self.retention, now_millis());
+
+        Ok(inner.rooms.recent(room, limit))
+    }
+
+    fn page(&self, room: i32, query: PageQuery) -> anyhow::Result<HistoryPage> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.stale += inner.rooms.prune(&self.retention, now_millis());
+
+        Ok(inner.rooms.page(room, &query))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::history::blocking;
+
+    #[test]
+    fn test_history_survives_reopen() {
+        let dir = std::env::temp_dir().join(format!("ws_s-history-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("history.jsonl");
+        let retention = RetentionPolicy {
+            max_messages_per_room: Some(3),
+            max_age: None,
+        };
+
+        {
+            let history = FileHistory::open(&path, retention).unwrap();
+            for i in 0..5 {
+                history.append(42, "Alice", &i.to_string()).unwrap();
+            }
+        }
+
+        // 書き込み途中で壊れた行があっても読み飛ばす
+        OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .write_all(b"{\"seq\":")
+            .unwrap();
+
+        let history = FileHistory::open(&path, retention).unwrap();
+        let contents: Vec<String> = history
+            .recent(42, 10)
+            .unwrap()
+            .into_iter()
+            .map(|m| m.content)
+            .collect();
+        assert_eq!(contents, vec!["2", "3", "4"]);
+
+        // 採番は続きから
+        assert_eq!(history.append(42, "Bob", "5").unwrap().seq, 6);
+
+        // 書き直した後のファイルには保存期間内のものしか残らない
+        let lines = fs::read_to_string(&path).unwrap().lines().count();
+        assert_eq!(lines, 4);
+        let broken_files = || {
+            fs::read_dir(&dir)
+                .unwrap()
+                .map(|entry| entry.unwrap().path())
+                .filter(|path| path.to_string_lossy().contains(".broken-"))
+                .collect::<Vec<_>>()
+        };
+        assert!(broken_files().is_empty());
+        drop(history);
+
+        // 途中の行が壊れていれば、元のファイルを残してから書き直す
+        let original = fs::read_to_string(&path)
+            .unwrap()
+            .replacen('\n', "\nnot json\n", 1);
+        fs::write(&path, &original).unwrap();
+        let history = FileHistory::open(&path, retention).unwrap();
+        assert_eq!(history.recent(42, 10).unwrap().len(), 3);
+        let kept = broken_files();
+        assert_eq!(kept.len(), 1);
+        assert!(kept[0]
+            .file_name()
+            .unwrap()
+            .to_string_lossy()
+            .starts_with("history.jsonl.broken-"));
+        assert_eq!(fs::read_to_string(&kept[0]).unwrap(), original);
+        assert!(!fs::read_to_string(&path).unwrap().contains("not json"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_blocking_appends_from_tasks() {
+        let dir = std::env::temp_dir().join(format!("ws_s-history-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("history.jsonl");
+        let history: std::sync::Arc<dyn HistoryStore> =
+            std::sync::Arc::new(FileHistory::open(&path, RetentionPolicy::default()).unwrap());
+
+        let tasks: Vec<_> = (0..4)
+            .map(|room| {
+                let history = history.clone();
+                tokio::spawn(async move {
+                    for i in 0..25 {
+                        blocking(&history, move |history| {
+                            history.append(room, "Alice", &i.to_string())
+                        })
+                        .await
+                        .unwrap();
+                    }
+                })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+
+        let recent = blocking(&history, |history| history.recent(3, 100))
+            .await
+            .unwrap();
+        assert_eq!(recent.len(), 25);
+        assert_eq!(fs::read_to_string(&path).unwrap().lines().count(), 100);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
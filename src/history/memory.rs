@@ -0,0 +1,232 @@
+use super::{now_millis, HistoryPage, HistoryStore, PageQuery, RetentionPolicy, StoredMessage};
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+
+/// メモリ上だけに保存する。テストやお試し用
+#[derive(Default)]
+pub struct MemoryHistory {
+    inner: Mutex<Rooms>,
+    retention: RetentionPolicy,
+}
+
+#[derive(Default)]
+pub(super) struct Rooms {
+    pub(super) rooms: HashMap<i32, VecDeque<StoredMessage>>,
+    pub(super) last_seq: u64,
+}
+
+impl Rooms {
+    /// 保存期間を過ぎたものを捨て、捨てた件数を返す
+    pub(super) fn prune(&mut self, retention: &RetentionPolicy, now: u64) -> usize {
+        let mut pruned = 0;
+        for messages in self.rooms.values_mut() {
+            if let Some(max) = retention.max_messages_per_room {
+                while messages.len() > max {
+                    messages.pop_front();
+                    pruned += 1;
+                }
+            }
+            while messages
+                .front()
+                .is_some_and(|message| !retention.retains(message, now))
+            {
+                messages.pop_front();
+                pruned += 1;
+            }
+        }
+        self.rooms.retain(|_, messages| !messages.is_empty());
+        pruned
+    }
+
+    pub(super) fn insert(&mut self, message: StoredMessage) {
+        self.last_seq = self.last_seq.max(message.seq);
+        self.rooms
+            .entry(message.room)
+            .or_default()
+            .push_back(message);
+    }
+
+    pub(super) fn next(&mut self, room: i32, sender: &str, content: &str) -> StoredMessage {
+        StoredMessage {
+            seq: self.last_seq + 1,
+            room,
+            sender: sender.to_string(),
+            content: content.to_string(),
+            timestamp: now_millis(),
+        }
+    }
+
+    pub(super) fn recent(&self, room: i32, limit: usize) -> Vec<StoredMessage> {
+        match self.rooms.get(&room) {
+            Some(messages) => messages
+                .iter()
+                .skip(messages.len().saturating_sub(limit))
+                .cloned()
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    pub(super) fn page(&self, room: i32, query: &PageQuery) -> HistoryPage {
+        let Some(messages) = self.rooms.get(&room) else {
+            return HistoryPage::default();
+        };
+
+        // seq の昇順に並んでいるので二分探索で範囲を絞る
+        let start = match query.after {
+            Some(after) => messages.partition_point(|message| message.seq <= after),
+            None => 0,
+        };
+        let end = match query.before {
+            Some(before) => messages.partition_point(|message| message.seq < before),
+            None => messages.len(),
+        }
+        .max(start);
+
+        let (start, end) = if query.after.is_some() && query.before.is_none() {
+            (start, end.min(start + query.limit))
+        } else {
+            (start.max(end.saturating_sub(query.limit)), end)
+        };
+
+        HistoryPage {
+            messages: messages.range(start..end).cloned().collect(),
+            has_older: start > 0,
+            has_newer: end < messages.len(),
+        }
+    }
+}
+
+impl MemoryHistory {
+    pub fn new(retention: RetentionPolicy) -> Self {
+        Self {
+            inner: Mutex::new(Rooms::default()),
+            retention,
+        }
+    }
+}
+
+impl HistoryStore for MemoryHistory {
+    fn append(&self, room: i32, sender: &str, content: &str) -> anyhow::Result<StoredMessage> {
+        let mut inner = self.inner.lock().unwrap();
+        let message = inner.next(room, sender, content);
+        inner.insert(message.clone());
+        inner.prune(&self.retention, message.timestamp);
+
+        Ok(message)
+    }
+
+    fn recent(&self, room: i32, limit: usize) -> anyhow::Result<Vec<StoredMessage>> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.prune(&self.retention, now_millis());
+
+        Ok(inner.recent(room, limit))
+    }
+
+    fn page(&self, room: i32, query: PageQuery) -> anyhow::Result<HistoryPage> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.prune(&self.retention, now_millis());
+
+        Ok(inner.page(room, &query))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_recent_is_per_room_and_ordered() {
+        let history = MemoryHistory::default();
+        for i in 0..5 {
+            history.append(1, "Alice", &format!("a{}", i)).unwrap();
+            history.append(2, "Bob", &format!("b{}", i)).unwrap();
+        }
+
+        let recent = history.recent(1, 3).unwrap();
+        let contents: Vec<&str> = recent.iter().map(|m| m.content.as_str()).collect();
+        assert_eq!(contents, vec!["a2", "a3", "a4"]);
+        assert!(recent.windows(2).all(|w| w[0].seq < w[1].seq));
+        assert!(history.recent(3, 10).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_retention_limits_messages_per_room() {
+        let history = MemoryHistory::new(RetentionPolicy {
+            max_messages_per_room: Some(2),
+            max_age: None,
+        });
+        for i in 0..5 {
+            history.append(1, "Alice", &i.to_string()).unwrap();
+        }
+
+        let recent = history.recent(1, 10).unwrap();
+        let contents: Vec<&str> = recent.iter().map(|m| m.content.as_str()).collect();
+        assert_eq!(contents, vec!["3", "4"]);
+        assert_eq!(recent[1].seq, 5);
+    }
+
+    #[test]
+    fn test_page_walks_backwards_and_forwards() {
+        let history = MemoryHistory::default();
+        for i in 1..=10 {
+            history.append(1, "Alice", &i.to_string()).unwrap();
+            history.append(2, "Bob", &i.to_string()).unwrap();
+        }
+        let contents = |page: &HistoryPage| -> Vec<String> {
+            page.messages.iter().map(|m| m.content.clone()).collect()
+        };
+
+        let latest = history
+            .page(
+                1,
+                PageQuery {
+                    limit: 3,
+                    ..PageQuery::default()
+                },
+            )
+            .unwrap();
+        assert_eq!(contents(&latest), vec!["8", "9", "10"]);
+        assert!(latest.has_older);
+        assert!(!latest.has_newer);
+
+        let older = history
+            .page(
+                1,
+                PageQuery {
+                    before: Some(latest.messages[0].seq),
+                    limit: 3,
+                    ..PageQuery::default()
+                },
+            )
+            .unwrap();
+        assert_eq!(contents(&older), vec!["5", "6", "7"]);
+        assert!(older.has_older && older.has_newer);
+
+        let newer = history
+            .page(
+                1,
+                PageQuery {
+                    after: Some(older.messages[2].seq),
+                    limit: 2,
+                    ..PageQuery::default()
+                },
+            )
+            .unwrap();
+        assert_eq!(contents(&newer), vec!["8", "9"]);
+        assert!(newer.has_newer);
+
+        let empty = history
+            .page(
+                1,
+                PageQuery {
+                    after: Some(latest.messages[2].seq),
+                    limit: 10,
+                    ..PageQuery::default()
+                },
+            )
+            .unwrap();
+        assert!(empty.messages.is_empty());
+        assert!(!empty.has_newer);
+    }
+}
@@ -0,0 +1,137 @@
+mod file;
+mod memory;
+
+pub use file::FileHistory;
+pub use memory::MemoryHistory;
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// 新しく参加したクライアントに返す履歴の既定の件数
+pub const DEFAULT_REPLAY_LIMIT: usize = 50;
+
+/// 履歴ファイルの既定のパス
+pub const DEFAULT_HISTORY_FILE: &str = "./history.jsonl";
+
+/// 1ルームあたりに残す件数の既定値
+pub const DEFAULT_MAX_MESSAGES_PER_ROOM: usize = 10_000;
+
+/// 履歴の保存先の種類
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum HistoryBackend {
+    /// 追記型のログファイル
+    File,
+    /// メモリ上のみ (再起動で消える)
+    Memory,
+}
+
+impl FromStr for HistoryBackend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "file" => Ok(HistoryBackend::File),
+            "memory" => Ok(HistoryBackend::Memory),
+            _ => Err(format!("unknown history backend `{}` (file, memory)", s)),
+        }
+    }
+}
+
+impl Display for HistoryBackend {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HistoryBackend::File => write!(f, "file"),
+            HistoryBackend::Memory => write!(f, "memory"),
+        }
+    }
+}
+
+/// 保存されたチャットメッセージ
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct StoredMessage {
+    /// 全ルームで共通の連番。後から保存したものほど大きい
+    pub seq: u64,
+    pub room: i32,
+    pub sender: String,
+    pub content: String,
+    /// UNIX 時間 (ミリ秒)
+    pub timestamp: u64,
+}
+
+/// 保存期間の上限。どちらも `None` なら無制限
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RetentionPolicy {
+    /// 1ルームあたりに残す件数
+    pub max_messages_per_room: Option<usize>,
+    /// これより古いメッセージは捨てる
+    pub max_age: Option<Duration>,
+}
+
+impl RetentionPolicy {
+    /// `now` の時点で残しておくべきか
+    pub fn retains(&self, message: &StoredMessage, now: u64) -> bool {
+        match self.max_age {
+            Some(max_age) => now.saturating_sub(message.timestamp) <= max_age.as_millis() as u64,
+            None => true,
+        }
+    }
+}
+
+/// 履歴をページ単位で取り出す条件。`seq` がこの範囲 (両端を含まない) のものを返す
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PageQuery {
+    pub before: Option<u64>,
+    pub after: Option<u64>,
+    pub limit: usize,
+}
+
+/// `HistoryStore::page` の結果
+#[derive(Debug, Clone, Default)]
+pub struct HistoryPage {
+    /// 古い順
+    pub messages: Vec<StoredMessage>,
+    /// このページより古いメッセージが残っているか
+    pub has_older: bool,
+    /// このページより新しいメッセージが残っているか
+    pub has_newer: bool,
+}
+
+/// チャット履歴の保存先
+pub trait HistoryStore: Send + Sync {
+    /// メッセージを保存し、採番したものを返す
+    fn append(&self, room: i32, sender: &str, content: &str) -> anyhow::Result<StoredMessage>;
+
+    /// ルームの直近 `limit` 件を古い順に返す
+    fn recent(&self, room: i32, limit: usize) -> anyhow::Result<Vec<StoredMessage>>;
+
+    /// ルームの履歴を1ページ分返す
+    ///
+    /// `after` だけを指定した場合はその直後から、それ以外は範囲の末尾から `limit` 件を返す。
+    fn page(&self, room: i32, query: PageQuery) -> anyhow::Result<HistoryPage>;
+}
+
+/// `history` を、ブロックしてよいスレッドで使う
+///
+/// `FileHistory` は書き込みや書き直しの間ロックを持ったままファイルを触るので、
+/// 非同期の処理からは直接呼ばずにこれを通す。
+pub async fn blocking<T, F>(history: &Arc<dyn HistoryStore>, f: F) -> anyhow::Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce(&dyn HistoryStore) -> anyhow::Result<T> + Send + 'static,
+{
+    let history = history.clone();
+    tokio::task::spawn_blocking(move || f(history.as_ref()))
+        .await
+        .context("history task failed")?
+}
+
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
@@ -1 +1,20 @@
+pub mod api;
+pub mod app;
+pub mod auth;
+pub mod config;
+pub mod connection;
+pub mod heartbeat;
+pub mod history;
+pub mod listener;
+pub mod metrics;
+pub mod moderation;
+pub mod origin;
+pub mod protocol_error;
+pub mod rate_limit;
+pub mod reload;
+pub mod send_queue;
+pub mod shutdown;
+pub mod socket_manager;
+pub mod tls;
+pub mod upload;
 pub mod utils;
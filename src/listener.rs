@@ -0,0 +1,166 @@
+use socket2::{Domain, Protocol, Socket, Type};
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use tokio::net::TcpListener;
+
+/// 待ち受けの backlog。tokio の `TcpListener::bind` と同じ
+const BACKLOG: i32 = 1024;
+
+/// 待ち受けごとに公開する経路
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RouteSet {
+    /// 全て
+    #[default]
+    All,
+    /// 管理 API (`/api/admin/*`) とメトリクスを除いたもの
+    Public,
+    /// 管理 API、メトリクス、ヘルスチェックだけ
+    Admin,
+}
+
+impl FromStr for RouteSet {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "all" => Ok(RouteSet::All),
+            "public" => Ok(RouteSet::Public),
+            "admin" => Ok(RouteSet::Admin),
+            _ => Err(format!("unknown route set `{}` (all, public, admin)", s)),
+        }
+    }
+}
+
+impl Display for RouteSet {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RouteSet::All => write!(f, "all"),
+            RouteSet::Public => write!(f, "public"),
+            RouteSet::Admin => write!(f, "admin"),
+        }
+    }
+}
+
+/// 待ち受けるアドレスと、そこで公開する経路
+///
+/// `[<経路>@]<アドレス>:<ポート>` と書く。アドレスは IPv4、`[...]` で囲んだ IPv6、
+/// ホスト名のどれでもよい。経路を省略すると全て公開する。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Listener {
+    pub routes: RouteSet,
+    /// 名前解決は待ち受ける時にする
+    pub addr: String,
+}
+
+impl FromStr for Listener {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (routes, addr) = match s.split_once('@') {
+            Some((routes, addr)) => (routes.trim().parse()?, addr.trim()),
+            None => (RouteSet::All, s),
+        };
+        if addr.parse::<SocketAddr>().is_err() && !is_host_port(addr) {
+            return Err(format!(
+                "invalid address `{}` (expected <ip>:<port>, [<ipv6>]:<port> or <host>:<port>)",
+                addr
+            ));
+        }
+        Ok(Self {
+            routes,
+            addr: addr.to_string(),
+        })
+    }
+}
+
+impl Display for Listener {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self.routes {
+            RouteSet::All => write!(f, "{}", self.addr),
+            routes => write!(f, "{}@{}", routes, self.addr),
+        }
+    }
+}
+
+fn is_host_port(addr: &str) -> bool {
+    let Some((host, port)) = addr.rsplit_once(':') else {
+        return false;
+    };
+    !host.is_empty()
+        && host
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.')
+        && port.parse::<u16>().is_ok()
+}
+
+impl Listener {
+    /// 名前解決した全てのアドレスで待ち受ける
+    pub async fn bind(&self) -> io::Result<Vec<TcpListener>> {
+        let mut addrs = Vec::new();
+        for addr in tokio::net::lookup_host(&self.addr).await? {
+            if !addrs.contains(&addr) {
+                addrs.push(addr);
+            }
+        }
+        if addrs.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{}: no address found", self.addr),
+            ));
+        }
+        addrs
+            .into_iter()
+            .map(|addr| {
+                bind(addr).map_err(|e| io::Error::new(e.kind(), format!("{}: {}", addr, e)))
+            })
+            .collect()
+    }
+}
+
+/// IPv6 のソケットは IPv6 だけで待ち受け、同じポートの IPv4 と並べられるようにする
+pub fn bind(addr: SocketAddr) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    #[cfg(not(windows))]
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(BACKLOG)?;
+    TcpListener::from_std(socket.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_listener() {
+        let cases = [
+            ("127.0.0.1:8080", RouteSet::All, "127.0.0.1:8080"),
+            ("[::1]:8080", RouteSet::All, "[::1]:8080"),
+            ("localhost:8080", RouteSet::All, "localhost:8080"),
+            ("admin@127.0.0.1:9090", RouteSet::Admin, "127.0.0.1:9090"),
+            ("public@[::]:443", RouteSet::Public, "[::]:443"),
+        ];
+        for (input, routes, addr) in cases {
+            let listener: Listener = input.parse().unwrap();
+            assert_eq!(listener.routes, routes, "{}", input);
+            assert_eq!(listener.addr, addr, "{}", input);
+            assert_eq!(listener.to_string(), input);
+        }
+
+        for input in [
+            "localhost",
+            "::1",
+            "::1:8080",
+            "host:port",
+            "private@[::1]:80",
+        ] {
+            assert!(input.parse::<Listener>().is_err(), "{}", input);
+        }
+    }
+}
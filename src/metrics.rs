@@ -0,0 +1,149 @@
+use crate::socket_manager::SocketManager;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// サーバー全体のカウンタ。`/api/metrics` で Prometheus のテキスト形式で公開する
+#[derive(Debug, Default)]
+pub struct Metrics {
+    pub connections_total: AtomicU64,
+    pub messages_broadcast: AtomicU64,
+    /// 送信キューが一杯で捨てたメッセージ
+    pub messages_dropped: AtomicU64,
+    pub slow_consumer_disconnects: AtomicU64,
+    /// Ping に応答しなかった接続
+    pub idle_disconnects: AtomicU64,
+    pub protocol_errors: AtomicU64,
+    /// 認証に失敗した接続要求
+    pub auth_failures: AtomicU64,
+    /// 許していないオリジンからの接続要求
+    pub origin_rejections: AtomicU64,
+    /// 頻度の制限を越えて捨てたフレーム
+    pub rate_limited: AtomicU64,
+    pub rate_limit_disconnects: AtomicU64,
+    /// キックや BAN で切った接続
+    pub kicked_connections: AtomicU64,
+    /// BAN されていて断った接続要求
+    pub banned_connections: AtomicU64,
+    /// 設定の読み直し
+    pub config_reloads: AtomicU64,
+    pub config_reload_failures: AtomicU64,
+}
+
+impl Metrics {
+    pub fn inc(counter: &AtomicU64) {
+        Self::add(counter, 1);
+    }
+
+    pub fn add(counter: &AtomicU64, n: u64) {
+        counter.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn render(&self, manager: &SocketManager) -> String {
+        let mut out = String::new();
+
+        let counters = [
+            (
+                "ws_s_connections_total",
+                "Total accepted WebSocket connections",
+                &self.connections_total,
+            ),
+            (
+                "ws_s_messages_broadcast_total",
+                "Messages broadcast to clients",
+                &self.messages_broadcast,
+            ),
+            (
+                "ws_s_messages_dropped_total",
+                "Messages dropped because a send queue was full",
+                &self.messages_dropped,
+            ),
+            (
+                "ws_s_slow_consumer_disconnects_total",
+                "Connections closed for falling too far behind",
+                &self.slow_consumer_disconnects,
+            ),
+            (
+                "ws_s_idle_disconnects_total",
+                "Connections closed for not answering pings",
+                &self.idle_disconnects,
+            ),
+            (
+                "ws_s_protocol_errors_total",
+                "Malformed or rejected frames received from clients",
+                &self.protocol_errors,
+            ),
+            (
+                "ws_s_auth_failures_total",
+                "WebSocket upgrades rejected for missing or invalid credentials",
+                &self.auth_failures,
+            ),
+            (
+                "ws_s_origin_rejections_total",
+                "WebSocket upgrades rejected for a disallowed Origin header",
+                &self.origin_rejections,
+            ),
+            (
+                "ws_s_rate_limited_total",
+                "Frames dropped for exceeding a per-connection rate limit",
+                &self.rate_limited,
+            ),
+            (
+                "ws_s_rate_limit_disconnects_total",
+                "Connections closed for repeatedly exceeding rate limits",
+                &self.rate_limit_disconnects,
+            ),
+            (
+                "ws_s_kicked_connections_total",
+                "Connections closed by a kick or ban",
+                &self.kicked_connections,
+            ),
+            (
+                "ws_s_banned_connections_total",
+                "WebSocket upgrades refused because of a ban",
+                &self.banned_connections,
+            ),
+            (
+                "ws_s_config_reloads_total",
+                "Successful configuration reloads",
+                &self.config_reloads,
+            ),
+            (
+                "ws_s_config_reload_failures_total",
+                "Configuration reloads rejected because of an error",
+                &self.config_reload_failures,
+            ),
+        ];
+        for (name, help, counter) in counters {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} counter", name);
+            let _ = writeln!(out, "{} {}", name, counter.load(Ordering::Relaxed));
+        }
+
+        let stats = manager.queue_stats();
+        let _ = writeln!(out, "# HELP ws_s_connections Currently connected clients");
+        let _ = writeln!(out, "# TYPE ws_s_connections gauge");
+        let _ = writeln!(out, "ws_s_connections {}", stats.len());
+
+        // 誰でも読めるので、接続の ID は出さずに合計と最大だけにする
+        let depths = stats.iter().map(|stat| stat.depth);
+        let gauges = [
+            (
+                "ws_s_send_queue_depth",
+                "Messages waiting in all send queues",
+                depths.clone().sum::<usize>(),
+            ),
+            (
+                "ws_s_send_queue_max_depth",
+                "Messages waiting in the fullest send queue",
+                depths.max().unwrap_or(0),
+            ),
+        ];
+        for (name, help, value) in gauges {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} gauge", name);
+            let _ = writeln!(out, "{} {}", name, value);
+        }
+
+        out
+    }
+}
@@ -0,0 +1,352 @@
+use crate::app::AppState;
+use crate::auth::Principal;
+use crate::history::now_millis;
+use crate::metrics::Metrics;
+use crate::socket_manager::Outbound;
+use axum::extract::ws::{close_code, CloseFrame};
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::io;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::Duration;
+use uuid::Uuid;
+
+/// BAN の一覧の既定の保存先
+pub const DEFAULT_BAN_FILE: &str = "./bans.json";
+
+/// Close フレームの理由は 123 バイトまで
+const MAX_CLOSE_REASON: usize = 120;
+
+/// キックや BAN の対象
+///
+/// `name:<名前>` (チャットで名乗った名前)、`user:<名前>` (認証された名前)、`ip:<アドレス>`、
+/// または接続の UUID と書く。UUID と IP アドレスは前置きを省略できる。
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(into = "String", try_from = "String")]
+pub enum BanTarget {
+    Connection(Uuid),
+    Name(String),
+    User(String),
+    Ip(IpAddr),
+}
+
+impl FromStr for BanTarget {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let non_empty = |value: &str| {
+            if value.is_empty() {
+                Err(format!("empty target `{}`", s))
+            } else {
+                Ok(value.to_string())
+            }
+        };
+        if let Some(name) = s.strip_prefix("name:") {
+            return non_empty(name).map(BanTarget::Name);
+        }
+        if let Some(user) = s.strip_prefix("user:") {
+            return non_empty(user).map(BanTarget::User);
+        }
+        if let Some(ip) = s.strip_prefix("ip:") {
+            return ip
+                .parse()
+                .map(BanTarget::Ip)
+                .map_err(|_| format!("invalid ip address `{}`", ip));
+        }
+        let id = s.strip_prefix("uuid:").unwrap_or(s);
+        if let Ok(id) = Uuid::parse_str(id) {
+            return Ok(BanTarget::Connection(id));
+        }
+        if let Ok(ip) = s.parse() {
+            return Ok(BanTarget::Ip(ip));
+        }
+        Err(format!(
+            "invalid target `{}` (name:<name>, user:<name>, ip:<address> or a connection uuid)",
+            s
+        ))
+    }
+}
+
+impl Display for BanTarget {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BanTarget::Connection(id) => write!(f, "{}", id),
+            BanTarget::Name(name) => write!(f, "name:{}", name),
+            BanTarget::User(user) => write!(f, "user:{}", user),
+            BanTarget::Ip(ip) => write!(f, "ip:{}", ip),
+        }
+    }
+}
+
+impl From<BanTarget> for String {
+    fn from(target: BanTarget) -> Self {
+        target.to_string()
+    }
+}
+
+impl TryFrom<String> for BanTarget {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Ban {
+    pub target: BanTarget,
+    #[serde(default)]
+    pub reason: String,
+    /// BAN した人。管理 API からなら `None`
+    pub by: Option<String>,
+    /// UNIX エポックからのミリ秒
+    pub created_at: u64,
+    /// `None` なら無期限
+    pub expires_at: Option<u64>,
+}
+
+impl Ban {
+    pub fn new(
+        target: BanTarget,
+        reason: impl Into<String>,
+        by: Option<String>,
+        duration: Option<Duration>,
+    ) -> Self {
+        let created_at = now_millis();
+        Self {
+            target,
+            reason: reason.into(),
+            by,
+            created_at,
+            expires_at: duration
+                .map(|duration| created_at.saturating_add(duration.as_millis() as u64)),
+        }
+    }
+
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+}
+
+/// BAN の一覧
+///
+/// 変えるたびに、一時ファイルに書いてから置き換える。期限の切れたものは読む時に取り除く。
+#[derive(Debug, Default)]
+pub struct BanList {
+    /// `None` ならメモリ上だけ
+    path: Option<PathBuf>,
+    bans: Mutex<Vec<Ban>>,
+}
+
+impl BanList {
+    /// 保存しない一覧
+    pub fn in_memory() -> Self {
+        Self::default()
+    }
+
+    /// `path` から読み込む。無ければ空から始める
+    ///
+    /// 壊れていれば、黙って BAN を解かないようにエラーにする。
+    pub fn open(path: &Path) -> io::Result<Self> {
+        Ok(Self {
+            path: Some(path.to_path_buf()),
+            bans: Mutex::new(read_bans(path)?),
+        })
+    }
+
+    /// ファイルを読み直し、有効な BAN の数を返す。読めなければ今の一覧を残す
+    pub fn reload(&self) -> io::Result<usize> {
+        if let Some(path) = &self.path {
+            let bans = read_bans(path)?;
+            *self.bans.lock().unwrap() = bans;
+        }
+        Ok(self.list().len())
+    }
+
+    /// 同じ対象の BAN があれば置き換える
+    pub fn ban(&self, ban: Ban) -> io::Result<()> {
+        let mut bans = self.bans.lock().unwrap();
+        bans.retain(|existing| existing.target != ban.target);
+        bans.push(ban);
+        self.persist(&bans)
+    }
+
+    /// 取り消した BAN を返す
+    pub fn unban(&self, target: &BanTarget) -> io::Result<Option<Ban>> {
+        let mut bans = self.bans.lock().unwrap();
+        let Some(position) = bans.iter().position(|ban| &ban.target == target) else {
+            return Ok(None);
+        };
+        let ban = bans.remove(position);
+        self.persist(&bans)?;
+        Ok(Some(ban))
+    }
+
+    /// 有効な BAN
+    pub fn list(&self) -> Vec<Ban> {
+        let mut bans = self.bans.lock().unwrap();
+        let now = now_millis();
+        bans.retain(|ban| !ban.is_expired(now));
+        bans.clone()
+    }
+
+    /// 認証された相手、名乗った名前、接続元のどれかに当てはまる BAN
+    pub fn find(
+        &self,
+        principal: Option<&Principal>,
+        name: Option<&str>,
+        ip: Option<IpAddr>,
+    ) -> Option<Ban> {
+        self.list().into_iter().find(|ban| match &ban.target {
+            BanTarget::User(user) => principal.is_some_and(|principal| &principal.name == user),
+            BanTarget::Name(banned) => name == Some(banned.as_str()),
+            BanTarget::Ip(banned) => ip == Some(*banned),
+            BanTarget::Connection(_) => false,
+        })
+    }
+
+    fn persist(&self, bans: &[Ban]) -> io::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let now = now_millis();
+        let active: Vec<&Ban> = bans.iter().filter(|ban| !ban.is_expired(now)).collect();
+        let tmp_path = path.with_extension("json.tmp");
+        let bytes = serde_json::to_vec_pretty(&active)?;
+        fs::write(&tmp_path, bytes)?;
+        fs::rename(&tmp_path, path)
+    }
+}
+
+fn read_bans(path: &Path) -> io::Result<Vec<Ban>> {
+    match fs::read(path) {
+        Ok(bytes) => serde_json::from_slice(&bytes).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}: {}", path.display(), e),
+            )
+        }),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e),
+    }
+}
+
+/// キックや BAN で切断する時の Close フレーム
+pub fn close_message(what: &str, reason: &str) -> Outbound {
+    let mut reason = if reason.is_empty() {
+        what.to_string()
+    } else {
+        format!("{}: {}", what, reason)
+    };
+    if reason.len() > MAX_CLOSE_REASON {
+        let mut end = MAX_CLOSE_REASON;
+        while !reason.is_char_boundary(end) {
+            end -= 1;
+        }
+        reason.truncate(end);
+    }
+    Outbound::Close(Some(CloseFrame {
+        code: close_code::POLICY,
+        reason: reason.into(),
+    }))
+}
+
+/// 当てはまる接続を切り、切った数を返す
+pub fn kick(state: &AppState, target: &BanTarget, reason: &str) -> usize {
+    let kicked = state.manager.kick(target, close_message("kicked", reason));
+    if kicked > 0 {
+        info!("kicked {} connection(s) matching {}", kicked, target);
+        Metrics::add(&state.metrics.kicked_connections, kicked as u64);
+    }
+    kicked
+}
+
+/// BAN を記録し、当てはまる接続を切る
+///
+/// 接続の UUID は繋ぎ直すと変わるので、その接続の認証された名前か接続元のアドレスを
+/// BAN する。どちらも分からなければ `InvalidInput` になる。
+pub fn ban(
+    state: &AppState,
+    target: BanTarget,
+    reason: &str,
+    by: Option<String>,
+    duration: Option<Duration>,
+) -> io::Result<(Ban, usize)> {
+    let target = match target {
+        BanTarget::Connection(id) => state.manager.identity(id).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("no connection {} to ban", id),
+            )
+        })?,
+        target => target,
+    };
+    let ban = Ban::new(target, reason, by, duration);
+    state.bans.ban(ban.clone())?;
+    info!("banned {} ({})", ban.target, ban.reason);
+
+    let kicked = state
+        .manager
+        .kick(&ban.target, close_message("banned", reason));
+    Metrics::add(&state.metrics.kicked_connections, kicked as u64);
+    Ok((ban, kicked))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_target() {
+        let id = Uuid::new_v4();
+        assert_eq!(id.to_string().parse(), Ok(BanTarget::Connection(id)));
+        assert_eq!("name:bob".parse(), Ok(BanTarget::Name("bob".to_string())));
+        assert_eq!("ip:::1".parse(), Ok(BanTarget::Ip("::1".parse().unwrap())));
+        assert_eq!(
+            "10.0.0.1".parse(),
+            Ok(BanTarget::Ip("10.0.0.1".parse().unwrap()))
+        );
+        assert!("bob".parse::<BanTarget>().is_err());
+        assert!("user:".parse::<BanTarget>().is_err());
+
+        let target = BanTarget::User("alice".to_string());
+        assert_eq!(target.to_string().parse(), Ok(target));
+    }
+
+    #[test]
+    fn test_ban_list_persists_and_expires() {
+        let dir = std::env::temp_dir().join(format!("ws_s-bans-{}", Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("bans.json");
+
+        let bans = BanList::open(&path).unwrap();
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        bans.ban(Ban::new(BanTarget::Ip(ip), "spam", None, None))
+            .unwrap();
+        bans.ban(Ban::new(
+            BanTarget::User("bob".to_string()),
+            "",
+            Some("mia".to_string()),
+            Some(Duration::ZERO),
+        ))
+        .unwrap();
+
+        let bans = BanList::open(&path).unwrap();
+        assert_eq!(bans.find(None, None, Some(ip)).unwrap().reason, "spam");
+        // 期限切れ
+        assert_eq!(bans.find(Some(&Principal::new("bob")), None, None), None);
+        assert_eq!(bans.list().len(), 1);
+
+        assert!(bans.unban(&BanTarget::Ip(ip)).unwrap().is_some());
+        assert!(BanList::open(&path).unwrap().list().is_empty());
+
+        fs::write(&path, "not json").unwrap();
+        assert!(BanList::open(&path).is_err());
+    }
+}
@@ -0,0 +1,228 @@
+use axum::http::{header, HeaderMap, HeaderValue, Method};
+use std::time::Duration;
+use tower_http::cors::{AllowHeaders, AllowOrigin, Any, CorsLayer};
+
+/// プリフライトの結果をブラウザにキャッシュさせる時間の既定値
+pub const DEFAULT_CORS_MAX_AGE: Duration = Duration::from_secs(86400);
+
+/// 既定で許すメソッド
+pub const DEFAULT_CORS_METHODS: [Method; 5] = [
+    Method::GET,
+    Method::POST,
+    Method::PUT,
+    Method::DELETE,
+    Method::OPTIONS,
+];
+
+/// 許すオリジン
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AllowedOrigins {
+    /// どこからでも許す
+    Any,
+    /// 並べたオリジンだけ許す。空なら他のオリジンからは全て断る
+    List(Vec<String>),
+}
+
+/// 他のオリジンからの API の呼び出しと `/ws` への接続を許す範囲
+///
+/// 同じオリジン (`Origin` が `Host` と一致する) と、`Origin` を付けない
+/// ブラウザ以外のクライアントはいつでも許す。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OriginPolicy {
+    pub origins: AllowedOrigins,
+    pub methods: Vec<Method>,
+    /// Cookie や `Authorization` を付けた呼び出しを許す
+    pub allow_credentials: bool,
+    pub max_age: Duration,
+}
+
+impl Default for OriginPolicy {
+    /// 開発用に、どこからでも許す
+    fn default() -> Self {
+        Self {
+            origins: AllowedOrigins::Any,
+            methods: DEFAULT_CORS_METHODS.to_vec(),
+            allow_credentials: false,
+            max_age: DEFAULT_CORS_MAX_AGE,
+        }
+    }
+}
+
+impl OriginPolicy {
+    /// 他のオリジンを全て断る
+    pub fn deny_all() -> Self {
+        Self::allow(Vec::new())
+    }
+
+    /// `origins` だけ許す。`*` を含めばどこからでも許す
+    pub fn allow(origins: Vec<String>) -> Self {
+        let origins = if origins.iter().any(|origin| origin == "*") {
+            AllowedOrigins::Any
+        } else {
+            AllowedOrigins::List(origins)
+        };
+        Self {
+            origins,
+            ..Self::default()
+        }
+    }
+
+    /// CorsLayer が受け付けない組み合わせを起動時に断る
+    pub fn validate(&self) -> Result<(), String> {
+        if self.allow_credentials && self.origins == AllowedOrigins::Any {
+            return Err("credentials cannot be allowed for any origin".to_string());
+        }
+        if self.methods.is_empty() {
+            return Err("at least one method must be allowed".to_string());
+        }
+        Ok(())
+    }
+
+    pub fn allows(&self, origin: &str) -> bool {
+        match &self.origins {
+            AllowedOrigins::Any => true,
+            AllowedOrigins::List(origins) => {
+                let origin = origin.trim_end_matches('/');
+                origins
+                    .iter()
+                    .any(|allowed| allowed.eq_ignore_ascii_case(origin))
+            }
+        }
+    }
+
+    /// WebSocket のアップグレード要求の `Origin` を確かめる
+    ///
+    /// ブラウザは他のサイトからでも Cookie を付けて接続するので、許していない
+    /// オリジンからの接続を断る。断った場合は理由を返す。
+    pub fn check_upgrade(&self, headers: &HeaderMap) -> Result<(), String> {
+        let Some(origin) = headers.get(header::ORIGIN) else {
+            return Ok(());
+        };
+        let Ok(origin) = origin.to_str() else {
+            return Err("invalid origin header".to_string());
+        };
+        if is_same_origin(origin, headers) || self.allows(origin) {
+            Ok(())
+        } else {
+            Err(format!("origin `{}` is not allowed", origin))
+        }
+    }
+
+    pub fn layer(&self) -> CorsLayer {
+        let layer = match &self.origins {
+            AllowedOrigins::Any => CorsLayer::new().allow_origin(Any),
+            AllowedOrigins::List(origins) => {
+                let origins = origins
+                    .iter()
+                    .filter_map(|origin| HeaderValue::from_str(origin).ok());
+                CorsLayer::new().allow_origin(AllowOrigin::list(origins))
+            }
+        };
+        // 資格情報を許す場合は `*` を使えないので、要求されたヘッダーを返す
+        let headers = if self.allow_credentials {
+            AllowHeaders::mirror_request()
+        } else {
+            AllowHeaders::any()
+        };
+        layer
+            .allow_methods(self.methods.clone())
+            .allow_headers(headers)
+            .allow_credentials(self.allow_credentials)
+            .max_age(self.max_age)
+    }
+}
+
+/// `--allowed-origin` の値を `<scheme>://<host>[:<port>]` に揃える
+pub fn parse_origin(s: &str) -> Result<String, String> {
+    let s = s.trim().trim_end_matches('/');
+    if s == "*" {
+        return Ok(s.to_string());
+    }
+    let invalid = || {
+        format!(
+            "invalid origin `{}` (expected <scheme>://<host>[:<port>])",
+            s
+        )
+    };
+    let (scheme, host) = s.split_once("://").ok_or_else(invalid)?;
+    if scheme.is_empty() || host.is_empty() || host.contains(['/', '?', '#']) {
+        return Err(invalid());
+    }
+    HeaderValue::from_str(s).map_err(|_| invalid())?;
+    Ok(s.to_ascii_lowercase())
+}
+
+fn is_same_origin(origin: &str, headers: &HeaderMap) -> bool {
+    let Some(host) = headers
+        .get(header::HOST)
+        .and_then(|host| host.to_str().ok())
+    else {
+        return false;
+    };
+    origin
+        .split_once("://")
+        .is_some_and(|(_, authority)| authority.eq_ignore_ascii_case(host))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(origin: &str, host: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::ORIGIN, HeaderValue::from_str(origin).unwrap());
+        headers.insert(header::HOST, HeaderValue::from_str(host).unwrap());
+        headers
+    }
+
+    #[test]
+    fn test_parse_origin() {
+        assert_eq!(
+            parse_origin("https://Chat.Example.com/"),
+            Ok("https://chat.example.com".to_string())
+        );
+        assert_eq!(
+            parse_origin("http://localhost:3000"),
+            Ok("http://localhost:3000".to_string())
+        );
+        assert_eq!(parse_origin("*"), Ok("*".to_string()));
+        assert!(parse_origin("chat.example.com").is_err());
+        assert!(parse_origin("https://chat.example.com/app").is_err());
+    }
+
+    #[test]
+    fn test_check_upgrade() {
+        let policy = OriginPolicy::allow(vec!["https://chat.example.com".to_string()]);
+        assert!(policy
+            .check_upgrade(&headers("https://chat.example.com", "ws.example.com"))
+            .is_ok());
+        assert!(policy
+            .check_upgrade(&headers("https://evil.example", "ws.example.com"))
+            .is_err());
+        // 同じオリジンと、Origin の無い要求は許す
+        assert!(OriginPolicy::deny_all()
+            .check_upgrade(&headers("http://127.0.0.1:8080", "127.0.0.1:8080"))
+            .is_ok());
+        assert!(OriginPolicy::deny_all()
+            .check_upgrade(&HeaderMap::new())
+            .is_ok());
+
+        assert!(OriginPolicy::default()
+            .check_upgrade(&headers("https://evil.example", "ws.example.com"))
+            .is_ok());
+    }
+
+    #[test]
+    fn test_validate() {
+        let policy = OriginPolicy {
+            allow_credentials: true,
+            ..OriginPolicy::default()
+        };
+        assert!(policy.validate().is_err());
+        let policy = OriginPolicy {
+            allow_credentials: true,
+            ..OriginPolicy::allow(vec!["https://chat.example.com".to_string()])
+        };
+        assert!(policy.validate().is_ok());
+    }
+}
@@ -0,0 +1,175 @@
+use crate::auth::{Action, Role};
+use crate::rate_limit::RateClass;
+use crate::socket_manager::Outbound;
+use crate::upload::{ChunkError, QuotaError, UploadError};
+use axum::extract::ws::{close_code, CloseFrame};
+use bytes::Bytes;
+use message_pack::{BinarySerializable, ErrorCode, ErrorMessage};
+use std::fmt::{Display, Formatter};
+
+/// クライアントから受け取ったフレームの処理に失敗したことを表す
+#[derive(Debug)]
+pub struct ProtocolError {
+    pub code: ErrorCode,
+    pub reason: String,
+}
+
+impl ProtocolError {
+    pub fn new(code: ErrorCode, reason: impl Into<String>) -> Self {
+        Self {
+            code,
+            reason: reason.into(),
+        }
+    }
+
+    /// デコードに失敗したフレームの種類と理由からエラーを作る
+    pub fn malformed(kind: &str, reason: impl Display) -> Self {
+        Self::new(
+            ErrorCode::MalformedFrame,
+            format!("malformed {} frame: {}", kind, reason),
+        )
+    }
+
+    /// サーバー側の入出力 (ファイルや履歴の保存先) の失敗
+    pub fn io(context: &str, err: impl Display) -> Self {
+        Self::new(ErrorCode::Io, format!("{}: {}", context, err))
+    }
+
+    /// 役割が足りずに断った操作
+    pub fn denied(action: Action, role: Role) -> Self {
+        Self::new(
+            ErrorCode::PermissionDenied,
+            format!("{} is not allowed to {}", role, action),
+        )
+    }
+
+    /// 頻度の制限を越えて捨てたフレーム
+    pub fn rate_limited(class: RateClass) -> Self {
+        Self::new(
+            ErrorCode::RateLimited,
+            format!("too many {} frames; slow down", class),
+        )
+    }
+
+    /// クライアント起因のエラーか。サーバー側の障害と権限の不足はエラー予算を消費しない。
+    /// 頻度の制限には別の段階がある
+    pub fn counts_against_budget(&self) -> bool {
+        !matches!(
+            self.code,
+            ErrorCode::Io | ErrorCode::PermissionDenied | ErrorCode::RateLimited
+        )
+    }
+
+    /// クライアントへ返す Error フレーム
+    pub fn to_message(&self) -> Outbound {
+        let message = ErrorMessage {
+            code: self.code,
+            reason: self.reason.clone(),
+        };
+        Outbound::Binary(Bytes::from(message.to_bytes()))
+    }
+}
+
+impl From<UploadError> for ProtocolError {
+    fn from(e: UploadError) -> Self {
+        let code = match e {
+            UploadError::InvalidFilename(_) => ErrorCode::InvalidFilename,
+            UploadError::FileTypeRejected(_) => ErrorCode::FileTypeRejected,
+            UploadError::Rejected(_) => ErrorCode::UploadRejected,
+            UploadError::Io(_) => ErrorCode::Io,
+        };
+        ProtocolError::new(code, e.to_string())
+    }
+}
+
+impl From<QuotaError> for ProtocolError {
+    fn from(e: QuotaError) -> Self {
+        let code = match e {
+            QuotaError::FileTooLarge { .. } => ErrorCode::FileTooLarge,
+            QuotaError::UserQuotaExceeded { .. } | QuotaError::RoomQuotaExceeded { .. } => {
+                ErrorCode::QuotaExceeded
+            }
+        };
+        ProtocolError::new(code, e.to_string())
+    }
+}
+
+impl From<ChunkError> for ProtocolError {
+    fn from(e: ChunkError) -> Self {
+        let code = match e {
+            ChunkError::Quota(e) => return e.into(),
+            ChunkError::InvalidFilename(_) => ErrorCode::InvalidFilename,
+            ChunkError::UnknownUpload => ErrorCode::UnknownUpload,
+            ChunkError::OffsetMismatch { .. } => ErrorCode::OffsetMismatch,
+            ChunkError::ChecksumMismatch(_) => ErrorCode::ChecksumMismatch,
+            ChunkError::InvalidUpload(_) => ErrorCode::InvalidUpload,
+            ChunkError::FileTypeRejected(_) => ErrorCode::FileTypeRejected,
+            ChunkError::Rejected(_) => ErrorCode::UploadRejected,
+            ChunkError::Io(_) => ErrorCode::Io,
+        };
+        ProtocolError::new(code, e.to_string())
+    }
+}
+
+impl Display for ProtocolError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "[{}] {}", self.code.to_u16(), self.reason)
+    }
+}
+
+impl std::error::Error for ProtocolError {}
+
+/// 接続ごとのエラー予算。使い切ったクライアントは切断する
+#[derive(Debug)]
+pub struct ErrorBudget {
+    limit: u32,
+    used: u32,
+}
+
+impl ErrorBudget {
+    pub fn new(limit: u32) -> Self {
+        Self { limit, used: 0 }
+    }
+
+    /// 上限を変える。使った分はそのまま
+    pub fn set_limit(&mut self, limit: u32) {
+        self.limit = limit;
+    }
+
+    /// エラーを記録し、予算を使い切った場合は `true` を返す
+    pub fn record(&mut self, err: &ProtocolError) -> bool {
+        if err.counts_against_budget() {
+            self.used = self.used.saturating_add(1);
+        }
+        self.is_exhausted()
+    }
+
+    pub fn is_exhausted(&self) -> bool {
+        self.used >= self.limit
+    }
+
+    /// 予算を使い切ったクライアントに送る Close フレーム
+    pub fn close_message(&self) -> Outbound {
+        Outbound::Close(Some(CloseFrame {
+            code: close_code::POLICY,
+            reason: format!("too many protocol errors ({})", self.used).into(),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_budget_ignores_server_side_errors() {
+        let mut budget = ErrorBudget::new(2);
+        let io = ProtocolError::io("write", std::io::Error::other("disk full"));
+        assert!(!budget.record(&io));
+        assert!(!budget.record(&io));
+
+        let bad = ProtocolError::new(ErrorCode::EmptyFrame, "empty frame");
+        assert!(!budget.record(&bad));
+        assert!(budget.record(&bad));
+    }
+}
@@ -0,0 +1,372 @@
+use crate::socket_manager::Outbound;
+use axum::extract::ws::{close_code, CloseFrame};
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+/// 制限を越えてもすぐには切断せず、この回数までは警告だけにする
+pub const DEFAULT_WARNINGS: u32 = 3;
+
+/// 警告の後、この回数までは受信を遅らせる
+pub const DEFAULT_THROTTLES: u32 = 3;
+
+/// 受信を遅らせる時間の既定値
+pub const DEFAULT_THROTTLE_DELAY: Duration = Duration::from_secs(1);
+
+/// この間違反が無ければ、違反の回数を忘れる
+pub const DEFAULT_COOLDOWN: Duration = Duration::from_secs(10);
+
+/// 制限をかけるフレームの種類
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RateClass {
+    /// チャット (テキストフレームを含む)
+    Chat,
+    /// ファイル転送と分割アップロードの開始
+    File,
+    /// `/list`
+    List,
+    /// 分割アップロードのチャンク
+    Chunk,
+}
+
+impl Display for RateClass {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RateClass::Chat => write!(f, "chat"),
+            RateClass::File => write!(f, "file transfer"),
+            RateClass::List => write!(f, "list"),
+            RateClass::Chunk => write!(f, "upload chunk"),
+        }
+    }
+}
+
+/// トークンバケットの設定。`<1秒あたりの件数>:<続けて送れる件数>` と書く (例: `5:10`)
+///
+/// 1秒あたりの件数が 0 なら制限しない。
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimit {
+    pub per_second: f64,
+    pub burst: u32,
+}
+
+impl RateLimit {
+    pub const UNLIMITED: RateLimit = RateLimit {
+        per_second: 0.0,
+        burst: 0,
+    };
+
+    pub fn new(per_second: f64, burst: u32) -> Self {
+        Self { per_second, burst }
+    }
+
+    pub fn is_unlimited(&self) -> bool {
+        self.per_second <= 0.0
+    }
+}
+
+impl FromStr for RateLimit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid rate limit `{}` (expected <per second>:<burst>)", s);
+        let (rate, burst) = match s.split_once(':') {
+            Some((rate, burst)) => (rate, Some(burst)),
+            None => (s, None),
+        };
+        let per_second: f64 = rate.trim().parse().map_err(|_| invalid())?;
+        if !per_second.is_finite() || per_second < 0.0 {
+            return Err(invalid());
+        }
+        // 省略した場合は1秒分
+        let burst = match burst {
+            Some(burst) => burst.trim().parse().map_err(|_| invalid())?,
+            None => per_second.ceil() as u32,
+        };
+        if per_second > 0.0 && burst == 0 {
+            return Err(invalid());
+        }
+        Ok(Self { per_second, burst })
+    }
+}
+
+impl Display for RateLimit {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if self.is_unlimited() {
+            write!(f, "0")
+        } else {
+            write!(f, "{}:{}", self.per_second, self.burst)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimitSettings {
+    pub chat: RateLimit,
+    pub file: RateLimit,
+    pub list: RateLimit,
+    pub chunk: RateLimit,
+    /// 切断までの段階。この回数までは警告
+    pub warnings: u32,
+    /// 警告の後、この回数までは受信を遅らせ、それを越えたら切断する
+    pub throttles: u32,
+    pub throttle_delay: Duration,
+    pub cooldown: Duration,
+}
+
+impl Default for RateLimitSettings {
+    fn default() -> Self {
+        Self {
+            chat: RateLimit::new(5.0, 20),
+            file: RateLimit::new(1.0, 5),
+            list: RateLimit::new(1.0, 5),
+            // 256 KiB のチャンクで 1 秒あたり 10 MiB
+            chunk: RateLimit::new(40.0, 80),
+            warnings: DEFAULT_WARNINGS,
+            throttles: DEFAULT_THROTTLES,
+            throttle_delay: DEFAULT_THROTTLE_DELAY,
+            cooldown: DEFAULT_COOLDOWN,
+        }
+    }
+}
+
+impl RateLimitSettings {
+    /// どの種類も制限しない
+    pub fn unlimited() -> Self {
+        Self {
+            chat: RateLimit::UNLIMITED,
+            file: RateLimit::UNLIMITED,
+            list: RateLimit::UNLIMITED,
+            chunk: RateLimit::UNLIMITED,
+            ..Self::default()
+        }
+    }
+
+    fn limit(&self, class: RateClass) -> RateLimit {
+        match class {
+            RateClass::Chat => self.chat,
+            RateClass::File => self.file,
+            RateClass::List => self.list,
+            RateClass::Chunk => self.chunk,
+        }
+    }
+}
+
+/// 制限を越えたフレームへの対応。どの場合もそのフレームは処理しない
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RateDecision {
+    Allow,
+    /// エラーを返すだけ
+    Warn,
+    /// エラーを返し、しばらく受信を止める
+    Throttle(Duration),
+    /// 切断する
+    Disconnect,
+}
+
+#[derive(Debug)]
+struct TokenBucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn full(limit: RateLimit, now: Instant) -> Self {
+        Self {
+            tokens: f64::from(limit.burst),
+            updated: now,
+        }
+    }
+
+    fn try_take(&mut self, limit: RateLimit, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limit.per_second).min(f64::from(limit.burst));
+        self.updated = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// 1接続分のトークンバケットと違反の回数
+///
+/// 受信タスクが持つので、ロックは要らない。
+#[derive(Debug)]
+pub struct RateLimiter {
+    settings: RateLimitSettings,
+    chat: TokenBucket,
+    file: TokenBucket,
+    list: TokenBucket,
+    chunk: TokenBucket,
+    violations: u32,
+    last_violation: Option<Instant>,
+}
+
+impl RateLimiter {
+    pub fn new(settings: RateLimitSettings, now: Instant) -> Self {
+        Self {
+            chat: TokenBucket::full(settings.chat, now),
+            file: TokenBucket::full(settings.file, now),
+            list: TokenBucket::full(settings.list, now),
+            chunk: TokenBucket::full(settings.chunk, now),
+            settings,
+            violations: 0,
+            last_violation: None,
+        }
+    }
+
+    /// 設定を差し替える。頻度が変わった種類はバケットを満たし直し、違反の回数は残す
+    pub fn update(&mut self, settings: RateLimitSettings, now: Instant) {
+        if settings.chat != self.settings.chat {
+            self.chat = TokenBucket::full(settings.chat, now);
+        }
+        if settings.file != self.settings.file {
+            self.file = TokenBucket::full(settings.file, now);
+        }
+        if settings.list != self.settings.list {
+            self.list = TokenBucket::full(settings.list, now);
+        }
+        if settings.chunk != self.settings.chunk {
+            self.chunk = TokenBucket::full(settings.chunk, now);
+        }
+        self.settings = settings;
+    }
+
+    /// `class` のフレームを1つ受け取ってよいか
+    pub fn check(&mut self, class: RateClass, now: Instant) -> RateDecision {
+        let limit = self.settings.limit(class);
+        if limit.is_unlimited() {
+            return RateDecision::Allow;
+        }
+        let bucket = match class {
+            RateClass::Chat => &mut self.chat,
+            RateClass::File => &mut self.file,
+            RateClass::List => &mut self.list,
+            RateClass::Chunk => &mut self.chunk,
+        };
+        if bucket.try_take(limit, now) {
+            return RateDecision::Allow;
+        }
+
+        let forgiven = self
+            .last_violation
+            .is_some_and(|last| now.saturating_duration_since(last) >= self.settings.cooldown);
+        if forgiven {
+            self.violations = 0;
+        }
+        self.violations = self.violations.saturating_add(1);
+        self.last_violation = Some(now);
+
+        if self.violations <= self.settings.warnings {
+            RateDecision::Warn
+        } else if self.violations <= self.settings.warnings + self.settings.throttles {
+            RateDecision::Throttle(self.settings.throttle_delay)
+        } else {
+            RateDecision::Disconnect
+        }
+    }
+
+    /// 制限を越え続けたクライアントに送る Close フレーム
+    pub fn close_message(&self) -> Outbound {
+        Outbound::Close(Some(CloseFrame {
+            code: close_code::POLICY,
+            reason: format!("rate limit exceeded ({} violations)", self.violations).into(),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_rate_limit() {
+        assert_eq!("5:10".parse(), Ok(RateLimit::new(5.0, 10)));
+        assert_eq!("0.5".parse(), Ok(RateLimit::new(0.5, 1)));
+        assert!("0".parse::<RateLimit>().unwrap().is_unlimited());
+        assert!("5:0".parse::<RateLimit>().is_err());
+        assert!("-1".parse::<RateLimit>().is_err());
+        assert!("fast".parse::<RateLimit>().is_err());
+        assert_eq!(RateLimit::new(2.5, 4).to_string(), "2.5:4");
+    }
+
+    #[test]
+    fn test_escalation() {
+        let settings = RateLimitSettings {
+            chat: RateLimit::new(1.0, 2),
+            warnings: 1,
+            throttles: 1,
+            ..RateLimitSettings::default()
+        };
+        let start = Instant::now();
+        let mut limiter = RateLimiter::new(settings, start);
+
+        assert_eq!(limiter.check(RateClass::Chat, start), RateDecision::Allow);
+        assert_eq!(limiter.check(RateClass::Chat, start), RateDecision::Allow);
+        assert_eq!(limiter.check(RateClass::Chat, start), RateDecision::Warn);
+        // 種類ごとに別のバケット
+        assert_eq!(limiter.check(RateClass::List, start), RateDecision::Allow);
+
+        // 1秒で1件分戻る
+        let later = start + Duration::from_secs(1);
+        assert_eq!(limiter.check(RateClass::Chat, later), RateDecision::Allow);
+        assert_eq!(
+            limiter.check(RateClass::Chat, later),
+            RateDecision::Throttle(DEFAULT_THROTTLE_DELAY)
+        );
+        assert_eq!(
+            limiter.check(RateClass::Chat, later),
+            RateDecision::Disconnect
+        );
+
+        // しばらく大人しくしていれば警告からやり直す
+        let quiet = later + DEFAULT_COOLDOWN;
+        let mut limiter = RateLimiter::new(settings, start);
+        for _ in 0..2 {
+            limiter.check(RateClass::Chat, start);
+        }
+        assert_eq!(limiter.check(RateClass::Chat, start), RateDecision::Warn);
+        assert_eq!(
+            limiter.check(RateClass::Chat, start),
+            RateDecision::Throttle(DEFAULT_THROTTLE_DELAY)
+        );
+        for _ in 0..2 {
+            assert_eq!(limiter.check(RateClass::Chat, quiet), RateDecision::Allow);
+        }
+        assert_eq!(limiter.check(RateClass::Chat, quiet), RateDecision::Warn);
+    }
+
+    #[test]
+    fn test_update_refills_changed_buckets() {
+        let settings = RateLimitSettings {
+            chat: RateLimit::new(1.0, 1),
+            list: RateLimit::new(1.0, 1),
+            ..RateLimitSettings::default()
+        };
+        let start = Instant::now();
+        let mut limiter = RateLimiter::new(settings, start);
+        assert_eq!(limiter.check(RateClass::Chat, start), RateDecision::Allow);
+        assert_eq!(limiter.check(RateClass::List, start), RateDecision::Allow);
+        assert_eq!(limiter.check(RateClass::Chat, start), RateDecision::Warn);
+
+        limiter.update(
+            RateLimitSettings {
+                chat: RateLimit::new(1.0, 3),
+                ..settings
+            },
+            start,
+        );
+        for _ in 0..3 {
+            assert_eq!(limiter.check(RateClass::Chat, start), RateDecision::Allow);
+        }
+        // 変わっていない種類は使った分が残り、違反の回数も続きから数える
+        assert_eq!(limiter.check(RateClass::List, start), RateDecision::Warn);
+        assert_eq!(limiter.check(RateClass::List, start), RateDecision::Warn);
+        assert_eq!(
+            limiter.check(RateClass::List, start),
+            RateDecision::Throttle(DEFAULT_THROTTLE_DELAY)
+        );
+    }
+}
@@ -0,0 +1,545 @@
+use crate::app::AppState;
+use crate::config::{AuthMethod, BlobMethod, Config, HistorySettings};
+use crate::history::now_millis;
+use crate::metrics::Metrics;
+use crate::rate_limit::RateLimitSettings;
+use crate::tls::{self, TlsFiles};
+use log::{info, warn};
+use serde::Serialize;
+use std::fmt::{Display, Formatter};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use tokio::sync::{watch, Notify};
+use tokio::time::MissedTickBehavior;
+
+/// 設定ファイルが書き換えられたかを確かめる間隔
+pub const CONFIG_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// 管理 API から頼んだ読み直しを待つ時間
+pub const RELOAD_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// 読み直しのきっかけ
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReloadTrigger {
+    /// SIGHUP
+    Signal,
+    /// 設定ファイルの書き換え
+    File,
+    /// `POST /api/admin/reload`
+    Api,
+}
+
+impl Display for ReloadTrigger {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ReloadTrigger::Signal => write!(f, "SIGHUP"),
+            ReloadTrigger::File => write!(f, "file change"),
+            ReloadTrigger::Api => write!(f, "admin API"),
+        }
+    }
+}
+
+/// 読み直しの結果。項目は設定ファイルでの名前
+#[derive(Debug, Clone, Serialize)]
+pub struct ReloadReport {
+    pub trigger: ReloadTrigger,
+    /// UNIX 時刻 (ミリ秒)
+    pub at: u64,
+    pub success: bool,
+    /// 変わって、実行中の接続にも反映した項目
+    pub applied: Vec<&'static str>,
+    /// 変わったが、反映するには再起動が要る項目
+    pub restart_required: Vec<&'static str>,
+    /// 読み直した BAN の数
+    pub bans: Option<usize>,
+    /// 失敗した場合の理由。この場合は何も変えていない
+    pub errors: Vec<String>,
+}
+
+impl ReloadReport {
+    pub fn failed(trigger: ReloadTrigger, errors: Vec<String>) -> Self {
+        Self {
+            trigger,
+            at: now_millis(),
+            success: false,
+            applied: Vec::new(),
+            restart_required: Vec::new(),
+            bans: None,
+            errors,
+        }
+    }
+}
+
+/// 読み直しの依頼と、最後の結果
+#[derive(Debug)]
+pub struct Reloads {
+    requested: Notify,
+    last: watch::Sender<Option<ReloadReport>>,
+}
+
+impl Default for Reloads {
+    fn default() -> Self {
+        Self {
+            requested: Notify::new(),
+            last: watch::Sender::new(None),
+        }
+    }
+}
+
+impl Reloads {
+    /// 読み直しを頼む。`run` が動いていなければ何も起きない
+    pub fn request(&self) {
+        self.requested.notify_one();
+    }
+
+    /// 最後の読み直しの結果。まだ読み直していなければ `None`
+    pub fn last(&self) -> Option<ReloadReport> {
+        self.last.borrow().clone()
+    }
+
+    /// 読み直しを頼み、その結果を待つ
+    pub async fn reload(&self) -> Option<ReloadReport> {
+        let mut last = self.last.subscribe();
+        self.request();
+        tokio::time::timeout(RELOAD_TIMEOUT, last.changed())
+            .await
+            .ok()?
+            .ok()?;
+        let report = last.borrow().clone();
+        report
+    }
+}
+
+/// 読み直した設定のうち、実行中に変えられるものを反映する
+///
+/// 役割や BAN のファイル、証明書が読めなければ何も変えない。再起動が要る項目は `running` に
+/// 残したままにするので、次に読み直した時も報告し続ける。
+pub fn apply(
+    state: &AppState,
+    running: &mut Config,
+    next: Config,
+    trigger: ReloadTrigger,
+) -> ReloadReport {
+    let roles = match next.role_policy() {
+        Ok(roles) => roles,
+        Err(e) => return ReloadReport::failed(trigger, vec![e.to_string()]),
+    };
+    // TLS の有無を切り替えるには待ち受け直す必要があるので、今の方式のまま読み直す
+    let tls_files = match (&running.tls, &next.tls) {
+        (Some(_), Some(files)) => Some(files.clone()),
+        (files, _) => files.clone(),
+    };
+    let cert = match (&state.tls, &tls_files) {
+        (Some(_), Some(files)) => match tls::certified_key(files) {
+            Ok(key) => Some(key),
+            Err(e) => return ReloadReport::failed(trigger, vec![e.to_string()]),
+        },
+        _ => None,
+    };
+    let bans = match state.bans.reload() {
+        Ok(bans) => bans,
+        Err(e) => return ReloadReport::failed(trigger, vec![e.to_string()]),
+    };
+    let (applied, restart_required) = changes(running, &next);
+
+    if let (Some(certs), Some(key)) = (&state.tls, cert) {
+        certs.replace(key);
+    }
+    log::set_max_level(next.log_level);
+    state.quota.set_limits(next.upload_limits);
+    state.settings.update(|settings| {
+        settings.max_protocol_errors = next.max_protocol_errors;
+        settings.upload_limits = next.upload_limits;
+        settings.rate_limits = RateLimitSettings {
+            cooldown: settings.rate_limits.cooldown,
+            ..next.rate_limits
+        };
+        settings.roles = roles;
+        settings.origins = next.origins.clone();
+        settings.heartbeat = next.heartbeat;
+        settings.history_replay = next.history.replay;
+    });
+    let history = HistorySettings {
+        replay: next.history.replay,
+        ..running.history.clone()
+    };
+    *running = Config {
+        listeners: running.listeners.clone(),
+        upload_dir: running.upload_dir.clone(),
+        auth: running.auth.clone(),
+        tls: tls_files,
+        send_queue: running.send_queue,
+        shutdown_timeout: running.shutdown_timeout,
+        history,
+        ban_file: running.ban_file.clone(),
+        uploads: running.uploads.clone(),
+        blobs: running.blobs.clone(),
+        ..next
+    };
+
+    ReloadReport {
+        trigger,
+        at: now_millis(),
+        success: true,
+        applied,
+        restart_required,
+        bans: Some(bans),
+        errors: Vec::new(),
+    }
+}
+
+/// 変わった項目を、すぐ反映できるものと再起動が要るものに分ける
+fn changes(running: &Config, next: &Config) -> (Vec<&'static str>, Vec<&'static str>) {
+    let mut restart_required = Vec::new();
+    if running.listeners != next.listeners {
+        restart_required.push("server.bind");
+    }
+    if running.upload_dir != next.upload_dir {
+        restart_required.push("server.upload_dir");
+    }
+    if running.auth != next.auth {
+        restart_required.push(match (&running.auth, &next.auth) {
+            (AuthMethod::Token(_), AuthMethod::Token(_)) => "auth.token_file",
+            (AuthMethod::Jwt(_), AuthMethod::Jwt(_)) => "auth.jwt_secret",
+            (AuthMethod::Password(_), AuthMethod::Password(_)) => "auth.password_file",
+            _ => "auth.backend",
+        });
+    }
+    if running.tls.is_some() != next.tls.is_some() {
+        restart_required.push("tls.cert_file");
+    }
+    // 送信キューは接続ごとにあるが、作った時の設定のまま変わらない。検査や保存先も
+    // 起動時に作ったものを使い続ける
+    let (old_queue, new_queue) = (running.send_queue, next.send_queue);
+    let (old_history, new_history) = (&running.history, &next.history);
+    let (old_uploads, new_uploads) = (&running.uploads, &next.uploads);
+    restart_required.extend(
+        [
+            (
+                "server.shutdown_timeout_secs",
+                running.shutdown_timeout != next.shutdown_timeout,
+            ),
+            ("send_queue.size", old_queue.capacity != new_queue.capacity),
+            (
+                "send_queue.slow_consumer_policy",
+                old_queue.policy != new_queue.policy,
+            ),
+            ("send_queue.max_lag", old_queue.max_lag != new_queue.max_lag),
+            (
+                "history.backend",
+                old_history.backend != new_history.backend,
+            ),
+            ("history.file", old_history.file != new_history.file),
+            (
+                "history.max_messages",
+                old_history.retention.max_messages_per_room
+                    != new_history.retention.max_messages_per_room,
+            ),
+            (
+                "history.max_age_secs",
+                old_history.retention.max_age != new_history.retention.max_age,
+            ),
+            ("moderation.ban_file", running.ban_file != next.ban_file),
+            (
+                "uploads.progress_interval_ms",
+                old_uploads.progress_interval != new_uploads.progress_interval,
+            ),
+            (
+                "uploads.refuse_executables",
+                old_uploads.refuse_executables != new_uploads.refuse_executables,
+            ),
+            (
+                "uploads.denied_types",
+                old_uploads.denied_types != new_uploads.denied_types,
+            ),
+            (
+                "uploads.scan_commands",
+                old_uploads.scan_commands != new_uploads.scan_commands,
+            ),
+            (
+                "uploads.scan_timeout_secs",
+                old_uploads.scan_timeout != new_uploads.scan_timeout,
+            ),
+            (
+                "uploads.scan_secrets",
+                old_uploads.scan_secrets != new_uploads.scan_secrets,
+            ),
+        ]
+        .into_iter()
+        .filter_map(|(key, changed)| changed.then_some(key)),
+    );
+    match (&running.blobs, &next.blobs) {
+        (BlobMethod::S3(old), BlobMethod::S3(new)) => restart_required.extend(
+            [
+                ("blobs.s3_endpoint", old.endpoint != new.endpoint),
+                ("blobs.s3_bucket", old.bucket != new.bucket),
+                ("blobs.s3_region", old.region != new.region),
+                ("blobs.s3_access_key", old.access_key != new.access_key),
+                ("blobs.s3_secret_key", old.secret_key != new.secret_key),
+                ("blobs.s3_prefix", old.prefix != new.prefix),
+            ]
+            .into_iter()
+            .filter_map(|(key, changed)| changed.then_some(key)),
+        ),
+        (old, new) if old != new => restart_required.push("blobs.backend"),
+        _ => {}
+    }
+
+    let (old, new) = (running.upload_limits, next.upload_limits);
+    let (old_cors, new_cors) = (&running.origins, &next.origins);
+    let tls_changed = |file: fn(&TlsFiles) -> &Path| match (&running.tls, &next.tls) {
+        (Some(old), Some(new)) => file(old) != file(new),
+        _ => false,
+    };
+    let applied = [
+        ("server.mode", running.mode != next.mode),
+        ("log.level", running.log_level != next.log_level),
+        (
+            "limits.max_upload_size",
+            old.max_file_size != new.max_file_size,
+        ),
+        ("limits.user_quota", old.user_quota != new.user_quota),
+        ("limits.room_quota", old.room_quota != new.room_quota),
+        (
+            "limits.max_protocol_errors",
+            running.max_protocol_errors != next.max_protocol_errors,
+        ),
+        (
+            "limits.chat_rate",
+            running.rate_limits.chat != next.rate_limits.chat,
+        ),
+        (
+            "limits.file_rate",
+            running.rate_limits.file != next.rate_limits.file,
+        ),
+        (
+            "limits.list_rate",
+            running.rate_limits.list != next.rate_limits.list,
+        ),
+        (
+            "limits.chunk_rate",
+            running.rate_limits.chunk != next.rate_limits.chunk,
+        ),
+        (
+            "limits.rate_limit_warnings",
+            running.rate_limits.warnings != next.rate_limits.warnings,
+        ),
+        (
+            "limits.rate_limit_throttles",
+            running.rate_limits.throttles != next.rate_limits.throttles,
+        ),
+        (
+            "limits.rate_limit_throttle_ms",
+            running.rate_limits.throttle_delay != next.rate_limits.throttle_delay,
+        ),
+        ("auth.roles_file", running.roles_file != next.roles_file),
+        (
+            "auth.default_role",
+            running.default_role != next.default_role,
+        ),
+        ("cors.allowed_origins", old_cors.origins != new_cors.origins),
+        ("cors.methods", old_cors.methods != new_cors.methods),
+        (
+            "cors.credentials",
+            old_cors.allow_credentials != new_cors.allow_credentials,
+        ),
+        ("tls.cert_file", tls_changed(|files| &files.cert)),
+        ("tls.key_file", tls_changed(|files| &files.key)),
+        (
+            "heartbeat.ping_interval_secs",
+            running.heartbeat.interval != next.heartbeat.interval,
+        ),
+        (
+            "heartbeat.pong_timeout_secs",
+            running.heartbeat.timeout != next.heartbeat.timeout,
+        ),
+        (
+            "history.replay",
+            running.history.replay != next.history.replay,
+        ),
+    ]
+    .into_iter()
+    .filter_map(|(key, changed)| changed.then_some(key))
+    .collect();
+
+    (applied, restart_required)
+}
+
+/// 結果をログに出し、管理 API から見えるようにする
+pub fn record(state: &AppState, report: ReloadReport) {
+    if report.success {
+        Metrics::inc(&state.metrics.config_reloads);
+        info!(
+            "reloaded configuration on {}: applied {:?}, {} ban(s)",
+            report.trigger,
+            report.applied,
+            report.bans.unwrap_or(0)
+        );
+        if !report.restart_required.is_empty() {
+            warn!(
+                "restart the server to apply {}",
+                report.restart_required.join(", ")
+            );
+        }
+    } else {
+        Metrics::inc(&state.metrics.config_reload_failures);
+        warn!(
+            "failed to reload configuration on {}, keeping the current one: {}",
+            report.trigger,
+            report.errors.join("; ")
+        );
+    }
+    state.reloads.last.send_replace(Some(report));
+}
+
+/// SIGHUP、設定ファイルの書き換え、管理 API からの依頼のたびに `load` で読み直して反映する
+///
+/// 設定ファイルは `CONFIG_POLL_INTERVAL` ごとに更新時刻を見る。
+pub async fn run<F>(state: AppState, mut running: Config, path: Option<PathBuf>, mut load: F)
+where
+    F: FnMut() -> Result<Config, Vec<String>>,
+{
+    let mut hangup = Hangup::new();
+    let mut modified = path.as_deref().and_then(modified_at);
+    let mut poll = tokio::time::interval_at(
+        tokio::time::Instant::now() + CONFIG_POLL_INTERVAL,
+        CONFIG_POLL_INTERVAL,
+    );
+    poll.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    loop {
+        let trigger = tokio::select! {
+            _ = hangup.recv() => ReloadTrigger::Signal,
+            _ = state.reloads.requested.notified() => ReloadTrigger::Api,
+            _ = poll.tick(), if path.is_some() => {
+                if path.as_deref().and_then(modified_at) == modified {
+                    continue;
+                }
+                ReloadTrigger::File
+            }
+        };
+        modified = path.as_deref().and_then(modified_at);
+
+        let report = match load() {
+            Ok(next) => apply(&state, &mut running, next, trigger),
+            Err(errors) => ReloadReport::failed(trigger, errors),
+        };
+        record(&state, report);
+    }
+}
+
+fn modified_at(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path)
+        .and_then(|meta| meta.modified())
+        .ok()
+}
+
+/// SIGHUP の受け口。Unix 以外や、登録できなかった場合は何も来ない
+struct Hangup {
+    #[cfg(unix)]
+    signal: Option<tokio::signal::unix::Signal>,
+}
+
+impl Hangup {
+    #[cfg(unix)]
+    fn new() -> Self {
+        use tokio::signal::unix::{signal, SignalKind};
+        let signal = signal(SignalKind::hangup())
+            .map_err(|e| warn!("failed to listen for SIGHUP: {}", e))
+            .ok();
+        Self { signal }
+    }
+
+    #[cfg(not(unix))]
+    fn new() -> Self {
+        Self {}
+    }
+
+    #[cfg(unix)]
+    async fn recv(&mut self) {
+        if let Some(signal) = &mut self.signal {
+            if signal.recv().await.is_some() {
+                return;
+            }
+        }
+        std::future::pending().await
+    }
+
+    #[cfg(not(unix))]
+    async fn recv(&mut self) {
+        std::future::pending().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ConfigLayer;
+
+    fn config(content: &str) -> Config {
+        Config::resolve(ConfigLayer::parse(content).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_changes() {
+        let running = config("");
+        let next = config(
+            r#"
+[server]
+bind = "0.0.0.0:9000"
+mode = "production"
+
+[limits]
+chat_rate = "0"
+rate_limit_warnings = 1
+
+[auth]
+backend = "jwt"
+jwt_secret = "secret"
+
+[heartbeat]
+ping_interval_secs = 0
+
+[history]
+file = "other.jsonl"
+replay = 5
+
+[send_queue]
+size = 10
+
+[uploads]
+scan_secrets = true
+
+[blobs]
+backend = "memory"
+"#,
+        );
+        let (applied, restart_required) = changes(&running, &next);
+        assert_eq!(
+            applied,
+            [
+                "server.mode",
+                "limits.chat_rate",
+                "limits.rate_limit_warnings",
+                "cors.allowed_origins",
+                "heartbeat.ping_interval_secs",
+                "history.replay"
+            ]
+        );
+        assert_eq!(
+            restart_required,
+            [
+                "server.bind",
+                "auth.backend",
+                "send_queue.size",
+                "history.file",
+                "uploads.scan_secrets",
+                "blobs.backend"
+            ]
+        );
+
+        let (applied, restart_required) = changes(&next, &next);
+        assert!(applied.is_empty() && restart_required.is_empty());
+    }
+}
@@ -0,0 +1,294 @@
+use crate::socket_manager::Outbound;
+use axum::extract::ws::{close_code, CloseFrame};
+use std::collections::VecDeque;
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
+use tokio::sync::Notify;
+
+/// 送信キューの既定の長さ
+pub const DEFAULT_SEND_QUEUE_CAPACITY: usize = 100;
+
+/// `Disconnect` の場合に、この件数を取りこぼしたクライアントを切断する
+pub const DEFAULT_MAX_LAG: u64 = 1000;
+
+/// 受信が追いつかないクライアントの送信キューが一杯になった時の振る舞い
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum SlowConsumerPolicy {
+    /// 古いメッセージを捨てて新しいメッセージを積む
+    DropOldest,
+    /// 新しいメッセージを捨てる
+    DropNewest,
+    /// 新しいメッセージを捨て、取りこぼしが閾値に達したら切断する
+    Disconnect,
+}
+
+impl FromStr for SlowConsumerPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "drop-oldest" => Ok(SlowConsumerPolicy::DropOldest),
+            "drop-newest" => Ok(SlowConsumerPolicy::DropNewest),
+            "disconnect" => Ok(SlowConsumerPolicy::Disconnect),
+            _ => Err(format!(
+                "unknown slow consumer policy `{}` (drop-oldest, drop-newest, disconnect)",
+                s
+            )),
+        }
+    }
+}
+
+impl Display for SlowConsumerPolicy {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SlowConsumerPolicy::DropOldest => write!(f, "drop-oldest"),
+            SlowConsumerPolicy::DropNewest => write!(f, "drop-newest"),
+            SlowConsumerPolicy::Disconnect => write!(f, "disconnect"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SendQueueSettings {
+    pub capacity: usize,
+    pub policy: SlowConsumerPolicy,
+    /// `SlowConsumerPolicy::Disconnect` で切断するまでに許容する取りこぼし件数。
+    /// キューが空になるたびに数え直す
+    pub max_lag: u64,
+}
+
+impl Default for SendQueueSettings {
+    fn default() -> Self {
+        Self {
+            capacity: DEFAULT_SEND_QUEUE_CAPACITY,
+            policy: SlowConsumerPolicy::DropOldest,
+            max_lag: DEFAULT_MAX_LAG,
+        }
+    }
+}
+
+/// `SendQueue::push` の結果
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum PushOutcome {
+    Queued,
+    /// キューが一杯だったのでメッセージを1件捨てた
+    Dropped,
+    /// 取りこぼしが閾値に達したので切断することにした
+    Disconnected,
+    /// 既に閉じられている
+    Closed,
+}
+
+/// 1接続分の送信キュー
+///
+/// `tokio::sync::mpsc` と違い、一杯になった時に古いメッセージを捨てられる。
+/// 取り出し側は接続ごとの送信タスクひとつだけ。
+pub struct SendQueue {
+    settings: SendQueueSettings,
+    messages: Mutex<VecDeque<Outbound>>,
+    notify: Notify,
+    /// まだクライアントに通知していない取りこぼし件数
+    missed: AtomicU64,
+    /// 接続してからの取りこぼし件数の合計
+    missed_total: AtomicU64,
+    /// キューが最後に空になってからの取りこぼし件数。追いついたクライアントは切断しない
+    lag: AtomicU64,
+    closed: AtomicBool,
+}
+
+impl SendQueue {
+    pub fn new(settings: SendQueueSettings) -> Self {
+        Self {
+            settings,
+            messages: Mutex::new(VecDeque::with_capacity(settings.capacity)),
+            notify: Notify::new(),
+            missed: AtomicU64::new(0),
+            missed_total: AtomicU64::new(0),
+            lag: AtomicU64::new(0),
+            closed: AtomicBool::new(false),
+        }
+    }
+
+    /// ポリシーに従ってメッセージを積む。待つことはない
+    pub fn push(&self, message: Outbound) -> PushOutcome {
+        if self.closed.load(Ordering::Acquire) {
+            return PushOutcome::Closed;
+        }
+
+        let outcome = {
+            let mut messages = self.messages.lock().unwrap();
+
+            if messages.len() < self.settings.capacity {
+                messages.push_back(message);
+                PushOutcome::Queued
+            } else {
+                self.missed_total.fetch_add(1, Ordering::Relaxed);
+                self.missed.fetch_add(1, Ordering::Relaxed);
+                let lag = self.lag.fetch_add(1, Ordering::Relaxed) + 1;
+
+                match self.settings.policy {
+                    SlowConsumerPolicy::DropOldest => {
+                        messages.pop_front();
+                        messages.push_back(message);
+                        PushOutcome::Dropped
+                    }
+                    SlowConsumerPolicy::DropNewest => PushOutcome::Dropped,
+                    SlowConsumerPolicy::Disconnect if lag < self.settings.max_lag => {
+                        PushOutcome::Dropped
+                    }
+                    SlowConsumerPolicy::Disconnect => {
+                        // 残っているメッセージは届けずに Close だけ送る
+                        messages.clear();
+                        messages.push_back(Outbound::Close(Some(CloseFrame {
+                            code: close_code::POLICY,
+                            reason: format!("slow consumer: missed {} messages", lag).into(),
+                        })));
+                        self.closed.store(true, Ordering::Release);
+                        PushOutcome::Disconnected
+                    }
+                }
+            }
+        };
+
+        self.notify.notify_one();
+        outcome
+    }
+
+    /// 容量に関係なく積む。Close や Ping フレームなど、捨てられては困るもの用
+    pub fn push_control(&self, message: Outbound) {
+        if self.closed.load(Ordering::Acquire) {
+            return;
+        }
+        self.messages.lock().unwrap().push_back(message);
+        self.notify.notify_one();
+    }
+
+    /// 次のメッセージを待つ。閉じられていて空なら `None`
+    pub async fn recv(&self) -> Option<Outbound> {
+        loop {
+            {
+                let mut messages = self.messages.lock().unwrap();
+                if let Some(message) = messages.pop_front() {
+                    if messages.is_empty() {
+                        self.lag.store(0, Ordering::Relaxed);
+                    }
+                    return Some(message);
+                }
+                if self.closed.load(Ordering::Acquire) {
+                    return None;
+                }
+            }
+            self.notify.notified().await;
+        }
+    }
+
+    /// まだ通知していない取りこぼし件数を取り出してリセットする
+    pub fn take_missed(&self) -> u64 {
+        self.missed.swap(0, Ordering::Relaxed)
+    }
+
+    /// 以降のメッセージを受け付けない。積まれている分は `recv` で取り出せる
+    pub fn close(&self) {
+        self.closed.store(true, Ordering::Release);
+        self.notify.notify_one();
+    }
+
+    pub fn depth(&self) -> usize {
+        self.messages.lock().unwrap().len()
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.settings.capacity
+    }
+
+    pub fn missed_total(&self) -> u64 {
+        self.missed_total.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn queue(policy: SlowConsumerPolicy, max_lag: u64) -> SendQueue {
+        SendQueue::new(SendQueueSettings {
+            capacity: 2,
+            policy,
+            max_lag,
+        })
+    }
+
+    async fn texts(queue: &SendQueue) -> Vec<String> {
+        queue.close();
+        let mut texts = Vec::new();
+        while let Some(message) = queue.recv().await {
+            match message {
+                Outbound::Text(bytes) => texts.push(String::from_utf8(bytes.to_vec()).unwrap()),
+                Outbound::Close(_) => texts.push("<close>".to_string()),
+                Outbound::Binary(_) => texts.push("<binary>".to_string()),
+                Outbound::Ping(_) => texts.push("<ping>".to_string()),
+            }
+        }
+        texts
+    }
+
+    #[tokio::test]
+    async fn test_drop_oldest() {
+        let queue = queue(SlowConsumerPolicy::DropOldest, 0);
+        for i in 0..4 {
+            queue.push(Outbound::text(i.to_string()));
+        }
+
+        assert_eq!(queue.take_missed(), 2);
+        assert_eq!(texts(&queue).await, vec!["2", "3"]);
+    }
+
+    #[tokio::test]
+    async fn test_drop_newest() {
+        let queue = queue(SlowConsumerPolicy::DropNewest, 0);
+        for i in 0..4 {
+            queue.push(Outbound::text(i.to_string()));
+        }
+
+        assert_eq!(queue.take_missed(), 2);
+        assert_eq!(texts(&queue).await, vec!["0", "1"]);
+    }
+
+    #[tokio::test]
+    async fn test_disconnect_after_max_lag() {
+        let queue = queue(SlowConsumerPolicy::Disconnect, 2);
+        assert_eq!(queue.push(Outbound::text("0")), PushOutcome::Queued);
+        assert_eq!(queue.push(Outbound::text("1")), PushOutcome::Queued);
+        assert_eq!(queue.push(Outbound::text("2")), PushOutcome::Dropped);
+        assert_eq!(queue.push(Outbound::text("3")), PushOutcome::Disconnected);
+        assert_eq!(queue.push(Outbound::text("4")), PushOutcome::Closed);
+
+        assert_eq!(texts(&queue).await, vec!["<close>"]);
+    }
+
+    #[tokio::test]
+    async fn test_recovered_client_is_not_disconnected() {
+        let queue = queue(SlowConsumerPolicy::Disconnect, 2);
+        for round in 0..3 {
+            assert_eq!(queue.push(Outbound::text("a")), PushOutcome::Queued);
+            assert_eq!(queue.push(Outbound::text("b")), PushOutcome::Queued);
+            assert_eq!(queue.push(Outbound::text("c")), PushOutcome::Dropped);
+            assert_eq!(queue.missed_total(), round + 1);
+
+            // 追いつけば、それまでの取りこぼしは数えない
+            queue.recv().await.unwrap();
+            queue.recv().await.unwrap();
+        }
+
+        assert_eq!(queue.push(Outbound::text("a")), PushOutcome::Queued);
+        assert_eq!(queue.push(Outbound::text("b")), PushOutcome::Queued);
+        assert_eq!(queue.push(Outbound::text("c")), PushOutcome::Dropped);
+        // 一部だけ読んでも、空になるまでは数え続ける
+        queue.recv().await.unwrap();
+        assert_eq!(queue.push(Outbound::text("d")), PushOutcome::Queued);
+        assert_eq!(queue.push(Outbound::text("e")), PushOutcome::Disconnected);
+        assert_eq!(texts(&queue).await, vec!["<close>"]);
+    }
+}
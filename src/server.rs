@@ -1,419 +1,240 @@
-use axum::extract::ws::{Message, WebSocket};
-use axum::extract::State;
-use axum::extract::WebSocketUpgrade;
-use axum::http::Method;
-use axum::response::sse::{Event, KeepAlive, Sse};
-use axum::routing::get;
-use axum::Json;
 use clap::Parser;
-use futures_util::stream::{self, Stream};
-use futures_util::{SinkExt, StreamExt};
-use log::{info, warn};
-use message_pack::{
-    get_type, BinaryDeserializable, FileTransferMessage, ListMessage, MessageType, TextMessage,
-};
+use log::{info, warn, LevelFilter};
 use simple_logger::SimpleLogger;
-use std::collections::HashMap;
-use std::convert::Infallible;
-use std::fs::exists;
-use std::net::SocketAddrV4;
+use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::sync::Arc;
-use std::time::Duration;
-use std::{env, fs};
-use tokio::fs::File;
-use tokio::io::AsyncWriteExt;
 use tokio::net::TcpListener;
-use tokio::sync::mpsc::Sender;
-use tokio::sync::{mpsc, Mutex};
-use tokio_stream::StreamExt as _;
-use tower_http::cors::{Any, CorsLayer};
-use tower_http::services::ServeDir;
-use uuid::Uuid;
+use ws_s::app::{self, AppState, ServerSettings};
+use ws_s::auth::password::hash_password;
+use ws_s::auth::{Authenticator, JwtAuth, PasswordFile, TokenFile};
+use ws_s::config::{AuthMethod, BlobMethod, Config, ConfigLayer, InvalidConfig};
+use ws_s::history::{FileHistory, HistoryBackend, HistoryStore, MemoryHistory};
+use ws_s::listener::RouteSet;
+use ws_s::moderation::BanList;
+use ws_s::reload;
+use ws_s::shutdown;
+use ws_s::tls::{self, TlsCerts};
+use ws_s::upload::blob::S3BlobStore;
+use ws_s::upload::{self, BlobStore, FsBlobStore, MemoryBlobStore, BLOBS_DIRNAME};
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
-    /// ホスト名 (環境変数から取得またはデフォルト値を適用)
-    #[arg(long, default_value_t = String::new())]
-    hostname: String,
-}
+    /// 設定ファイル (TOML)。コマンドラインと環境変数 `WS_S_*` の指定が優先する
+    #[arg(long, env = "WS_S_CONFIG")]
+    config: Option<PathBuf>,
 
-struct SocketWrapper {
-    id: Uuid,
-    socket: Sender<String>,
-}
+    #[command(flatten)]
+    settings: ConfigLayer,
 
-struct SocketManager {
-    sockets: Arc<Mutex<HashMap<Uuid, SocketWrapper>>>,
+    /// 標準入力から読んだパスワードのハッシュを表示して終了する
+    #[arg(long)]
+    hash_password: bool,
 }
 
-impl SocketManager {
-    fn new() -> Self {
-        Self {
-            sockets: Arc::new(Mutex::new(HashMap::new())),
-        }
-    }
-
-    async fn add(&mut self, socket: Sender<String>) -> Uuid {
-        let id = Uuid::new_v4();
-        let socket = SocketWrapper { id, socket };
-        let mut sockets = self.sockets.lock().await;
-        sockets.insert(id, socket);
-
-        id
-    }
-
-    async fn remove(&mut self, id: Uuid) {
-        let mut sockets = self.sockets.lock().await;
-
-        if sockets.remove(&id).is_some() {
-            info!("Socket with ID {} removed", id);
-        }
-    }
+fn blob_store(config: &Config) -> anyhow::Result<Arc<dyn BlobStore>> {
+    Ok(match &config.blobs {
+        BlobMethod::Fs => Arc::new(FsBlobStore::new(config.upload_dir.join(BLOBS_DIRNAME))),
+        BlobMethod::Memory => Arc::new(MemoryBlobStore::default()),
+        BlobMethod::S3(s3) => Arc::new(S3BlobStore::new(s3.clone())?),
+    })
+}
 
-    async fn broadcast(&self, message: String) {
-        let sockets = self.sockets.lock().await;
-        for (_, socket_wrapper) in sockets.iter() {
-            if let Err(err) = socket_wrapper.socket.send(message.clone()).await {
-                warn!("Failed to send message to {}: {}", socket_wrapper.id, err);
-            }
+/// 設定ファイルの上に、コマンドラインと環境変数の指定を重ねる
+fn load_config(args: &Args) -> anyhow::Result<Config> {
+    let file = match &args.config {
+        Some(path) => {
+            ConfigLayer::load(path).map_err(|e| anyhow::anyhow!("{}: {}", path.display(), e))?
         }
-    }
-
-    async fn direct_message(&self, id: Uuid, message: String) {
-        let sockets = self.sockets.lock().await; // ロックガードを束縛
-        let target_socket = sockets.get(&id).unwrap(); // ロックガードからデータを取得
+        None => ConfigLayer::default(),
+    };
+    Ok(Config::resolve(args.settings.clone().or(file))?)
+}
 
-        if let Err(err) = target_socket.socket.send(message.clone()).await {
-            warn!("Failed to send message to {}: {}", id, err);
+fn authenticator(config: &Config) -> anyhow::Result<Option<Arc<dyn Authenticator>>> {
+    Ok(match &config.auth {
+        AuthMethod::None => None,
+        AuthMethod::Token(path) => {
+            let tokens =
+                TokenFile::load(path).map_err(|e| anyhow::anyhow!("{}: {}", path.display(), e))?;
+            Some(Arc::new(tokens))
         }
-    }
-
-    #[allow(dead_code)]
-    async fn dump(&self) {
-        let sockets = self.sockets.lock().await; // 非同期ロックを取得
-        info!("Current sockets:");
-        for (id, _sender) in sockets.iter() {
-            info!("\t{}", id);
+        AuthMethod::Jwt(secret) => Some(Arc::new(JwtAuth::new(secret.as_bytes()))),
+        AuthMethod::Password(path) => {
+            let users = PasswordFile::load(path)
+                .map_err(|e| anyhow::anyhow!("{}: {}", path.display(), e))?;
+            Some(Arc::new(users))
         }
-    }
+    })
 }
 
-const UPLOAD_DIRNAME: &str = "./uploads";
-
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     let args = Args::parse();
-
-    // 環境変数 "HOSTNAME" の取得
-    let env_hostname = env::var("HOSTNAME").ok();
-    // コマンドライン引数または環境変数、最後にデフォルト値を設定
-    let hostname = if !args.hostname.is_empty() {
-        args.hostname
-    } else if let Some(env) = env_hostname {
-        env
-    } else {
-        String::from("127.0.0.1:8080") // デフォルト値
+    if args.hash_password {
+        let mut password = String::new();
+        std::io::stdin().read_line(&mut password)?;
+        println!(
+            "{}",
+            hash_password(password.trim_end_matches(['\r', '\n']))?
+        );
+        return Ok(());
+    }
+    let config = load_config(&args)?;
+    // 読み直しでログの詳しさを変えられるように、絞り込みは `log` の側でする
+    SimpleLogger::new().with_level(LevelFilter::Trace).init()?;
+    log::set_max_level(config.log_level);
+
+    let auth = authenticator(&config)?;
+    let blobs = blob_store(&config)?;
+    let roles = config.role_policy()?;
+    let certs = match &config.tls {
+        Some(files) => Some(Arc::new(TlsCerts::load(files)?)),
+        None => None,
     };
 
-    info!("hostname: {}", hostname);
-
-    SimpleLogger::new()
-        .with_level(log::LevelFilter::Info) // デフォルトログレベル (Info以上のみ出力)
-        .init()?;
-
-    let addr: SocketAddrV4 = hostname.parse()?;
-
-    match exists(UPLOAD_DIRNAME) {
-        Ok(true) => match fs::metadata(UPLOAD_DIRNAME) {
-            Ok(metadata) => {
-                if !metadata.is_dir() {
-                    warn!("Error: {} is not a directory", UPLOAD_DIRNAME);
-                    std::process::exit(1);
-                } else {
-                    // do nothing
-                }
-            }
-            Err(_) => {
-                warn!("Error: cannot check is {} a directory", UPLOAD_DIRNAME);
-                std::process::exit(1);
-            }
-        },
-        Ok(false) => {
-            if fs::create_dir(UPLOAD_DIRNAME).is_err() {
-                warn!("Error: creating directory {}", UPLOAD_DIRNAME);
-                std::process::exit(1);
-            } else {
-                warn!("Directory {} is created", UPLOAD_DIRNAME);
-            }
-        }
-        Err(_) => {
-            let e =  fs::create_dir(UPLOAD_DIRNAME);
-            warn!("Error: {:?}", e);
-            std::process::exit(1);
-        },
+    if let Err(e) = upload::prepare_dir(&config.upload_dir) {
+        warn!(
+            "Error: preparing directory {}: {}",
+            config.upload_dir.display(),
+            e
+        );
+        std::process::exit(1);
+    }
+    // 壊れた一覧で起動すると、アップロード済みのファイルが一覧から消える
+    if let Err(e) = upload::FileIndex::check(&config.upload_dir) {
+        warn!("Error: reading file index: {}", e);
+        std::process::exit(1);
+    }
+    // 使用量を失うと容量の上限が効かなくなる
+    if let Err(e) = upload::QuotaLedger::check(&config.upload_dir) {
+        warn!("Error: reading quota usage: {}", e);
+        std::process::exit(1);
     }
 
-    let socket: std::io::Result<TcpListener> = TcpListener::bind(&addr).await;
-    let listener: TcpListener = socket.expect("Failed to bind socket");
-
-    let socket_manager = Arc::new(Mutex::new(SocketManager::new()));
-
-    let sse_sent = Arc::new(Mutex::new(0));
-
-    let cors = cors_handler().await;
-
-    let app = axum::Router::new()
-        .nest_service("/", ServeDir::new("./front/dist"))
-        .route(
-            "/api/health.json",
-            axum::routing::get(|| async { Json("{\"success\": \"true\"}") }),
-        )
-        .route(
-            "/api/sse",
-            get({
-                let sse_sent = sse_sent.clone();
-                move || sse_handler(sse_sent)
-            }),
-        )
-        .route("/ws", axum::routing::get(handle_websocket))
-        .layer(cors)
-        .with_state(socket_manager)
-        .with_state(sse_sent);
-
-    axum::serve(listener, app.into_make_service()).await?;
-
-    Ok(())
-}
-
-async fn sse_handler(sent: Arc<Mutex<i32>>) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
-    let stream = stream::unfold(sent.clone(), |sent_ref| async move {
-        let current_count;
-
-        {
-            let mut count = sent_ref.lock().await; // ロックをスコープ内で限定
-            *count += 1;
-            current_count = *count; // カウントの値をコピー
-        }
-
-        // ロックが解放された後にイベントを生成
-
-        if current_count > 100 {
-            {
-                let mut count = sent_ref.lock().await; // ロックをスコープ内で限定
-                *count = 0;
-                info!("reset sent count");
-            }
-            None
-        } else {
-            let event = Event::default().data(format!("hi! ({})", current_count));
-            Some((Ok(event), sent_ref)) // `sent_ref` を次に渡す（move 必要なし）
+    // ホスト名なら、解決した全てのアドレスで待ち受ける
+    let scheme = if certs.is_some() { "https" } else { "http" };
+    let mut listeners: Vec<(TcpListener, RouteSet)> = Vec::new();
+    for listener in &config.listeners {
+        let sockets = listener
+            .bind()
+            .await
+            .map_err(|e| anyhow::anyhow!("failed to bind {}: {}", listener.addr, e))?;
+        for socket in sockets {
+            info!(
+                "listening on {}://{} ({} routes)",
+                scheme,
+                socket.local_addr()?,
+                listener.routes
+            );
+            listeners.push((socket, listener.routes));
         }
-    })
-    .throttle(Duration::from_secs(1));
-
-    Sse::new(stream).keep_alive(KeepAlive::default())
-}
-
-async fn handle_websocket(
-    State(manager): State<Arc<Mutex<SocketManager>>>,
-    ws: WebSocketUpgrade,
-) -> axum::response::Response {
-    ws.on_upgrade(move |socket| handle_socket(manager, socket))
-}
-
-async fn handle_socket(manager: Arc<Mutex<SocketManager>>, mut socket: WebSocket) {
-    if let Err(e) = socket
-        .send(Message::from("connected(server)".to_string()))
-        .await
-    {
-        warn!("Error while sending connected message: {:?}", e);
-        return;
     }
 
-    let (mut sender, mut receiver) = socket.split();
-    let (tx, mut rx) = mpsc::channel::<String>(100);
+    let state = AppState::new(ServerSettings {
+        upload_dir: config.upload_dir.clone(),
+        max_protocol_errors: config.max_protocol_errors,
+        send_queue: config.send_queue,
+        history_replay: config.history.replay,
+        upload_limits: config.upload_limits,
+        refuse_executables: config.uploads.refuse_executables,
+        scanners: config.uploads.scan_pipeline(),
+        upload_progress_interval: config.uploads.progress_interval,
+        roles,
+        rate_limits: config.rate_limits,
+        origins: config.origins.clone(),
+        heartbeat: config.heartbeat,
+    });
 
-    // クライアントを管理に追加
-    let uuid = {
-        let mut manager = manager.lock().await;
-        manager.add(tx.clone()).await
+    let history = &config.history;
+    let history: Arc<dyn HistoryStore> = match history.backend {
+        HistoryBackend::File => Arc::new(FileHistory::open(&history.file, history.retention)?),
+        HistoryBackend::Memory => Arc::new(MemoryHistory::new(history.retention)),
     };
-
-    // クライアントへの送信タスク
-    let manager_clone = manager.clone();
-    tokio::spawn(async move {
-        while let Some(message) = rx.recv().await {
-            if sender.send(Message::Text(message)).await.is_err() {
-                warn!("Error sending message to client");
-                break;
-            }
+    let bans = BanList::open(&config.ban_file)?;
+    let mut state = state
+        .with_history(history)
+        .with_blobs(blobs)
+        .with_bans(Arc::new(bans));
+    match auth {
+        Some(auth) => {
+            info!("authenticating connections with {}", config.auth.backend());
+            state = state.with_auth(auth);
         }
-
-        // クライアント切断時に管理から削除
-        manager_clone.lock().await.remove(uuid).await;
-    });
-
-    // クライアントから受信タスク
-    let manager_clone = manager.clone();
-    let manager_clone2 = manager.clone();
-    let uuid_clone = uuid;
-    tokio::spawn(async move {
-        while let Some(Ok(msg)) = futures_util::StreamExt::next(&mut receiver).await {
-            match msg {
-                Message::Text(text) => {
-                    // // メッセージを全クライアントに送信 (ブロードキャスト)
-                    let message_string = text.trim().to_string(); // 安全に加工
-                    info!("received: {}", message_string);
-
-                    // 受け取ったメッセージを全クライアントにブロードキャスト
-                    let manager = manager_clone.lock().await; // ロックを取得
-                    manager.broadcast(message_string).await;
-                }
-                Message::Binary(m) => {
-                    // let m: Vec<u8> = msg.into_data();
-
-                    match get_type(&m[0]) {
-                        MessageType::Chat => {
-                            // chat
-                            let chat_message = TextMessage::from_bytes(&m).unwrap();
-                            // チャットメッセージを何らかの形で文字列に変換してブロードキャスト
-                            let message_string = format!(
-                                "[Room {} - {}]: {}",
-                                chat_message.room, chat_message.sender, chat_message.content
-                            );
-
-                            // クライアントにブロードキャスト
-                            let manager = manager_clone.lock().await; // ロックを取得
-                            manager.broadcast(message_string.clone()).await;
-                        }
-                        MessageType::Exit => {
-                            // exit
-                            // let d = TextMessage::from_bytes(&*m).unwrap();
-                            info!("received exit message");
-
-                            // ロックを使って離脱メッセージをブロードキャスト
-                            let leave_message = format!("User {} has left the chat.", uuid);
-                            {
-                                let manager = manager_clone.lock().await;
-                                manager.broadcast(leave_message).await;
-                            } // ロックを解除
-
-                            // UUIDの削除
-                            {
-                                let mut manager = manager_clone.lock().await;
-                                manager.remove(uuid).await;
-                            } // ロックを解除
-
-                            // スレッド終了
-                            break;
-                        }
-                        MessageType::FileTransfer => {
-                            // file transfer
-                            let d: FileTransferMessage =
-                                FileTransferMessage::from_bytes(&m).unwrap();
-
-                            match d.category {
-                                MessageType::FileTransfer => {
-                                    if d.filename.is_empty() {
-                                        warn!("Invalid filename received");
-                                        return;
-                                    }
-
-                                    let default_path = UPLOAD_DIRNAME.to_string();
-                                    let full_path = format!("{}/{}", default_path, d.filename);
-
-                                    let transferred_bytes = format_bytes(d.content.len() as u64);
-                                    info!(
-                                        "uploaded: {} {} bytes transferred.",
-                                        full_path,
-                                        transferred_bytes.clone()
-                                    );
-                                    let mut f = File::create(full_path).await.unwrap();
-
-                                    f.write_all(&d.content).await.unwrap();
-
-                                    {
-                                        let manager = manager_clone.lock().await;
-                                        manager
-                                            .direct_message(
-                                                uuid,
-                                                format!("{} bytes transferred.", transferred_bytes),
-                                            )
-                                            .await;
-                                    } // ロックを解除
-                                }
-                                _ => {
-                                    warn!("Invalid message category (3)");
-                                }
-                            }
-                        }
-                        MessageType::List => {
-                            let d: ListMessage = ListMessage::from_bytes(&m).unwrap();
-
-                            match d.target.as_str() {
-                                "socket" => {
-                                    let manager = manager_clone.lock().await;
-                                    let mut messages: Vec<String> = Vec::new();
-                                    for (id, _socket_wrapper) in manager.sockets.lock().await.iter()
-                                    {
-                                        messages.push(format!("{}", id));
-                                    }
-                                    manager.direct_message(uuid, messages.join("\n")).await;
-                                }
-                                _ => {
-                                    let manager = manager_clone.lock().await;
-                                    let message = "Invalid target";
-                                    manager.direct_message(uuid, message.to_string()).await;
-                                }
-                            }
-                        }
-                        _ => {
-                            warn!("Invalid message category (4)");
-                        }
+        None => warn!("the admin API is disabled because connections are not authenticated"),
+    }
+    if let Some(certs) = &certs {
+        state = state.with_tls(certs.clone());
+    }
+    info!(
+        "running in {} mode, allowing origins {:?}",
+        config.mode,
+        state.settings.current().origins.origins
+    );
+    info!("storing uploads in {}", config.blobs.backend());
+    let scanners = &state.settings.current().scanners;
+    if !scanners.is_empty() {
+        info!("scanning uploads with {:?}", scanners);
+    }
+    state.uploads.migrate_legacy().await;
+
+    let tls_config = certs.map(tls::server_config).transpose()?;
+
+    // 合図があれば新しい接続の受け付けを止め、処理中の HTTP リクエストを待つ
+    let servers: Vec<_> = listeners
+        .into_iter()
+        .map(|(listener, routes)| {
+            let app = app::router_for(state.clone(), routes);
+            let stopping = state.shutdown.clone();
+            let tls_config = tls_config.clone();
+            tokio::spawn(async move {
+                match tls_config {
+                    Some(config) => tls::serve(listener, app, config, stopping.requested()).await,
+                    None => {
+                        axum::serve(
+                            listener,
+                            app.into_make_service_with_connect_info::<SocketAddr>(),
+                        )
+                        .with_graceful_shutdown(async move { stopping.requested().await })
+                        .await
                     }
                 }
-                _ => {
-                    warn!("Received unknown message {:?}", msg);
-                }
+            })
+        })
+        .collect();
+
+    // SIGHUP や設定ファイルの書き換えで読み直す
+    let shutdown_timeout = config.shutdown_timeout;
+    let config_path = args.config.clone();
+    let reloader = tokio::spawn(reload::run(state.clone(), config, config_path, move || {
+        load_config(&args).map_err(|e| match e.downcast_ref::<InvalidConfig>() {
+            Some(invalid) => invalid.0.iter().map(ToString::to_string).collect(),
+            None => vec![e.to_string()],
+        })
+    }));
+
+    shutdown::signal().await;
+    reloader.abort();
+    info!("received shutdown signal");
+    shutdown::begin(&state);
+
+    let drained = tokio::time::timeout(shutdown_timeout, async {
+        for server in servers {
+            if let Ok(Err(e)) = server.await {
+                warn!("server error: {}", e);
             }
         }
-
-        // クライアント切断時に管理から削除
-        manager_clone2.lock().await.remove(uuid_clone).await;
-    });
-}
-
-fn format_bytes(bytes: u64) -> String {
-    const KIB: u64 = 1024;
-    const MIB: u64 = KIB * 1024;
-    const GIB: u64 = MIB * 1024;
-    const TIB: u64 = GIB * 1024;
-
-    if bytes >= TIB {
-        format!("{:.2} TiB", bytes as f64 / TIB as f64)
-    } else if bytes >= GIB {
-        format!("{:.2} GiB", bytes as f64 / GIB as f64)
-    } else if bytes >= MIB {
-        format!("{:.2} MiB", bytes as f64 / MIB as f64)
-    } else if bytes >= KIB {
-        format!("{:.2} KiB", bytes as f64 / KIB as f64)
-    } else {
-        format!("{} B", bytes)
+        state.shutdown.drained().await;
+    })
+    .await;
+    if drained.is_err() {
+        warn!(
+            "gave up waiting for connections after {:?}",
+            shutdown_timeout
+        );
     }
-}
+    info!("bye");
 
-pub async fn cors_handler() -> CorsLayer {
-    CorsLayer::new()
-        .allow_origin(Any)
-        .allow_methods(vec![
-            Method::GET,
-            Method::POST,
-            Method::PUT,
-            Method::DELETE,
-            Method::OPTIONS,
-        ])
-        .allow_headers(Any)
-        // .allow_credentials(true)
-        .max_age(Duration::from_secs(86400)) // 1日間のプリフライトキャッシュ
+    Ok(())
 }
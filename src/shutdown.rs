@@ -0,0 +1,135 @@
+use crate::app::AppState;
+use crate::socket_manager::Outbound;
+use axum::extract::ws::{close_code, CloseFrame};
+use log::info;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch;
+
+/// 停止の合図の後、アップロードや履歴の書き込みが終わるのを待つ時間の既定値
+pub const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// 停止を知らせる時にクライアントへ送る文言
+pub const SHUTDOWN_MESSAGE: &str = "server shutting down";
+
+/// 停止の合図と、途中で止めたくない処理の数
+#[derive(Debug)]
+pub struct Shutdown {
+    stopping: watch::Sender<bool>,
+    in_flight: watch::Sender<usize>,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self {
+            stopping: watch::Sender::new(false),
+            in_flight: watch::Sender::new(0),
+        }
+    }
+}
+
+impl Shutdown {
+    pub fn is_stopping(&self) -> bool {
+        *self.stopping.borrow()
+    }
+
+    /// 最初の呼び出しなら `true`
+    pub fn begin(&self) -> bool {
+        !self.stopping.send_replace(true)
+    }
+
+    /// `begin` が呼ばれるまで待つ
+    pub async fn requested(&self) {
+        let mut stopping = self.stopping.subscribe();
+        let _ = stopping.wait_for(|stopping| *stopping).await;
+    }
+
+    /// ガードを持っている間は `drained` が終わらない
+    pub fn track(self: &Arc<Self>) -> InFlight {
+        self.in_flight.send_modify(|count| *count += 1);
+        InFlight(self.clone())
+    }
+
+    /// 全てのガードが捨てられるまで待つ
+    pub async fn drained(&self) {
+        let mut in_flight = self.in_flight.subscribe();
+        let _ = in_flight.wait_for(|count| *count == 0).await;
+    }
+}
+
+/// 途中で止めたくない処理の間持っておくガード
+#[derive(Debug)]
+pub struct InFlight(Arc<Shutdown>);
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.in_flight.send_modify(|count| *count -= 1);
+    }
+}
+
+/// SIGINT か SIGTERM を待つ
+pub async fn signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("failed to listen for ctrl-c");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("failed to listen for SIGTERM")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
+    }
+}
+
+/// 新しい接続を断るようにし、全員に停止を知らせて 1001 で Close を送る
+///
+/// 送り終えるのとアップロードの書き込みが終わるのは `Shutdown::drained` で待つ。
+pub fn begin(state: &AppState) {
+    if !state.shutdown.begin() {
+        return;
+    }
+    state.manager.broadcast(SHUTDOWN_MESSAGE);
+    let closed = state.manager.close_all(close_message());
+    info!("shutting down: closing {} connection(s)", closed);
+}
+
+/// 停止する時にクライアントへ送る Close フレーム。クライアントは繋ぎ直してよい
+pub fn close_message() -> Outbound {
+    Outbound::Close(Some(CloseFrame {
+        code: close_code::AWAY,
+        reason: SHUTDOWN_MESSAGE.into(),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_drained_waits_for_guards() {
+        let shutdown = Arc::new(Shutdown::default());
+        assert!(!shutdown.is_stopping());
+        assert!(shutdown.begin());
+        assert!(!shutdown.begin());
+        shutdown.requested().await;
+
+        let guard = shutdown.track();
+        let drained = tokio::time::timeout(Duration::from_millis(50), shutdown.drained());
+        assert!(drained.await.is_err());
+
+        drop(guard);
+        tokio::time::timeout(Duration::from_secs(1), shutdown.drained())
+            .await
+            .unwrap();
+    }
+}
@@ -0,0 +1,392 @@
+use crate::auth::Principal;
+use crate::metrics::Metrics;
+use crate::moderation::BanTarget;
+use crate::send_queue::{PushOutcome, SendQueue, SendQueueSettings};
+use axum::extract::ws::{CloseFrame, Message};
+use bytes::Bytes;
+use dashmap::DashMap;
+use log::{info, warn};
+use std::net::IpAddr;
+use std::sync::Arc;
+use uuid::Uuid;
+
+/// 各接続の送信キューに積むフレーム
+///
+/// ブロードキャストでは同じ `Bytes` を全員で共有し、WebSocket のフレームへの
+/// 変換は各接続の送信タスクで行う。
+#[derive(Debug, Clone)]
+pub enum Outbound {
+    Text(Bytes),
+    Binary(Bytes),
+    Close(Option<CloseFrame<'static>>),
+    Ping(Bytes),
+}
+
+impl Outbound {
+    pub fn text(message: impl Into<String>) -> Self {
+        Outbound::Text(Bytes::from(message.into()))
+    }
+
+    /// 送信タスクで WebSocket のフレームに変える
+    ///
+    /// axum 0.7 の `Message` は `String` と `Vec<u8>` しか持てないので、他の接続と共有している
+    /// `Bytes` はここで1回コピーする。最後に残った1つや、1つの接続にだけ送るものは
+    /// 元の領域をそのまま使う。
+    pub fn into_message(self) -> Message {
+        match self {
+            Outbound::Text(bytes) => Message::Text(
+                String::from_utf8(Vec::from(bytes))
+                    // Text は String からしか作られないので UTF-8 として正しい
+                    .unwrap_or_else(|e| String::from_utf8_lossy(e.as_bytes()).into_owned()),
+            ),
+            Outbound::Binary(bytes) => Message::Binary(Vec::from(bytes)),
+            Outbound::Close(frame) => Message::Close(frame),
+            Outbound::Ping(bytes) => Message::Ping(Vec::from(bytes)),
+        }
+    }
+}
+
+pub struct SocketWrapper {
+    pub id: Uuid,
+    pub socket: Arc<SendQueue>,
+    /// 参加中のルーム。`None` なら全ルームのメッセージを受け取る
+    pub room: Option<i32>,
+    /// 認証された相手。認証を設定していなければ `None`
+    pub principal: Option<Principal>,
+    /// チャットやルームへの参加で最後に名乗った名前
+    pub name: Option<String>,
+    /// 接続元。分からなければ `None`
+    pub addr: Option<IpAddr>,
+}
+
+impl SocketWrapper {
+    fn receives(&self, room: i32) -> bool {
+        self.room.is_none_or(|joined| joined == room)
+    }
+
+    fn matches(&self, target: &BanTarget) -> bool {
+        match target {
+            BanTarget::Connection(id) => self.id == *id,
+            BanTarget::Name(name) => self.name.as_ref() == Some(name),
+            BanTarget::User(user) => self
+                .principal
+                .as_ref()
+                .is_some_and(|principal| &principal.name == user),
+            BanTarget::Ip(ip) => self.addr == Some(*ip),
+        }
+    }
+}
+
+/// 1接続分の送信キューの状態
+#[derive(Debug, Clone)]
+pub struct QueueStats {
+    pub id: Uuid,
+    pub room: Option<i32>,
+    pub principal: Option<Principal>,
+    pub addr: Option<IpAddr>,
+    pub depth: usize,
+    pub capacity: usize,
+    pub missed_total: u64,
+}
+
+/// 接続中のクライアントの一覧
+///
+/// 内部は並行ハッシュマップで、`Clone` しても同じ一覧を指す。
+/// ロックを保持したまま `await` することはない。
+#[derive(Clone)]
+pub struct SocketManager {
+    sockets: Arc<DashMap<Uuid, SocketWrapper>>,
+    queue_settings: SendQueueSettings,
+    metrics: Arc<Metrics>,
+}
+
+impl Default for SocketManager {
+    fn default() -> Self {
+        Self::new(SendQueueSettings::default(), Arc::new(Metrics::default()))
+    }
+}
+
+impl SocketManager {
+    pub fn new(queue_settings: SendQueueSettings, metrics: Arc<Metrics>) -> Self {
+        Self {
+            sockets: Arc::new(DashMap::new()),
+            queue_settings,
+            metrics,
+        }
+    }
+
+    /// 新しい接続を登録し、その接続の送信キューを返す
+    pub fn add(&self) -> (Uuid, Arc<SendQueue>) {
+        self.add_as(None, None)
+    }
+
+    /// 認証された相手と接続元を添えて登録する
+    pub fn add_as(
+        &self,
+        principal: Option<Principal>,
+        addr: Option<IpAddr>,
+    ) -> (Uuid, Arc<SendQueue>) {
+        let id = Uuid::new_v4();
+        let queue = Arc::new(SendQueue::new(self.queue_settings));
+        let socket = SocketWrapper {
+            id,
+            socket: queue.clone(),
+            room: None,
+            principal,
+            name: None,
+            addr,
+        };
+        self.sockets.insert(id, socket);
+        Metrics::inc(&self.metrics.connections_total);
+
+        (id, queue)
+    }
+
+    pub fn remove(&self, id: Uuid) {
+        if self.sockets.remove(&id).is_some() {
+            info!("Socket with ID {} removed", id);
+        }
+    }
+
+    /// ルームに参加する。以降はそのルーム宛てのメッセージだけを受け取る
+    pub fn join(&self, id: Uuid, room: i32) {
+        if let Some(mut socket) = self.sockets.get_mut(&id) {
+            socket.room = Some(room);
+        }
+    }
+
+    /// 名乗った名前を覚える
+    pub fn set_name(&self, id: Uuid, name: &str) {
+        if let Some(mut socket) = self.sockets.get_mut(&id) {
+            if socket.name.as_deref() != Some(name) {
+                socket.name = Some(name.to_string());
+            }
+        }
+    }
+
+    /// 登録されている (切断されていない) 接続か
+    pub fn contains(&self, id: Uuid) -> bool {
+        self.sockets.contains_key(&id)
+    }
+
+    /// 繋ぎ直しても変わらない接続の相手。認証された名前、無ければ接続元のアドレス
+    pub fn identity(&self, id: Uuid) -> Option<BanTarget> {
+        let socket = self.sockets.get(&id)?;
+        socket
+            .principal
+            .as_ref()
+            .map(|principal| BanTarget::User(principal.name.clone()))
+            .or_else(|| socket.addr.map(BanTarget::Ip))
+    }
+
+    /// 当てはまる接続に `close` を送って一覧から外し、その数を返す
+    ///
+    /// 外した接続にはもう何も届かない。受信タスクは `contains` で気付いて終わる。
+    pub fn kick(&self, target: &BanTarget, close: Outbound) -> usize {
+        let kicked = self.close_where(|socket| socket.matches(target), close);
+        for id in &kicked {
+            info!("Socket with ID {} kicked", id);
+        }
+        kicked.len()
+    }
+
+    /// 全ての接続に `close` を送って一覧から外し、その数を返す
+    pub fn close_all(&self, close: Outbound) -> usize {
+        self.close_where(|_| true, close).len()
+    }
+
+    /// 外した接続の ID を返す
+    fn close_where(&self, filter: impl Fn(&SocketWrapper) -> bool, close: Outbound) -> Vec<Uuid> {
+        let ids: Vec<Uuid> = self
+            .sockets
+            .iter()
+            .filter(|socket| filter(socket))
+            .map(|socket| socket.id)
+            .collect();
+
+        ids.into_iter()
+            .filter(|id| match self.sockets.remove(id) {
+                Some((_, socket)) => {
+                    socket.socket.push_control(close.clone());
+                    true
+                }
+                None => false,
+            })
+            .collect()
+    }
+
+    /// 誰かが参加しているルームか
+    pub fn is_occupied(&self, room: i32) -> bool {
+        self.sockets.iter().any(|socket| socket.room == Some(room))
+    }
+
+    pub fn principal(&self, id: Uuid) -> Option<Principal> {
+        self.sockets.get(&id)?.principal.clone()
+    }
+
+    pub fn len(&self) -> usize {
+        self.sockets.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sockets.is_empty()
+    }
+
+    pub fn ids(&self) -> Vec<Uuid> {
+        self.sockets.iter().map(|entry| *entry.key()).collect()
+    }
+
+    pub fn queue_stats(&self) -> Vec<QueueStats> {
+        self.sockets
+            .iter()
+            .map(|entry| QueueStats {
+                id: entry.id,
+                room: entry.room,
+                principal: entry.principal.clone(),
+                addr: entry.addr,
+                depth: entry.socket.depth(),
+                capacity: entry.socket.capacity(),
+                missed_total: entry.socket.missed_total(),
+            })
+            .collect()
+    }
+
+    /// 全クライアントへ送る。キューが一杯のクライアントはポリシーに従って扱い、待つことはない
+    pub fn broadcast(&self, message: impl Into<Bytes>) {
+        let message = Outbound::Text(message.into());
+        Metrics::inc(&self.metrics.messages_broadcast);
+
+        for socket_wrapper in self.sockets.iter() {
+            self.record(
+                socket_wrapper.id,
+                socket_wrapper.socket.push(message.clone()),
+            );
+        }
+    }
+
+    /// ルームの参加者 (とルームに参加していないクライアント) へ送る
+    pub fn broadcast_room(&self, room: i32, message: impl Into<Bytes>) {
+        let message = Outbound::Text(message.into());
+        Metrics::inc(&self.metrics.messages_broadcast);
+
+        for socket_wrapper in self.sockets.iter().filter(|s| s.receives(room)) {
+            self.record(
+                socket_wrapper.id,
+                socket_wrapper.socket.push(message.clone()),
+            );
+        }
+    }
+
+    pub fn direct_message(&self, id: Uuid, message: impl Into<String>) {
+        self.send_to(id, Outbound::text(message));
+    }
+
+    /// 任意のフレーム (バイナリや Close、Ping を含む) を特定のクライアントへ送る
+    pub fn send_to(&self, id: Uuid, message: Outbound) {
+        let Some(socket) = self.sockets.get(&id).map(|s| s.socket.clone()) else {
+            // 既に切断済み
+            return;
+        };
+
+        if matches!(message, Outbound::Close(_) | Outbound::Ping(_)) {
+            socket.push_control(message);
+        } else {
+            self.record(id, socket.push(message));
+        }
+    }
+
+    fn record(&self, id: Uuid, outcome: PushOutcome) {
+        match outcome {
+            PushOutcome::Queued | PushOutcome::Closed => {}
+            PushOutcome::Dropped => {
+                Metrics::inc(&self.metrics.messages_dropped);
+            }
+            PushOutcome::Disconnected => {
+                Metrics::inc(&self.metrics.messages_dropped);
+                Metrics::inc(&self.metrics.slow_consumer_disconnects);
+                warn!("Disconnecting slow consumer {}", id);
+            }
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn dump(&self) {
+        info!("Current sockets:");
+        for id in self.ids() {
+            info!("\t{}", id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::send_queue::SlowConsumerPolicy;
+    use std::sync::atomic::Ordering;
+
+    #[test]
+    fn test_into_message_reuses_unshared_bytes() {
+        let text = Outbound::text("hello");
+        let Outbound::Text(bytes) = &text else {
+            unreachable!()
+        };
+        let ptr = bytes.as_ptr();
+        match text.into_message() {
+            Message::Text(text) => {
+                assert_eq!(text, "hello");
+                assert_eq!(text.as_ptr(), ptr);
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+
+        // 共有している間はコピーし、最後の1つは元の領域を使う
+        let shared = Bytes::from(vec![1u8, 2, 3]);
+        let ptr = shared.as_ptr();
+        let first = Outbound::Binary(shared.clone()).into_message();
+        let last = Outbound::Binary(shared).into_message();
+        match (first, last) {
+            (Message::Binary(first), Message::Binary(last)) => {
+                assert_eq!(first, [1, 2, 3]);
+                assert_ne!(first.as_ptr(), ptr);
+                assert_eq!(last, [1, 2, 3]);
+                assert_eq!(last.as_ptr(), ptr);
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_broadcast_skips_full_queue() {
+        let metrics = Arc::new(Metrics::default());
+        let manager = SocketManager::new(
+            SendQueueSettings {
+                capacity: 5,
+                policy: SlowConsumerPolicy::DropNewest,
+                max_lag: 0,
+            },
+            metrics.clone(),
+        );
+        let (slow_id, _slow) = manager.add();
+        let (_, fast) = manager.add();
+
+        // 受信しないクライアントがいても他のクライアントへの配信は止まらない
+        for i in 0..8 {
+            manager.broadcast(format!("message {}", i));
+            if i < 5 {
+                match fast.recv().await {
+                    Some(Outbound::Text(bytes)) => assert_eq!(bytes, format!("message {}", i)),
+                    other => panic!("unexpected {:?}", other),
+                }
+            }
+        }
+
+        let slow_stats = manager
+            .queue_stats()
+            .into_iter()
+            .find(|stat| stat.id == slow_id)
+            .unwrap();
+        assert_eq!(slow_stats.depth, 5);
+        assert_eq!(slow_stats.missed_total, 3);
+        assert_eq!(metrics.messages_dropped.load(Ordering::Relaxed), 3);
+    }
+}
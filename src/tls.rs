@@ -0,0 +1,277 @@
+use axum::extract::ConnectInfo;
+use axum::{Extension, Router};
+use hyper::server::conn::http1;
+use hyper_util::rt::TokioIo;
+use hyper_util::service::TowerToHyperService;
+use log::{debug, info, warn};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{ring, verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use rustls::{ClientConfig, DigitallySignedStruct, RootCertStore, ServerConfig, SignatureScheme};
+use std::future::Future;
+use std::io::{self, BufReader};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::sync::watch;
+use tokio_rustls::TlsAcceptor;
+use tower::Layer;
+
+/// TLS のハンドシェイクを待つ時間
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// 証明書と秘密鍵のファイル (どちらも PEM)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TlsFiles {
+    /// サーバーの証明書。中間証明書を続けて書ける
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+/// 読み直せるサーバー証明書
+///
+/// ハンドシェイクのたびに今の証明書を渡すので、差し替えた後の接続から新しい証明書を使う。
+#[derive(Debug)]
+pub struct TlsCerts {
+    current: RwLock<Arc<CertifiedKey>>,
+}
+
+impl TlsCerts {
+    pub fn load(files: &TlsFiles) -> io::Result<Self> {
+        Ok(Self {
+            current: RwLock::new(Arc::new(certified_key(files)?)),
+        })
+    }
+
+    /// 読めなければ今の証明書を使い続ける
+    pub fn reload(&self, files: &TlsFiles) -> io::Result<()> {
+        self.replace(certified_key(files)?);
+        info!("loaded TLS certificate from {}", files.cert.display());
+        Ok(())
+    }
+
+    /// 次のハンドシェイクから `key` を使う
+    pub fn replace(&self, key: CertifiedKey) {
+        *self.current.write().unwrap() = Arc::new(key);
+    }
+}
+
+impl ResolvesServerCert for TlsCerts {
+    fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.read().unwrap().clone())
+    }
+}
+
+/// 暗号の実装は ring に揃える
+pub fn provider() -> Arc<CryptoProvider> {
+    Arc::new(ring::default_provider())
+}
+
+/// 証明書と鍵を読み、対になっているかを確かめる
+pub fn certified_key(files: &TlsFiles) -> io::Result<CertifiedKey> {
+    let invalid = |path: &Path, message: String| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{}: {}", path.display(), message),
+        )
+    };
+    let certs = read_certs(&files.cert)?;
+    if certs.is_empty() {
+        return Err(invalid(&files.cert, "no certificate found".to_string()));
+    }
+    let key = read_key(&files.key)?;
+    let key = provider()
+        .key_provider
+        .load_private_key(key)
+        .map_err(|e| invalid(&files.key, e.to_string()))?;
+    let certified = CertifiedKey::new(certs, key);
+    certified
+        .keys_match()
+        .map_err(|e| invalid(&files.key, e.to_string()))?;
+    Ok(certified)
+}
+
+fn read_certs(path: &Path) -> io::Result<Vec<CertificateDer<'static>>> {
+    let mut reader = BufReader::new(std::fs::File::open(path)?);
+    rustls_pemfile::certs(&mut reader)
+        .collect::<io::Result<_>>()
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))
+}
+
+fn read_key(path: &Path) -> io::Result<PrivateKeyDer<'static>> {
+    let mut reader = BufReader::new(std::fs::File::open(path)?);
+    rustls_pemfile::private_key(&mut reader)?.ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{}: no private key found", path.display()),
+        )
+    })
+}
+
+/// `certs` の証明書で HTTP/1.1 を受け付ける設定
+pub fn server_config(certs: Arc<TlsCerts>) -> io::Result<Arc<ServerConfig>> {
+    let mut config = ServerConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()
+        .map_err(io::Error::other)?
+        .with_no_client_auth()
+        .with_cert_resolver(certs);
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    Ok(Arc::new(config))
+}
+
+/// `axum::serve` の TLS 版。`signal` が終わると新しい接続を断り、処理中のリクエストを待つ
+///
+/// WebSocket に切り替えた接続は `AppState::shutdown` の側で閉じる。
+pub async fn serve<F>(
+    listener: TcpListener,
+    app: Router,
+    config: Arc<ServerConfig>,
+    signal: F,
+) -> io::Result<()>
+where
+    F: Future<Output = ()> + Send,
+{
+    let acceptor = TlsAcceptor::from(config);
+    let (stop_tx, stop_rx) = watch::channel(false);
+    // 全ての接続が受信側を捨てるまで待つ
+    let (close_tx, close_rx) = watch::channel(());
+    tokio::pin!(signal);
+
+    loop {
+        let (stream, addr) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    // ファイル記述子が足りない場合などは、少し待ってから続ける
+                    warn!("failed to accept: {}", e);
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                    continue;
+                }
+            },
+            _ = &mut signal => break,
+        };
+
+        let acceptor = acceptor.clone();
+        let service = TowerToHyperService::new(Extension(ConnectInfo(addr)).layer(app.clone()));
+        let mut stop = stop_rx.clone();
+        let close_rx = close_rx.clone();
+        tokio::spawn(async move {
+            let stream =
+                match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                    Ok(Ok(stream)) => stream,
+                    Ok(Err(e)) => {
+                        debug!("TLS handshake with {} failed: {}", addr, e);
+                        return;
+                    }
+                    Err(_) => {
+                        debug!("TLS handshake with {} timed out", addr);
+                        return;
+                    }
+                };
+            let conn = http1::Builder::new()
+                .serve_connection(TokioIo::new(stream), service)
+                .with_upgrades();
+            tokio::pin!(conn);
+            let result = tokio::select! {
+                result = conn.as_mut() => result,
+                _ = async { stop.wait_for(|stop| *stop).await.is_ok() } => {
+                    conn.as_mut().graceful_shutdown();
+                    conn.await
+                }
+            };
+            if let Err(e) = result {
+                debug!("connection from {} failed: {}", addr, e);
+            }
+            drop(close_rx);
+        });
+    }
+
+    drop(listener);
+    stop_tx.send_replace(true);
+    drop(close_rx);
+    close_tx.closed().await;
+    Ok(())
+}
+
+/// `wss://` と `https://` に使うクライアントの設定
+///
+/// `ca_file` を指定すると、組み込みのルート証明書の代わりにその中の証明書だけを信頼する。
+/// `insecure` ならサーバーの証明書を確かめない。
+pub fn client_config(ca_file: Option<&Path>, insecure: bool) -> io::Result<ClientConfig> {
+    let builder = ClientConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()
+        .map_err(io::Error::other)?;
+    if insecure {
+        return Ok(builder
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(NoVerification(provider())))
+            .with_no_client_auth());
+    }
+
+    let mut roots = RootCertStore::empty();
+    match ca_file {
+        Some(path) => {
+            let (added, _) = roots.add_parsable_certificates(read_certs(path)?);
+            if added == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{}: no certificate found", path.display()),
+                ));
+            }
+        }
+        None => roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+    }
+    Ok(builder.with_root_certificates(roots).with_no_client_auth())
+}
+
+/// `--insecure` の時に使う。署名だけは確かめる
+#[derive(Debug)]
+struct NoVerification(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for NoVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
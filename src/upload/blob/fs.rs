@@ -0,0 +1,118 @@
+use super::{BlobStore, BlobStream};
+use async_trait::async_trait;
+use bytes::Bytes;
+use std::io::{self, SeekFrom};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use tokio::fs::{self, File};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio_util::io::ReaderStream;
+use uuid::Uuid;
+
+/// 書き込み途中のファイルの接頭辞。キーは `.` で始まらない
+const TEMP_PREFIX: &str = ".tmp-";
+
+/// ディレクトリの中に blob を置く。キーの `/` はサブディレクトリになる
+///
+/// 一時ファイルに書き切ってから移すので、書き込み途中の blob が見えることはない。
+#[derive(Debug, Clone)]
+pub struct FsBlobStore {
+    root: PathBuf,
+}
+
+impl FsBlobStore {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// blob の実際の場所
+    pub fn path(&self, key: &str) -> PathBuf {
+        self.root.join(key)
+    }
+
+    fn temp_path(&self) -> PathBuf {
+        self.root.join(format!("{}{}", TEMP_PREFIX, Uuid::new_v4()))
+    }
+
+    /// 一時ファイルを `key` の場所に移す
+    async fn install(&self, key: &str, tmp_path: &Path) -> io::Result<()> {
+        let path = self.path(key);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        fs::rename(tmp_path, &path).await
+    }
+}
+
+#[async_trait]
+impl BlobStore for FsBlobStore {
+    async fn put(&self, key: &str, data: Bytes) -> io::Result<()> {
+        fs::create_dir_all(&self.root).await?;
+        let tmp_path = self.temp_path();
+        let result = async {
+            let mut f = File::create(&tmp_path).await?;
+            f.write_all(&data).await?;
+            f.sync_all().await?;
+            self.install(key, &tmp_path).await
+        }
+        .await;
+
+        if result.is_err() {
+            let _ = fs::remove_file(&tmp_path).await;
+        }
+        result
+    }
+
+    async fn put_file(&self, key: &str, path: &Path) -> io::Result<()> {
+        fs::create_dir_all(&self.root).await?;
+        // 同じファイルシステムなら移すだけで済む
+        if self.install(key, path).await.is_ok() {
+            return Ok(());
+        }
+
+        let tmp_path = self.temp_path();
+        let result = async {
+            fs::copy(path, &tmp_path).await?;
+            File::open(&tmp_path).await?.sync_all().await?;
+            self.install(key, &tmp_path).await
+        }
+        .await;
+
+        if result.is_err() {
+            let _ = fs::remove_file(&tmp_path).await;
+        }
+        result
+    }
+
+    async fn get(&self, key: &str, range: Option<Range<u64>>) -> io::Result<Option<BlobStream>> {
+        let mut f = match File::open(self.path(key)).await {
+            Ok(f) => f,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let stream: BlobStream = match range {
+            Some(range) => {
+                f.seek(SeekFrom::Start(range.start)).await?;
+                let len = range.end.saturating_sub(range.start);
+                Box::pin(ReaderStream::new(f.take(len)))
+            }
+            None => Box::pin(ReaderStream::new(f)),
+        };
+        Ok(Some(stream))
+    }
+
+    async fn exists(&self, key: &str) -> io::Result<bool> {
+        fs::try_exists(self.path(key)).await
+    }
+
+    async fn delete(&self, key: &str) -> io::Result<()> {
+        match fs::remove_file(self.path(key)).await {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,61 @@
+use super::{BlobStore, BlobStream};
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures_util::stream;
+use std::collections::HashMap;
+use std::io;
+use std::ops::Range;
+use std::path::Path;
+use std::sync::Mutex;
+
+/// メモリ上の blob。テストや一時的なサーバー向け
+#[derive(Debug, Default)]
+pub struct MemoryBlobStore {
+    blobs: Mutex<HashMap<String, Bytes>>,
+}
+
+impl MemoryBlobStore {
+    pub fn len(&self) -> usize {
+        self.blobs.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[async_trait]
+impl BlobStore for MemoryBlobStore {
+    async fn put(&self, key: &str, data: Bytes) -> io::Result<()> {
+        self.blobs.lock().unwrap().insert(key.to_string(), data);
+        Ok(())
+    }
+
+    async fn put_file(&self, key: &str, path: &Path) -> io::Result<()> {
+        let data = tokio::fs::read(path).await?;
+        self.put(key, Bytes::from(data)).await
+    }
+
+    async fn get(&self, key: &str, range: Option<Range<u64>>) -> io::Result<Option<BlobStream>> {
+        let Some(data) = self.blobs.lock().unwrap().get(key).cloned() else {
+            return Ok(None);
+        };
+        let data = match range {
+            Some(range) => {
+                let len = data.len() as u64;
+                data.slice(range.start.min(len) as usize..range.end.min(len) as usize)
+            }
+            None => data,
+        };
+        Ok(Some(Box::pin(stream::once(async { Ok(data) }))))
+    }
+
+    async fn exists(&self, key: &str) -> io::Result<bool> {
+        Ok(self.blobs.lock().unwrap().contains_key(key))
+    }
+
+    async fn delete(&self, key: &str) -> io::Result<()> {
+        self.blobs.lock().unwrap().remove(key);
+        Ok(())
+    }
+}
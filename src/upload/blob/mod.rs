@@ -0,0 +1,87 @@
+mod fs;
+mod memory;
+mod s3;
+
+pub use fs::FsBlobStore;
+pub use memory::MemoryBlobStore;
+pub use s3::{S3BlobStore, S3Config};
+
+use async_trait::async_trait;
+use bytes::{Bytes, BytesMut};
+use futures_util::stream::{Stream, StreamExt};
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::ops::Range;
+use std::path::Path;
+use std::pin::Pin;
+use std::str::FromStr;
+
+/// blob の中身。先頭から順に読む
+pub type BlobStream = Pin<Box<dyn Stream<Item = io::Result<Bytes>> + Send>>;
+
+/// blob の保存先の種類
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum BlobBackend {
+    /// アップロード先のディレクトリの中
+    Fs,
+    /// メモリ上のみ (再起動で消える)
+    Memory,
+    /// S3 互換のオブジェクトストレージ
+    S3,
+}
+
+impl FromStr for BlobBackend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fs" => Ok(BlobBackend::Fs),
+            "memory" => Ok(BlobBackend::Memory),
+            "s3" => Ok(BlobBackend::S3),
+            _ => Err(format!("unknown blob backend `{}` (fs, memory, s3)", s)),
+        }
+    }
+}
+
+impl Display for BlobBackend {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BlobBackend::Fs => write!(f, "fs"),
+            BlobBackend::Memory => write!(f, "memory"),
+            BlobBackend::S3 => write!(f, "s3"),
+        }
+    }
+}
+
+/// アップロードされたファイルの中身の保存先
+///
+/// キーは `UploadStore` が中身の SHA-256 から作る。同じキーには常に同じ中身が入るので、
+/// 実装は上書きを気にしなくてよい。
+#[async_trait]
+pub trait BlobStore: Send + Sync {
+    /// `data` を `key` として保存する
+    async fn put(&self, key: &str, data: Bytes) -> io::Result<()>;
+
+    /// ファイルの中身を `key` として保存する。成功した後の `path` は残っていなくてもよい
+    async fn put_file(&self, key: &str, path: &Path) -> io::Result<()>;
+
+    /// `range` (終端を含まない) の部分を読む。`None` なら全体。キーが無ければ `None`
+    async fn get(&self, key: &str, range: Option<Range<u64>>) -> io::Result<Option<BlobStream>>;
+
+    async fn exists(&self, key: &str) -> io::Result<bool>;
+
+    /// 無いキーを消してもエラーにしない
+    async fn delete(&self, key: &str) -> io::Result<()>;
+}
+
+/// 全体をメモリに読み込む
+pub async fn read_all(store: &dyn BlobStore, key: &str) -> io::Result<Option<Bytes>> {
+    let Some(mut stream) = store.get(key, None).await? else {
+        return Ok(None);
+    };
+    let mut buf = BytesMut::new();
+    while let Some(chunk) = stream.next().await {
+        buf.extend_from_slice(&chunk?);
+    }
+    Ok(Some(buf.freeze()))
+}
@@ -0,0 +1,598 @@
+use super::quota::{QuotaError, QuotaLedger, Reservation};
+use super::scan::Rejection;
+use super::{sanitize_filename, FileRecord, FilenameError, UploadError, UploadStore};
+use crate::utils::to_hex;
+use hmac::{Hmac, Mac};
+use log::{info, warn};
+use message_pack::{UploadBeginMessage, UploadChunkMessage, UploadId, UploadProgressMessage};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::io::{self, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use tokio::fs::{self, OpenOptions};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio::sync::{Mutex, OnceCell};
+use uuid::Uuid;
+
+/// 受信途中のファイルを置くディレクトリの名前。アップロード先のディレクトリの中に作る
+pub const PARTIAL_DIRNAME: &str = ".partial";
+
+/// これだけの間チャンクが届かなかった分割アップロードは捨てる
+pub const DEFAULT_PARTIAL_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// セッションの鍵を作る秘密の値を置くファイルの名前。受信途中のファイルと同じディレクトリに置く
+const SECRET_FILENAME: &str = "secret";
+
+/// 進み具合を知らせる間隔の既定値
+pub const DEFAULT_PROGRESS_INTERVAL: Duration = Duration::from_millis(500);
+
+#[derive(Debug)]
+pub enum ChunkError {
+    InvalidFilename(FilenameError),
+    Quota(QuotaError),
+    /// 開始されていない、または既に完了した ID
+    UnknownUpload,
+    OffsetMismatch {
+        expected: u64,
+        actual: u64,
+    },
+    ChecksumMismatch(String),
+    /// 開始時の宣言と矛盾する
+    InvalidUpload(String),
+    /// 設定で受け付けない種類。検出した MIME タイプを持つ
+    FileTypeRejected(String),
+    /// 検査で断られた
+    Rejected(Rejection),
+    Io(io::Error),
+}
+
+impl Display for ChunkError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ChunkError::InvalidFilename(e) => write!(f, "invalid filename: {}", e),
+            ChunkError::Quota(e) => write!(f, "{}", e),
+            ChunkError::UnknownUpload => write!(f, "unknown upload id"),
+            ChunkError::OffsetMismatch { expected, actual } => write!(
+                f,
+                "chunk offset {} does not match, expected offset {}",
+                actual, expected
+            ),
+            ChunkError::ChecksumMismatch(what) => write!(f, "checksum mismatch: {}", what),
+            ChunkError::InvalidUpload(reason) => write!(f, "invalid upload: {}", reason),
+            ChunkError::FileTypeRejected(content_type) => {
+                write!(f, "files of type {} are not accepted", content_type)
+            }
+            ChunkError::Rejected(rejection) => write!(f, "{}", rejection),
+            ChunkError::Io(e) => write!(f, "failed to store upload: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ChunkError {}
+
+impl From<FilenameError> for ChunkError {
+    fn from(e: FilenameError) -> Self {
+        ChunkError::InvalidFilename(e)
+    }
+}
+
+impl From<QuotaError> for ChunkError {
+    fn from(e: QuotaError) -> Self {
+        ChunkError::Quota(e)
+    }
+}
+
+impl From<io::Error> for ChunkError {
+    fn from(e: io::Error) -> Self {
+        ChunkError::Io(e)
+    }
+}
+
+impl From<UploadError> for ChunkError {
+    fn from(e: UploadError) -> Self {
+        match e {
+            UploadError::InvalidFilename(e) => ChunkError::InvalidFilename(e),
+            UploadError::FileTypeRejected(content_type) => {
+                ChunkError::FileTypeRejected(content_type)
+            }
+            UploadError::Rejected(rejection) => ChunkError::Rejected(rejection),
+            UploadError::Io(e) => ChunkError::Io(e),
+        }
+    }
+}
+
+/// 開始時に宣言された内容。再起動後に再開できるようにファイルにも保存する
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+struct UploadMeta {
+    sender: String,
+    room: i32,
+    /// 正規化済みのファイル名
+    filename: String,
+    size: u64,
+    sha256: String,
+}
+
+impl UploadMeta {
+    fn from_begin(begin: &UploadBeginMessage, filename: String) -> Self {
+        Self {
+            sender: begin.sender.clone(),
+            room: begin.room,
+            filename,
+            size: begin.size,
+            sha256: to_hex(&begin.sha256),
+        }
+    }
+}
+
+struct Session {
+    /// クライアントが付けた ID。受信確認などで返す
+    upload_id: UploadId,
+    /// 最後に begin した接続。チャンクと commit はこの接続からしか受け付けない
+    owner: Uuid,
+    meta: UploadMeta,
+    /// 受信済みの大きさ。次のチャンクはここから始まる
+    offset: u64,
+    reservation: Option<Reservation>,
+    touched: Instant,
+    /// 最後に進み具合を知らせた時刻
+    reported: Instant,
+}
+
+/// サーバーの中でセッションを区別する鍵。ファイル名にも使う
+type SessionKey = [u8; 16];
+
+#[derive(Default)]
+struct Sessions {
+    by_key: HashMap<SessionKey, Arc<Mutex<Session>>>,
+    /// 接続と、その接続が begin したクライアントの ID から鍵を引く
+    owned: HashMap<(Uuid, UploadId), SessionKey>,
+}
+
+/// begin / chunk / commit で送られてくるファイルの受信途中の状態
+///
+/// チャンクは `<upload_dir>/.partial/<鍵>.part` に追記し、commit で SHA-256 を
+/// 確かめてから `UploadStore` にリンクする。接続が切れても、サーバーが再起動しても、
+/// 同じ ID で begin を送り直せば受信済みの位置から再開できる。
+///
+/// クライアントの ID は推測できるので、鍵はサーバーだけが知る秘密の値と送信者の名前を
+/// 混ぜて作る。チャンクと commit は最後に begin した接続からしか受け付けないので、
+/// 他のクライアントが途中のアップロードに書き込んだり、完了させたりはできない。
+pub struct ChunkedUploads {
+    dir: PathBuf,
+    store: UploadStore,
+    quota: Arc<QuotaLedger>,
+    ttl: Duration,
+    progress_interval: Duration,
+    secret: OnceCell<[u8; 32]>,
+    sessions: Mutex<Sessions>,
+}
+
+impl ChunkedUploads {
+    pub fn new(store: UploadStore, quota: Arc<QuotaLedger>) -> Self {
+        Self {
+            dir: store.dir().join(PARTIAL_DIRNAME),
+            store,
+            quota,
+            ttl: DEFAULT_PARTIAL_TTL,
+            progress_interval: DEFAULT_PROGRESS_INTERVAL,
+            secret: OnceCell::new(),
+            sessions: Mutex::new(Sessions::default()),
+        }
+    }
+
+    /// 進み具合を知らせる間隔
+    pub fn progress_interval(mut self, interval: Duration) -> Self {
+        self.progress_interval = interval;
+        self
+    }
+
+    /// `owner` の接続で分割アップロードを始める。既にある ID なら受信済みのオフセットを返す
+    ///
+    /// 同じ送信者が同じ ID で begin し直すと、以降はその接続からチャンクを受け付ける。
+    pub async fn begin(&self, owner: Uuid, begin: &UploadBeginMessage) -> Result<u64, ChunkError> {
+        let filename = sanitize_filename(&begin.filename)?;
+        let meta = UploadMeta::from_begin(begin, filename);
+        let key = self.key(&meta.sender, &begin.upload_id).await?;
+
+        self.purge_stale().await;
+
+        let mut sessions = self.sessions.lock().await;
+        let session = match sessions.by_key.get(&key) {
+            Some(session) => session.clone(),
+            None => {
+                let session = match self.load(&key, &begin.upload_id, owner).await? {
+                    Some(session) => session,
+                    None => {
+                        // 新しいアップロードは何も書く前に容量を確保する
+                        let reservation = self.quota.reserve(&meta.sender, meta.room, meta.size)?;
+                        match self.create(&key, &begin.upload_id, owner, &meta).await {
+                            Ok(session) => Session {
+                                reservation: Some(reservation),
+                                ..session
+                            },
+                            Err(e) => {
+                                self.quota.release(reservation);
+                                return Err(e);
+                            }
+                        }
+                    }
+                };
+                let session = Arc::new(Mutex::new(session));
+                sessions.by_key.insert(key, session.clone());
+                session
+            }
+        };
+        drop(sessions);
+
+        let mut session = session.lock().await;
+        if session.meta != meta {
+            return Err(ChunkError::InvalidUpload(
+                "upload id is already used for a different file".to_string(),
+            ));
+        }
+        // 前の接続からはもう受け付けない
+        {
+            let mut sessions = self.sessions.lock().await;
+            sessions.owned.remove(&(session.owner, session.upload_id));
+            sessions.owned.insert((owner, begin.upload_id), key);
+        }
+        session.owner = owner;
+
+        // 再起動後の再開では容量をもう一度確保する
+        if session.reservation.is_none() {
+            session.reservation = Some(self.quota.reserve(&meta.sender, meta.room, meta.size)?);
+        }
+        session.touched = Instant::now();
+
+        if session.offset > 0 {
+            info!(
+                "resuming upload {} at {} / {}",
+                to_hex(&begin.upload_id),
+                session.offset,
+                meta.size
+            );
+        }
+        Ok(session.offset)
+    }
+
+    /// `owner` の接続が送ったチャンクを追記し、新しいオフセットを返す
+    pub async fn write_chunk(
+        &self,
+        owner: Uuid,
+        chunk: &UploadChunkMessage,
+    ) -> Result<u64, ChunkError> {
+        let (key, session) = self.session(owner, &chunk.upload_id).await?;
+        let mut session = session.lock().await;
+        if session.owner != owner {
+            return Err(ChunkError::UnknownUpload);
+        }
+
+        if !chunk.verify() {
+            return Err(ChunkError::ChecksumMismatch(format!(
+                "chunk at offset {}",
+                chunk.offset
+            )));
+        }
+        if chunk.offset != session.offset {
+            return Err(ChunkError::OffsetMismatch {
+                expected: session.offset,
+                actual: chunk.offset,
+            });
+        }
+        let end = session.offset + chunk.data.len() as u64;
+        if end > session.meta.size {
+            return Err(ChunkError::InvalidUpload(format!(
+                "chunk ends at {} but the file is {} bytes",
+                end, session.meta.size
+            )));
+        }
+        // 受け付けない種類なら残りを送らせない
+        if chunk.offset == 0 {
+            if let Err(e) = self.store.check_type(&chunk.data) {
+                self.discard(&key, &mut session).await;
+                return Err(e.into());
+            }
+        }
+
+        // 前回の書き込みが途中で失敗していても、受信済みの位置から上書きする
+        let mut f = OpenOptions::new()
+            .write(true)
+            .open(self.data_path(&key))
+            .await?;
+        f.seek(SeekFrom::Start(chunk.offset)).await?;
+        f.write_all(&chunk.data).await?;
+        f.flush().await?;
+
+        session.offset = end;
+        session.touched = Instant::now();
+        Ok(end)
+    }
+
+    /// 前回知らせてから `progress_interval` 以上経っていれば進み具合を返す
+    ///
+    /// 最後のチャンクを受け取った後は間隔に関わらず返す。
+    pub async fn progress(
+        &self,
+        owner: Uuid,
+        upload_id: &UploadId,
+    ) -> Option<UploadProgressMessage> {
+        let (_, session) = self.session(owner, upload_id).await.ok()?;
+        let mut session = session.lock().await;
+
+        let done = session.offset == session.meta.size;
+        if !done && session.reported.elapsed() < self.progress_interval {
+            return None;
+        }
+        session.reported = Instant::now();
+        Some(UploadProgressMessage {
+            upload_id: *upload_id,
+            received: session.offset,
+            total: session.meta.size,
+        })
+    }
+
+    /// `owner` の接続が始めたアップロードの大きさと SHA-256 を確かめて保存する
+    pub async fn commit(
+        &self,
+        owner: Uuid,
+        upload_id: &UploadId,
+    ) -> Result<FileRecord, ChunkError> {
+        let (key, session) = self.session(owner, upload_id).await?;
+        let mut session = session.lock().await;
+        if session.owner != owner {
+            return Err(ChunkError::UnknownUpload);
+        }
+
+        if session.offset != session.meta.size {
+            return Err(ChunkError::InvalidUpload(format!(
+                "only {} of {} bytes received",
+                session.offset, session.meta.size
+            )));
+        }
+
+        let data_path = self.data_path(&key);
+        let digest = sha256_file(&data_path).await?;
+        if digest != session.meta.sha256 {
+            // 受信済みの内容が壊れているので、最初からやり直してもらう
+            self.discard(&key, &mut session).await;
+            return Err(ChunkError::ChecksumMismatch(format!(
+                "sha256 of {} is {}",
+                session.meta.filename, digest
+            )));
+        }
+
+        fs::File::open(&data_path).await?.sync_all().await?;
+        let meta = &session.meta;
+        let saved = self
+            .store
+            .save_file(
+                &meta.filename,
+                &data_path,
+                &meta.sha256,
+                &meta.sender,
+                meta.room,
+            )
+            .await;
+        // 受信済みのファイルは保存に失敗しても残らないので、やり直してもらう
+        let record = match saved {
+            Ok(record) => record,
+            Err(e) => {
+                self.discard(&key, &mut session).await;
+                return Err(e.into());
+            }
+        };
+        if let Some(reservation) = session.reservation.take() {
+            self.quota.commit(reservation, record.size).await;
+        }
+        self.discard(&key, &mut session).await;
+
+        Ok(record)
+    }
+
+    /// `owner` の接続が begin したセッション。他の接続のものは見つからないのと同じに扱う
+    async fn session(
+        &self,
+        owner: Uuid,
+        upload_id: &UploadId,
+    ) -> Result<(SessionKey, Arc<Mutex<Session>>), ChunkError> {
+        let sessions = self.sessions.lock().await;
+        let key = *sessions
+            .owned
+            .get(&(owner, *upload_id))
+            .ok_or(ChunkError::UnknownUpload)?;
+        let session = sessions
+            .by_key
+            .get(&key)
+            .cloned()
+            .ok_or(ChunkError::UnknownUpload)?;
+        Ok((key, session))
+    }
+
+    /// 送信者ごとに、クライアントの ID とサーバーの秘密の値から鍵を作る
+    async fn key(&self, sender: &str, upload_id: &UploadId) -> io::Result<SessionKey> {
+        let secret = self.secret.get_or_try_init(|| self.load_secret()).await?;
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC accepts any key length");
+        mac.update(sender.as_bytes());
+        mac.update(&[0]);
+        mac.update(upload_id);
+        let digest = mac.finalize().into_bytes();
+        let mut key = SessionKey::default();
+        key.copy_from_slice(&digest[..16]);
+        Ok(key)
+    }
+
+    /// 再起動しても再開できるように、秘密の値はファイルに残す
+    async fn load_secret(&self) -> io::Result<[u8; 32]> {
+        let path = self.dir.join(SECRET_FILENAME);
+        match fs::read(&path).await {
+            Ok(bytes) if bytes.len() == 32 => {
+                let mut secret = [0; 32];
+                secret.copy_from_slice(&bytes);
+                return Ok(secret);
+            }
+            Ok(_) => warn!("{} is broken, generating a new one", path.display()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+
+        let mut secret = [0; 32];
+        secret[..16].copy_from_slice(Uuid::new_v4().as_bytes());
+        secret[16..].copy_from_slice(Uuid::new_v4().as_bytes());
+        fs::create_dir_all(&self.dir).await?;
+        let tmp_path = path.with_extension("tmp");
+        fs::write(&tmp_path, secret).await?;
+        fs::rename(&tmp_path, &path).await?;
+        Ok(secret)
+    }
+
+    async fn create(
+        &self,
+        key: &SessionKey,
+        upload_id: &UploadId,
+        owner: Uuid,
+        meta: &UploadMeta,
+    ) -> Result<Session, ChunkError> {
+        fs::create_dir_all(&self.dir).await?;
+        fs::File::create(self.data_path(key)).await?;
+
+        let meta_path = self.meta_path(key);
+        let tmp_path = meta_path.with_extension("json.tmp");
+        fs::write(
+            &tmp_path,
+            serde_json::to_vec(meta).map_err(io::Error::from)?,
+        )
+        .await?;
+        fs::rename(&tmp_path, &meta_path).await?;
+
+        Ok(Session {
+            upload_id: *upload_id,
+            owner,
+            meta: meta.clone(),
+            offset: 0,
+            reservation: None,
+            touched: Instant::now(),
+            reported: Instant::now(),
+        })
+    }
+
+    /// 再起動前に始まった分割アップロードを読み込む
+    async fn load(
+        &self,
+        key: &SessionKey,
+        upload_id: &UploadId,
+        owner: Uuid,
+    ) -> Result<Option<Session>, ChunkError> {
+        let meta = match fs::read(self.meta_path(key)).await {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let Ok(meta) = serde_json::from_slice::<UploadMeta>(&meta) else {
+            warn!("broken upload metadata for {}", to_hex(key));
+            return Ok(None);
+        };
+        let offset = match fs::metadata(self.data_path(key)).await {
+            Ok(metadata) => metadata.len().min(meta.size),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+
+        Ok(Some(Session {
+            upload_id: *upload_id,
+            owner,
+            meta,
+            offset,
+            reservation: None,
+            touched: Instant::now(),
+            reported: Instant::now(),
+        }))
+    }
+
+    /// 受信途中のファイルと確保した容量を捨てる
+    async fn discard(&self, key: &SessionKey, session: &mut Session) {
+        if let Some(reservation) = session.reservation.take() {
+            self.quota.release(reservation);
+        }
+        let _ = fs::remove_file(self.data_path(key)).await;
+        let _ = fs::remove_file(self.meta_path(key)).await;
+        let mut sessions = self.sessions.lock().await;
+        sessions.by_key.remove(key);
+        sessions.owned.remove(&(session.owner, session.upload_id));
+    }
+
+    /// 長い間チャンクが届かない分割アップロードを捨てる
+    async fn purge_stale(&self) {
+        let stale: Vec<(SessionKey, Arc<Mutex<Session>>)> = {
+            let sessions = self.sessions.lock().await;
+            let mut stale = Vec::new();
+            for (key, session) in sessions.by_key.iter() {
+                // 使用中のセッションは対象外
+                if let Ok(guard) = session.try_lock() {
+                    if guard.touched.elapsed() > self.ttl {
+                        stale.push((*key, session.clone()));
+                    }
+                }
+            }
+            stale
+        };
+        for (key, session) in stale {
+            info!("discarding stale upload {}", to_hex(&key));
+            self.discard(&key, &mut *session.lock().await).await;
+        }
+
+        // 再起動前から残っているもの
+        let Ok(mut entries) = fs::read_dir(&self.dir).await else {
+            return;
+        };
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            let path = entry.path();
+            if entry.file_name() == SECRET_FILENAME {
+                continue;
+            }
+            let Ok(modified) = entry.metadata().await.and_then(|m| m.modified()) else {
+                continue;
+            };
+            let expired = SystemTime::now()
+                .duration_since(modified)
+                .is_ok_and(|age| age > self.ttl);
+            if expired && !self.is_active(&path).await {
+                let _ = fs::remove_file(&path).await;
+            }
+        }
+    }
+
+    async fn is_active(&self, path: &Path) -> bool {
+        let sessions = self.sessions.lock().await;
+        sessions.by_key.keys().any(|key| {
+            let hex = to_hex(key);
+            path.file_stem()
+                .is_some_and(|stem| stem.to_string_lossy().starts_with(&hex))
+        })
+    }
+
+    fn data_path(&self, key: &SessionKey) -> PathBuf {
+        self.dir.join(format!("{}.part", to_hex(key)))
+    }
+
+    fn meta_path(&self, key: &SessionKey) -> PathBuf {
+        self.dir.join(format!("{}.json", to_hex(key)))
+    }
+}
+
+async fn sha256_file(path: &Path) -> io::Result<String> {
+    let mut f = fs::File::open(path).await?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        let n = f.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    Ok(to_hex(&hasher.finalize()))
+}
@@ -0,0 +1,214 @@
+use std::fmt::{Display, Formatter};
+
+/// 保存するファイル名の最大長 (バイト)
+pub const MAX_FILENAME_BYTES: usize = 255;
+
+/// Windows で予約されているデバイス名
+const RESERVED_NAMES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum FilenameError {
+    Empty,
+    Absolute,
+    ParentDirectory,
+    ControlCharacter,
+}
+
+impl Display for FilenameError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FilenameError::Empty => write!(f, "filename is empty"),
+            FilenameError::Absolute => write!(f, "absolute paths are not allowed"),
+            FilenameError::ParentDirectory => write!(f, "`..` is not allowed in filenames"),
+            FilenameError::ControlCharacter => {
+                write!(f, "control characters are not allowed in filenames")
+            }
+        }
+    }
+}
+
+impl std::error::Error for FilenameError {}
+
+/// クライアントから受け取ったファイル名を、アップロード先のディレクトリに置ける名前にする
+///
+/// 絶対パス、`..`、制御文字を含むものは拒否する。ディレクトリ部分は捨て、
+/// Windows で使えない文字は `_` に置き換える。
+pub fn sanitize_filename(raw: &str) -> Result<String, FilenameError> {
+    let raw = raw.trim();
+    if raw.is_empty() {
+        return Err(FilenameError::Empty);
+    }
+    if raw.chars().any(char::is_control) {
+        return Err(FilenameError::ControlCharacter);
+    }
+    if is_absolute(raw) {
+        return Err(FilenameError::Absolute);
+    }
+
+    let components: Vec<&str> = raw
+        .split(['/', '\\'])
+        .filter(|component| !component.is_empty() && *component != ".")
+        .collect();
+    if components.contains(&"..") {
+        return Err(FilenameError::ParentDirectory);
+    }
+
+    let Some(basename) = components.last() else {
+        return Err(FilenameError::Empty);
+    };
+
+    let mut name: String = basename
+        .chars()
+        .map(|c| match c {
+            '<' | '>' | ':' | '"' | '|' | '?' | '*' => '_',
+            c => c,
+        })
+        .collect();
+
+    // Windows では末尾のドットや空白が消えてしまう
+    name = name.trim_end_matches(['.', ' ']).to_string();
+    if name.is_empty() {
+        return Err(FilenameError::Empty);
+    }
+
+    // 隠しファイルや、サーバーが内部で使うファイルと紛れないようにする
+    if name.starts_with('.') {
+        name.insert(0, '_');
+    }
+
+    let stem = name.split('.').next().unwrap_or_default();
+    if RESERVED_NAMES
+        .iter()
+        .any(|reserved| stem.eq_ignore_ascii_case(reserved))
+    {
+        name.insert(0, '_');
+    }
+
+    Ok(truncate(&name, MAX_FILENAME_BYTES))
+}
+
+fn is_absolute(raw: &str) -> bool {
+    let bytes = raw.as_bytes();
+    raw.starts_with(['/', '\\'])
+        // C:foo や C:\foo
+        || (bytes.len() >= 2 && bytes[0].is_ascii_alphabetic() && bytes[1] == b':')
+}
+
+/// 拡張子を残したまま `max` バイトに収める
+fn truncate(name: &str, max: usize) -> String {
+    if name.len() <= max {
+        return name.to_string();
+    }
+
+    let (stem, extension) = split_extension(name);
+    let extension = if extension.len() + 1 < max / 2 {
+        extension
+    } else {
+        ""
+    };
+    let budget = max
+        - if extension.is_empty() {
+            0
+        } else {
+            extension.len() + 1
+        };
+
+    let mut end = budget.min(stem.len());
+    while !stem.is_char_boundary(end) {
+        end -= 1;
+    }
+
+    if extension.is_empty() {
+        stem[..end].to_string()
+    } else {
+        format!("{}.{}", &stem[..end], extension)
+    }
+}
+
+/// `report.tar.gz` -> (`report.tar`, `gz`)
+pub fn split_extension(name: &str) -> (&str, &str) {
+    match name.rfind('.') {
+        Some(i) if i > 0 => (&name[..i], &name[i + 1..]),
+        _ => (name, ""),
+    }
+}
+
+/// 衝突した場合の候補。`report.txt` -> `report (1).txt`
+pub fn numbered(name: &str, n: u32) -> String {
+    let (stem, extension) = split_extension(name);
+    let numbered = if extension.is_empty() {
+        format!("{} ({})", stem, n)
+    } else {
+        format!("{} ({}).{}", stem, n, extension)
+    };
+    truncate(&numbered, MAX_FILENAME_BYTES)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rejects_hostile_filenames() {
+        let cases = [
+            ("", FilenameError::Empty),
+            ("   ", FilenameError::Empty),
+            ("/etc/passwd", FilenameError::Absolute),
+            ("\\\\server\\share\\x", FilenameError::Absolute),
+            ("C:\\Windows\\win.ini", FilenameError::Absolute),
+            ("c:evil", FilenameError::Absolute),
+            ("../../etc/cron.d/x", FilenameError::ParentDirectory),
+            ("uploads/../../x", FilenameError::ParentDirectory),
+            ("..\\..\\x", FilenameError::ParentDirectory),
+            ("..", FilenameError::ParentDirectory),
+            ("evil\n.txt", FilenameError::ControlCharacter),
+            ("nul\0byte", FilenameError::ControlCharacter),
+            ("bell\u{7}", FilenameError::ControlCharacter),
+            ("./", FilenameError::Empty),
+            ("...", FilenameError::Empty),
+        ];
+
+        for (raw, expected) in cases {
+            assert_eq!(sanitize_filename(raw), Err(expected), "{:?}", raw);
+        }
+    }
+
+    #[test]
+    fn test_normalises_filenames() {
+        let cases = [
+            ("report.txt", "report.txt"),
+            ("  spaced.txt  ", "spaced.txt"),
+            ("./nested/dir/file.rs", "file.rs"),
+            ("what?.txt", "what_.txt"),
+            ("a<b>c:d|e*f\".txt", "a_b_c_d_e_f_.txt"),
+            ("trailing. . ", "trailing"),
+            (".bashrc", "_.bashrc"),
+            ("CON", "_CON"),
+            ("con.txt", "_con.txt"),
+            ("console.txt", "console.txt"),
+            ("日本語のファイル.md", "日本語のファイル.md"),
+        ];
+
+        for (raw, expected) in cases {
+            assert_eq!(sanitize_filename(raw).as_deref(), Ok(expected), "{:?}", raw);
+        }
+    }
+
+    #[test]
+    fn test_long_names_keep_extension() {
+        let long = format!("{}.txt", "あ".repeat(200));
+        let name = sanitize_filename(&long).unwrap();
+        assert!(name.len() <= MAX_FILENAME_BYTES);
+        assert!(name.ends_with(".txt"));
+    }
+
+    #[test]
+    fn test_numbered() {
+        assert_eq!(numbered("report.txt", 1), "report (1).txt");
+        assert_eq!(numbered("archive.tar.gz", 2), "archive.tar (2).gz");
+        assert_eq!(numbered("README", 3), "README (3)");
+    }
+}
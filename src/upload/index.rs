@@ -0,0 +1,278 @@
+use crate::history::now_millis;
+use log::{error, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+
+/// 共有されたファイルの一覧を記録するファイルの名前。アップロード先のディレクトリに置く
+pub const INDEX_FILENAME: &str = ".files.json";
+
+/// 共有されたファイル。中身は SHA-256 で名前を付けた blob として保存する
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct FileRecord {
+    /// ダウンロードに使う ID
+    pub id: String,
+    /// 表示とダウンロードに使う名前。同じ名前のファイルがいくつあってもよい
+    pub name: String,
+    /// 中身の SHA-256。以前の形式の一覧では空
+    #[serde(default)]
+    pub sha256: String,
+    pub size: u64,
+    /// 中身の先頭から調べた種類。分からなければ名前から推測したもの
+    pub content_type: String,
+    /// 縮小版があるか
+    #[serde(default)]
+    pub thumbnail: bool,
+    pub sender: String,
+    /// 一覧ができる前から置かれていたファイルは `None`
+    pub room: Option<i32>,
+    /// UNIX エポックからのミリ秒
+    pub uploaded_at: u64,
+}
+
+impl FileRecord {
+    pub fn new(name: &str, sha256: &str, size: u64, sender: &str, room: Option<i32>) -> Self {
+        Self {
+            // 入力しやすいように短くする
+            id: Uuid::new_v4().simple().to_string()[..12].to_string(),
+            name: name.to_string(),
+            sha256: sha256.to_string(),
+            size,
+            content_type: mime_guess::from_path(name)
+                .first_or_octet_stream()
+                .to_string(),
+            thumbnail: false,
+            sender: sender.to_string(),
+            room,
+            uploaded_at: now_millis(),
+        }
+    }
+}
+
+/// ファイルの一覧と、blob ごとの参照数
+///
+/// 参照数は一覧から数え直せるので保存しない。ロックは `UploadStore` が持つ。
+#[derive(Debug, Default)]
+pub struct FileIndex {
+    path: PathBuf,
+    records: Vec<FileRecord>,
+    refs: HashMap<String, usize>,
+    /// 読めなかった一覧を退避できなかった場合は、上書きしないように保存しない
+    read_only: bool,
+    /// 一覧を変えるたびに増やす。古い一覧で新しいものを上書きしないために使う
+    version: u64,
+}
+
+/// 保存する一覧の写し。ロックを放してから、ブロックしてよいスレッドで `write` する
+pub struct IndexSnapshot {
+    path: PathBuf,
+    records: Vec<FileRecord>,
+    read_only: bool,
+    version: u64,
+}
+
+impl IndexSnapshot {
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    /// 一時ファイルに書いてから置き換える
+    pub fn write(&self) -> io::Result<()> {
+        if self.read_only {
+            warn!("not saving {}: it could not be read", self.path.display());
+            return Ok(());
+        }
+        let tmp_path = self.path.with_extension("json.tmp");
+        fs::write(&tmp_path, serde_json::to_vec_pretty(&self.records)?)?;
+        fs::rename(&tmp_path, &self.path)
+    }
+}
+
+/// 一覧を読む。無ければ空
+fn read_records(path: &Path) -> io::Result<Vec<FileRecord>> {
+    let bytes = match fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => {
+            return Err(io::Error::new(
+                e.kind(),
+                format!("{}: {}", path.display(), e),
+            ))
+        }
+    };
+    serde_json::from_slice(&bytes).map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{}: {}", path.display(), e),
+        )
+    })
+}
+
+impl FileIndex {
+    /// `dir` の一覧が読めるか確かめる。サーバーは読めなければ起動しない
+    pub fn check(dir: &Path) -> io::Result<()> {
+        Self::read(dir).map(|_| ())
+    }
+
+    /// `dir` の一覧を読むだけで、読めなくても退避しない
+    pub(crate) fn read(dir: &Path) -> io::Result<Vec<FileRecord>> {
+        read_records(&dir.join(INDEX_FILENAME))
+    }
+
+    /// `dir` の一覧を読み込む
+    ///
+    /// 読めなければ空から始める。その場合、元の一覧は `.files.json.broken-<ミリ秒>` に
+    /// 退避し、次の保存で上書きしないようにする。退避もできなければ保存しない。
+    pub fn load(dir: &Path) -> Self {
+        let path = dir.join(INDEX_FILENAME);
+        let mut read_only = false;
+        let records = read_records(&path).unwrap_or_else(|e| {
+            let aside = path.with_extension(format!("json.broken-{}", now_millis()));
+            match fs::rename(&path, &aside) {
+                Ok(()) => error!(
+                    "cannot read file index, moved it to {} and starting empty: {}",
+                    aside.display(),
+                    e
+                ),
+                Err(rename_error) => {
+                    error!(
+                        "cannot read file index, not saving changes to it: {} ({})",
+                        e, rename_error
+                    );
+                    read_only = true;
+                }
+            }
+            Vec::new()
+        });
+
+        let mut index = Self {
+            path,
+            records: Vec::new(),
+            refs: HashMap::new(),
+            read_only,
+            version: 0,
+        };
+        for record in records {
+            index.insert(record);
+        }
+        index
+    }
+
+    /// 一覧に加え、blob の参照数を返す
+    pub fn insert(&mut self, record: FileRecord) -> usize {
+        let refs = self.refs.entry(record.sha256.clone()).or_default();
+        *refs += 1;
+        let refs = *refs;
+        self.records.push(record);
+        refs
+    }
+
+    /// 一覧から外し、blob の残りの参照数と一緒に返す
+    pub fn remove(&mut self, id: &str) -> Option<(FileRecord, usize)> {
+        let position = self.records.iter().position(|record| record.id == id)?;
+        let record = self.records.remove(position);
+
+        let refs = self.refs.get_mut(&record.sha256).map_or(0, |refs| {
+            *refs = refs.saturating_sub(1);
+            *refs
+        });
+        if refs == 0 {
+            self.refs.remove(&record.sha256);
+        }
+        Some((record, refs))
+    }
+
+    /// 以前の形式で記録されたもの (中身のハッシュが無いもの) を取り出す
+    pub fn take_legacy(&mut self) -> Vec<FileRecord> {
+        let (legacy, records) = std::mem::take(&mut self.records)
+            .into_iter()
+            .partition(|record| record.sha256.is_empty());
+        self.records = records;
+        self.refs.remove("");
+        legacy
+    }
+
+    pub fn get(&self, id: &str) -> Option<&FileRecord> {
+        self.records.iter().find(|record| record.id == id)
+    }
+
+    pub fn records(&self) -> &[FileRecord] {
+        &self.records
+    }
+
+    pub fn refs(&self, sha256: &str) -> usize {
+        self.refs.get(sha256).copied().unwrap_or(0)
+    }
+
+    /// 今の一覧を写し、新しい版を付ける
+    pub fn snapshot(&mut self) -> IndexSnapshot {
+        self.version += 1;
+        IndexSnapshot {
+            path: self.path.clone(),
+            records: self.records.clone(),
+            read_only: self.read_only,
+            version: self.version,
+        }
+    }
+
+    /// この場で保存する。非同期のコードからは `snapshot` を使う
+    pub fn persist(&mut self) -> io::Result<()> {
+        self.snapshot().write()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_refs_follow_records() {
+        let mut index = FileIndex::default();
+        let first = FileRecord::new("a.txt", "aaaa", 3, "Alice", Some(1));
+        let second = FileRecord::new("copy of a.txt", "aaaa", 3, "Bob", Some(2));
+        let other = FileRecord::new("b.png", "bbbb", 3, "Bob", Some(2));
+        assert_eq!(first.content_type, "text/plain");
+        assert_eq!(other.content_type, "image/png");
+
+        assert_eq!(index.insert(first.clone()), 1);
+        assert_eq!(index.insert(second.clone()), 2);
+        assert_eq!(index.insert(other), 1);
+
+        assert_eq!(index.remove(&first.id).unwrap().1, 1);
+        assert_eq!(index.refs("aaaa"), 1);
+        assert_eq!(index.remove(&second.id).unwrap().1, 0);
+        assert_eq!(index.refs("aaaa"), 0);
+        assert!(index.remove(&second.id).is_none());
+        assert_eq!(index.records().len(), 1);
+    }
+
+    #[test]
+    fn test_broken_index_is_kept_aside() {
+        let dir = std::env::temp_dir().join(format!("ws_s-index-{}", Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        FileIndex::check(&dir).unwrap();
+        fs::write(dir.join(INDEX_FILENAME), b"{not json").unwrap();
+        assert_eq!(
+            FileIndex::check(&dir).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+
+        let mut index = FileIndex::load(&dir);
+        assert!(index.records().is_empty());
+        index.insert(FileRecord::new("a.txt", "aaaa", 3, "Alice", None));
+        index.persist().unwrap();
+
+        let broken: Vec<_> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .filter(|name| name.starts_with(".files.json.broken-"))
+            .collect();
+        assert_eq!(broken.len(), 1);
+        assert_eq!(fs::read(dir.join(&broken[0])).unwrap(), b"{not json");
+        assert_eq!(FileIndex::load(&dir).records().len(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
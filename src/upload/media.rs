@@ -0,0 +1,103 @@
+use image::{ImageFormat, ImageReader, Limits};
+use infer::MatcherType;
+use std::io::{self, Cursor};
+use std::path::Path;
+use tokio::io::AsyncReadExt;
+
+/// 種類を調べるために読む先頭の大きさ
+pub const SNIFF_LEN: usize = 8 * 1024;
+
+/// 縮小版の幅と高さの上限
+pub const THUMBNAIL_SIZE: u32 = 256;
+
+/// 縮小版を作る画像の幅と高さの上限。展開すると巨大になる画像を避ける
+const MAX_IMAGE_DIMENSION: u32 = 16_384;
+
+/// 縮小版を作る時に確保してよいメモリ
+const MAX_IMAGE_ALLOC: u64 = 256 * 1024 * 1024;
+
+/// 先頭のバイト列から種類を調べる。分からなければ名前から推測する
+pub fn sniff(head: &[u8], name: &str) -> String {
+    match infer::get(head) {
+        Some(kind) => kind.mime_type().to_string(),
+        None => mime_guess::from_path(name)
+            .first_or_octet_stream()
+            .to_string(),
+    }
+}
+
+/// 実行ファイルやスクリプト
+pub fn is_executable(head: &[u8]) -> bool {
+    head.starts_with(b"#!")
+        || infer::get(head).is_some_and(|kind| kind.matcher_type() == MatcherType::App)
+}
+
+/// 縮小版を作れる画像の種類
+pub fn is_thumbnailable(content_type: &str) -> bool {
+    matches!(
+        content_type,
+        "image/png" | "image/jpeg" | "image/gif" | "image/webp" | "image/bmp"
+    )
+}
+
+/// `THUMBNAIL_SIZE` に収まるように縮小した PNG
+pub fn make_thumbnail(data: &[u8]) -> image::ImageResult<Vec<u8>> {
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_IMAGE_DIMENSION);
+    limits.max_image_height = Some(MAX_IMAGE_DIMENSION);
+    limits.max_alloc = Some(MAX_IMAGE_ALLOC);
+
+    let mut reader = ImageReader::new(Cursor::new(data)).with_guessed_format()?;
+    reader.limits(limits);
+    let thumbnail = reader.decode()?.thumbnail(THUMBNAIL_SIZE, THUMBNAIL_SIZE);
+
+    let mut png = Vec::new();
+    thumbnail.write_to(&mut Cursor::new(&mut png), ImageFormat::Png)?;
+    Ok(png)
+}
+
+/// ファイルの先頭 `SNIFF_LEN` バイト
+pub async fn read_head(path: &Path) -> io::Result<Vec<u8>> {
+    let f = tokio::fs::File::open(path).await?;
+    let mut head = Vec::with_capacity(SNIFF_LEN);
+    f.take(SNIFF_LEN as u64).read_to_end(&mut head).await?;
+    Ok(head)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{ImageBuffer, Rgb};
+
+    #[test]
+    fn test_sniff_prefers_magic_bytes() {
+        let png = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR";
+        assert_eq!(sniff(png, "notes.txt"), "image/png");
+        assert_eq!(sniff(b"hello", "notes.txt"), "text/plain");
+        assert_eq!(sniff(b"hello", "noext"), "application/octet-stream");
+    }
+
+    #[test]
+    fn test_executables() {
+        let mut elf = b"\x7fELF\x02\x01\x01".to_vec();
+        elf.resize(64, 0);
+        assert!(is_executable(&elf));
+        assert!(is_executable(b"MZ\x90\0\x03\0\0\0"));
+        assert!(is_executable(b"#!/bin/sh\nrm -rf /"));
+        assert!(!is_executable(b"hello"));
+    }
+
+    #[test]
+    fn test_thumbnail_fits_in_bounds() {
+        let image = ImageBuffer::from_pixel(1024, 512, Rgb([255u8, 0, 0]));
+        let mut png = Vec::new();
+        image
+            .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+            .unwrap();
+
+        let thumbnail = make_thumbnail(&png).unwrap();
+        let decoded = image::load_from_memory(&thumbnail).unwrap();
+        assert_eq!((decoded.width(), decoded.height()), (256, 128));
+        assert!(make_thumbnail(b"not an image").is_err());
+    }
+}
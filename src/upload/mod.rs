@@ -0,0 +1,677 @@
+pub mod blob;
+pub mod chunked;
+pub mod filename;
+pub mod index;
+pub mod media;
+pub mod quota;
+pub mod scan;
+
+pub use blob::{BlobBackend, BlobStore, BlobStream, FsBlobStore, MemoryBlobStore};
+pub use chunked::{ChunkError, ChunkedUploads};
+pub use filename::{sanitize_filename, FilenameError};
+pub use index::{FileIndex, FileRecord, IndexSnapshot};
+pub use quota::{QuotaError, QuotaLedger, UploadLimits, Usage};
+pub use scan::{
+    CommandScanner, Rejection, ScanPipeline, ScanTarget, SecretScanner, TypePolicy, UploadScanner,
+    Verdict,
+};
+
+use crate::utils::to_hex;
+use bytes::Bytes;
+use log::{info, warn};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::future::Future;
+use std::io;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tokio::fs;
+use tokio::io::AsyncReadExt;
+use tokio::sync::OwnedMutexGuard;
+use uuid::Uuid;
+
+/// ファイルシステムに置く場合の blob のディレクトリの名前。アップロード先のディレクトリの中に作る
+pub const BLOBS_DIRNAME: &str = "blobs";
+
+/// 検査が済むまでファイルを置くディレクトリの名前。アップロード先のディレクトリの中に作る
+pub const QUARANTINE_DIRNAME: &str = ".quarantine";
+
+/// 一覧ができる前から置かれていたファイルの送信者として表示する名前
+const UNKNOWN_SENDER: &str = "-";
+
+#[derive(Debug)]
+pub enum UploadError {
+    InvalidFilename(FilenameError),
+    /// 設定で受け付けない種類。検出した MIME タイプを持つ
+    FileTypeRejected(String),
+    /// 検査で断られた
+    Rejected(Rejection),
+    Io(io::Error),
+}
+
+impl Display for UploadError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UploadError::InvalidFilename(e) => write!(f, "invalid filename: {}", e),
+            UploadError::FileTypeRejected(content_type) => {
+                write!(f, "files of type {} are not accepted", content_type)
+            }
+            UploadError::Rejected(rejection) => write!(f, "{}", rejection),
+            UploadError::Io(e) => write!(f, "failed to store upload: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for UploadError {}
+
+impl From<FilenameError> for UploadError {
+    fn from(e: FilenameError) -> Self {
+        UploadError::InvalidFilename(e)
+    }
+}
+
+impl From<io::Error> for UploadError {
+    fn from(e: io::Error) -> Self {
+        UploadError::Io(e)
+    }
+}
+
+/// アップロード先のディレクトリを用意する
+pub fn prepare_dir(dir: &Path) -> io::Result<()> {
+    if dir.exists() && !dir.is_dir() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("{} is not a directory", dir.display()),
+        ));
+    }
+    std::fs::create_dir_all(dir)
+}
+
+/// 中身の SHA-256 から blob のキーを作る。`ab/abcd...`
+pub fn blob_key(sha256: &str) -> String {
+    format!("{}/{}", sha256.get(..2).unwrap_or("00"), sha256)
+}
+
+/// 縮小版のキー。`thumbnails/ab/abcd....png`
+pub fn thumbnail_key(sha256: &str) -> String {
+    format!("thumbnails/{}.png", blob_key(sha256))
+}
+
+/// アップロードされたファイルの保存先
+///
+/// 中身は SHA-256 をキーにして `BlobStore` に保存し、名前や送信者は一覧 (`FileIndex`) に
+/// 記録する。一覧はどの保存先でもアップロード先のディレクトリに置く。同じ中身は1つの blob
+/// を共有し、一覧から最後の参照が消えた時に blob も消す。
+///
+/// 種類は名前ではなく中身の先頭から調べる。画像には縮小版を作る。検査 (`ScanPipeline`) を
+/// 設定した場合は、隔離した場所で全ての検査を通ってから一覧に加える。
+#[derive(Clone)]
+pub struct UploadStore {
+    dir: PathBuf,
+    blobs: Arc<dyn BlobStore>,
+    index: Arc<Mutex<FileIndex>>,
+    /// 一覧の書き込みを1つずつにする。中身は最後に保存した版
+    index_written: Arc<tokio::sync::Mutex<u64>>,
+    locks: Arc<BlobLocks>,
+    refuse_executables: bool,
+    scanners: ScanPipeline,
+}
+
+impl UploadStore {
+    /// `dir` の一覧を読み込む
+    pub fn open(dir: impl Into<PathBuf>, blobs: Arc<dyn BlobStore>) -> Self {
+        let dir = dir.into();
+        let index = FileIndex::load(&dir);
+        // 検査の途中で止まったファイルは、誰にも見えないまま残っている
+        let _ = std::fs::remove_dir_all(dir.join(QUARANTINE_DIRNAME));
+
+        Self {
+            dir,
+            blobs,
+            index: Arc::new(Mutex::new(index)),
+            index_written: Arc::new(tokio::sync::Mutex::new(0)),
+            locks: Arc::new(BlobLocks::default()),
+            refuse_executables: false,
+            scanners: ScanPipeline::default(),
+        }
+    }
+
+    /// 実行ファイルやスクリプトを受け付けない
+    pub fn refuse_executables(mut self, refuse: bool) -> Self {
+        self.refuse_executables = refuse;
+        self
+    }
+
+    /// 公開する前に通す検査
+    pub fn scanners(mut self, scanners: ScanPipeline) -> Self {
+        self.scanners = scanners;
+        self
+    }
+
+    /// 先頭のバイト列から、受け付けられる種類か確かめる
+    pub fn check_type(&self, head: &[u8]) -> Result<(), UploadError> {
+        if self.refuse_executables && media::is_executable(head) {
+            let content_type = media::sniff(head, "");
+            return Err(UploadError::FileTypeRejected(content_type));
+        }
+        Ok(())
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn blobs(&self) -> &Arc<dyn BlobStore> {
+        &self.blobs
+    }
+
+    /// 名前のまま `dir` に置かれているファイルを blob に移す。移した件数を返す
+    pub async fn migrate_legacy(&self) -> usize {
+        let mut candidates: Vec<(String, String, Option<i32>)> = self
+            .index
+            .lock()
+            .unwrap()
+            .take_legacy()
+            .into_iter()
+            .map(|record| (record.name, record.sender, record.room))
+            .collect();
+        if let Ok(mut entries) = fs::read_dir(&self.dir).await {
+            while let Ok(Some(entry)) = entries.next_entry().await {
+                let name = entry.file_name().to_string_lossy().into_owned();
+                // `.` 始まりは一時ファイルや管理用のファイル
+                let is_file = entry.file_type().await.is_ok_and(|t| t.is_file());
+                if is_file && !name.starts_with('.') && !candidates.iter().any(|c| c.0 == name) {
+                    candidates.push((name, UNKNOWN_SENDER.to_string(), None));
+                }
+            }
+        }
+
+        let mut migrated = 0;
+        for (name, sender, room) in candidates {
+            let path = self.dir.join(&name);
+            let Ok((sha256, size)) = hash_file(&path).await else {
+                continue;
+            };
+            let Ok(head) = media::read_head(&path).await else {
+                continue;
+            };
+            let mut record = FileRecord::new(&name, &sha256, size, &sender, room);
+            record.content_type = media::sniff(&head, &name);
+
+            let key = blob_key(&sha256);
+            let thumbnail = thumbnail(
+                media::is_thumbnailable(&record.content_type),
+                read_file(&path),
+            );
+            match self
+                .link(record, self.blobs.put_file(&key, &path), thumbnail)
+                .await
+            {
+                Ok(_) => {
+                    let _ = fs::remove_file(&path).await;
+                    migrated += 1;
+                }
+                Err(e) => warn!("failed to move {} into blob storage: {}", path.display(), e),
+            }
+        }
+        if migrated > 0 {
+            info!("moved {} existing uploads into blob storage", migrated);
+        }
+        migrated
+    }
+
+    /// 中身を保存して一覧に加える
+    pub async fn save(
+        &self,
+        filename: &str,
+        content: Bytes,
+        sender: &str,
+        room: i32,
+    ) -> Result<FileRecord, UploadError> {
+        let name = sanitize_filename(filename)?;
+        let head = &content[..content.len().min(media::SNIFF_LEN)];
+        self.check_type(head)?;
+
+        let sha256 = to_hex(&Sha256::digest(&content));
+        let mut record = FileRecord::new(&name, &sha256, content.len() as u64, sender, Some(room));
+        record.content_type = media::sniff(head, &name);
+
+        let key = blob_key(&sha256);
+        let thumbnail = thumbnail(media::is_thumbnailable(&record.content_type), async {
+            Ok(content.clone())
+        });
+        if self.scanners.is_empty() {
+            return self
+                .link(record, self.blobs.put(&key, content.clone()), thumbnail)
+                .await;
+        }
+
+        // 検査にはファイルの場所を渡す
+        let path = self
+            .dir
+            .join(QUARANTINE_DIRNAME)
+            .join(Uuid::new_v4().to_string());
+        let result = async {
+            fs::create_dir_all(self.dir.join(QUARANTINE_DIRNAME)).await?;
+            fs::write(&path, &content).await?;
+            self.scan(&record, &path).await?;
+            self.link(record, self.blobs.put_file(&key, &path), thumbnail)
+                .await
+        }
+        .await;
+
+        let _ = fs::remove_file(&path).await;
+        result
+    }
+
+    /// 書き込み済みのファイルを blob に移して一覧に加える。`path` は残らない
+    ///
+    /// `sha256` は呼び出し側で確かめておく。
+    pub async fn save_file(
+        &self,
+        filename: &str,
+        path: &Path,
+        sha256: &str,
+        sender: &str,
+        room: i32,
+    ) -> Result<FileRecord, UploadError> {
+        let result = async {
+            let name = sanitize_filename(filename)?;
+            let head = media::read_head(path).await?;
+            self.check_type(&head)?;
+
+            let size = fs::metadata(path).await?.len();
+            let mut record = FileRecord::new(&name, sha256, size, sender, Some(room));
+            record.content_type = media::sniff(&head, &name);
+            self.scan(&record, path).await?;
+
+            let key = blob_key(sha256);
+            let thumbnail = thumbnail(
+                media::is_thumbnailable(&record.content_type),
+                read_file(path),
+            );
+            self.link(record, self.blobs.put_file(&key, path), thumbnail)
+                .await
+        }
+        .await;
+
+        let _ = fs::remove_file(path).await;
+        result
+    }
+
+    /// 一覧から外す。他から参照されていなければ blob も消す
+    ///
+    /// 一覧を保存できなければ一覧に戻し、blob も消さない。
+    pub async fn delete(&self, id: &str) -> io::Result<Option<FileRecord>> {
+        let Some(record) = self.get(id) else {
+            return Ok(None);
+        };
+        let _guard = self.locks.lock(&record.sha256).await;
+
+        let (record, refs, snapshot) = {
+            let mut index = self.index.lock().unwrap();
+            let Some((record, refs)) = index.remove(id) else {
+                return Ok(None);
+            };
+            (record, refs, index.snapshot())
+        };
+        if let Err(e) = self.save_index(snapshot).await {
+            self.index.lock().unwrap().insert(record);
+            return Err(e);
+        }
+        if refs == 0 {
+            if let Err(e) = self.blobs.delete(&blob_key(&record.sha256)).await {
+                warn!("failed to remove blob {}: {}", record.sha256, e);
+            }
+            if record.thumbnail {
+                if let Err(e) = self.blobs.delete(&thumbnail_key(&record.sha256)).await {
+                    warn!("failed to remove thumbnail {}: {}", record.sha256, e);
+                }
+            }
+        }
+
+        info!(
+            "deleted upload {} ({}, {} references left)",
+            record.id, record.name, refs
+        );
+        Ok(Some(record))
+    }
+
+    pub fn get(&self, id: &str) -> Option<FileRecord> {
+        self.index.lock().unwrap().get(id).cloned()
+    }
+
+    /// 古い順
+    pub fn list(&self) -> Vec<FileRecord> {
+        self.index.lock().unwrap().records().to_vec()
+    }
+
+    /// ルームで共有されたファイル。古い順
+    pub fn list_room(&self, room: i32) -> Vec<FileRecord> {
+        self.index
+            .lock()
+            .unwrap()
+            .records()
+            .iter()
+            .filter(|record| record.room == Some(room))
+            .cloned()
+            .collect()
+    }
+
+    /// 同じ中身を参照している一覧の件数
+    pub fn refs(&self, sha256: &str) -> usize {
+        self.index.lock().unwrap().refs(sha256)
+    }
+
+    /// 中身を読む。`range` は終端を含まない
+    pub async fn open_blob(
+        &self,
+        record: &FileRecord,
+        range: Option<Range<u64>>,
+    ) -> io::Result<Option<BlobStream>> {
+        self.blobs.get(&blob_key(&record.sha256), range).await
+    }
+
+    /// 縮小版を読む。作れなかったファイルは `None`
+    pub async fn open_thumbnail(&self, record: &FileRecord) -> io::Result<Option<BlobStream>> {
+        if !record.thumbnail {
+            return Ok(None);
+        }
+        self.blobs.get(&thumbnail_key(&record.sha256), None).await
+    }
+
+    /// 中身を全てメモリに読み込む
+    pub async fn read(&self, record: &FileRecord) -> io::Result<Option<Bytes>> {
+        blob::read_all(self.blobs.as_ref(), &blob_key(&record.sha256)).await
+    }
+
+    /// 設定された検査を全て通るか確かめる
+    async fn scan(&self, record: &FileRecord, path: &Path) -> Result<(), UploadError> {
+        self.scanners
+            .run(&ScanTarget { path, record })
+            .await
+            .map_err(UploadError::Rejected)
+    }
+
+    /// blob を保存して一覧に加える
+    ///
+    /// 同じ中身の blob が既にあれば `put` と `thumbnail` は実行しない。同じ中身への削除とは
+    /// 順番に行うので、最後の参照を消している最中の blob に繋ぐことはない。
+    async fn link(
+        &self,
+        mut record: FileRecord,
+        put: impl Future<Output = io::Result<()>>,
+        thumbnail: impl Future<Output = Option<Bytes>>,
+    ) -> Result<FileRecord, UploadError> {
+        let _guard = self.locks.lock(&record.sha256).await;
+
+        // 一覧に無い blob は削除に失敗した残りかもしれないので、置き直す
+        let key = blob_key(&record.sha256);
+        let existing = self
+            .index
+            .lock()
+            .unwrap()
+            .records()
+            .iter()
+            .find(|existing| existing.sha256 == record.sha256)
+            .map(|existing| existing.thumbnail);
+        match existing {
+            Some(has_thumbnail) if self.blobs.exists(&key).await? => {
+                info!(
+                    "upload {:?} has the same content as {}",
+                    record.name, record.sha256
+                );
+                record.thumbnail = has_thumbnail;
+            }
+            _ => {
+                put.await?;
+                if let Some(png) = thumbnail.await {
+                    match self.blobs.put(&thumbnail_key(&record.sha256), png).await {
+                        Ok(()) => record.thumbnail = true,
+                        Err(e) => warn!("failed to store thumbnail {}: {}", record.sha256, e),
+                    }
+                }
+            }
+        }
+
+        let snapshot = {
+            let mut index = self.index.lock().unwrap();
+            index.insert(record.clone());
+            index.snapshot()
+        };
+        if let Err(e) = self.save_index(snapshot).await {
+            self.index.lock().unwrap().remove(&record.id);
+            return Err(e.into());
+        }
+        info!("stored upload {:?} as {}", record.name, record.sha256);
+        Ok(record)
+    }
+
+    /// ブロックしてよいスレッドで一覧を保存する
+    ///
+    /// 書き込みは1つずつ行い、既にもっと新しい版を保存していれば何もしない。
+    async fn save_index(&self, snapshot: IndexSnapshot) -> io::Result<()> {
+        let mut written = self.index_written.lock().await;
+        let version = snapshot.version();
+        if *written >= version {
+            return Ok(());
+        }
+        tokio::task::spawn_blocking(move || snapshot.write())
+            .await
+            .map_err(io::Error::other)??;
+        *written = version;
+        Ok(())
+    }
+}
+
+/// 中身ごとのロック。使われなくなったものは外す
+#[derive(Default)]
+struct BlobLocks {
+    locks: Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>,
+}
+
+impl BlobLocks {
+    async fn lock(&self, sha256: &str) -> BlobGuard<'_> {
+        let lock = self
+            .locks
+            .lock()
+            .unwrap()
+            .entry(sha256.to_string())
+            .or_default()
+            .clone();
+        BlobGuard {
+            locks: self,
+            guard: Some(lock.lock_owned().await),
+        }
+    }
+}
+
+struct BlobGuard<'a> {
+    locks: &'a BlobLocks,
+    guard: Option<OwnedMutexGuard<()>>,
+}
+
+impl Drop for BlobGuard<'_> {
+    fn drop(&mut self) {
+        self.guard.take();
+        // 待っている人がいるロックは残る
+        self.locks
+            .locks
+            .lock()
+            .unwrap()
+            .retain(|_, lock| Arc::strong_count(lock) > 1);
+    }
+}
+
+/// 画像なら縮小版を作る。失敗しても保存は続ける
+async fn thumbnail(
+    thumbnailable: bool,
+    load: impl Future<Output = io::Result<Bytes>>,
+) -> Option<Bytes> {
+    if !thumbnailable {
+        return None;
+    }
+    let data = load.await.ok()?;
+    match tokio::task::spawn_blocking(move || media::make_thumbnail(&data)).await {
+        Ok(Ok(png)) => Some(Bytes::from(png)),
+        Ok(Err(e)) => {
+            warn!("cannot make a thumbnail: {}", e);
+            None
+        }
+        Err(e) => {
+            warn!("thumbnail task failed: {}", e);
+            None
+        }
+    }
+}
+
+async fn read_file(path: &Path) -> io::Result<Bytes> {
+    fs::read(path).await.map(Bytes::from)
+}
+
+async fn hash_file(path: &Path) -> io::Result<(String, u64)> {
+    let mut f = fs::File::open(path).await?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; 64 * 1024];
+    let mut size = 0;
+    loop {
+        let n = f.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+        size += n as u64;
+    }
+    Ok((to_hex(&hasher.finalize()), size))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+
+    fn temp_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("ws_s-upload-{}", Uuid::new_v4()));
+        prepare_dir(&dir).unwrap();
+        dir
+    }
+
+    fn fs_store(dir: &Path) -> (UploadStore, FsBlobStore) {
+        let blobs = FsBlobStore::new(dir.join(BLOBS_DIRNAME));
+        (UploadStore::open(dir, Arc::new(blobs.clone())), blobs)
+    }
+
+    #[tokio::test]
+    async fn test_identical_content_is_stored_once() {
+        let dir = temp_dir();
+        let (store, blobs) = fs_store(&dir);
+        let save = |name: &'static str, content: &'static [u8], sender: &'static str| {
+            store.save(name, Bytes::from_static(content), sender, 1)
+        };
+
+        let first = save("report.txt", b"same", "Alice").await.unwrap();
+        let second = save("copy.txt", b"same", "Bob").await.unwrap();
+        let other = save("report.txt", b"other", "Alice").await.unwrap();
+
+        // 同じ名前でも上書きしない
+        assert_ne!(first.id, other.id);
+        assert_eq!(first.sha256, second.sha256);
+        assert_ne!(first.sha256, other.sha256);
+        assert_eq!(store.refs(&first.sha256), 2);
+        assert_eq!(store.read(&other).await.unwrap().unwrap(), "other");
+
+        // 片方を消しても、もう片方からは読める
+        store.delete(&first.id).await.unwrap().unwrap();
+        assert_eq!(store.read(&second).await.unwrap().unwrap(), "same");
+        store.delete(&second.id).await.unwrap().unwrap();
+        assert!(!blobs.path(&blob_key(&second.sha256)).exists());
+        assert!(store.delete(&second.id).await.unwrap().is_none());
+
+        // 一時ファイルは残らない
+        let leftovers = std::fs::read_dir(blobs.root())
+            .unwrap()
+            .filter(|entry| entry.as_ref().unwrap().file_type().unwrap().is_file())
+            .count();
+        assert_eq!(leftovers, 0);
+
+        // 一覧は再起動後も残る
+        let (reopened, _) = fs_store(&dir);
+        assert_eq!(reopened.list(), vec![other]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_index_save_errors_are_returned() {
+        let dir = temp_dir();
+        let (store, _) = fs_store(&dir);
+        let kept = store
+            .save("kept.txt", Bytes::from_static(b"kept"), "Alice", 1)
+            .await
+            .unwrap();
+
+        // 一覧の場所にディレクトリがあると置き換えられない
+        let index_path = dir.join(index::INDEX_FILENAME);
+        std::fs::remove_file(&index_path).unwrap();
+        std::fs::create_dir(&index_path).unwrap();
+        let result = store
+            .save("lost.txt", Bytes::from_static(b"lost"), "Alice", 1)
+            .await;
+        assert!(matches!(result, Err(UploadError::Io(_))), "{:?}", result);
+        assert!(store.delete(&kept.id).await.is_err());
+        assert_eq!(store.list(), vec![kept.clone()]);
+
+        std::fs::remove_dir(&index_path).unwrap();
+        assert_eq!(store.delete(&kept.id).await.unwrap(), Some(kept));
+        assert!(store.list().is_empty());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_legacy_files_are_moved_into_blobs() {
+        let dir = temp_dir();
+        std::fs::write(dir.join("old.txt"), b"old").unwrap();
+        std::fs::write(dir.join("old (1).txt"), b"old").unwrap();
+
+        let blobs = Arc::new(MemoryBlobStore::default());
+        let store = UploadStore::open(&dir, blobs.clone());
+        assert_eq!(store.migrate_legacy().await, 2);
+
+        let records = store.list();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].sha256, records[1].sha256);
+        assert_eq!(store.refs(&records[0].sha256), 2);
+        assert_eq!(blobs.len(), 1);
+        assert!(!dir.join("old.txt").exists());
+        assert_eq!(store.read(&records[0]).await.unwrap().unwrap(), "old");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_hostile_names_are_rejected() {
+        let dir = temp_dir();
+        let (store, _) = fs_store(&dir);
+        let evil = || Bytes::from_static(b"evil");
+
+        assert!(matches!(
+            store.save("../../etc/cron.d/x", evil(), "Mallory", 1).await,
+            Err(UploadError::InvalidFilename(FilenameError::ParentDirectory))
+        ));
+        assert!(matches!(
+            store.save("/tmp/evil", evil(), "Mallory", 1).await,
+            Err(UploadError::InvalidFilename(FilenameError::Absolute))
+        ));
+
+        let stored = store
+            .save("nested/dir/ok.txt", Bytes::from_static(b"ok"), "Alice", 1)
+            .await
+            .unwrap();
+        assert_eq!(stored.name, "ok.txt");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,488 @@
+use super::index::{FileIndex, FileRecord};
+use crate::history::now_millis;
+use crate::utils::format_bytes;
+use log::{error, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, RwLock};
+
+/// 使用量を記録するファイルの名前。アップロード先のディレクトリに置く
+pub const USAGE_FILENAME: &str = ".usage.json";
+
+/// 1ファイルの大きさの既定の上限
+pub const DEFAULT_MAX_FILE_SIZE: u64 = 64 * 1024 * 1024;
+
+/// 1ユーザーあたりの保存容量の既定の上限
+pub const DEFAULT_USER_QUOTA: u64 = 1024 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct UploadLimits {
+    pub max_file_size: u64,
+    /// `None` なら無制限
+    pub user_quota: Option<u64>,
+    /// `None` なら無制限
+    pub room_quota: Option<u64>,
+}
+
+impl Default for UploadLimits {
+    fn default() -> Self {
+        Self {
+            max_file_size: DEFAULT_MAX_FILE_SIZE,
+            user_quota: Some(DEFAULT_USER_QUOTA),
+            room_quota: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum QuotaError {
+    FileTooLarge {
+        size: u64,
+        max: u64,
+    },
+    UserQuotaExceeded {
+        user: String,
+        used: u64,
+        size: u64,
+        quota: u64,
+    },
+    RoomQuotaExceeded {
+        room: i32,
+        used: u64,
+        size: u64,
+        quota: u64,
+    },
+}
+
+impl Display for QuotaError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            QuotaError::FileTooLarge { size, max } => write!(
+                f,
+                "file is too large: {} (limit {})",
+                format_bytes(*size),
+                format_bytes(*max)
+            ),
+            QuotaError::UserQuotaExceeded {
+                user,
+                used,
+                size,
+                quota,
+            } => write!(
+                f,
+                "storage quota of {} exceeded: {} used + {} > {}",
+                user,
+                format_bytes(*used),
+                format_bytes(*size),
+                format_bytes(*quota)
+            ),
+            QuotaError::RoomQuotaExceeded {
+                room,
+                used,
+                size,
+                quota,
+            } => write!(
+                f,
+                "storage quota of room {} exceeded: {} used + {} > {}",
+                room,
+                format_bytes(*used),
+                format_bytes(*size),
+                format_bytes(*quota)
+            ),
+        }
+    }
+}
+
+impl std::error::Error for QuotaError {}
+
+/// ユーザーごと、ルームごとの使用量 (バイト)
+#[derive(Debug, Clone, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Usage {
+    pub users: BTreeMap<String, u64>,
+    pub rooms: BTreeMap<i32, u64>,
+}
+
+impl Usage {
+    pub fn user(&self, user: &str) -> u64 {
+        self.users.get(user).copied().unwrap_or(0)
+    }
+
+    pub fn room(&self, room: i32) -> u64 {
+        self.rooms.get(&room).copied().unwrap_or(0)
+    }
+
+    /// ファイルの一覧から数え直す。一覧ができる前のファイルはルームに数えない
+    pub fn from_records(records: &[FileRecord]) -> Self {
+        let mut usage = Self::default();
+        for record in records {
+            *usage.users.entry(record.sender.clone()).or_default() += record.size;
+            if let Some(room) = record.room {
+                *usage.rooms.entry(room).or_default() += record.size;
+            }
+        }
+        usage
+    }
+}
+
+/// 使用量を読む。無ければ空
+fn read_usage(path: &Path) -> io::Result<Usage> {
+    let bytes = match fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Usage::default()),
+        Err(e) => {
+            return Err(io::Error::new(
+                e.kind(),
+                format!("{}: {}", path.display(), e),
+            ))
+        }
+    };
+    serde_json::from_slice(&bytes).map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{}: {}", path.display(), e),
+        )
+    })
+}
+
+/// 一時ファイルに書いてから置き換える
+fn write_usage(path: &Path, usage: &Usage) -> io::Result<()> {
+    let tmp_path = path.with_extension("json.tmp");
+    fs::write(&tmp_path, serde_json::to_vec_pretty(usage)?)?;
+    fs::rename(&tmp_path, path)
+}
+
+/// `QuotaLedger::reserve` で確保した分。保存に成功したら `commit`、失敗したら `release` する
+#[derive(Debug)]
+#[must_use]
+pub struct Reservation {
+    user: String,
+    room: i32,
+    size: u64,
+}
+
+#[derive(Default)]
+struct Inner {
+    usage: Usage,
+    /// 使用量を変えるたびに増やす。古い使用量で新しいものを上書きしないために使う
+    version: u64,
+    /// 保存中のアップロードの分
+    pending_users: HashMap<String, u64>,
+    pending_rooms: HashMap<i32, u64>,
+}
+
+/// 保存容量の上限と使用量の台帳
+///
+/// 使用量はアップロード先のディレクトリの JSON ファイルに保存し、再起動後も引き継ぐ。
+pub struct QuotaLedger {
+    path: PathBuf,
+    limits: RwLock<UploadLimits>,
+    inner: Mutex<Inner>,
+    /// 書き込みを1つずつにする。中身は最後に保存した `Inner::version`
+    written: tokio::sync::Mutex<u64>,
+}
+
+impl QuotaLedger {
+    /// `dir` の使用量ファイルが読めるか確かめる。サーバーは読めなければ起動しない
+    pub fn check(dir: &Path) -> io::Result<()> {
+        read_usage(&dir.join(USAGE_FILENAME)).map(|_| ())
+    }
+
+    /// `dir` の使用量ファイルを読み込む
+    ///
+    /// 読めなければ元のファイルを `.usage.json.broken-<ミリ秒>` に退避し、ファイルの一覧から
+    /// 数え直す。
+    pub fn open(dir: &Path, limits: UploadLimits) -> Self {
+        let path = dir.join(USAGE_FILENAME);
+        let usage = read_usage(&path).unwrap_or_else(|e| {
+            let aside = path.with_extension(format!("json.broken-{}", now_millis()));
+            if let Err(rename_error) = fs::rename(&path, &aside) {
+                warn!("failed to move {} aside: {}", path.display(), rename_error);
+            }
+            let records = FileIndex::read(dir).unwrap_or_default();
+            error!(
+                "cannot read quota usage, moved it to {} and counted {} file(s) again: {}",
+                aside.display(),
+                records.len(),
+                e
+            );
+            Usage::from_records(&records)
+        });
+
+        Self {
+            path,
+            limits: RwLock::new(limits),
+            inner: Mutex::new(Inner {
+                usage,
+                ..Inner::default()
+            }),
+            written: tokio::sync::Mutex::new(0),
+        }
+    }
+
+    pub fn limits(&self) -> UploadLimits {
+        *self.limits.read().unwrap()
+    }
+
+    /// 上限を変える。確保済みの分はそのまま
+    pub fn set_limits(&self, limits: UploadLimits) {
+        *self.limits.write().unwrap() = limits;
+    }
+
+    /// 上限を超えないか確認し、保存が終わるまでの分を確保する
+    pub fn reserve(&self, user: &str, room: i32, size: u64) -> Result<Reservation, QuotaError> {
+        let limits = self.limits();
+        if size > limits.max_file_size {
+            return Err(QuotaError::FileTooLarge {
+                size,
+                max: limits.max_file_size,
+            });
+        }
+
+        let mut inner = self.inner.lock().unwrap();
+
+        if let Some(quota) = limits.user_quota {
+            let used = inner.usage.user(user) + inner.pending_users.get(user).copied().unwrap_or(0);
+            if used + size > quota {
+                return Err(QuotaError::UserQuotaExceeded {
+                    user: user.to_string(),
+                    used,
+                    size,
+                    quota,
+                });
+            }
+        }
+        if let Some(quota) = limits.room_quota {
+            let used =
+                inner.usage.room(room) + inner.pending_rooms.get(&room).copied().unwrap_or(0);
+            if used + size > quota {
+                return Err(QuotaError::RoomQuotaExceeded {
+                    room,
+                    used,
+                    size,
+                    quota,
+                });
+            }
+        }
+
+        *inner.pending_users.entry(user.to_string()).or_default() += size;
+        *inner.pending_rooms.entry(room).or_default() += size;
+
+        Ok(Reservation {
+            user: user.to_string(),
+            room,
+            size,
+        })
+    }
+
+    /// 確保した分を使用量に加えて保存する。実際のサイズが分かっていればそれを渡す
+    pub async fn commit(&self, reservation: Reservation, actual_size: u64) {
+        let snapshot = {
+            let mut inner = self.inner.lock().unwrap();
+            Self::unpend(&mut inner, &reservation);
+            *inner.usage.users.entry(reservation.user).or_default() += actual_size;
+            *inner.usage.rooms.entry(reservation.room).or_default() += actual_size;
+            Self::snapshot(&mut inner)
+        };
+        self.persist(snapshot).await;
+    }
+
+    /// 保存に失敗した場合に確保した分を戻す
+    pub fn release(&self, reservation: Reservation) {
+        let mut inner = self.inner.lock().unwrap();
+        Self::unpend(&mut inner, &reservation);
+    }
+
+    /// 削除したファイルの分を使用量から引く。一覧ができる前のファイルはルームが無い
+    pub async fn credit(&self, user: &str, room: Option<i32>, size: u64) {
+        let snapshot = {
+            let mut inner = self.inner.lock().unwrap();
+            if let Some(used) = inner.usage.users.get_mut(user) {
+                *used = used.saturating_sub(size);
+            }
+            if let Some(used) = room.and_then(|room| inner.usage.rooms.get_mut(&room)) {
+                *used = used.saturating_sub(size);
+            }
+            Self::snapshot(&mut inner)
+        };
+        self.persist(snapshot).await;
+    }
+
+    pub fn usage(&self) -> Usage {
+        self.inner.lock().unwrap().usage.clone()
+    }
+
+    fn unpend(inner: &mut Inner, reservation: &Reservation) {
+        if let Some(pending) = inner.pending_users.get_mut(&reservation.user) {
+            *pending = pending.saturating_sub(reservation.size);
+            if *pending == 0 {
+                inner.pending_users.remove(&reservation.user);
+            }
+        }
+        if let Some(pending) = inner.pending_rooms.get_mut(&reservation.room) {
+            *pending = pending.saturating_sub(reservation.size);
+            if *pending == 0 {
+                inner.pending_rooms.remove(&reservation.room);
+            }
+        }
+    }
+
+    /// 変えた使用量に版を付けて写す。`inner` のロックを持ったまま呼ぶ
+    fn snapshot(inner: &mut Inner) -> (u64, Usage) {
+        inner.version += 1;
+        (inner.version, inner.usage.clone())
+    }
+
+    /// ブロックしてよいスレッドで保存する
+    ///
+    /// 書き込みは1つずつ行い、既にもっと新しい版を保存していれば何もしない。
+    async fn persist(&self, (version, usage): (u64, Usage)) {
+        let mut written = self.written.lock().await;
+        if *written >= version {
+            return;
+        }
+        let path = self.path.clone();
+        let result = tokio::task::spawn_blocking(move || write_usage(&path, &usage))
+            .await
+            .unwrap_or_else(|e| Err(io::Error::other(e)));
+        match result {
+            Ok(()) => *written = version,
+            Err(e) => warn!("failed to save {}: {}", self.path.display(), e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("ws_s-quota-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[tokio::test]
+    async fn test_limits_and_persistence() {
+        let dir = temp_dir();
+        let limits = UploadLimits {
+            max_file_size: 100,
+            user_quota: Some(150),
+            room_quota: Some(250),
+        };
+
+        {
+            let ledger = QuotaLedger::open(&dir, limits);
+            assert!(matches!(
+                ledger.reserve("alice", 1, 101),
+                Err(QuotaError::FileTooLarge { .. })
+            ));
+
+            let first = ledger.reserve("alice", 1, 100).unwrap();
+            // 保存中の分も上限に数える
+            assert!(matches!(
+                ledger.reserve("alice", 1, 60),
+                Err(QuotaError::UserQuotaExceeded { used: 100, .. })
+            ));
+            ledger.commit(first, 100).await;
+
+            let failed = ledger.reserve("bob", 1, 100).unwrap();
+            ledger.release(failed);
+            let second = ledger.reserve("bob", 1, 100).unwrap();
+            ledger.commit(second, 100).await;
+
+            assert!(matches!(
+                ledger.reserve("carol", 1, 60),
+                Err(QuotaError::RoomQuotaExceeded { used: 200, .. })
+            ));
+        }
+
+        // 再起動後も使用量は残る
+        let ledger = QuotaLedger::open(&dir, limits);
+        let usage = ledger.usage();
+        assert_eq!(usage.user("alice"), 100);
+        assert_eq!(usage.user("bob"), 100);
+        assert_eq!(usage.room(1), 200);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_concurrent_commits_are_all_saved() {
+        let dir = temp_dir();
+        let limits = UploadLimits {
+            max_file_size: 100,
+            user_quota: None,
+            room_quota: None,
+        };
+        let ledger = std::sync::Arc::new(QuotaLedger::open(&dir, limits));
+
+        let tasks: Vec<_> = (0..8)
+            .map(|i| {
+                let ledger = ledger.clone();
+                tokio::spawn(async move {
+                    let user = format!("user{}", i);
+                    for _ in 0..20 {
+                        let reservation = ledger.reserve(&user, 1, 1).unwrap();
+                        ledger.commit(reservation, 1).await;
+                    }
+                    ledger.credit(&user, Some(1), 5).await;
+                })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+
+        // 最後に保存した内容がメモリ上の使用量と一致する
+        let saved = QuotaLedger::open(&dir, limits).usage();
+        assert_eq!(saved, ledger.usage());
+        assert_eq!(saved.room(1), 8 * 15);
+        assert!(!dir.join(".usage.json.tmp").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_broken_usage_is_counted_again() {
+        let dir = temp_dir();
+        let mut index = FileIndex::load(&dir);
+        index.insert(FileRecord::new("a.txt", "aaaa", 30, "alice", Some(1)));
+        index.insert(FileRecord::new("b.txt", "bbbb", 20, "bob", Some(1)));
+        index.insert(FileRecord::new("old.txt", "", 5, "alice", None));
+        index.persist().unwrap();
+
+        QuotaLedger::check(&dir).unwrap();
+        fs::write(dir.join(USAGE_FILENAME), b"{\"users\":").unwrap();
+        assert_eq!(
+            QuotaLedger::check(&dir).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+
+        // 壊れたファイルは残し、一覧から数え直した使用量で続ける
+        let ledger = QuotaLedger::open(&dir, UploadLimits::default());
+        let usage = ledger.usage();
+        assert_eq!(usage.user("alice"), 35);
+        assert_eq!(usage.user("bob"), 20);
+        assert_eq!(usage.room(1), 50);
+        let reservation = ledger.reserve("carol", 2, 1).unwrap();
+        ledger.commit(reservation, 1).await;
+
+        let broken: Vec<_> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .filter(|name| name.starts_with(".usage.json.broken-"))
+            .collect();
+        assert_eq!(broken.len(), 1);
+        assert_eq!(fs::read(dir.join(&broken[0])).unwrap(), b"{\"users\":");
+        assert_eq!(
+            QuotaLedger::open(&dir, UploadLimits::default()).usage(),
+            ledger.usage()
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
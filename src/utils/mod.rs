@@ -1,5 +1,7 @@
+pub mod format;
 pub mod parsing;
 
+pub use format::{format_bytes, format_progress, parse_duration, parse_size, to_hex};
 pub use parsing::{
     parse_arguments, replace_full_width_spaces_to_half_width_spaces_if_not_in_quotes,
 };
@@ -0,0 +1,215 @@
+mod common;
+
+use common::{
+    chat_frame, expect_text, file_frame, join_frame, start_server, test_settings, Client,
+};
+use futures_util::{SinkExt, StreamExt};
+use jsonwebtoken::{EncodingKey, Header};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio_tungstenite::connect_async;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::http::{header, HeaderValue, StatusCode};
+use tokio_tungstenite::tungstenite::{self, Message};
+use ws_s::app::AppState;
+use ws_s::auth::jwt::Claims;
+use ws_s::auth::password::hash_password;
+use ws_s::auth::{Authenticator, JwtAuth, PasswordFile, Principal, TokenFile};
+
+async fn start(auth: Arc<dyn Authenticator>) -> (SocketAddr, AppState) {
+    let state = AppState::new(test_settings()).with_auth(auth);
+    (start_server(state.clone()).await, state)
+}
+
+/// 認証情報を付けて接続する。拒否された場合はステータスコードを返す
+async fn connect_with(
+    addr: SocketAddr,
+    query: &str,
+    header: Option<(header::HeaderName, String)>,
+) -> Result<Client, StatusCode> {
+    let mut request = format!("ws://{}/ws{}", addr, query)
+        .into_client_request()
+        .unwrap();
+    if let Some((name, value)) = header {
+        request
+            .headers_mut()
+            .insert(name, HeaderValue::from_str(&value).unwrap());
+    }
+
+    match connect_async(request).await {
+        Ok((mut client, _)) => {
+            match client.next().await {
+                Some(Ok(Message::Text(text))) => assert_eq!(text, "connected(server)"),
+                other => panic!("unexpected greeting: {:?}", other),
+            }
+            Ok(client)
+        }
+        Err(tungstenite::Error::Http(response)) => Err(response.status()),
+        Err(e) => panic!("failed to connect: {}", e),
+    }
+}
+
+/// 接続中のクライアントが `name` で認証された1つだけになるまで待つ
+///
+/// 接続の登録は挨拶を送った後に、前の接続の削除は切断に気付いた後に行われる。
+async fn expect_only(state: &AppState, name: &str) {
+    let expected = vec![Some(Principal::new(name))];
+    let mut principals = Vec::new();
+    for _ in 0..100 {
+        principals = state
+            .manager
+            .ids()
+            .into_iter()
+            .map(|id| state.manager.principal(id))
+            .collect();
+        if principals == expected {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    panic!("expected only {}, got {:?}", name, principals);
+}
+
+#[tokio::test]
+async fn test_token_from_query_header_or_cookie() {
+    let tokens = TokenFile::parse("alice:alice-token\nbob:bob-token\n").unwrap();
+    let (addr, state) = start(Arc::new(tokens)).await;
+
+    assert_eq!(
+        connect_with(addr, "", None).await.err(),
+        Some(StatusCode::UNAUTHORIZED)
+    );
+    assert_eq!(
+        connect_with(addr, "?token=wrong", None).await.err(),
+        Some(StatusCode::UNAUTHORIZED)
+    );
+
+    let client = connect_with(addr, "?token=alice-token", None)
+        .await
+        .unwrap();
+    expect_only(&state, "alice").await;
+    drop(client);
+
+    let header = (header::AUTHORIZATION, "Bearer bob-token".to_string());
+    let client = connect_with(addr, "", Some(header)).await.unwrap();
+    expect_only(&state, "bob").await;
+    drop(client);
+
+    let cookie = (header::COOKIE, "ws_s_token=alice-token".to_string());
+    let _client = connect_with(addr, "", Some(cookie)).await.unwrap();
+    expect_only(&state, "alice").await;
+
+    let metrics = state.metrics.render(&state.manager);
+    assert!(
+        metrics.contains("ws_s_auth_failures_total 2"),
+        "{}",
+        metrics
+    );
+}
+
+#[tokio::test]
+async fn test_jwt() {
+    let (addr, state) = start(Arc::new(JwtAuth::new(b"jwt-secret"))).await;
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let sign = |secret: &[u8], exp: u64| {
+        let claims = Claims {
+            sub: "carol".to_string(),
+            exp,
+        };
+        jsonwebtoken::encode(
+            &Header::default(),
+            &claims,
+            &EncodingKey::from_secret(secret),
+        )
+        .unwrap()
+    };
+
+    let forged = (
+        header::AUTHORIZATION,
+        format!("Bearer {}", sign(b"guessed", now + 60)),
+    );
+    assert_eq!(
+        connect_with(addr, "", Some(forged)).await.err(),
+        Some(StatusCode::UNAUTHORIZED)
+    );
+    let expired = (
+        header::AUTHORIZATION,
+        format!("Bearer {}", sign(b"jwt-secret", now - 3600)),
+    );
+    assert_eq!(
+        connect_with(addr, "", Some(expired)).await.err(),
+        Some(StatusCode::UNAUTHORIZED)
+    );
+
+    let valid = (
+        header::AUTHORIZATION,
+        format!("Bearer {}", sign(b"jwt-secret", now + 60)),
+    );
+    let _client = connect_with(addr, "", Some(valid)).await.unwrap();
+    expect_only(&state, "carol").await;
+}
+
+#[tokio::test]
+async fn test_password() {
+    let users = format!("dave:{}\n", hash_password("correct horse").unwrap());
+    let (addr, state) = start(Arc::new(PasswordFile::parse(&users).unwrap())).await;
+
+    // dave:battery staple
+    let wrong = (
+        header::AUTHORIZATION,
+        "Basic ZGF2ZTpiYXR0ZXJ5IHN0YXBsZQ==".to_string(),
+    );
+    assert_eq!(
+        connect_with(addr, "", Some(wrong)).await.err(),
+        Some(StatusCode::UNAUTHORIZED)
+    );
+    // トークンしか無い場合も断る
+    assert_eq!(
+        connect_with(addr, "?token=x", None).await.err(),
+        Some(StatusCode::UNAUTHORIZED)
+    );
+
+    // dave:correct horse
+    let basic = (
+        header::AUTHORIZATION,
+        "Basic ZGF2ZTpjb3JyZWN0IGhvcnNl".to_string(),
+    );
+    let client = connect_with(addr, "", Some(basic)).await.unwrap();
+    expect_only(&state, "dave").await;
+    drop(client);
+
+    let _client = connect_with(addr, "?user=dave&password=correct%20horse", None)
+        .await
+        .unwrap();
+    expect_only(&state, "dave").await;
+}
+
+#[tokio::test]
+async fn test_authenticated_name_overrides_declared_sender() {
+    let tokens = TokenFile::parse("alice:alice-token\nbob:bob-token\n").unwrap();
+    let (addr, state) = start(Arc::new(tokens)).await;
+    let mut bob = connect_with(addr, "?token=bob-token", None).await.unwrap();
+    bob.send(join_frame("alice", 1)).await.unwrap();
+
+    // 名乗った名前ではなく、認証された名前で配信して記録する
+    bob.send(chat_frame("alice", 1, "hi")).await.unwrap();
+    assert_eq!(expect_text(&mut bob).await, "[Room 1 - bob]: hi");
+    let recent = state.history.recent(1, 10).unwrap();
+    assert_eq!(recent[0].sender, "bob");
+
+    // アップロードも認証された名前の割り当てから使う
+    bob.send(file_frame("alice", 1, "notes.txt", b"hello".to_vec()))
+        .await
+        .unwrap();
+    expect_text(&mut bob).await;
+    let shared = expect_text(&mut bob).await;
+    assert!(shared.starts_with("[Room 1 - bob] shared"), "{}", shared);
+    let usage = state.quota.usage();
+    assert_eq!(usage.users.get("bob"), Some(&5));
+    assert_eq!(usage.users.get("alice"), None);
+    assert_eq!(state.uploads.list_room(1)[0].sender, "bob");
+}
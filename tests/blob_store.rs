@@ -0,0 +1,241 @@
+mod common;
+
+use axum::body::{to_bytes, Body, Bytes};
+use axum::extract::{Request, State};
+use axum::http::{header, Method, StatusCode};
+use axum::response::{IntoResponse, Response};
+use common::{connect, expect_text, file_frame, start_server, temp_dir, test_settings};
+use futures_util::{SinkExt, StreamExt};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use tokio::net::TcpListener;
+use tower::ServiceExt;
+use ws_s::app::{self, AppState};
+use ws_s::upload::blob::{read_all, S3BlobStore, S3Config};
+use ws_s::upload::{BlobStore, FsBlobStore, MemoryBlobStore};
+
+type Objects = Arc<Mutex<HashMap<String, Bytes>>>;
+
+/// S3 の代わり。署名の中身は確かめず、形式だけを見る
+async fn fake_s3(State(objects): State<Objects>, request: Request) -> Response {
+    let signed = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| {
+            value.starts_with("AWS4-HMAC-SHA256 Credential=test-key/")
+                && value.contains("SignedHeaders=host;")
+        });
+    if !signed || !request.headers().contains_key("x-amz-date") {
+        return StatusCode::FORBIDDEN.into_response();
+    }
+
+    let key = request.uri().path().to_string();
+    let method = request.method().clone();
+    let range = request
+        .headers()
+        .get(header::RANGE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("bytes="))
+        .and_then(|value| value.split_once('-'))
+        .map(|(start, end)| {
+            (
+                start.parse::<usize>().unwrap(),
+                end.parse::<usize>().unwrap(),
+            )
+        });
+    let has_length = request.headers().contains_key(header::CONTENT_LENGTH);
+    let body = to_bytes(request.into_body(), usize::MAX).await.unwrap();
+
+    let mut objects = objects.lock().unwrap();
+    match method {
+        Method::PUT if !has_length => StatusCode::LENGTH_REQUIRED.into_response(),
+        Method::PUT => {
+            objects.insert(key, body);
+            StatusCode::OK.into_response()
+        }
+        Method::GET | Method::HEAD => match (objects.get(&key), range) {
+            (Some(data), Some((start, end))) => {
+                let end = end.min(data.len() - 1);
+                (StatusCode::PARTIAL_CONTENT, data.slice(start..end + 1)).into_response()
+            }
+            (Some(data), None) => data.clone().into_response(),
+            (None, _) => StatusCode::NOT_FOUND.into_response(),
+        },
+        Method::DELETE => {
+            objects.remove(&key);
+            StatusCode::NO_CONTENT.into_response()
+        }
+        _ => StatusCode::METHOD_NOT_ALLOWED.into_response(),
+    }
+}
+
+async fn start_fake_s3() -> (SocketAddr, Objects) {
+    let objects = Objects::default();
+    let router = axum::Router::new()
+        .fallback(fake_s3)
+        .with_state(objects.clone());
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, router).await.unwrap() });
+
+    (addr, objects)
+}
+
+fn fake_s3_config(addr: SocketAddr) -> S3Config {
+    S3Config {
+        endpoint: format!("http://{}", addr),
+        bucket: "uploads".to_string(),
+        region: "us-east-1".to_string(),
+        access_key: "test-key".to_string(),
+        secret_key: "test-secret".to_string(),
+        prefix: "ws_s/".to_string(),
+    }
+}
+
+async fn read_range(store: &dyn BlobStore, key: &str, range: std::ops::Range<u64>) -> Vec<u8> {
+    let mut stream = store.get(key, Some(range)).await.unwrap().unwrap();
+    let mut data = Vec::new();
+    while let Some(chunk) = stream.next().await {
+        data.extend_from_slice(&chunk.unwrap());
+    }
+    data
+}
+
+/// どの保存先でも同じように振る舞うこと
+async fn exercise(store: &dyn BlobStore) {
+    let key = "ab/abcdef";
+    assert!(!store.exists(key).await.unwrap());
+    assert!(store.get(key, None).await.unwrap().is_none());
+
+    store
+        .put(key, Bytes::from_static(b"hello, world"))
+        .await
+        .unwrap();
+    assert!(store.exists(key).await.unwrap());
+    assert_eq!(read_all(store, key).await.unwrap().unwrap(), "hello, world");
+    assert_eq!(read_range(store, key, 7..12).await, b"world");
+
+    let path = temp_dir("blob-source").join("source.bin");
+    std::fs::write(&path, b"from a file").unwrap();
+    store.put_file("cd/cdef", &path).await.unwrap();
+    assert_eq!(
+        read_all(store, "cd/cdef").await.unwrap().unwrap(),
+        "from a file"
+    );
+
+    store.delete(key).await.unwrap();
+    assert!(!store.exists(key).await.unwrap());
+    // 無いものを消してもよい
+    store.delete(key).await.unwrap();
+    store.delete("cd/cdef").await.unwrap();
+}
+
+#[tokio::test]
+async fn test_fs_blob_store() {
+    let root = temp_dir("blobs");
+    exercise(&FsBlobStore::new(&root)).await;
+    assert!(!root.join("ab/abcdef").exists());
+}
+
+#[tokio::test]
+async fn test_memory_blob_store() {
+    let store = MemoryBlobStore::default();
+    exercise(&store).await;
+    assert!(store.is_empty());
+}
+
+#[tokio::test]
+async fn test_s3_blob_store_against_local_stand_in() {
+    let (addr, objects) = start_fake_s3().await;
+    let store = S3BlobStore::new(fake_s3_config(addr)).unwrap();
+    exercise(&store).await;
+    assert!(objects.lock().unwrap().is_empty());
+
+    // バケットとキーの前置きはパスに入る
+    store
+        .put("ef/ef01", Bytes::from_static(b"x"))
+        .await
+        .unwrap();
+    assert!(objects
+        .lock()
+        .unwrap()
+        .contains_key("/uploads/ws_s/ef/ef01"));
+}
+
+/// `WS_S_TEST_S3_ENDPOINT` などを設定した場合だけ、本物の S3 互換ストレージで確かめる
+///
+/// ```bash
+/// docker run -p 9000:9000 minio/minio server /data
+/// mc mb local/ws-s-test
+/// WS_S_TEST_S3_ENDPOINT=http://127.0.0.1:9000 WS_S_TEST_S3_BUCKET=ws-s-test \
+///     AWS_ACCESS_KEY_ID=minioadmin AWS_SECRET_ACCESS_KEY=minioadmin cargo test --test blob_store
+/// ```
+#[tokio::test]
+async fn test_s3_blob_store_against_minio() {
+    let env = |name: &str| std::env::var(name).ok();
+    let (Some(endpoint), Some(bucket), Some(access_key), Some(secret_key)) = (
+        env("WS_S_TEST_S3_ENDPOINT"),
+        env("WS_S_TEST_S3_BUCKET"),
+        env("AWS_ACCESS_KEY_ID"),
+        env("AWS_SECRET_ACCESS_KEY"),
+    ) else {
+        eprintln!("WS_S_TEST_S3_ENDPOINT is not set, skipping");
+        return;
+    };
+
+    let store = S3BlobStore::new(S3Config {
+        endpoint,
+        bucket,
+        region: env("WS_S_TEST_S3_REGION").unwrap_or_else(|| "us-east-1".to_string()),
+        access_key,
+        secret_key,
+        prefix: format!("test-{}/", uuid::Uuid::new_v4()),
+    })
+    .unwrap();
+    exercise(&store).await;
+}
+
+#[tokio::test]
+async fn test_server_keeps_uploads_in_object_storage() {
+    let (addr, objects) = start_fake_s3().await;
+    let store = S3BlobStore::new(fake_s3_config(addr)).unwrap();
+    let settings = test_settings();
+    let state = AppState::new(settings.clone()).with_blobs(Arc::new(store));
+    let server = start_server(state.clone()).await;
+
+    let mut client = connect(server).await;
+    client
+        .send(file_frame(
+            "Alice",
+            42,
+            "notes.txt",
+            b"hello, world".to_vec(),
+        ))
+        .await
+        .unwrap();
+    assert!(expect_text(&mut client)
+        .await
+        .contains("saved as notes.txt"));
+    let shared = expect_text(&mut client).await;
+    let id = shared.rsplit(' ').next().unwrap();
+
+    // 中身はオブジェクトストレージにだけある
+    assert_eq!(objects.lock().unwrap().len(), 1);
+    assert!(!settings.upload_dir.join("blobs").exists());
+
+    let response = app::router(state.clone())
+        .oneshot(
+            axum::http::Request::get(format!("/api/files/{}", id))
+                .header(header::RANGE, "bytes=7-11")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+    assert_eq!(response.headers()[header::CONTENT_RANGE], "bytes 7-11/12");
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    assert_eq!(body, "world");
+}
@@ -0,0 +1,292 @@
+mod common;
+
+use common::{connect, expect_error, expect_text, join_frame, start_server, test_settings, Client};
+use futures_util::{SinkExt, StreamExt};
+use message_pack::{
+    BinaryDeserializable, BinarySerializable, ErrorCode, MessageType, UploadAckMessage,
+    UploadBeginMessage, UploadChunkMessage, UploadCommitMessage, UploadId, UploadProgressMessage,
+};
+use sha2::{Digest, Sha256};
+use std::time::Duration;
+use tokio_tungstenite::tungstenite::Message;
+use ws_s::app::{AppState, ServerSettings};
+
+const UPLOAD_ID: UploadId = [9; 16];
+
+fn begin_frame(content: &[u8]) -> Message {
+    begin_frame_as("Alice", content)
+}
+
+fn begin_frame_as(sender: &str, content: &[u8]) -> Message {
+    Message::Binary(
+        UploadBeginMessage {
+            upload_id: UPLOAD_ID,
+            sender: sender.to_string(),
+            room: 42,
+            filename: "big.bin".to_string(),
+            size: content.len() as u64,
+            sha256: Sha256::digest(content).into(),
+        }
+        .to_bytes(),
+    )
+}
+
+fn chunk_frame(offset: usize, data: &[u8]) -> Message {
+    Message::Binary(UploadChunkMessage::new(UPLOAD_ID, offset as u64, data.to_vec()).to_bytes())
+}
+
+/// 受信確認のオフセット。進み具合は読み飛ばす
+async fn expect_ack(client: &mut Client) -> u64 {
+    loop {
+        match client.next().await {
+            Some(Ok(Message::Binary(bytes)))
+                if bytes.first() == Some(&MessageType::UploadProgress.to_bytes()) => {}
+            Some(Ok(Message::Binary(bytes))) => {
+                let ack = UploadAckMessage::from_bytes(&bytes).unwrap();
+                assert_eq!(ack.upload_id, UPLOAD_ID);
+                return ack.offset;
+            }
+            other => panic!("expected upload ack, got {:?}", other),
+        }
+    }
+}
+
+async fn expect_progress(client: &mut Client) -> (u64, u64) {
+    match client.next().await {
+        Some(Ok(Message::Binary(bytes))) => {
+            let progress = UploadProgressMessage::from_bytes(&bytes).unwrap();
+            assert_eq!(progress.upload_id, UPLOAD_ID);
+            (progress.received, progress.total)
+        }
+        other => panic!("expected upload progress, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn test_resume_after_disconnect_and_restart() {
+    let settings = test_settings();
+    let content: Vec<u8> = (0..30_000u32).map(|i| (i % 251) as u8).collect();
+
+    let addr = start_server(AppState::new(settings.clone())).await;
+    let mut client = connect(addr).await;
+    client.send(begin_frame(&content)).await.unwrap();
+    assert_eq!(expect_ack(&mut client).await, 0);
+    client
+        .send(chunk_frame(0, &content[..10_000]))
+        .await
+        .unwrap();
+    assert_eq!(expect_ack(&mut client).await, 10_000);
+
+    // 壊れたチャンクや位置のずれたチャンクは受け付けない
+    let mut corrupted =
+        UploadChunkMessage::new(UPLOAD_ID, 10_000, content[10_000..20_000].to_vec());
+    corrupted.crc32 ^= 1;
+    client
+        .send(Message::Binary(corrupted.to_bytes()))
+        .await
+        .unwrap();
+    assert_eq!(
+        expect_error(&mut client).await.code,
+        ErrorCode::ChecksumMismatch
+    );
+    client
+        .send(chunk_frame(20_000, &content[20_000..]))
+        .await
+        .unwrap();
+    assert_eq!(
+        expect_error(&mut client).await.code,
+        ErrorCode::OffsetMismatch
+    );
+    drop(client);
+
+    // 切断後に同じ ID で begin を送り直すと続きから
+    let mut client = connect(addr).await;
+    client.send(begin_frame(&content)).await.unwrap();
+    assert_eq!(expect_ack(&mut client).await, 10_000);
+    client
+        .send(chunk_frame(10_000, &content[10_000..20_000]))
+        .await
+        .unwrap();
+    assert_eq!(expect_ack(&mut client).await, 20_000);
+    drop(client);
+
+    // 同じアップロード先でサーバーを起動し直しても続きから
+    let state = AppState::new(settings.clone());
+    let addr = start_server(state.clone()).await;
+    let mut client = connect(addr).await;
+    client.send(begin_frame(&content)).await.unwrap();
+    assert_eq!(expect_ack(&mut client).await, 20_000);
+    client
+        .send(chunk_frame(20_000, &content[20_000..]))
+        .await
+        .unwrap();
+    assert_eq!(expect_ack(&mut client).await, 30_000);
+
+    client
+        .send(Message::Binary(
+            UploadCommitMessage {
+                upload_id: UPLOAD_ID,
+            }
+            .to_bytes(),
+        ))
+        .await
+        .unwrap();
+    assert!(expect_text(&mut client)
+        .await
+        .ends_with("bytes transferred. (saved as big.bin)"));
+    assert!(expect_text(&mut client).await.contains("shared big.bin"));
+    let record = state.uploads.list().pop().unwrap();
+    assert_eq!(record.name, "big.bin");
+    assert_eq!(state.uploads.read(&record).await.unwrap().unwrap(), content);
+
+    // 完了した ID には送れない
+    client.send(chunk_frame(0, b"late")).await.unwrap();
+    assert_eq!(
+        expect_error(&mut client).await.code,
+        ErrorCode::UnknownUpload
+    );
+}
+
+#[tokio::test]
+async fn test_commit_with_wrong_hash_is_rejected() {
+    let state = AppState::new(test_settings());
+    let addr = start_server(state.clone()).await;
+    let mut client = connect(addr).await;
+
+    client.send(begin_frame(b"expected")).await.unwrap();
+    assert_eq!(expect_ack(&mut client).await, 0);
+    client.send(chunk_frame(0, b"tampered")).await.unwrap();
+    assert_eq!(expect_ack(&mut client).await, 8);
+    client
+        .send(Message::Binary(
+            UploadCommitMessage {
+                upload_id: UPLOAD_ID,
+            }
+            .to_bytes(),
+        ))
+        .await
+        .unwrap();
+
+    assert_eq!(
+        expect_error(&mut client).await.code,
+        ErrorCode::ChecksumMismatch
+    );
+    assert!(state.uploads.list().is_empty());
+}
+
+#[tokio::test]
+async fn test_progress_is_reported_at_intervals() {
+    let content: Vec<u8> = (0..30_000u32).map(|i| (i % 251) as u8).collect();
+    let commit = || {
+        Message::Binary(
+            UploadCommitMessage {
+                upload_id: UPLOAD_ID,
+            }
+            .to_bytes(),
+        )
+    };
+
+    // 間隔が 0 ならチャンクごとに知らせる
+    let state = AppState::new(ServerSettings {
+        upload_progress_interval: Duration::ZERO,
+        ..test_settings()
+    });
+    let addr = start_server(state.clone()).await;
+    let mut member = connect(addr).await;
+    member.send(join_frame("Bob", 42)).await.unwrap();
+    let mut client = connect(addr).await;
+    client.send(begin_frame(&content)).await.unwrap();
+    assert_eq!(expect_ack(&mut client).await, 0);
+    for offset in [0, 10_000, 20_000] {
+        client
+            .send(chunk_frame(offset, &content[offset..offset + 10_000]))
+            .await
+            .unwrap();
+        assert_eq!(
+            expect_progress(&mut client).await,
+            (offset as u64 + 10_000, 30_000)
+        );
+        assert_eq!(expect_ack(&mut client).await, offset as u64 + 10_000);
+    }
+    client.send(commit()).await.unwrap();
+    assert!(expect_text(&mut client)
+        .await
+        .contains("(saved as big.bin)"));
+
+    // 完了はルームにも知らせる
+    assert!(expect_text(&mut member).await.contains("shared big.bin"));
+
+    // 間隔が長ければ、最後のチャンクの後だけ知らせる
+    let state = AppState::new(ServerSettings {
+        upload_progress_interval: Duration::from_secs(3600),
+        ..test_settings()
+    });
+    let addr = start_server(state).await;
+    let mut client = connect(addr).await;
+    client.send(begin_frame(&content)).await.unwrap();
+    assert_eq!(expect_ack(&mut client).await, 0);
+    client
+        .send(chunk_frame(0, &content[..10_000]))
+        .await
+        .unwrap();
+    assert!(matches!(
+        client.next().await,
+        Some(Ok(Message::Binary(bytes))) if UploadAckMessage::from_bytes(&bytes).is_ok()
+    ));
+    client
+        .send(chunk_frame(10_000, &content[10_000..]))
+        .await
+        .unwrap();
+    assert_eq!(expect_progress(&mut client).await, (30_000, 30_000));
+    assert_eq!(expect_ack(&mut client).await, 30_000);
+}
+
+#[tokio::test]
+async fn test_other_connections_cannot_write_or_commit() {
+    let state = AppState::new(test_settings());
+    let addr = start_server(state.clone()).await;
+    let commit = || {
+        Message::Binary(
+            UploadCommitMessage {
+                upload_id: UPLOAD_ID,
+            }
+            .to_bytes(),
+        )
+    };
+    let mut alice = connect(addr).await;
+    alice.send(begin_frame(b"hello, world")).await.unwrap();
+    assert_eq!(expect_ack(&mut alice).await, 0);
+    alice.send(chunk_frame(0, b"hello, ")).await.unwrap();
+    assert_eq!(expect_ack(&mut alice).await, 7);
+
+    // 同じ ID を知っていても、begin していない接続からは書き込めない
+    let mut mallory = connect(addr).await;
+    mallory.send(chunk_frame(7, b"WORLD")).await.unwrap();
+    assert_eq!(
+        expect_error(&mut mallory).await.code,
+        ErrorCode::UnknownUpload
+    );
+    mallory.send(commit()).await.unwrap();
+    assert_eq!(
+        expect_error(&mut mallory).await.code,
+        ErrorCode::UnknownUpload
+    );
+
+    // 別の送信者として同じ ID で始めても、別のアップロードになる
+    mallory
+        .send(begin_frame_as("Mallory", b"hello, world"))
+        .await
+        .unwrap();
+    assert_eq!(expect_ack(&mut mallory).await, 0);
+
+    alice.send(chunk_frame(7, b"world")).await.unwrap();
+    assert_eq!(expect_ack(&mut alice).await, 12);
+    alice.send(commit()).await.unwrap();
+    assert!(expect_text(&mut alice).await.contains("(saved as big.bin)"));
+    let record = state.uploads.list().pop().unwrap();
+    assert_eq!(record.sender, "Alice");
+    assert_eq!(
+        state.uploads.read(&record).await.unwrap().unwrap(),
+        &b"hello, world"[..]
+    );
+}
@@ -0,0 +1,154 @@
+#![allow(dead_code)]
+
+use futures_util::StreamExt;
+use message_pack::{
+    BinaryDeserializable, BinarySerializable, ErrorMessage, FileTransferMessage, JoinMessage,
+    ListMessage, MessageType, TextMessage,
+};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::net::{TcpListener, TcpStream};
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{connect_async, MaybeTlsStream, WebSocketStream};
+use ws_s::app::{self, AppState, ServerSettings};
+use ws_s::auth::{Role, RolePolicy, TokenFile};
+
+/// `with_admin` で作った状態の管理 API に通るトークン
+pub const ADMIN_TOKEN: &str = "admin-token";
+
+pub type Client = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// テストごとの一時ディレクトリ
+pub fn temp_dir(prefix: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("ws_s-{}-{}", prefix, uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+pub fn test_settings() -> ServerSettings {
+    ServerSettings {
+        upload_dir: temp_dir("uploads"),
+        ..ServerSettings::default()
+    }
+}
+
+/// `state` と中身を共有し、管理 API に `ADMIN_TOKEN` で入れる状態
+///
+/// WebSocket の接続は元の `state` のまま認証せずに済む。
+pub fn with_admin(state: &AppState) -> AppState {
+    state.settings.update(|settings| {
+        settings.roles = RolePolicy::parse("admin:admin\n", Role::Member).unwrap();
+    });
+    let tokens = format!("admin:{}\n", ADMIN_TOKEN);
+    state
+        .clone()
+        .with_auth(Arc::new(TokenFile::parse(&tokens).unwrap()))
+}
+
+/// 管理者のトークンを付けたリクエスト
+pub fn admin_request(method: axum::http::Method, uri: &str) -> axum::http::request::Builder {
+    axum::http::Request::builder()
+        .method(method)
+        .uri(uri)
+        .header(
+            axum::http::header::AUTHORIZATION,
+            format!("Bearer {}", ADMIN_TOKEN),
+        )
+}
+
+/// ランダムなポートでサーバーを起動する
+pub async fn start_server(state: AppState) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        axum::serve(
+            listener,
+            app::router(state).into_make_service_with_connect_info::<SocketAddr>(),
+        )
+        .await
+        .unwrap();
+    });
+
+    addr
+}
+
+pub async fn connect(addr: SocketAddr) -> Client {
+    connect_with_query(addr, "").await
+}
+
+/// `?token=...` のようなクエリを付けて接続する
+pub async fn connect_with_query(addr: SocketAddr, query: &str) -> Client {
+    let (mut client, _) = connect_async(format!("ws://{}/ws{}", addr, query))
+        .await
+        .unwrap();
+
+    // 接続直後の挨拶を読み捨てる
+    match client.next().await {
+        Some(Ok(Message::Text(text))) => assert_eq!(text, "connected(server)"),
+        other => panic!("unexpected greeting: {:?}", other),
+    }
+
+    client
+}
+
+pub async fn expect_text(client: &mut Client) -> String {
+    match client.next().await {
+        Some(Ok(Message::Text(text))) => text,
+        other => panic!("expected text frame, got {:?}", other),
+    }
+}
+
+pub async fn expect_error(client: &mut Client) -> ErrorMessage {
+    match client.next().await {
+        Some(Ok(Message::Binary(bytes))) => ErrorMessage::from_bytes(&bytes).unwrap(),
+        other => panic!("expected error frame, got {:?}", other),
+    }
+}
+
+pub fn chat_frame(sender: &str, room: i32, content: &str) -> Message {
+    Message::Binary(
+        TextMessage {
+            sender: sender.to_string(),
+            room,
+            category: MessageType::Chat,
+            content: content.to_string(),
+        }
+        .to_bytes(),
+    )
+}
+
+pub fn join_frame(sender: &str, room: i32) -> Message {
+    Message::Binary(
+        JoinMessage {
+            sender: sender.to_string(),
+            room,
+        }
+        .to_bytes(),
+    )
+}
+
+pub fn list_frame(sender: &str, room: i32, target: &str) -> Message {
+    Message::Binary(
+        ListMessage {
+            category: MessageType::List,
+            room,
+            target: target.to_string(),
+            sender: sender.to_string(),
+        }
+        .to_bytes(),
+    )
+}
+
+pub fn file_frame(sender: &str, room: i32, filename: &str, content: Vec<u8>) -> Message {
+    Message::Binary(
+        FileTransferMessage {
+            sender: sender.to_string(),
+            filename: filename.to_string(),
+            room,
+            content,
+            category: MessageType::FileTransfer,
+        }
+        .to_bytes(),
+    )
+}
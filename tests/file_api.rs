@@ -0,0 +1,194 @@
+mod common;
+
+use axum::body::{to_bytes, Body};
+use axum::http::{header, Method, Request, StatusCode};
+use common::{
+    admin_request, connect, expect_text, file_frame, join_frame, start_server, test_settings,
+    with_admin, Client,
+};
+use futures_util::SinkExt;
+use message_pack::{BinarySerializable, ListMessage, MessageType};
+use serde_json::Value;
+use std::sync::Arc;
+use tokio_tungstenite::tungstenite::Message;
+use tower::ServiceExt;
+use ws_s::app::{self, AppState};
+use ws_s::upload::MemoryBlobStore;
+
+async fn get(state: &AppState, request: Request<Body>) -> (StatusCode, header::HeaderMap, Vec<u8>) {
+    let response = app::router(state.clone()).oneshot(request).await.unwrap();
+    let status = response.status();
+    let headers = response.headers().clone();
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+
+    (status, headers, body.to_vec())
+}
+
+#[tokio::test]
+async fn test_share_list_and_download() {
+    let state = AppState::new(test_settings());
+    let addr = start_server(state.clone()).await;
+    let mut uploader = connect(addr).await;
+    let mut member = connect(addr).await;
+    member.send(join_frame("Bob", 42)).await.unwrap();
+
+    uploader
+        .send(file_frame(
+            "Alice",
+            42,
+            "notes.txt",
+            b"hello, world".to_vec(),
+        ))
+        .await
+        .unwrap();
+    assert!(expect_text(&mut uploader)
+        .await
+        .contains("saved as notes.txt"));
+
+    // ルームの参加者に共有を知らせる
+    let shared = expect_text(&mut member).await;
+    assert!(
+        shared.starts_with("[Room 42 - Alice] shared notes.txt"),
+        "{}",
+        shared
+    );
+    let id = shared.rsplit(' ').next().unwrap().to_string();
+    assert_eq!(expect_text(&mut uploader).await, shared);
+
+    uploader
+        .send(Message::Binary(
+            ListMessage {
+                sender: "Alice".to_string(),
+                target: "files".to_string(),
+                room: 42,
+                category: MessageType::List,
+            }
+            .to_bytes(),
+        ))
+        .await
+        .unwrap();
+    let listing = expect_text(&mut uploader).await;
+    assert!(
+        listing.starts_with(&format!("{} notes.txt", id)),
+        "{}",
+        listing
+    );
+
+    let (status, _, body) = get(
+        &state,
+        Request::get("/api/files?room=42")
+            .body(Body::empty())
+            .unwrap(),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let files: Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(files["files"][0]["id"], id.as_str());
+    assert_eq!(files["files"][0]["sender"], "Alice");
+
+    let (status, headers, body) = get(
+        &state,
+        Request::get(format!("/api/files/{}", id))
+            .body(Body::empty())
+            .unwrap(),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, b"hello, world");
+    assert!(headers[header::CONTENT_TYPE]
+        .to_str()
+        .unwrap()
+        .starts_with("text/plain"));
+    assert!(headers[header::CONTENT_DISPOSITION]
+        .to_str()
+        .unwrap()
+        .contains("filename=\"notes.txt\""));
+
+    let (status, headers, body) = get(
+        &state,
+        Request::get(format!("/api/files/{}", id))
+            .header(header::RANGE, "bytes=7-11")
+            .body(Body::empty())
+            .unwrap(),
+    )
+    .await;
+    assert_eq!(status, StatusCode::PARTIAL_CONTENT);
+    assert_eq!(body, b"world");
+    assert_eq!(headers[header::CONTENT_RANGE], "bytes 7-11/12");
+
+    let (status, _, _) = get(
+        &state,
+        Request::get("/api/files/..%2F.usage.json")
+            .body(Body::empty())
+            .unwrap(),
+    )
+    .await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+/// `saved as` の応答と共有の知らせを読み、共有された ID を返す
+async fn upload(client: &mut Client, sender: &str, filename: &str, content: &[u8]) -> String {
+    client
+        .send(file_frame(sender, 42, filename, content.to_vec()))
+        .await
+        .unwrap();
+    let saved = expect_text(client).await;
+    assert!(saved.contains("saved as"), "{}", saved);
+    let shared = expect_text(client).await;
+    shared.rsplit(' ').next().unwrap().to_string()
+}
+
+#[tokio::test]
+async fn test_identical_uploads_share_one_blob() {
+    let blobs = Arc::new(MemoryBlobStore::default());
+    let state = AppState::new(test_settings()).with_blobs(blobs.clone());
+    let addr = start_server(state.clone()).await;
+    let mut client = connect(addr).await;
+
+    let first = upload(&mut client, "Alice", "slides.pdf", b"same bytes").await;
+    let second = upload(&mut client, "Bob", "copy.pdf", b"same bytes").await;
+    assert_ne!(first, second);
+
+    let record = state.uploads.get(&first).unwrap();
+    assert_eq!(blobs.len(), 1);
+    assert_eq!(state.uploads.refs(&record.sha256), 2);
+    assert_eq!(state.quota.usage().users["Alice"], 10);
+
+    // 片方を消してももう片方はダウンロードできる
+    let delete = |id: &str| {
+        admin_request(Method::DELETE, &format!("/api/admin/files/{}", id))
+            .body(Body::empty())
+            .unwrap()
+    };
+    let admin = with_admin(&state);
+    let (status, _, body) = get(&admin, delete(&first)).await;
+    assert_eq!(status, StatusCode::OK);
+    let deleted: Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(deleted["name"], "slides.pdf");
+    assert_eq!(state.quota.usage().users["Alice"], 0);
+
+    let (status, _, _) = get(
+        &state,
+        Request::get(format!("/api/files/{}", first))
+            .body(Body::empty())
+            .unwrap(),
+    )
+    .await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    let (status, _, body) = get(
+        &state,
+        Request::get(format!("/api/files/{}", second))
+            .body(Body::empty())
+            .unwrap(),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, b"same bytes");
+
+    // 最後の参照を消すと blob も消える
+    let (status, _, _) = get(&admin, delete(&second)).await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(blobs.is_empty());
+    let (status, _, _) = get(&admin, delete(&second)).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
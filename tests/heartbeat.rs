@@ -0,0 +1,69 @@
+mod common;
+
+use common::{chat_frame, connect, start_server, test_settings, Client};
+use futures_util::{SinkExt, StreamExt};
+use std::time::Duration;
+use tokio::time::{timeout, Instant};
+use tokio_tungstenite::tungstenite::Message;
+use ws_s::app::{AppState, ServerSettings};
+use ws_s::heartbeat::HeartbeatSettings;
+
+/// Ping は読み飛ばして次のテキストを待つ。読んでいる間は tungstenite が Pong を返す
+async fn next_text(client: &mut Client, pings: &mut usize) -> String {
+    loop {
+        match timeout(Duration::from_secs(5), client.next()).await {
+            Ok(Some(Ok(Message::Ping(_)))) => *pings += 1,
+            Ok(Some(Ok(Message::Text(text)))) => return text,
+            other => panic!("expected text frame, got {:?}", other),
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_unresponsive_client_is_reaped() {
+    let settings = ServerSettings {
+        heartbeat: HeartbeatSettings {
+            interval: Duration::from_millis(100),
+            timeout: Duration::from_millis(100),
+        },
+        ..test_settings()
+    };
+    let state = AppState::new(settings);
+    let addr = start_server(state.clone()).await;
+    let mut live = connect(addr).await;
+    // 挨拶の後は読まないので、Pong を返さない
+    let _dead = connect(addr).await;
+
+    let mut pings = 0;
+    let leave = next_text(&mut live, &mut pings).await;
+    assert!(
+        leave.starts_with("User ") && leave.ends_with(" has left the chat."),
+        "{}",
+        leave
+    );
+    assert_eq!(state.manager.queue_stats().len(), 1);
+
+    // 応答している接続は、何度 Ping されても残る
+    let until = Instant::now() + Duration::from_millis(500);
+    while let Ok(message) = tokio::time::timeout_at(until, live.next()).await {
+        match message {
+            Some(Ok(Message::Ping(_))) => pings += 1,
+            other => panic!("expected ping, got {:?}", other),
+        }
+    }
+    assert!(pings >= 3, "{}", pings);
+    live.send(chat_frame("Alice", 1, "still here"))
+        .await
+        .unwrap();
+    assert_eq!(
+        next_text(&mut live, &mut pings).await,
+        "[Room 1 - Alice]: still here"
+    );
+
+    let metrics = state.metrics.render(&state.manager);
+    assert!(
+        metrics.contains("ws_s_idle_disconnects_total 1"),
+        "{}",
+        metrics
+    );
+}
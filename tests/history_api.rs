@@ -0,0 +1,80 @@
+mod common;
+
+use axum::body::{to_bytes, Body};
+use axum::http::{Request, StatusCode};
+use common::test_settings;
+use serde_json::Value;
+use tower::ServiceExt;
+use ws_s::app::{self, AppState};
+
+async fn get(state: &AppState, uri: &str) -> (StatusCode, Value) {
+    let response = app::router(state.clone())
+        .oneshot(Request::get(uri).body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    let status = response.status();
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+
+    (status, serde_json::from_slice(&body).unwrap())
+}
+
+fn contents(page: &Value) -> Vec<&str> {
+    page["messages"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|m| m["content"].as_str().unwrap())
+        .collect()
+}
+
+#[tokio::test]
+async fn test_paginate_room_history() {
+    let state = AppState::new(test_settings());
+    for i in 1..=7 {
+        state
+            .history
+            .append(42, "Alice", &format!("m{}", i))
+            .unwrap();
+        state.history.append(7, "Bob", "other room").unwrap();
+    }
+
+    let (status, latest) = get(&state, "/api/rooms/42/messages?limit=3").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(contents(&latest), vec!["m5", "m6", "m7"]);
+    assert_eq!(latest["has_newer"], false);
+
+    let prev = latest["prev_cursor"].as_str().unwrap();
+    let (_, older) = get(
+        &state,
+        &format!("/api/rooms/42/messages?limit=3&before={}", prev),
+    )
+    .await;
+    assert_eq!(contents(&older), vec!["m2", "m3", "m4"]);
+
+    let prev = older["prev_cursor"].as_str().unwrap();
+    let (_, oldest) = get(
+        &state,
+        &format!("/api/rooms/42/messages?limit=3&before={}", prev),
+    )
+    .await;
+    assert_eq!(contents(&oldest), vec!["m1"]);
+    assert!(oldest["prev_cursor"].is_null());
+
+    // 新着の確認
+    let next = latest["next_cursor"].as_str().unwrap().to_string();
+    state.history.append(42, "Alice", "m8").unwrap();
+    let (_, newer) = get(&state, &format!("/api/rooms/42/messages?after={}", next)).await;
+    assert_eq!(contents(&newer), vec!["m8"]);
+}
+
+#[tokio::test]
+async fn test_rejects_invalid_cursor() {
+    let state = AppState::new(test_settings());
+
+    let (status, body) = get(&state, "/api/rooms/42/messages?before=12").await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert!(body["error"].as_str().unwrap().contains("before"));
+
+    let (status, _) = get(&state, "/api/rooms/42/messages?limit=0").await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+}
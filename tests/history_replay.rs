@@ -0,0 +1,59 @@
+mod common;
+
+use common::{chat_frame, connect, expect_text, join_frame, start_server, test_settings};
+use futures_util::SinkExt;
+use std::sync::Arc;
+use std::time::Duration;
+use ws_s::app::{AppState, ServerSettings};
+use ws_s::history::{HistoryStore, MemoryHistory};
+
+#[tokio::test]
+async fn test_join_replays_recent_messages_of_the_room() {
+    let history = Arc::new(MemoryHistory::default());
+    let state = AppState::new(ServerSettings {
+        history_replay: 3,
+        ..test_settings()
+    })
+    .with_history(history.clone());
+    let addr = start_server(state).await;
+
+    let mut alice = connect(addr).await;
+    alice.send(join_frame("Alice", 1)).await.unwrap();
+    for i in 0..5 {
+        alice
+            .send(chat_frame("Alice", 1, &format!("hello {}", i)))
+            .await
+            .unwrap();
+        assert_eq!(
+            expect_text(&mut alice).await,
+            format!("[Room 1 - Alice]: hello {}", i)
+        );
+    }
+    alice
+        .send(chat_frame("Alice", 2, "elsewhere"))
+        .await
+        .unwrap();
+
+    // 後から参加したクライアントには、そのルームの直近の3件だけが届く
+    let mut bob = connect(addr).await;
+    bob.send(join_frame("Bob", 1)).await.unwrap();
+    for i in 2..5 {
+        assert_eq!(
+            expect_text(&mut bob).await,
+            format!("[Room 1 - Alice]: hello {}", i)
+        );
+    }
+
+    // 別のルームのメッセージは届かない
+    alice
+        .send(chat_frame("Alice", 2, "not for bob"))
+        .await
+        .unwrap();
+    alice.send(chat_frame("Alice", 1, "for bob")).await.unwrap();
+    let next = tokio::time::timeout(Duration::from_secs(5), expect_text(&mut bob))
+        .await
+        .unwrap();
+    assert_eq!(next, "[Room 1 - Alice]: for bob");
+
+    assert_eq!(history.recent(2, 10).unwrap().len(), 2);
+}
@@ -0,0 +1,133 @@
+mod common;
+
+use axum::body::{to_bytes, Body};
+use axum::http::{Method, StatusCode};
+use common::{
+    admin_request, chat_frame, connect, expect_text, join_frame, start_server, test_settings,
+    with_admin,
+};
+use futures_util::SinkExt;
+use std::net::SocketAddr;
+use tokio::net::TcpListener;
+use tower::ServiceExt;
+use ws_s::app::{self, AppState};
+use ws_s::listener::{Listener, RouteSet};
+
+async fn status(state: &AppState, routes: RouteSet, uri: &str) -> StatusCode {
+    let request = admin_request(Method::GET, uri).body(Body::empty()).unwrap();
+    app::router_for(state.clone(), routes)
+        .oneshot(request)
+        .await
+        .unwrap()
+        .status()
+}
+
+fn serve(listener: TcpListener, state: AppState, routes: RouteSet) -> SocketAddr {
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        axum::serve(
+            listener,
+            app::router_for(state, routes).into_make_service_with_connect_info::<SocketAddr>(),
+        )
+        .await
+        .unwrap();
+    });
+    addr
+}
+
+async fn bind(addr: &str) -> Vec<TcpListener> {
+    addr.parse::<Listener>().unwrap().bind().await.unwrap()
+}
+
+#[tokio::test]
+async fn test_route_sets() {
+    let state = with_admin(&AppState::new(test_settings()));
+    let cases = [
+        (RouteSet::All, "/api/files", StatusCode::OK),
+        (RouteSet::All, "/api/metrics", StatusCode::OK),
+        (RouteSet::All, "/api/admin/usage", StatusCode::OK),
+        (RouteSet::Public, "/api/files", StatusCode::OK),
+        (RouteSet::Public, "/api/metrics", StatusCode::NOT_FOUND),
+        (RouteSet::Public, "/api/admin/usage", StatusCode::NOT_FOUND),
+        (RouteSet::Admin, "/api/health.json", StatusCode::OK),
+        (RouteSet::Admin, "/api/metrics", StatusCode::OK),
+        (RouteSet::Admin, "/api/admin/usage", StatusCode::OK),
+        (RouteSet::Admin, "/api/files", StatusCode::NOT_FOUND),
+        (RouteSet::Admin, "/ws", StatusCode::NOT_FOUND),
+    ];
+    for (routes, uri, expected) in cases {
+        assert_eq!(
+            status(&state, routes, uri).await,
+            expected,
+            "{} {}",
+            routes,
+            uri
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_metrics_hide_connection_ids() {
+    let state = AppState::new(test_settings());
+    let addr = start_server(state.clone()).await;
+    let _alice = connect(addr).await;
+    let _bob = connect(addr).await;
+    // 挨拶は一覧に加える前に送られる
+    while state.manager.queue_stats().len() < 2 {
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+    }
+    let ids: Vec<_> = state
+        .manager
+        .queue_stats()
+        .iter()
+        .map(|stat| stat.id.to_string())
+        .collect();
+    assert_eq!(ids.len(), 2);
+
+    // 認証情報を付けずに読む
+    let request = axum::http::Request::get("/api/metrics")
+        .body(Body::empty())
+        .unwrap();
+    let response = app::router_for(state, RouteSet::All)
+        .oneshot(request)
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let body = String::from_utf8(body.to_vec()).unwrap();
+    for id in &ids {
+        assert!(!body.contains(id.as_str()), "{}", body);
+    }
+    assert!(body.contains("\nws_s_connections 2\n"), "{}", body);
+    assert!(body.contains("\nws_s_send_queue_depth 0\n"), "{}", body);
+    assert!(body.contains("\nws_s_send_queue_max_depth 0\n"), "{}", body);
+}
+
+#[tokio::test]
+async fn test_ipv4_and_ipv6_on_one_port() {
+    let state = AppState::new(test_settings());
+    let v6 = bind("[::1]:0").await.remove(0);
+    let port = v6.local_addr().unwrap().port();
+    let v4 = bind(&format!("127.0.0.1:{}", port)).await.remove(0);
+
+    let v6 = serve(v6, state.clone(), RouteSet::All);
+    let v4 = serve(v4, state.clone(), RouteSet::Public);
+    assert!(v6.is_ipv6() && v4.is_ipv4());
+
+    // 別の待ち受けに繋いだクライアントにも同じルームのメッセージが届く
+    let mut bob = connect(v4).await;
+    bob.send(join_frame("Bob", 42)).await.unwrap();
+    let mut alice = connect(v6).await;
+    alice.send(chat_frame("Alice", 42, "hello")).await.unwrap();
+    assert_eq!(expect_text(&mut bob).await, "[Room 42 - Alice]: hello");
+}
+
+#[tokio::test]
+async fn test_bind_hostname() {
+    let listeners = bind("localhost:0").await;
+    assert!(!listeners.is_empty());
+    for listener in listeners {
+        let addr = listener.local_addr().unwrap();
+        assert!(addr.ip().is_loopback(), "{}", addr);
+    }
+}
@@ -0,0 +1,111 @@
+mod common;
+
+use common::{chat_frame, connect, expect_error, expect_text, start_server, test_settings};
+use futures_util::{SinkExt, StreamExt};
+use message_pack::{BinarySerializable, ErrorCode, FileTransferMessage, ListMessage, MessageType};
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::tungstenite::Message;
+use ws_s::app::{AppState, ServerSettings};
+
+fn settings(max_protocol_errors: u32) -> ServerSettings {
+    ServerSettings {
+        max_protocol_errors,
+        ..test_settings()
+    }
+}
+
+#[tokio::test]
+async fn test_garbage_frames_get_error_replies() {
+    let addr = start_server(AppState::new(settings(100))).await;
+    let mut client = connect(addr).await;
+
+    let truncated_chat = match chat_frame("Alice", 42, "truncated") {
+        Message::Binary(bytes) => bytes[..8].to_vec(),
+        _ => unreachable!(),
+    };
+    let cases: Vec<(Vec<u8>, ErrorCode)> = vec![
+        (vec![], ErrorCode::EmptyFrame),
+        (vec![0xff, 0x00, 0x01], ErrorCode::UnknownCategory),
+        (vec![0x00], ErrorCode::UnknownCategory),
+        (vec![0x01], ErrorCode::MalformedFrame),
+        (truncated_chat, ErrorCode::MalformedFrame),
+        (
+            vec![0x01, 0, 0, 0, 42, 2, 0xff, 0xfe, 0, 0, 0],
+            ErrorCode::MalformedFrame,
+        ),
+        (
+            vec![0x03, 0, 0, 0, 42, 0, 0, 0xff, 0xff, 0xff, 0xff],
+            ErrorCode::MalformedFrame,
+        ),
+        (vec![0x04, 0, 0], ErrorCode::MalformedFrame),
+        (vec![0x06, 0, 0, 0], ErrorCode::MalformedFrame),
+        (
+            ListMessage {
+                sender: "Alice".to_string(),
+                target: "nothing".to_string(),
+                room: 42,
+                category: MessageType::List,
+            }
+            .to_bytes(),
+            ErrorCode::InvalidTarget,
+        ),
+        (
+            FileTransferMessage {
+                sender: "Alice".to_string(),
+                filename: "../../etc/cron.d/x".to_string(),
+                room: 42,
+                content: b"* * * * * root evil".to_vec(),
+                category: MessageType::FileTransfer,
+            }
+            .to_bytes(),
+            ErrorCode::InvalidFilename,
+        ),
+    ];
+
+    for (frame, code) in cases {
+        client.send(Message::Binary(frame.clone())).await.unwrap();
+        let error = expect_error(&mut client).await;
+        assert_eq!(error.code, code, "frame {:?}: {}", frame, error.reason);
+        assert!(!error.reason.is_empty());
+    }
+
+    // エラーの後も接続は生きている
+    client
+        .send(chat_frame("Alice", 42, "still alive"))
+        .await
+        .unwrap();
+    assert_eq!(
+        expect_text(&mut client).await,
+        "[Room 42 - Alice]: still alive"
+    );
+}
+
+#[tokio::test]
+async fn test_error_budget_closes_connection() {
+    let addr = start_server(AppState::new(settings(3))).await;
+    let mut offender = connect(addr).await;
+    let mut bystander = connect(addr).await;
+
+    for _ in 0..3 {
+        offender.send(Message::Binary(vec![])).await.unwrap();
+        assert_eq!(
+            expect_error(&mut offender).await.code,
+            ErrorCode::EmptyFrame
+        );
+    }
+
+    match offender.next().await {
+        Some(Ok(Message::Close(Some(frame)))) => assert_eq!(frame.code, CloseCode::Policy),
+        other => panic!("expected close frame, got {:?}", other),
+    }
+
+    // 他のクライアントには影響しない
+    bystander
+        .send(chat_frame("Alice", 42, "hello"))
+        .await
+        .unwrap();
+    assert_eq!(
+        expect_text(&mut bystander).await,
+        "[Room 42 - Alice]: hello"
+    );
+}
@@ -0,0 +1,166 @@
+mod common;
+
+use axum::body::{to_bytes, Body};
+use axum::http::{header, Request, StatusCode};
+use common::{connect, expect_error, expect_text, file_frame, start_server, test_settings};
+use futures_util::SinkExt;
+use image::{ImageBuffer, ImageFormat, Rgb};
+use message_pack::{BinarySerializable, ErrorCode, UploadBeginMessage, UploadChunkMessage};
+use sha2::{Digest, Sha256};
+use std::io::Cursor;
+use tokio_tungstenite::tungstenite::Message;
+use tower::ServiceExt;
+use ws_s::app::{self, AppState, ServerSettings};
+
+/// 64bit Linux の ELF ヘッダー
+fn elf() -> Vec<u8> {
+    let mut elf = b"\x7fELF\x02\x01\x01\0\0\0\0\0\0\0\0\0\x02\0\x3e\0".to_vec();
+    elf.resize(64, 0);
+    elf
+}
+
+fn png(width: u32, height: u32) -> Vec<u8> {
+    let image = ImageBuffer::from_pixel(width, height, Rgb([0u8, 128, 255]));
+    let mut png = Vec::new();
+    image
+        .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+        .unwrap();
+    png
+}
+
+async fn get(state: &AppState, uri: String) -> (StatusCode, header::HeaderMap, Vec<u8>) {
+    let response = app::router(state.clone())
+        .oneshot(Request::get(uri).body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    let status = response.status();
+    let headers = response.headers().clone();
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+
+    (status, headers, body.to_vec())
+}
+
+#[tokio::test]
+async fn test_images_get_sniffed_type_and_thumbnail() {
+    let state = AppState::new(test_settings());
+    let addr = start_server(state.clone()).await;
+    let mut client = connect(addr).await;
+
+    // 拡張子ではなく中身で判断する
+    client
+        .send(file_frame("Alice", 42, "photo.txt", png(800, 400)))
+        .await
+        .unwrap();
+    assert!(expect_text(&mut client)
+        .await
+        .contains("saved as photo.txt"));
+    let photo = expect_text(&mut client).await;
+    let photo = photo.rsplit(' ').next().unwrap();
+
+    client
+        .send(file_frame("Alice", 42, "notes.txt", b"hello".to_vec()))
+        .await
+        .unwrap();
+    assert!(expect_text(&mut client)
+        .await
+        .contains("saved as notes.txt"));
+    let notes = expect_text(&mut client).await;
+    let notes = notes.rsplit(' ').next().unwrap();
+
+    let record = state.uploads.get(photo).unwrap();
+    assert_eq!(record.content_type, "image/png");
+    assert!(record.thumbnail);
+    let record = state.uploads.get(notes).unwrap();
+    assert_eq!(record.content_type, "text/plain");
+    assert!(!record.thumbnail);
+
+    let (status, headers, body) = get(&state, format!("/api/files/{}/thumbnail", photo)).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(headers[header::CONTENT_TYPE], "image/png");
+    let thumbnail = image::load_from_memory(&body).unwrap();
+    assert_eq!((thumbnail.width(), thumbnail.height()), (256, 128));
+
+    let (status, _, _) = get(&state, format!("/api/files/{}/thumbnail", notes)).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+
+    // 本体は検出した種類で返す
+    let (_, headers, _) = get(&state, format!("/api/files/{}", photo)).await;
+    assert_eq!(headers[header::CONTENT_TYPE], "image/png");
+    assert_eq!(headers[header::X_CONTENT_TYPE_OPTIONS], "nosniff");
+}
+
+#[tokio::test]
+async fn test_executables_can_be_refused() {
+    let state = AppState::new(ServerSettings {
+        refuse_executables: true,
+        ..test_settings()
+    });
+    let addr = start_server(state.clone()).await;
+    let mut client = connect(addr).await;
+
+    client
+        .send(file_frame("Mallory", 42, "cat.jpg", elf()))
+        .await
+        .unwrap();
+    let error = expect_error(&mut client).await;
+    assert_eq!(error.code, ErrorCode::FileTypeRejected);
+    assert!(
+        error.reason.contains("application/x-executable"),
+        "{}",
+        error.reason
+    );
+
+    client
+        .send(file_frame(
+            "Mallory",
+            42,
+            "run.sh",
+            b"#!/bin/sh\necho hi\n".to_vec(),
+        ))
+        .await
+        .unwrap();
+    assert_eq!(
+        expect_error(&mut client).await.code,
+        ErrorCode::FileTypeRejected
+    );
+
+    // 分割アップロードは最初のチャンクで断る
+    let upload_id = [7; 16];
+    client
+        .send(Message::Binary(
+            UploadBeginMessage {
+                upload_id,
+                sender: "Mallory".to_string(),
+                room: 42,
+                filename: "tool".to_string(),
+                size: elf().len() as u64,
+                sha256: Sha256::digest(elf()).into(),
+            }
+            .to_bytes(),
+        ))
+        .await
+        .unwrap();
+    let _ack = futures_util::StreamExt::next(&mut client).await;
+    client
+        .send(Message::Binary(
+            UploadChunkMessage::new(upload_id, 0, elf()).to_bytes(),
+        ))
+        .await
+        .unwrap();
+    assert_eq!(
+        expect_error(&mut client).await.code,
+        ErrorCode::FileTypeRejected
+    );
+
+    assert!(state.uploads.list().is_empty());
+    assert_eq!(
+        state
+            .quota
+            .usage()
+            .users
+            .get("Mallory")
+            .copied()
+            .unwrap_or(0),
+        0
+    );
+}
@@ -0,0 +1,265 @@
+mod common;
+
+use axum::body::{to_bytes, Body};
+use axum::http::{header, Method, Request};
+use common::{
+    admin_request, chat_frame, connect, connect_with_query, expect_error, expect_text, join_frame,
+    list_frame, start_server, temp_dir, test_settings, with_admin, Client,
+};
+use futures_util::{SinkExt, StreamExt};
+use message_pack::{BinarySerializable, ErrorCode, ModerationAction, ModerationMessage};
+use serde_json::{json, Value};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio_tungstenite::connect_async;
+use tokio_tungstenite::tungstenite::http::StatusCode;
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::tungstenite::{self, Message};
+use tower::ServiceExt;
+use ws_s::app::{self, AppState, ServerSettings};
+use ws_s::auth::{Role, RolePolicy, TokenFile};
+use ws_s::moderation::BanList;
+
+const TOKENS: &str = "mia:mia-token\nbob:bob-token\n";
+
+fn moderation_frame(action: ModerationAction, target: &str, duration_secs: u64) -> Message {
+    Message::Binary(
+        ModerationMessage {
+            action,
+            sender: "mia".to_string(),
+            room: 1,
+            target: target.to_string(),
+            duration_secs,
+            reason: "spam".to_string(),
+        }
+        .to_bytes(),
+    )
+}
+
+/// 断られた場合はステータスコードを返す
+async fn try_connect(addr: SocketAddr, query: &str) -> Result<Client, StatusCode> {
+    match connect_async(format!("ws://{}/ws{}", addr, query)).await {
+        Ok((mut client, _)) => {
+            client.next().await;
+            Ok(client)
+        }
+        Err(tungstenite::Error::Http(response)) => Err(response.status()),
+        Err(e) => panic!("failed to connect: {}", e),
+    }
+}
+
+/// 接続の登録は挨拶の後なので、1往復して登録を待つ
+async fn settle(client: &mut Client) {
+    client.send(list_frame("", 1, "files")).await.unwrap();
+    expect_text(client).await;
+}
+
+async fn expect_close(client: &mut Client) -> String {
+    match client.next().await {
+        Some(Ok(Message::Close(Some(frame)))) => {
+            assert_eq!(frame.code, CloseCode::Policy);
+            frame.reason.into_owned()
+        }
+        other => panic!("expected close frame, got {:?}", other),
+    }
+}
+
+async fn admin(state: &AppState, method: Method, uri: &str, body: Option<Value>) -> Value {
+    let request = admin_request(method, uri)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(body.map_or_else(Body::empty, |body| Body::from(body.to_string())))
+        .unwrap();
+    let response = app::router(with_admin(state))
+        .oneshot(request)
+        .await
+        .unwrap();
+    let status = response.status();
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    assert!(
+        status.is_success(),
+        "{}: {}",
+        status,
+        String::from_utf8_lossy(&body)
+    );
+    serde_json::from_slice(&body).unwrap()
+}
+
+#[tokio::test]
+async fn test_moderator_kicks_and_bans_over_websocket() {
+    let settings = ServerSettings {
+        roles: RolePolicy::parse("mia:moderator\n", Role::Member).unwrap(),
+        ..test_settings()
+    };
+    let state = AppState::new(settings).with_auth(Arc::new(TokenFile::parse(TOKENS).unwrap()));
+    let addr = start_server(state.clone()).await;
+    let mut mia = connect_with_query(addr, "?token=mia-token").await;
+    let mut bob = connect_with_query(addr, "?token=bob-token").await;
+
+    // メンバーはキックできない
+    bob.send(moderation_frame(ModerationAction::Kick, "user:mia", 0))
+        .await
+        .unwrap();
+    assert_eq!(
+        expect_error(&mut bob).await.code,
+        ErrorCode::PermissionDenied
+    );
+
+    // 認証した接続は別の名前を名乗っても認証された名前になる
+    bob.send(join_frame("bobby", 1)).await.unwrap();
+    settle(&mut bob).await;
+    mia.send(moderation_frame(ModerationAction::Kick, "name:bobby", 0))
+        .await
+        .unwrap();
+    assert_eq!(
+        expect_text(&mut mia).await,
+        "kicked 0 connection(s) matching name:bobby"
+    );
+    mia.send(moderation_frame(ModerationAction::Kick, "name:bob", 0))
+        .await
+        .unwrap();
+    assert_eq!(
+        expect_text(&mut mia).await,
+        "kicked 1 connection(s) matching name:bob"
+    );
+    assert_eq!(expect_close(&mut bob).await, "kicked: spam");
+
+    // キックなら繋ぎ直せるが、BAN すると断る
+    let mut bob = connect_with_query(addr, "?token=bob-token").await;
+    settle(&mut bob).await;
+    mia.send(moderation_frame(ModerationAction::Ban, "user:bob", 3600))
+        .await
+        .unwrap();
+    assert_eq!(
+        expect_text(&mut mia).await,
+        "banned user:bob for 3600s, closed 1 connection(s)"
+    );
+    assert_eq!(expect_close(&mut bob).await, "banned: spam");
+    assert_eq!(
+        try_connect(addr, "?token=bob-token").await.err(),
+        Some(StatusCode::FORBIDDEN)
+    );
+    let ban = &state.bans.list()[0];
+    assert_eq!(ban.by.as_deref(), Some("mia"));
+    assert!(ban.expires_at.is_some());
+
+    mia.send(moderation_frame(ModerationAction::Unban, "user:bob", 0))
+        .await
+        .unwrap();
+    assert_eq!(expect_text(&mut mia).await, "unbanned user:bob");
+    assert!(try_connect(addr, "?token=bob-token").await.is_ok());
+
+    let metrics = state.metrics.render(&state.manager);
+    assert!(
+        metrics.contains("ws_s_kicked_connections_total 2"),
+        "{}",
+        metrics
+    );
+    assert!(
+        metrics.contains("ws_s_banned_connections_total 1"),
+        "{}",
+        metrics
+    );
+}
+
+#[tokio::test]
+async fn test_admin_api_bans_persist() {
+    let path = temp_dir("bans").join("bans.json");
+    let state = AppState::new(test_settings()).with_bans(Arc::new(BanList::open(&path).unwrap()));
+    let addr = start_server(state.clone()).await;
+    let mut client = connect(addr).await;
+    settle(&mut client).await;
+
+    let body = admin(
+        &state,
+        Method::POST,
+        "/api/admin/bans",
+        Some(json!({"target": "127.0.0.1", "reason": "flood", "duration": "1h"})),
+    )
+    .await;
+    assert_eq!(body["kicked"], 1);
+    assert_eq!(body["ban"]["target"], "ip:127.0.0.1");
+    assert_eq!(expect_close(&mut client).await, "banned: flood");
+    assert_eq!(
+        try_connect(addr, "").await.err(),
+        Some(StatusCode::FORBIDDEN)
+    );
+
+    // 再起動しても残る
+    let reopened = BanList::open(&path).unwrap();
+    assert_eq!(reopened.list().len(), 1);
+    let listed = admin(&state, Method::GET, "/api/admin/bans", None).await;
+    assert_eq!(listed[0]["reason"], "flood");
+    assert_eq!(listed[0]["by"], "admin");
+
+    admin(&state, Method::DELETE, "/api/admin/bans/ip:127.0.0.1", None).await;
+    assert!(BanList::open(&path).unwrap().list().is_empty());
+
+    // 名前の BAN は名乗った時に切る
+    admin(
+        &state,
+        Method::POST,
+        "/api/admin/bans",
+        Some(json!({"target": "name:troll"})),
+    )
+    .await;
+    let mut troll = connect(addr).await;
+    troll.send(chat_frame("troll", 1, "hi")).await.unwrap();
+    assert_eq!(expect_close(&mut troll).await, "banned");
+
+    let mut client = connect(addr).await;
+    settle(&mut client).await;
+    let body = admin(
+        &state,
+        Method::POST,
+        "/api/admin/kick",
+        Some(json!({"target": "ip:127.0.0.1"})),
+    )
+    .await;
+    assert_eq!(body["kicked"], 1);
+    assert_eq!(expect_close(&mut client).await, "kicked");
+}
+
+#[tokio::test]
+async fn test_admin_api_requires_moderator() {
+    let settings = ServerSettings {
+        roles: RolePolicy::parse("mia:moderator\n", Role::Member).unwrap(),
+        ..test_settings()
+    };
+    let anonymous = AppState::new(settings);
+    let state = anonymous
+        .clone()
+        .with_auth(Arc::new(TokenFile::parse(TOKENS).unwrap()));
+    let ban = |token: Option<&str>| {
+        let mut request =
+            Request::post("/api/admin/bans").header(header::CONTENT_TYPE, "application/json");
+        if let Some(token) = token {
+            request = request.header(header::AUTHORIZATION, format!("Bearer {}", token));
+        }
+        request
+            .body(Body::from(json!({"target": "user:bob"}).to_string()))
+            .unwrap()
+    };
+    let status = |state: &AppState, request: Request<Body>| {
+        let router = app::router(state.clone());
+        async move { router.oneshot(request).await.unwrap().status() }
+    };
+
+    // 認証しない設定では誰も使えない
+    assert_eq!(
+        status(&anonymous, ban(None)).await,
+        StatusCode::UNAUTHORIZED
+    );
+    assert_eq!(status(&state, ban(None)).await, StatusCode::UNAUTHORIZED);
+    assert_eq!(
+        status(&state, ban(Some("wrong"))).await,
+        StatusCode::UNAUTHORIZED
+    );
+    assert_eq!(
+        status(&state, ban(Some("bob-token"))).await,
+        StatusCode::FORBIDDEN
+    );
+    assert!(state.bans.list().is_empty());
+
+    assert_eq!(status(&state, ban(Some("mia-token"))).await, StatusCode::OK);
+    assert_eq!(state.bans.list()[0].by.as_deref(), Some("mia"));
+}
@@ -0,0 +1,103 @@
+mod common;
+
+use axum::body::Body;
+use axum::http::{header, Method, Request};
+use common::{connect, start_server, test_settings};
+use std::net::SocketAddr;
+use tokio_tungstenite::connect_async;
+use tokio_tungstenite::tungstenite;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::http::{HeaderValue, StatusCode};
+use tower::ServiceExt;
+use ws_s::app::{self, AppState, ServerSettings};
+use ws_s::origin::OriginPolicy;
+
+const ALLOWED: &str = "https://chat.example.com";
+
+fn settings(origins: OriginPolicy) -> ServerSettings {
+    ServerSettings {
+        origins,
+        ..test_settings()
+    }
+}
+
+/// `Origin` を付けて接続し、断られればステータスコードを返す
+async fn upgrade(addr: SocketAddr, origin: &str) -> Result<(), StatusCode> {
+    let mut request = format!("ws://{}/ws", addr).into_client_request().unwrap();
+    request
+        .headers_mut()
+        .insert(header::ORIGIN, HeaderValue::from_str(origin).unwrap());
+    match connect_async(request).await {
+        Ok(_) => Ok(()),
+        Err(tungstenite::Error::Http(response)) => Err(response.status()),
+        Err(e) => panic!("failed to connect: {}", e),
+    }
+}
+
+async fn preflight(state: &AppState, origin: &str) -> Option<String> {
+    let request = Request::builder()
+        .method(Method::OPTIONS)
+        .uri("/api/health.json")
+        .header(header::ORIGIN, origin)
+        .header(header::ACCESS_CONTROL_REQUEST_METHOD, "POST")
+        .body(Body::empty())
+        .unwrap();
+    let response = app::router(state.clone()).oneshot(request).await.unwrap();
+    response
+        .headers()
+        .get(header::ACCESS_CONTROL_ALLOW_ORIGIN)
+        .map(|value| value.to_str().unwrap().to_string())
+}
+
+#[tokio::test]
+async fn test_websocket_origin_allow_list() {
+    let state = AppState::new(settings(OriginPolicy::allow(vec![ALLOWED.to_string()])));
+    let addr = start_server(state.clone()).await;
+
+    assert_eq!(upgrade(addr, ALLOWED).await, Ok(()));
+    assert_eq!(
+        upgrade(addr, "https://evil.example").await,
+        Err(StatusCode::FORBIDDEN)
+    );
+    // 同じオリジンのページと、Origin を付けないクライアントは許す
+    assert_eq!(upgrade(addr, &format!("http://{}", addr)).await, Ok(()));
+    connect(addr).await;
+
+    let metrics = state.metrics.render(&state.manager);
+    assert!(
+        metrics.contains("ws_s_origin_rejections_total 1"),
+        "{}",
+        metrics
+    );
+
+    assert_eq!(preflight(&state, ALLOWED).await.as_deref(), Some(ALLOWED));
+    assert_eq!(preflight(&state, "https://evil.example").await, None);
+}
+
+#[tokio::test]
+async fn test_deny_all_and_credentials() {
+    let addr = start_server(AppState::new(settings(OriginPolicy::deny_all()))).await;
+    assert_eq!(upgrade(addr, ALLOWED).await, Err(StatusCode::FORBIDDEN));
+
+    let state = AppState::new(settings(OriginPolicy {
+        allow_credentials: true,
+        methods: vec![Method::GET],
+        ..OriginPolicy::allow(vec![ALLOWED.to_string()])
+    }));
+    let request = Request::builder()
+        .method(Method::OPTIONS)
+        .uri("/api/health.json")
+        .header(header::ORIGIN, ALLOWED)
+        .header(header::ACCESS_CONTROL_REQUEST_METHOD, "GET")
+        .header(header::ACCESS_CONTROL_REQUEST_HEADERS, "authorization")
+        .body(Body::empty())
+        .unwrap();
+    let response = app::router(state).oneshot(request).await.unwrap();
+    let headers = response.headers();
+    assert_eq!(headers[header::ACCESS_CONTROL_ALLOW_CREDENTIALS], "true");
+    assert_eq!(
+        headers[header::ACCESS_CONTROL_ALLOW_HEADERS],
+        "authorization"
+    );
+    assert_eq!(headers[header::ACCESS_CONTROL_ALLOW_METHODS], "GET");
+}
@@ -0,0 +1,121 @@
+mod common;
+
+use common::{
+    chat_frame, connect, expect_error, expect_text, list_frame, start_server, test_settings,
+};
+use futures_util::{SinkExt, StreamExt};
+use message_pack::{BinarySerializable, ErrorCode, UploadChunkMessage};
+use std::time::{Duration, Instant};
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::tungstenite::Message;
+use ws_s::app::{AppState, ServerSettings};
+use ws_s::rate_limit::{RateLimit, RateLimitSettings};
+
+fn settings() -> ServerSettings {
+    ServerSettings {
+        rate_limits: RateLimitSettings {
+            // 試験の間に補充されないよう、ゆっくりにする
+            chat: RateLimit::new(0.1, 3),
+            warnings: 1,
+            throttles: 1,
+            throttle_delay: Duration::from_millis(200),
+            ..RateLimitSettings::default()
+        },
+        ..test_settings()
+    }
+}
+
+#[tokio::test]
+async fn test_flooding_escalates_to_disconnect() {
+    let state = AppState::new(settings());
+    let addr = start_server(state.clone()).await;
+    let mut flooder = connect(addr).await;
+    let mut bystander = connect(addr).await;
+
+    for i in 0..3 {
+        flooder
+            .send(chat_frame("Alice", 1, &format!("spam {}", i)))
+            .await
+            .unwrap();
+        assert_eq!(
+            expect_text(&mut flooder).await,
+            format!("[Room 1 - Alice]: spam {}", i)
+        );
+    }
+    // 他の種類のフレームは別に数える
+    flooder.send(list_frame("Alice", 1, "files")).await.unwrap();
+    expect_text(&mut flooder).await;
+
+    // 1回目は警告だけ
+    flooder.send(chat_frame("Alice", 1, "spam")).await.unwrap();
+    let error = expect_error(&mut flooder).await;
+    assert_eq!(error.code, ErrorCode::RateLimited);
+    assert_eq!(error.reason, "too many chat frames; slow down");
+
+    // 2回目は受信を遅らせる
+    let throttled = Instant::now();
+    flooder.send(chat_frame("Alice", 1, "spam")).await.unwrap();
+    flooder.send(chat_frame("Alice", 1, "spam")).await.unwrap();
+    assert_eq!(
+        expect_error(&mut flooder).await.code,
+        ErrorCode::RateLimited
+    );
+
+    // 3回目で切断
+    match flooder.next().await {
+        Some(Ok(Message::Close(Some(frame)))) => assert_eq!(frame.code, CloseCode::Policy),
+        other => panic!("expected close frame, got {:?}", other),
+    }
+    assert!(throttled.elapsed() >= Duration::from_millis(200));
+
+    let metrics = state.metrics.render(&state.manager);
+    assert!(metrics.contains("ws_s_rate_limited_total 3"), "{}", metrics);
+    assert!(
+        metrics.contains("ws_s_rate_limit_disconnects_total 1"),
+        "{}",
+        metrics
+    );
+
+    // 捨てたフレームは誰にも届いていない
+    for i in 0..3 {
+        assert_eq!(
+            expect_text(&mut bystander).await,
+            format!("[Room 1 - Alice]: spam {}", i)
+        );
+    }
+    bystander
+        .send(chat_frame("Bob", 1, "still here"))
+        .await
+        .unwrap();
+    assert_eq!(
+        expect_text(&mut bystander).await,
+        "[Room 1 - Bob]: still here"
+    );
+}
+
+#[tokio::test]
+async fn test_upload_chunks_are_limited() {
+    let state = AppState::new(ServerSettings {
+        rate_limits: RateLimitSettings {
+            chunk: RateLimit::new(0.1, 2),
+            ..RateLimitSettings::default()
+        },
+        ..test_settings()
+    });
+    let addr = start_server(state).await;
+    let mut client = connect(addr).await;
+
+    // 始めていないアップロードへのチャンクでも数える
+    let chunk = Message::Binary(UploadChunkMessage::new([1; 16], 0, b"data".to_vec()).to_bytes());
+    for _ in 0..2 {
+        client.send(chunk.clone()).await.unwrap();
+        assert_eq!(
+            expect_error(&mut client).await.code,
+            ErrorCode::UnknownUpload
+        );
+    }
+    client.send(chunk).await.unwrap();
+    let error = expect_error(&mut client).await;
+    assert_eq!(error.code, ErrorCode::RateLimited);
+    assert_eq!(error.reason, "too many upload chunk frames; slow down");
+}
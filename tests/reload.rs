@@ -0,0 +1,215 @@
+mod common;
+
+use axum::body::{to_bytes, Body};
+use axum::http::{header, Method};
+use common::{
+    admin_request, chat_frame, connect, expect_error, expect_text, start_server, temp_dir,
+    with_admin,
+};
+use futures_util::SinkExt;
+use message_pack::ErrorCode;
+use serde_json::Value;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time::Duration;
+use tokio_tungstenite::connect_async;
+use tokio_tungstenite::tungstenite;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::http::{HeaderValue, StatusCode};
+use tower::ServiceExt;
+use ws_s::app::{self, AppState, ServerSettings};
+use ws_s::config::{Config, ConfigLayer, InvalidConfig};
+use ws_s::reload::{self, ReloadTrigger};
+
+const ALLOWED: &str = "https://chat.example.com";
+
+fn config(content: &str) -> Config {
+    Config::resolve(ConfigLayer::parse(content).unwrap()).unwrap()
+}
+
+fn state(config: &Config) -> AppState {
+    AppState::new(ServerSettings {
+        upload_dir: config.upload_dir.clone(),
+        max_protocol_errors: config.max_protocol_errors,
+        upload_limits: config.upload_limits,
+        rate_limits: config.rate_limits,
+        origins: config.origins.clone(),
+        ..ServerSettings::default()
+    })
+}
+
+async fn upgrade(addr: SocketAddr, origin: &str) -> Result<(), StatusCode> {
+    let mut request = format!("ws://{}/ws", addr).into_client_request().unwrap();
+    request
+        .headers_mut()
+        .insert(header::ORIGIN, HeaderValue::from_str(origin).unwrap());
+    match connect_async(request).await {
+        Ok(_) => Ok(()),
+        Err(tungstenite::Error::Http(response)) => Err(response.status()),
+        Err(e) => panic!("failed to connect: {}", e),
+    }
+}
+
+async fn admin(state: &AppState, method: Method) -> (StatusCode, Value) {
+    let request = admin_request(method, "/api/admin/reload")
+        .body(Body::empty())
+        .unwrap();
+    let response = app::router(with_admin(state))
+        .oneshot(request)
+        .await
+        .unwrap();
+    let status = response.status();
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    (status, serde_json::from_slice(&body).unwrap())
+}
+
+/// `run` に渡す読み込み。サーバーの `--config` と同じく設定ファイルだけを読む
+fn load(path: &Path) -> Result<Config, Vec<String>> {
+    let layer = ConfigLayer::load(path).map_err(|e| vec![e.to_string()])?;
+    Config::resolve(layer)
+        .map_err(|InvalidConfig(errors)| errors.iter().map(ToString::to_string).collect())
+}
+
+#[tokio::test]
+async fn test_reload_applies_to_running_connections() {
+    let dir = temp_dir("reload");
+    let mut running = config(&format!(
+        "[server]\nupload_dir = {:?}\n[limits]\nchat_rate = \"1:1\"\n",
+        dir
+    ));
+    let state = state(&running);
+    let addr = start_server(state.clone()).await;
+    let mut client = connect(addr).await;
+    assert_eq!(upgrade(addr, "https://evil.example").await, Ok(()));
+
+    client.send(chat_frame("Alice", 1, "one")).await.unwrap();
+    assert_eq!(expect_text(&mut client).await, "[Room 1 - Alice]: one");
+    client.send(chat_frame("Alice", 1, "two")).await.unwrap();
+    assert_eq!(expect_error(&mut client).await.code, ErrorCode::RateLimited);
+
+    let next = config(&format!(
+        r#"
+[server]
+bind = "127.0.0.1:9000"
+mode = "production"
+upload_dir = {:?}
+
+[limits]
+chat_rate = "0"
+user_quota = "1MiB"
+
+[cors]
+allowed_origins = ["{}"]
+
+[history]
+replay = 1
+
+[send_queue]
+size = 10
+"#,
+        dir, ALLOWED
+    ));
+    let report = reload::apply(&state, &mut running, next, ReloadTrigger::Api);
+    assert!(report.success, "{:?}", report);
+    assert_eq!(
+        report.applied,
+        [
+            "server.mode",
+            "limits.user_quota",
+            "limits.chat_rate",
+            "cors.allowed_origins",
+            "history.replay"
+        ]
+    );
+    assert_eq!(report.restart_required, ["server.bind", "send_queue.size"]);
+    assert_eq!(report.bans, Some(0));
+    assert_eq!(state.settings.current().history_replay, 1);
+
+    // 切らずに、続けて送れるようになる
+    for i in 0..5 {
+        client
+            .send(chat_frame("Alice", 1, &format!("more {}", i)))
+            .await
+            .unwrap();
+        assert_eq!(
+            expect_text(&mut client).await,
+            format!("[Room 1 - Alice]: more {}", i)
+        );
+    }
+    assert_eq!(state.quota.limits().user_quota, Some(1024 * 1024));
+    assert_eq!(
+        upgrade(addr, "https://evil.example").await,
+        Err(StatusCode::FORBIDDEN)
+    );
+    assert_eq!(upgrade(addr, ALLOWED).await, Ok(()));
+
+    // 再起動が要る項目は元のままにして、報告し続ける
+    let again = config(&format!(
+        "[server]\nbind = \"127.0.0.1:9000\"\nmode = \"production\"\nupload_dir = {:?}\n\
+         [limits]\nchat_rate = \"0\"\nuser_quota = \"1MiB\"\n\
+         [cors]\nallowed_origins = [\"{}\"]\n\
+         [history]\nreplay = 1\n[send_queue]\nsize = 10\n",
+        dir, ALLOWED
+    ));
+    let report = reload::apply(&state, &mut running, again, ReloadTrigger::Signal);
+    assert!(report.applied.is_empty(), "{:?}", report);
+    assert_eq!(report.restart_required, ["server.bind", "send_queue.size"]);
+}
+
+#[tokio::test]
+async fn test_reload_from_api_and_file() {
+    let dir = temp_dir("reload");
+    let path = dir.join("ws_s.toml");
+    std::fs::write(&path, "[limits]\nmax_protocol_errors = 5\n").unwrap();
+    let running = load(&path).unwrap();
+    let state = state(&running);
+    tokio::spawn(reload::run(state.clone(), running, Some(path.clone()), {
+        let path = path.clone();
+        move || load(&path)
+    }));
+
+    let (status, _) = admin(&state, Method::GET).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+
+    std::fs::write(&path, "[limits]\nmax_protocol_errors = 2\n").unwrap();
+    let (status, report) = admin(&state, Method::POST).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(report["trigger"], "api");
+    assert_eq!(report["success"], true);
+    assert_eq!(report["applied"][0], "limits.max_protocol_errors");
+    assert_eq!(state.settings.current().max_protocol_errors, 2);
+
+    // 誤りがあれば今の設定を残す
+    std::fs::write(&path, "[limits]\nmax_upload_size = \"huge\"\n").unwrap();
+    let (_, report) = admin(&state, Method::POST).await;
+    assert_eq!(report["success"], false);
+    assert_eq!(
+        report["errors"][0],
+        "limits.max_upload_size: invalid size `huge`"
+    );
+    assert_eq!(state.settings.current().max_protocol_errors, 2);
+
+    // 書き換えただけで読み直す
+    std::fs::write(&path, "[limits]\nmax_protocol_errors = 7\n").unwrap();
+    tokio::time::timeout(Duration::from_secs(10), async {
+        while state.settings.current().max_protocol_errors != 7 {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    })
+    .await
+    .expect("file change was not picked up");
+    let (_, report) = admin(&state, Method::GET).await;
+    assert_eq!(report["trigger"], "file");
+
+    let metrics = state.metrics.render(&state.manager);
+    assert!(
+        metrics.contains("ws_s_config_reloads_total 2"),
+        "{}",
+        metrics
+    );
+    assert!(
+        metrics.contains("ws_s_config_reload_failures_total 1"),
+        "{}",
+        metrics
+    );
+}
@@ -0,0 +1,192 @@
+mod common;
+
+use axum::body::{to_bytes, Body};
+use axum::http::{Request, StatusCode};
+use common::{
+    chat_frame, connect, connect_with_query, expect_error, expect_text, file_frame, join_frame,
+    list_frame, start_server, test_settings,
+};
+use futures_util::SinkExt;
+use message_pack::ErrorCode;
+use serde_json::Value;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tower::ServiceExt;
+use ws_s::app::{self, AppState, ServerSettings};
+use ws_s::auth::{Role, RolePolicy, TokenFile};
+
+async fn start(roles: &str, tokens: Option<&str>) -> SocketAddr {
+    let settings = ServerSettings {
+        roles: RolePolicy::parse(roles, Role::Member).unwrap(),
+        ..test_settings()
+    };
+    let mut state = AppState::new(settings);
+    if let Some(tokens) = tokens {
+        state = state.with_auth(Arc::new(TokenFile::parse(tokens).unwrap()));
+    }
+    start_server(state).await
+}
+
+#[tokio::test]
+async fn test_guest_can_read_but_not_post_or_upload() {
+    let addr = start(
+        "gary:guest\nmia:moderator\n",
+        Some("gary:gary-token\nmia:mia-token\n"),
+    )
+    .await;
+    let mut mia = connect_with_query(addr, "?token=mia-token").await;
+    let mut gary = connect_with_query(addr, "?token=gary-token").await;
+
+    // 誰もいないルームを作れるのはメンバー以上
+    gary.send(join_frame("gary", 2)).await.unwrap();
+    assert_eq!(
+        expect_error(&mut gary).await.code,
+        ErrorCode::PermissionDenied
+    );
+
+    mia.send(join_frame("mia", 1)).await.unwrap();
+    mia.send(chat_frame("mia", 1, "first")).await.unwrap();
+    assert_eq!(expect_text(&mut mia).await, "[Room 1 - mia]: first");
+    // ルームに入るまでは全ルームのメッセージが届く
+    assert_eq!(expect_text(&mut gary).await, "[Room 1 - mia]: first");
+    gary.send(join_frame("gary", 1)).await.unwrap();
+    // 読むのはゲストでもできる
+    assert_eq!(expect_text(&mut gary).await, "[Room 1 - mia]: first");
+
+    gary.send(chat_frame("gary", 1, "hi")).await.unwrap();
+    let error = expect_error(&mut gary).await;
+    assert_eq!(error.code, ErrorCode::PermissionDenied);
+    assert_eq!(error.reason, "guest is not allowed to post");
+    gary.send(file_frame("gary", 1, "a.txt", b"hello".to_vec()))
+        .await
+        .unwrap();
+    assert_eq!(
+        expect_error(&mut gary).await.code,
+        ErrorCode::PermissionDenied
+    );
+
+    mia.send(chat_frame("mia", 1, "welcome")).await.unwrap();
+    assert_eq!(expect_text(&mut mia).await, "[Room 1 - mia]: welcome");
+    assert_eq!(expect_text(&mut gary).await, "[Room 1 - mia]: welcome");
+
+    // UUID を見られるのはモデレーター以上
+    gary.send(list_frame("gary", 1, "socket")).await.unwrap();
+    let listing = expect_text(&mut gary).await;
+    let mut lines: Vec<&str> = listing.lines().collect();
+    lines.sort();
+    assert_eq!(lines, ["gary (room: 1)", "mia (room: 1)"]);
+
+    mia.send(list_frame("mia", 1, "socket")).await.unwrap();
+    let listing = expect_text(&mut mia).await;
+    assert_eq!(listing.lines().count(), 2);
+    assert!(
+        listing
+            .lines()
+            .all(|line| line.contains("queue: ") && uuid::Uuid::parse_str(&line[..36]).is_ok()),
+        "{}",
+        listing
+    );
+}
+
+#[tokio::test]
+async fn test_roles_per_room() {
+    // 認証しない接続にも `*` の役割が当てはまる
+    let addr = start("*@7:guest\n", None).await;
+    let mut client = connect(addr).await;
+
+    // 役割を決めてあるルームは誰もいなくても入れる
+    client.send(join_frame("anon", 7)).await.unwrap();
+    client.send(chat_frame("anon", 7, "hi")).await.unwrap();
+    assert_eq!(
+        expect_error(&mut client).await.code,
+        ErrorCode::PermissionDenied
+    );
+
+    client.send(join_frame("anon", 8)).await.unwrap();
+    client.send(chat_frame("anon", 8, "hi")).await.unwrap();
+    assert_eq!(expect_text(&mut client).await, "[Room 8 - anon]: hi");
+
+    // 権限の不足ではエラー予算を使わない
+    for _ in 0..10 {
+        client.send(chat_frame("anon", 7, "hi")).await.unwrap();
+        assert_eq!(
+            expect_error(&mut client).await.code,
+            ErrorCode::PermissionDenied
+        );
+    }
+    client.send(list_frame("anon", 8, "socket")).await.unwrap();
+    assert_eq!(expect_text(&mut client).await, "anonymous (room: 8)");
+}
+
+#[tokio::test]
+async fn test_http_reads_follow_roles() {
+    let settings = ServerSettings {
+        roles: RolePolicy::parse("*@5:none\nmia@5:member\n", Role::Member).unwrap(),
+        ..test_settings()
+    };
+    let state = AppState::new(settings).with_auth(Arc::new(
+        TokenFile::parse("mia:mia-token\nbob:bob-token\n").unwrap(),
+    ));
+    let addr = start_server(state.clone()).await;
+    let get = |uri: String| {
+        let router = app::router(state.clone());
+        async move {
+            let request = Request::get(uri).body(Body::empty()).unwrap();
+            let response = router.oneshot(request).await.unwrap();
+            let status = response.status();
+            let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+            (status, serde_json::from_slice::<Value>(&body).ok())
+        }
+    };
+
+    let mut mia = connect_with_query(addr, "?token=mia-token").await;
+    mia.send(join_frame("mia", 5)).await.unwrap();
+    mia.send(chat_frame("mia", 5, "secret")).await.unwrap();
+    assert_eq!(expect_text(&mut mia).await, "[Room 5 - mia]: secret");
+    for room in [5, 1] {
+        mia.send(file_frame("mia", room, "a.txt", b"hello".to_vec()))
+            .await
+            .unwrap();
+        expect_text(&mut mia).await;
+        if room == 5 {
+            expect_text(&mut mia).await;
+        }
+    }
+    let secret = state.uploads.list_room(5)[0].id.clone();
+
+    // 認証しなければ何も読めない
+    let (status, _) = get("/api/files".to_string()).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let (status, _) = get("/api/rooms/1/messages".to_string()).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+    // 読めないルームのものは一覧に出さず、直接取りに来ても断る
+    let (status, body) = get("/api/files?token=bob-token".to_string()).await;
+    assert_eq!(status, StatusCode::OK);
+    let files = body.unwrap()["files"].as_array().unwrap().clone();
+    assert_eq!(files.len(), 1);
+    assert_eq!(files[0]["room"], 1);
+    for uri in [
+        "/api/files?room=5&token=bob-token".to_string(),
+        format!("/api/files/{}?token=bob-token", secret),
+        format!("/api/files/{}/thumbnail?token=bob-token", secret),
+        "/api/rooms/5/messages?token=bob-token".to_string(),
+    ] {
+        assert_eq!(get(uri.clone()).await.0, StatusCode::FORBIDDEN, "{}", uri);
+    }
+
+    let (status, body) = get("/api/rooms/5/messages?token=mia-token".to_string()).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body.unwrap()["messages"][0]["content"], "secret");
+    let (status, body) = get("/api/files?token=mia-token".to_string()).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body.unwrap()["files"].as_array().unwrap().len(), 2);
+
+    // WebSocket でも入れない
+    let mut bob = connect_with_query(addr, "?token=bob-token").await;
+    bob.send(join_frame("bob", 5)).await.unwrap();
+    assert_eq!(
+        expect_error(&mut bob).await.code,
+        ErrorCode::PermissionDenied
+    );
+}
@@ -0,0 +1,49 @@
+mod common;
+
+use common::{connect, expect_text, join_frame, list_frame, start_server, test_settings};
+use futures_util::{SinkExt, StreamExt};
+use std::time::Duration;
+use tokio_tungstenite::connect_async;
+use tokio_tungstenite::tungstenite::http::StatusCode;
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::tungstenite::{self, Message};
+use ws_s::app::AppState;
+use ws_s::shutdown::{self, SHUTDOWN_MESSAGE};
+
+#[tokio::test]
+async fn test_shutdown_notifies_and_drains() {
+    let state = AppState::new(test_settings());
+    let addr = start_server(state.clone()).await;
+    let mut clients = vec![connect(addr).await, connect(addr).await];
+    clients[1].send(join_frame("Bob", 3)).await.unwrap();
+    // 登録を待つ
+    for client in &mut clients {
+        client.send(list_frame("", 3, "files")).await.unwrap();
+        expect_text(client).await;
+    }
+
+    shutdown::begin(&state);
+    for client in &mut clients {
+        assert_eq!(expect_text(client).await, SHUTDOWN_MESSAGE);
+        match client.next().await {
+            Some(Ok(Message::Close(Some(frame)))) => {
+                assert_eq!(frame.code, CloseCode::Away);
+                assert_eq!(frame.reason, SHUTDOWN_MESSAGE);
+            }
+            other => panic!("expected close frame, got {:?}", other),
+        }
+    }
+
+    // 新しい接続は断る
+    match connect_async(format!("ws://{}/ws", addr)).await {
+        Err(tungstenite::Error::Http(response)) => {
+            assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE)
+        }
+        other => panic!("expected 503, got {:?}", other.map(|_| ())),
+    }
+
+    tokio::time::timeout(Duration::from_secs(5), state.shutdown.drained())
+        .await
+        .expect("connections were not drained");
+    assert!(state.manager.is_empty());
+}
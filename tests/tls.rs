@@ -0,0 +1,149 @@
+mod common;
+
+use common::{chat_frame, expect_text, temp_dir, test_settings, Client};
+use futures_util::{SinkExt, StreamExt};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::net::TcpListener;
+use tokio_tungstenite::tungstenite::{self, Message};
+use tokio_tungstenite::{connect_async_tls_with_config, Connector};
+use ws_s::app::{self, AppState};
+use ws_s::config::{Config, ConfigLayer};
+use ws_s::reload::{self, ReloadTrigger};
+use ws_s::tls::{self, TlsCerts, TlsFiles};
+
+/// `localhost` 向けの自己署名の証明書を作る。証明書がそのまま CA になる
+fn self_signed(dir: &Path, name: &str) -> TlsFiles {
+    let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+    let files = TlsFiles {
+        cert: dir.join(format!("{}.crt", name)),
+        key: dir.join(format!("{}.key", name)),
+    };
+    std::fs::write(&files.cert, cert.cert.pem()).unwrap();
+    std::fs::write(&files.key, cert.key_pair.serialize_pem()).unwrap();
+    files
+}
+
+async fn start_tls_server(state: AppState, certs: Arc<TlsCerts>) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let config = tls::server_config(certs).unwrap();
+    tokio::spawn(tls::serve(
+        listener,
+        app::router(state),
+        config,
+        std::future::pending(),
+    ));
+    addr
+}
+
+async fn connect(
+    addr: SocketAddr,
+    ca_file: Option<&Path>,
+    insecure: bool,
+) -> Result<Client, tungstenite::Error> {
+    let config = tls::client_config(ca_file, insecure).unwrap();
+    let url = format!("wss://localhost:{}/ws", addr.port());
+    let connector = Connector::Rustls(Arc::new(config));
+    let (mut client, _) = connect_async_tls_with_config(url, None, false, Some(connector)).await?;
+    match client.next().await {
+        Some(Ok(Message::Text(text))) => assert_eq!(text, "connected(server)"),
+        other => panic!("unexpected greeting: {:?}", other),
+    }
+    Ok(client)
+}
+
+fn config(content: &str) -> Config {
+    Config::resolve(ConfigLayer::parse(content).unwrap()).unwrap()
+}
+
+fn tls_section(files: &TlsFiles) -> String {
+    format!(
+        "[tls]\ncert_file = {:?}\nkey_file = {:?}\n",
+        files.cert, files.key
+    )
+}
+
+#[tokio::test]
+async fn test_wss_and_https() {
+    let dir = temp_dir("tls");
+    let files = self_signed(&dir, "server");
+    let certs = Arc::new(TlsCerts::load(&files).unwrap());
+    let addr = start_tls_server(AppState::new(test_settings()), certs).await;
+
+    let mut client = connect(addr, Some(&files.cert), false).await.unwrap();
+    client.send(chat_frame("Alice", 1, "hello")).await.unwrap();
+    assert_eq!(expect_text(&mut client).await, "[Room 1 - Alice]: hello");
+
+    // 組み込みのルート証明書では自己署名の証明書を信頼しない
+    let error = connect(addr, None, false).await.err().unwrap();
+    assert!(error.to_string().contains("UnknownIssuer"), "{}", error);
+    assert!(connect(addr, None, true).await.is_ok());
+
+    let http = reqwest::Client::builder()
+        .use_preconfigured_tls(tls::client_config(Some(&files.cert), false).unwrap())
+        .build()
+        .unwrap();
+    let response = http
+        .get(format!("https://localhost:{}/api/health.json", addr.port()))
+        .send()
+        .await
+        .unwrap();
+    assert!(response.status().is_success());
+}
+
+#[tokio::test]
+async fn test_reload_certificate() {
+    let dir = temp_dir("tls");
+    let old = self_signed(&dir, "old");
+    let new = self_signed(&dir, "new");
+    let certs = Arc::new(TlsCerts::load(&old).unwrap());
+    let state = AppState::new(test_settings()).with_tls(certs.clone());
+    let addr = start_tls_server(state.clone(), certs).await;
+    let mut client = connect(addr, Some(&old.cert), false).await.unwrap();
+
+    let mut running = config(&tls_section(&old));
+    let report = reload::apply(
+        &state,
+        &mut running,
+        config(&tls_section(&new)),
+        ReloadTrigger::Signal,
+    );
+    assert!(report.success, "{:?}", report);
+    assert_eq!(report.applied, ["tls.cert_file", "tls.key_file"]);
+    assert_eq!(running.tls, Some(new.clone()));
+
+    // 新しい接続から新しい証明書を使い、繋がっている接続はそのまま
+    assert!(connect(addr, Some(&old.cert), false).await.is_err());
+    assert!(connect(addr, Some(&new.cert), false).await.is_ok());
+    client
+        .send(chat_frame("Alice", 1, "still here"))
+        .await
+        .unwrap();
+    assert_eq!(
+        expect_text(&mut client).await,
+        "[Room 1 - Alice]: still here"
+    );
+
+    // 鍵が合わなければ今の証明書を使い続ける
+    let broken = TlsFiles {
+        cert: old.cert.clone(),
+        key: new.key.clone(),
+    };
+    let report = reload::apply(
+        &state,
+        &mut running,
+        config(&tls_section(&broken)),
+        ReloadTrigger::Api,
+    );
+    assert!(!report.success);
+    assert_eq!(running.tls, Some(new.clone()));
+    assert!(connect(addr, Some(&new.cert), false).await.is_ok());
+
+    // TLS をやめるには再起動が要る
+    let report = reload::apply(&state, &mut running, config(""), ReloadTrigger::Api);
+    assert!(report.success, "{:?}", report);
+    assert_eq!(report.restart_required, ["tls.cert_file"]);
+    assert_eq!(running.tls, Some(new));
+}
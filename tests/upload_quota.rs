@@ -0,0 +1,105 @@
+mod common;
+
+use axum::body::{to_bytes, Body};
+use axum::http::Method;
+use common::{
+    admin_request, connect, expect_error, expect_text, file_frame, start_server, test_settings,
+    with_admin,
+};
+use futures_util::SinkExt;
+use message_pack::{BinarySerializable, ErrorCode, ListMessage, MessageType};
+use serde_json::Value;
+use tokio_tungstenite::tungstenite::Message;
+use tower::ServiceExt;
+use ws_s::app::{self, AppState, ServerSettings};
+use ws_s::upload::UploadLimits;
+
+fn settings() -> ServerSettings {
+    ServerSettings {
+        upload_limits: UploadLimits {
+            max_file_size: 100,
+            user_quota: Some(150),
+            room_quota: None,
+        },
+        ..test_settings()
+    }
+}
+
+#[tokio::test]
+async fn test_uploads_over_limit_are_refused() {
+    let state = AppState::new(settings());
+    let addr = start_server(state.clone()).await;
+    let mut client = connect(addr).await;
+
+    client
+        .send(file_frame("Alice", 42, "big.bin", vec![0; 101]))
+        .await
+        .unwrap();
+    assert_eq!(
+        expect_error(&mut client).await.code,
+        ErrorCode::FileTooLarge
+    );
+
+    client
+        .send(file_frame("Alice", 42, "a.bin", vec![0; 100]))
+        .await
+        .unwrap();
+    assert!(expect_text(&mut client)
+        .await
+        .contains("bytes transferred. (saved as a.bin)"));
+    assert!(expect_text(&mut client)
+        .await
+        .starts_with("[Room 42 - Alice] shared a.bin"));
+
+    client
+        .send(file_frame("Alice", 42, "b.bin", vec![0; 60]))
+        .await
+        .unwrap();
+    assert_eq!(
+        expect_error(&mut client).await.code,
+        ErrorCode::QuotaExceeded
+    );
+    assert_eq!(state.uploads.list().len(), 1);
+
+    // 他のユーザーの容量は別に数える
+    client
+        .send(file_frame("Bob", 42, "b.bin", vec![0; 60]))
+        .await
+        .unwrap();
+    assert!(expect_text(&mut client).await.contains("saved as b.bin"));
+    assert!(expect_text(&mut client).await.contains("shared b.bin"));
+
+    client
+        .send(Message::Binary(
+            ListMessage {
+                sender: "Alice".to_string(),
+                target: "files".to_string(),
+                room: 42,
+                category: MessageType::List,
+            }
+            .to_bytes(),
+        ))
+        .await
+        .unwrap();
+    let listing = expect_text(&mut client).await;
+    assert!(listing.contains("user Alice: 100 B / 150 B"), "{}", listing);
+    assert!(
+        listing.contains("room 42: 160 B / unlimited"),
+        "{}",
+        listing
+    );
+
+    let response = app::router(with_admin(&state))
+        .oneshot(
+            admin_request(Method::GET, "/api/admin/usage")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let usage: Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(usage["limits"]["user_quota"], 150);
+    assert_eq!(usage["usage"]["users"]["Alice"], 100);
+    assert_eq!(usage["usage"]["rooms"]["42"], 160);
+}
@@ -0,0 +1,226 @@
+#![cfg(unix)]
+
+mod common;
+
+use async_trait::async_trait;
+use common::{
+    connect, expect_error, expect_text, file_frame, join_frame, start_server, temp_dir,
+    test_settings,
+};
+use futures_util::{SinkExt, StreamExt};
+use message_pack::{
+    BinarySerializable, ErrorCode, UploadBeginMessage, UploadChunkMessage, UploadCommitMessage,
+};
+use sha2::{Digest, Sha256};
+use std::io;
+use std::os::unix::fs::PermissionsExt;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Notify;
+use tokio_tungstenite::tungstenite::Message;
+use ws_s::app::{AppState, ServerSettings};
+use ws_s::upload::{
+    CommandScanner, ScanPipeline, ScanTarget, UploadScanner, Verdict, QUARANTINE_DIRNAME,
+};
+
+/// ウイルス検査の代わり。`EICAR` を含むファイルを断り、見せられた名前を記録する
+fn fake_antivirus() -> (PathBuf, PathBuf) {
+    let dir = temp_dir("scanner");
+    let log = dir.join("scanned.log");
+    let script = dir.join("scan.sh");
+    std::fs::write(
+        &script,
+        format!(
+            r#"#!/bin/sh
+echo "$WS_S_FILENAME $WS_S_SENDER $WS_S_ROOM" >> '{}'
+if grep -q EICAR "$1"; then
+    echo "Eicar-Test-Signature FOUND"
+    exit 1
+fi
+exit 0
+"#,
+            log.display()
+        ),
+    )
+    .unwrap();
+    std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+    (script, log)
+}
+
+fn alice_usage(state: &AppState) -> u64 {
+    state.quota.usage().users.get("Alice").copied().unwrap_or(0)
+}
+
+#[tokio::test]
+async fn test_command_scanner_rejects_infected_uploads() {
+    let (script, log) = fake_antivirus();
+    let settings = ServerSettings {
+        scanners: ScanPipeline::new()
+            .with(CommandScanner::new(script.to_string_lossy(), Vec::new())),
+        ..test_settings()
+    };
+    let state = AppState::new(settings.clone());
+    let addr = start_server(state.clone()).await;
+    let mut client = connect(addr).await;
+
+    client
+        .send(file_frame("Alice", 42, "notes.txt", b"hello".to_vec()))
+        .await
+        .unwrap();
+    assert!(expect_text(&mut client)
+        .await
+        .contains("saved as notes.txt"));
+    let _shared = expect_text(&mut client).await;
+
+    client
+        .send(file_frame(
+            "Alice",
+            42,
+            "invoice.pdf",
+            b"X5O!P%@AP EICAR test".to_vec(),
+        ))
+        .await
+        .unwrap();
+    let error = expect_error(&mut client).await;
+    assert_eq!(error.code, ErrorCode::UploadRejected);
+    assert_eq!(
+        error.reason,
+        "rejected by scan.sh: Eicar-Test-Signature FOUND"
+    );
+
+    // 分割アップロードも最後に検査する
+    let content = b"EICAR in pieces".to_vec();
+    let upload_id = [3; 16];
+    client
+        .send(Message::Binary(
+            UploadBeginMessage {
+                upload_id,
+                sender: "Alice".to_string(),
+                room: 42,
+                filename: "big.bin".to_string(),
+                size: content.len() as u64,
+                sha256: Sha256::digest(&content).into(),
+            }
+            .to_bytes(),
+        ))
+        .await
+        .unwrap();
+    let _ack = client.next().await;
+    client
+        .send(Message::Binary(
+            UploadChunkMessage::new(upload_id, 0, content).to_bytes(),
+        ))
+        .await
+        .unwrap();
+    let _progress = client.next().await;
+    let _ack = client.next().await;
+    client
+        .send(Message::Binary(
+            UploadCommitMessage { upload_id }.to_bytes(),
+        ))
+        .await
+        .unwrap();
+    assert_eq!(
+        expect_error(&mut client).await.code,
+        ErrorCode::UploadRejected
+    );
+
+    // 断ったファイルは残らず、容量も返す
+    let records = state.uploads.list();
+    assert_eq!(records.len(), 1);
+    assert_eq!(records[0].name, "notes.txt");
+    assert_eq!(alice_usage(&state), 5);
+    let quarantined = std::fs::read_dir(settings.upload_dir.join(QUARANTINE_DIRNAME))
+        .unwrap()
+        .count();
+    assert_eq!(quarantined, 0);
+
+    // 検査には元の名前などを渡す
+    assert_eq!(
+        std::fs::read_to_string(&log).unwrap(),
+        "notes.txt Alice 42\ninvoice.pdf Alice 42\nbig.bin Alice 42\n"
+    );
+}
+
+#[tokio::test]
+async fn test_scanner_that_does_not_finish_rejects() {
+    let scanner = CommandScanner::parse("sh -c 'sleep 10'")
+        .unwrap()
+        .timeout(Duration::from_millis(200));
+    let state = AppState::new(ServerSettings {
+        scanners: ScanPipeline::new().with(scanner),
+        ..test_settings()
+    });
+    let addr = start_server(state.clone()).await;
+    let mut client = connect(addr).await;
+
+    client
+        .send(file_frame("Alice", 42, "notes.txt", b"hello".to_vec()))
+        .await
+        .unwrap();
+    let error = expect_error(&mut client).await;
+    assert_eq!(error.code, ErrorCode::UploadRejected);
+    assert_eq!(
+        error.reason,
+        "rejected by sh: the file could not be scanned"
+    );
+    assert!(state.uploads.list().is_empty());
+    assert_eq!(alice_usage(&state), 0);
+}
+
+/// 止めている間は検査の途中のまま待つ
+#[derive(Clone, Default)]
+struct Gate {
+    entered: Arc<Notify>,
+    release: Arc<Notify>,
+}
+
+#[async_trait]
+impl UploadScanner for Gate {
+    fn name(&self) -> &str {
+        "gate"
+    }
+
+    async fn scan(&self, target: &ScanTarget<'_>) -> io::Result<Verdict> {
+        assert!(target.path.exists());
+        self.entered.notify_one();
+        self.release.notified().await;
+        Ok(Verdict::Accept)
+    }
+}
+
+#[tokio::test]
+async fn test_uploads_stay_hidden_until_every_scanner_passes() {
+    let gate = Gate::default();
+    let state = AppState::new(ServerSettings {
+        scanners: ScanPipeline::new().with(gate.clone()),
+        ..test_settings()
+    });
+    let addr = start_server(state.clone()).await;
+    let mut uploader = connect(addr).await;
+    let mut watcher = connect(addr).await;
+    watcher.send(join_frame("Bob", 42)).await.unwrap();
+
+    uploader
+        .send(file_frame("Alice", 42, "notes.txt", b"hello".to_vec()))
+        .await
+        .unwrap();
+    gate.entered.notified().await;
+
+    // 検査中は一覧にも載らず、ルームにも知らせない
+    assert!(state.uploads.list().is_empty());
+    assert!(
+        tokio::time::timeout(Duration::from_millis(100), watcher.next())
+            .await
+            .is_err()
+    );
+
+    gate.release.notify_one();
+    assert!(expect_text(&mut uploader)
+        .await
+        .contains("saved as notes.txt"));
+    assert!(expect_text(&mut watcher).await.contains("shared notes.txt"));
+    assert_eq!(state.uploads.list().len(), 1);
+}